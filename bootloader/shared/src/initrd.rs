@@ -0,0 +1,18 @@
+//! The initrd on the disk image. kbuild pads the kernel image to `KERNEL_SIZE` and
+//! puts the initrd right after it: a header sector, then the archive.
+
+/// The first bytes of the header sector
+pub const INITRD_MAGIC: [u8; 8] = *b"ORUSTSRD";
+/// The header takes a whole sector
+pub const INITRD_HEADER_SIZE: usize = 512;
+
+/// Parse the header sector, returns the size of the archive after it.
+/// The header is the magic followed by the size as a little endian u32.
+pub fn parse_header(header: &[u8]) -> Option<usize> {
+    if header.len() < INITRD_HEADER_SIZE || header[..8] != INITRD_MAGIC {
+        return None
+    }
+    let mut size = [0_u8; 4];
+    size.copy_from_slice(&header[8..12]);
+    Some(u32::from_le_bytes(size) as usize)
+}
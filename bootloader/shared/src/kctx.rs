@@ -2,14 +2,42 @@
 
 use i386::{
     driver::mem::e820::E820MemInfo, 
-    driver::disk::ata::pio::ATADiskInfo, mem::paging::Paging
+    driver::disk::ata::{pio::ATADisk, ATADriver, ATA_MAX_DISKS},
+    driver::disk::atapi::ATAPIDisk,
+    driver::disk::ahci::{AHCIDisk, AHCIDriver, AHCI_MAX_DISKS},
+    driver::disk::virtio::blk::{VirtIOBlkDisk, VIRTIO_MAX_DISKS},
+    mem::paging::Paging
 };
 
 use crate::mem::MEMINFO_MAX;
 
+/// The disk which the kernel is loaded from
+#[derive(Clone, Copy)]
+pub enum BootDisk {
+    ATA(ATADriver),
+    AHCI(AHCIDriver),
+    VirtIO(VirtIOBlkDisk)
+}
+
+/// The initrd archive loaded into memory by stage 3
+#[derive(Clone, Copy)]
+pub struct Initrd {
+    pub start: usize,
+    pub size: usize
+}
 
 pub struct KernelContext {
-    pub disk_info: ATADiskInfo,
+    /// every ATA disk detected on both IDE channels
+    pub disks: [Option<ATADisk>; ATA_MAX_DISKS],
+    /// every ATAPI device (CD-ROM) detected on both IDE channels
+    pub cdroms: [Option<ATAPIDisk>; ATA_MAX_DISKS],
+    /// every SATA disk detected on the AHCI controller
+    pub sata_disks: [Option<AHCIDisk>; AHCI_MAX_DISKS],
+    /// every virtio block device on the PCI bus
+    pub virtio_disks: [Option<VirtIOBlkDisk>; VIRTIO_MAX_DISKS],
+    pub boot_disk: BootDisk,
+    /// None if the disk image has no initrd
+    pub initrd: Option<Initrd>,
     pub mem_info: E820MemInfo<MEMINFO_MAX>,
     pub kernel_paging: &'static dyn Paging
 }
@@ -41,6 +41,10 @@ pub const STAGE3_END: usize = 327680;
 
 pub const KERNEL_START: usize = 1048576;
 pub const KERNEL_END: usize = 2097152;
+pub const KERNEL_HEAP_START: usize = 2097152;
+pub const KERNEL_HEAP_END: usize = 4194304;
+pub const INITRD_START: usize = 4194304;
+pub const INITRD_END: usize = 4718592;
 
 pub const REAL_MODE_MAX_ADDRESS: usize = 0x100000;
 
@@ -53,3 +57,5 @@ pub const STAGE2_SIZE: usize = STAGE2_END - STAGE2_START;
 pub const STAGE3_SIZE: usize = STAGE3_END - STAGE3_START;
 
 pub const KERNEL_SIZE: usize = KERNEL_END - KERNEL_START;
+pub const KERNEL_HEAP_SIZE: usize = KERNEL_HEAP_END - KERNEL_HEAP_START;
+pub const INITRD_SIZE: usize = INITRD_END - INITRD_START;
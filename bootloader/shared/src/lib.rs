@@ -4,3 +4,4 @@ pub mod layout;
 pub mod gdt;
 pub mod mem;
 pub mod kctx;
+pub mod initrd;
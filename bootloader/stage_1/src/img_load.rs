@@ -3,12 +3,7 @@
 use core::{intrinsics::transmute, marker::PhantomData};
 use i386::{
     utils::disk::size_to_lba,
-    fs::{
-        FSError, 
-        FileSystem, 
-        nofs::real::NoFSReal
-    },
-    driver::disk::dap::DAPError
+    driver::disk::dap::{boot_read, DAPError}
 };
 use shared::layout::{STAGE1_SIZE, STAGE2_SIZE, STAGE2_START};
 
@@ -23,12 +18,16 @@ pub static STAGE2_PTR: PhantomData<()> = PhantomData;
 /// The disk index of the first hard disk.
 pub const STAGE_DISK: u8 = 0x80;
 
+// `boot_read` transfers at most 64KiB and addresses LBAs below 65536
+const _: () = assert!(STAGE2_SIZE <= 0x10000);
+const _: () = assert!(size_to_lba(STAGE1_SIZE + STAGE2_SIZE) <= 0x10000);
+
 #[inline]
-pub fn load_stage2() -> Result<(), FSError<DAPError>> {
-    let fs = NoFSReal::new(STAGE_DISK)?;
+pub fn load_stage2() -> Result<(), DAPError> {
+    // read the disk directly without nofs to keep the boot sector small, stage 2
+    // fits in a single extended read, or is read sector by sector without extensions
     let lba = size_to_lba(STAGE1_SIZE);
     let stage_2 = unsafe { transmute::<usize, &mut [u8; STAGE2_SIZE]>(STAGE2_START) };
 
-    fs.read(lba, stage_2)?;
-    Ok(())
+    boot_read(STAGE_DISK, lba, stage_2)
 }
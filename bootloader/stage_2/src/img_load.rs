@@ -3,9 +3,9 @@
 use core::{intrinsics::transmute, marker::PhantomData};
 
 use i386::{
-    utils::disk::size_to_lba,
-    fs::{FSError, FileSystem, nofs::real::NoFSReal},
-    driver::disk::dap::DAPError
+    utils::disk::{size_to_lba, SECTOR_SIZE},
+    fs::{FileError, FileSystem, nofs::{NoFS, FILE_NAME}},
+    driver::disk::{dap::DAPDisk, block::DeviceError}
 };
 use shared::layout::{
     STAGE1_SIZE, 
@@ -23,15 +23,16 @@ pub static STAGE3_PTR: PhantomData<()> = PhantomData;
 pub const STAGE_DISK: u8 = 0x80;
 
 #[inline]
-pub fn load_stage3() -> Result<(), FSError<DAPError>> {
-    let fs = NoFSReal::new(STAGE_DISK)?;
+pub fn load_stage3() -> Result<(), FileError> {
+    let fs = NoFS::new(DAPDisk::new(STAGE_DISK).map_err(|e| FileError::Io(e.status()))?);
+    let disk = fs.open(FILE_NAME)?;
 
     let stage_3 = unsafe { 
         transmute::<usize, &mut [u8; STAGE3_SIZE]>(STAGE3_START) 
     };
-    let start_lba = size_to_lba(STAGE1_SIZE + STAGE2_SIZE);
+    let start = size_to_lba(STAGE1_SIZE + STAGE2_SIZE) * SECTOR_SIZE as u64;
 
-    fs.read(start_lba, stage_3)?;
+    fs.read_at(disk, start, stage_3)?;
     Ok(())
 }
 
@@ -1,7 +1,10 @@
 use core::fmt::{Arguments, Write};
 use i386::driver::screen::{
+    Color,
+    ColorCode,
     Cursor, 
     Screen, 
+    DEFAULT_COLOR,
     s80x25c16::{Buffer, WIDTH, HEIGHT}
 };
 
@@ -11,6 +14,7 @@ static mut VIDEO_BUFFER: Buffer = [[0; WIDTH]; HEIGHT];
 /// FIXME: consider using lazy_static with a mutex here
 pub static mut SCREEN: Screen<Buffer> = Screen {
     cursor: Cursor(0, 0),
+    color: DEFAULT_COLOR,
     buf: unsafe { &mut VIDEO_BUFFER }
 };
 
@@ -18,10 +22,24 @@ pub fn scr_clear() {
     unsafe { SCREEN.clear() }
 }
 
+/// the color of error messages
+pub const ERROR_COLOR: ColorCode = ColorCode::new(Color::LightRed, Color::Black);
+
 pub fn _print(s: Arguments) -> core::fmt::Result {
     unsafe { SCREEN.write_fmt(s) }
 }
 
+/// print in `color`, then go back to the previous color
+pub fn _print_color(color: ColorCode, s: Arguments) -> core::fmt::Result {
+    unsafe {
+        let prev = SCREEN.color;
+        SCREEN.set_color(color);
+        let res = SCREEN.write_fmt(s);
+        SCREEN.set_color(prev);
+        res
+    }
+}
+
 /// print with format string 
 #[macro_export]
 macro_rules! print {
@@ -34,3 +52,16 @@ macro_rules! println {
     () => (print!("\n"));
     ($($arg:tt)*) => (print!("{}\n", format_args!($($arg)*)));
 }
+
+/// print with format string in a color
+#[macro_export]
+macro_rules! cprint {
+    ($color:expr, $($arg:tt)*) => ($crate::display::_print_color($color, format_args!($($arg)*)).unwrap());
+}
+
+/// print with format string with newline in a color
+#[macro_export]
+macro_rules! cprintln {
+    ($color:expr) => (print!("\n"));
+    ($color:expr, $($arg:tt)*) => (cprint!($color, "{}\n", format_args!($($arg)*)));
+}
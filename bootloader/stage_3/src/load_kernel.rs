@@ -1,34 +1,82 @@
 //! The module provides function for loading kernel from disk into memory.
 //! Since we need to load kernel to 1MB, which exceeds the real mode addressing
-//! limit, we use ATA command to do this work.
+//! limit, we use ATA or AHCI commands to do this work.
+//! The initrd after the kernel image is loaded here too.
 
 use core::{
     intrinsics::transmute,
-    marker::PhantomData
+    marker::PhantomData,
+    slice
 };
-use alloc::string::String;
+use alloc::string::{String, ToString};
 use i386::{
-    utils::disk::size_to_lba,
-    driver::disk::ata::ATAError,
+    utils::disk::{size_to_lba, SECTOR_SIZE},
     fs::{
-        FSError, 
         FileSystem,
-        nofs::protected::NoFSProtected
+        nofs::FILE_NAME
     },
 };
-use shared::layout::*;
+use shared::{
+    layout::*,
+    initrd::{parse_header, INITRD_HEADER_SIZE},
+    kctx::Initrd
+};
 
 #[link_section = ".kernel"]
 pub static KERNEL_PTR: PhantomData<()> = PhantomData;
 
-pub fn load_kernel(fs: &NoFSProtected) -> Result<(), String> {
-    let kernel_lba = size_to_lba(STAGE1_SIZE + STAGE2_SIZE + STAGE3_SIZE);
+/// The byte offset of the kernel image on the boot disk
+fn kernel_offset() -> u64 {
+    size_to_lba(STAGE1_SIZE + STAGE2_SIZE + STAGE3_SIZE) * SECTOR_SIZE as u64
+}
+
+/// Load the kernel and the initrd with the nofs driver of the boot disk
+pub fn load_images(fs: &impl FileSystem) -> Result<Option<Initrd>, String> {
+    load_kernel(fs)?;
+    load_initrd(fs)
+}
+
+/// kbuild pads the kernel image to `KERNEL_SIZE` (`kernel_size()` of the same layout),
+/// so a shorter read means the disk image ends in the middle of the kernel.
+fn load_kernel(fs: &impl FileSystem) -> Result<(), String> {
+    let disk = fs.open(FILE_NAME).map_err(|e| e.to_string())?;
+    let kernel_start = kernel_offset();
     
     let kernel_buf = unsafe { 
         transmute::<usize, &mut [u8; KERNEL_SIZE]>(KERNEL_START)
     };
 
-    fs.read(kernel_lba, kernel_buf)
-        .map_err(|x| <FSError<ATAError> as Into<String>>::into(x))?;
+    let len = fs.read_at(disk, kernel_start, kernel_buf)
+        .map_err(|e| e.to_string())?;
+    if len != KERNEL_SIZE {
+        return Err(String::from("Kernel image truncated."))
+    }
     Ok(())
 }
+
+/// Load the initrd to `INITRD_START`, the disk image may have none
+fn load_initrd(fs: &impl FileSystem) -> Result<Option<Initrd>, String> {
+    let disk = fs.open(FILE_NAME).map_err(|e| e.to_string())?;
+    let initrd_start = kernel_offset() + KERNEL_SIZE as u64;
+
+    let mut header = [0_u8; INITRD_HEADER_SIZE];
+    let len = fs.read_at(disk, initrd_start, &mut header)
+        .map_err(|e| e.to_string())?;
+    let size = match parse_header(&header[..len]) {
+        Some(size) => size,
+        None => return Ok(None)
+    };
+    if size > INITRD_SIZE {
+        return Err(String::from("Initrd too large."))
+    }
+
+    let initrd_buf = unsafe {
+        slice::from_raw_parts_mut(INITRD_START as *mut u8, size)
+    };
+    let len = fs.read_at(disk, initrd_start + INITRD_HEADER_SIZE as u64, initrd_buf)
+        .map_err(|e| e.to_string())?;
+    if len != size {
+        return Err(String::from("Initrd truncated."))
+    }
+    Ok(Some(Initrd { start: INITRD_START, size }))
+}
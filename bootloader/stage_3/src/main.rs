@@ -18,18 +18,20 @@ use core::{
     arch::asm
 };
 use alloc::string::String;
-use display::scr_clear;
+use display::{scr_clear, ERROR_COLOR};
 use i386::{
-    fs::{
-        FSError, 
-        nofs::protected::NoFSProtected
-    },
-    driver::disk::ata::{ATADriver, ATAError}
+    fs::nofs::NoFS,
+    driver::disk::{
+        ata::ATADriver,
+        atapi::ATAPIDriver,
+        ahci::{AHCIController, AHCIMemory, AHCI_MAX_DISKS},
+        virtio::blk::{VirtIOBlkDisk, VirtIOMemory}
+    }
 };
-use load_kernel::load_kernel;
+use load_kernel::load_images;
 use shared::{
     mem::MEMINFO,
-    kctx::KernelContext
+    kctx::{BootDisk, KernelContext}
 };
 use static_alloc::Bump;
 
@@ -39,12 +41,17 @@ use crate::{load_kernel::KERNEL_PTR, paging::{KERNEL_PAGING, enable_paging}};
 #[global_allocator]
 static ALLOC: Bump<[u8; 1 << 16]> = Bump::uninit();
 
+/// command lists and received FIS areas of AHCI ports, kept alive for the kernel
+static mut AHCI_MEM: AHCIMemory = AHCIMemory::new();
+/// virtqueues of virtio block devices, kept alive for the kernel
+static mut VIRTIO_MEM: VirtIOMemory = VirtIOMemory::new();
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     if let Some(msg) = info.message() {
-        println!("Error: {}", msg);
+        cprintln!(ERROR_COLOR, "Error: {}", msg);
     } else {
-        println!("Unknown Error.");
+        cprintln!(ERROR_COLOR, "Unknown Error.");
     }
     unsafe { asm!("hlt") }
     loop {}
@@ -52,7 +59,7 @@ fn panic(info: &PanicInfo) -> ! {
 
 #[alloc_error_handler]
 fn oom(_layout: Layout) -> ! {
-    println!("Alloc Error.");
+    cprintln!(ERROR_COLOR, "Alloc Error.");
     unsafe { asm!("hlt") }
     loop {}
 }
@@ -60,16 +67,37 @@ fn oom(_layout: Layout) -> ! {
 /// The main function of stage 3. 
 /// This function should collect all possible errors so we can deal with them in _start.
 fn main() -> Result<KernelContext, String> {
-    let fs = NoFSProtected::new(ATADriver::PRIMARY)
-        .map_err(|x| <FSError<ATAError> as Into<String>>::into(x))?;
-    load_kernel(&fs)?;
+    let disks = ATADriver::enumerate();
+    let sata_disks = match AHCIController::probe() {
+        Some(hba) => hba.enumerate(unsafe { &mut AHCI_MEM }),
+        None => [None; AHCI_MAX_DISKS]
+    };
+    let virtio_disks = VirtIOBlkDisk::enumerate(unsafe { &mut VIRTIO_MEM });
+
+    // the first ATA disk is the one BIOS booted from (0x80), machines without
+    // legacy IDE (like q35) boot from the first AHCI disk instead, and the
+    // first virtio disk is used if the image is attached with if=virtio
+    let (boot_disk, initrd) = if let Some(disk) = disks.iter().flatten().next() {
+        (BootDisk::ATA(disk.driver), load_images(&NoFS::new(*disk))?)
+    } else if let Some(disk) = sata_disks.iter().flatten().next() {
+        (BootDisk::AHCI(disk.driver), load_images(&NoFS::new(*disk))?)
+    } else if let Some(disk) = virtio_disks.iter().flatten().next() {
+        (BootDisk::VirtIO(*disk), load_images(&NoFS::new(*disk))?)
+    } else {
+        return Err(String::from("No disk found."))
+    };
     println!("Kernel loaded.");
 
     enable_paging();
     // switch to real mode and poweroff, just for illustrating our mode switching works.
     // crate::mode_switch::to_real(crate::mode_switch::poweroff as u16);
     Ok(KernelContext {
-        disk_info: fs.get_disk_info(),
+        disks,
+        cdroms: ATAPIDriver::enumerate(),
+        sata_disks,
+        virtio_disks,
+        boot_disk,
+        initrd,
         mem_info: unsafe { MEMINFO.clone() },
         kernel_paging: &KERNEL_PAGING
     })
@@ -7,8 +7,10 @@ use i386::mem::paging::{
 #[allow(dead_code)]
 const KERNEL_PAGENUM: usize = 2;
 const MB: u64 = 1 << 20;
+const GB: u64 = 1 << 30;
 
-/// 4MB kernel PDT page table entry (directly map virtual address to the same physical address)
+/// 6MB kernel PDT page table entry (directly map virtual address to the same physical address),
+/// the kernel and its heap take the first 4MB, the initrd the last 2MB
 static KERNEL_PDT: PDTable = PDTable::with_entries([
     PDEntry::new_page(
         true, 
@@ -25,9 +27,21 @@ static KERNEL_PDT: PDTable = PDTable::with_entries([
         false, 
         2 * MB, 
         false
+    ),
+    PDEntry::new_page(
+        true, 
+        false, 
+        PATMemoryType::new(false, false, false), 
+        false, 
+        4 * MB, 
+        false
     )
 ]);
 
+/// The top 1GiB is identity mapped with caching disabled, memory mapped I/O
+/// (like the registers of AHCI controllers) lives there.
+static mut MMIO_PDT: PDTable = PDTable::new();
+
 /// kernel top level page table
 static mut KERNEL_PDPT: PDPTable = PDPTable::new();
 
@@ -40,5 +54,22 @@ pub fn enable_paging() {
     )};
 
     unsafe { KERNEL_PDPT.entries[1] = PDPTEntry(0xffffffffffffffff); }
+
+    unsafe {
+        MMIO_PDT.entries.iter_mut().enumerate().for_each(|(i, entry)| {
+            *entry = PDEntry::new_page(
+                true,
+                false,
+                PATMemoryType::new(false, true, true),
+                false,
+                3 * GB + i as u64 * 2 * MB,
+                false
+            )
+        });
+        KERNEL_PDPT.entries[3] = PDPTEntry::new(
+            PATMemoryType::new(false, false, false),
+            &MMIO_PDT as *const PDTable as u64
+        );
+    }
     KERNEL_PAGING.enable();
 }
@@ -3,3 +3,6 @@
 pub mod mem;
 pub mod disk;
 pub mod screen;
+pub mod pci;
+pub mod serial;
+pub mod keyboard;
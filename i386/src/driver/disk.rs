@@ -1,4 +1,9 @@
 //! Drivers for disk access.
 
 pub mod ata;
+pub mod ahci;
+pub mod atapi;
 pub mod dap;
+pub mod block;
+pub mod virtio;
+pub mod ram;
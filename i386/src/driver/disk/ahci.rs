@@ -0,0 +1,530 @@
+//! Support for SATA disks behind an AHCI host bus adapter.
+//! The HBA is found on the PCI bus, its registers are memory mapped at ABAR (BAR5).
+//! Each port owns a command list, a received FIS area and a command table, which
+//! the HBA accesses with DMA. We only use the first command slot of each port and
+//! wait for the completion by polling.
+//! See https://wiki.osdev.org/AHCI
+
+use core::{
+    hint::spin_loop,
+    intrinsics::transmute,
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{compiler_fence, Ordering}
+};
+use super::ata::{
+    ATACommand, ATAError, ATAStatus, ATA_DEFAULT_TIMEOUT,
+    identify::ATADiskInfo,
+    pio::ATAPIOMode
+};
+use crate::{
+    driver::pci::{self, PCIBar, PCIDevice, CMD_BUS_MASTER, CMD_MEM_SPACE},
+    driver::disk::block::BlockDevice,
+    utils::disk::*
+};
+
+/// PCI class code of mass storage controllers
+const PCI_CLASS_STORAGE: u8 = 0x01;
+/// PCI subclass of SATA controllers
+const PCI_SUBCLASS_SATA: u8 = 0x06;
+/// prog if of AHCI 1.0 controllers
+const PCI_PROG_IF_AHCI: u8 = 0x01;
+
+/// HBA capabilities
+const HBA_CAP: u32 = 0x00;
+/// global HBA control
+const HBA_GHC: u32 = 0x04;
+/// ports implemented
+const HBA_PI: u32 = 0x0c;
+
+/// GHC bit 31: AHCI enable
+const GHC_AE: u32 = 1 << 31;
+/// CAP bit 31: supports 64-bit addressing
+const CAP_S64A: u32 = 1 << 31;
+
+/// the port registers start at ABAR + 0x100, each port takes 0x80 bytes
+const PORT_BASE: u32 = 0x100;
+const PORT_SIZE: u32 = 0x80;
+pub const AHCI_MAX_PORTS: usize = 32;
+
+/// command list base address
+const PX_CLB: u32 = 0x00;
+const PX_CLBU: u32 = 0x04;
+/// FIS base address
+const PX_FB: u32 = 0x08;
+const PX_FBU: u32 = 0x0c;
+/// interrupt status
+const PX_IS: u32 = 0x10;
+/// command and status
+const PX_CMD: u32 = 0x18;
+/// task file data, a copy of the ATA status and error registers
+const PX_TFD: u32 = 0x20;
+/// signature of the attached device
+const PX_SIG: u32 = 0x24;
+/// SATA status
+const PX_SSTS: u32 = 0x28;
+/// SATA error
+const PX_SERR: u32 = 0x30;
+/// command issue
+const PX_CI: u32 = 0x38;
+
+/// PxCMD bit 0: start processing the command list
+const CMD_ST: u32 = 1 << 0;
+/// PxCMD bit 4: FIS receive enable
+const CMD_FRE: u32 = 1 << 4;
+/// PxCMD bit 14: FIS receive running
+const CMD_FR: u32 = 1 << 14;
+/// PxCMD bit 15: command list running
+const CMD_CR: u32 = 1 << 15;
+
+/// PxIS bit 30: task file error status
+const IS_TFES: u32 = 1 << 30;
+
+/// PxSSTS bit 0:3, device detected and phy communication established
+const SSTS_DET_PRESENT: u32 = 3;
+/// PxSSTS bit 8:11, interface in active state
+const SSTS_IPM_ACTIVE: u32 = 1;
+
+/// signature of SATA disks, ATAPI devices report 0xEB140101
+const SIG_ATA: u32 = 0x00000101;
+
+/// FIS type of a register FIS sent from host to device
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// set in a register H2D FIS to update the command register
+const FIS_H2D_COMMAND: u8 = 1 << 7;
+/// the LBA bit of the device register
+const FIS_DEVICE_LBA: u8 = 1 << 6;
+
+/// command header bit 6: the direction of the transfer is host to device
+const HDR_WRITE: u16 = 1 << 6;
+
+/// number of command slots in a command list
+const CMD_SLOTS: usize = 32;
+/// a single PRD describes at most 4MiB
+const PRD_MAX_BYTES: usize = 1 << 22;
+/// PRD entries in each command table, enough for the 32MiB of a LBA48 command
+pub const AHCI_PRDT_ENTRIES: usize = 8;
+
+/// The maximum number of disks we can drive, each of them needs an `AHCIPortMemory`
+pub const AHCI_MAX_DISKS: usize = 8;
+
+/// A command header in the command list
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct AHCICmdHeader {
+    /// bit 0:4 is the length of the command FIS in dwords, bit 6 is the direction
+    flags: u16,
+    /// number of PRD entries
+    prdtl: u16,
+    /// bytes transferred, updated by the HBA
+    prdbc: u32,
+    /// physical address of the command table, must be 128 bytes aligned
+    ctba: u32,
+    ctbau: u32,
+    _reserved: [u32; 4]
+}
+
+/// A physical region descriptor in the command table
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct AHCIPRD {
+    /// physical address of the region, must be even
+    dba: u32,
+    dbau: u32,
+    _reserved: u32,
+    /// bit 0:21 is the size of the region minus 1, bit 31 requests an interrupt
+    dbc: u32
+}
+
+/// The command table of a command slot
+#[repr(C, align(128))]
+struct AHCICmdTable {
+    /// command FIS
+    cfis: [u8; 64],
+    /// ATAPI command packet
+    acmd: [u8; 16],
+    _reserved: [u8; 48],
+    prdt: [AHCIPRD; AHCI_PRDT_ENTRIES]
+}
+
+/// The memory accessed by the HBA for a single port. The command list must be
+/// 1KiB aligned and the received FIS area must be 256 bytes aligned.
+#[repr(C, align(1024))]
+pub struct AHCIPortMemory {
+    cmd_list: [AHCICmdHeader; CMD_SLOTS],
+    /// the received FIS area, written by the HBA
+    fis: [u8; 256],
+    cmd_table: AHCICmdTable
+}
+
+impl AHCIPortMemory {
+    pub const fn new() -> Self {
+        Self {
+            cmd_list: [AHCICmdHeader {
+                flags: 0, prdtl: 0, prdbc: 0, ctba: 0, ctbau: 0, _reserved: [0; 4]
+            }; CMD_SLOTS],
+            fis: [0; 256],
+            cmd_table: AHCICmdTable {
+                cfis: [0; 64],
+                acmd: [0; 16],
+                _reserved: [0; 48],
+                prdt: [AHCIPRD { dba: 0, dbau: 0, _reserved: 0, dbc: 0 }; AHCI_PRDT_ENTRIES]
+            }
+        }
+    }
+}
+
+/// The memory for all ports we can drive. Since our kernel is identity mapped,
+/// it can be placed in any static variable below 4MiB, and must never be freed.
+pub struct AHCIMemory {
+    ports: [AHCIPortMemory; AHCI_MAX_DISKS]
+}
+
+impl AHCIMemory {
+    const EMPTY_PORT: AHCIPortMemory = AHCIPortMemory::new();
+
+    pub const fn new() -> Self {
+        Self { ports: [Self::EMPTY_PORT; AHCI_MAX_DISKS] }
+    }
+}
+
+/// A SATA disk attached to an AHCI port
+#[derive(Clone, Copy)]
+pub struct AHCIDisk {
+    pub driver: AHCIDriver,
+    pub info: ATADiskInfo
+}
+
+impl AHCIDisk {
+    fn mode(&self) -> Result<ATAPIOMode, ATAError> {
+        self.info.pio_mode().ok_or(ATAError::LBANotSupported)
+    }
+}
+
+impl BlockDevice for AHCIDisk {
+    type Error = ATAError;
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE as usize
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info.max_sectors()
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), ATAError> {
+        self.driver.read_sectors(self.mode()?, lba, buf)
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), ATAError> {
+        self.driver.write_sectors(self.mode()?, lba, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), ATAError> {
+        self.driver.flush(self.mode()?)
+    }
+}
+
+/// The AHCI host bus adapter
+#[derive(Clone, Copy)]
+pub struct AHCIController {
+    pub pci: PCIDevice,
+    /// physical address of the HBA registers (BAR5)
+    abar: u32
+}
+
+const fn port_reg(abar: u32, port: u8, reg: u32) -> u32 {
+    abar + PORT_BASE + port as u32 * PORT_SIZE + reg
+}
+
+fn mmio_read(addr: u32) -> u32 {
+    unsafe { read_volatile(addr as *const u32) }
+}
+
+fn mmio_write(addr: u32, data: u32) {
+    unsafe { write_volatile(addr as *mut u32, data) }
+}
+
+impl AHCIController {
+    /// Find the AHCI controller on the PCI bus, enable memory space access
+    /// and bus mastering, then switch the HBA into AHCI mode.
+    pub fn probe() -> Option<Self> {
+        let pci = pci::find_by_class(PCI_CLASS_STORAGE, PCI_SUBCLASS_SATA)?;
+        if pci.prog_if() != PCI_PROG_IF_AHCI {
+            return None
+        }
+        let abar = match pci.bar(5) {
+            PCIBar::Memory(base) => base,
+            _ => return None
+        };
+        pci.enable(CMD_MEM_SPACE | CMD_BUS_MASTER);
+
+        let hba = Self { pci, abar };
+        mmio_write(abar + HBA_GHC, mmio_read(abar + HBA_GHC) | GHC_AE);
+        Some(hba)
+    }
+
+    /// Returns true if the HBA can access memory above 4GiB
+    pub fn supports_64bit(&self) -> bool {
+        mmio_read(self.abar + HBA_CAP) & CAP_S64A != 0
+    }
+
+    /// Probe all implemented ports, every SATA disk found gets an `AHCIPortMemory`
+    /// from `mem`. Ports without a device, ATAPI devices and disks beyond
+    /// `AHCI_MAX_DISKS` are ignored.
+    pub fn enumerate(&self, mem: &'static mut AHCIMemory) -> [Option<AHCIDisk>; AHCI_MAX_DISKS] {
+        let mut disks = [None; AHCI_MAX_DISKS];
+        let mut slots = disks.iter_mut().zip(mem.ports.iter_mut());
+        let implemented = mmio_read(self.abar + HBA_PI);
+
+        for port in 0..AHCI_MAX_PORTS as u8 {
+            if implemented & (1 << port) == 0 || !self.device_present(port) {
+                continue
+            }
+            let (slot, port_mem) = match slots.next() {
+                Some(next) => next,
+                None => break
+            };
+            let driver = AHCIDriver::new(self.abar, port, port_mem);
+            if driver.start().is_err() {
+                continue
+            }
+            if let Ok(info) = driver.identify() {
+                *slot = Some(AHCIDisk { driver, info })
+            }
+        }
+        disks
+    }
+
+    /// Check whether an active SATA disk is attached to the port
+    fn device_present(&self, port: u8) -> bool {
+        let ssts = mmio_read(port_reg(self.abar, port, PX_SSTS));
+        ssts & 0xf == SSTS_DET_PRESENT
+            && (ssts >> 8) & 0xf == SSTS_IPM_ACTIVE
+            && mmio_read(port_reg(self.abar, port, PX_SIG)) == SIG_ATA
+    }
+}
+
+/// The driver of a single port.
+#[derive(Clone, Copy)]
+pub struct AHCIDriver {
+    abar: u32,
+    pub port: u8,
+    mem: *mut AHCIPortMemory
+}
+
+/// The port memory is owned by the HBA once the port is started, the driver
+/// only touches it while issuing a command.
+unsafe impl Send for AHCIDriver {}
+
+impl AHCIDriver {
+    /// The port memory is handed over to the HBA in `start`
+    fn new(abar: u32, port: u8, mem: &'static mut AHCIPortMemory) -> Self {
+        Self { abar, port, mem }
+    }
+
+    const fn reg(&self, reg: u32) -> u32 { port_reg(self.abar, self.port, reg) }
+
+    /// Poll the port register `reg` until `ready` returns true, and return its last value.
+    /// Fails with `ATAError::Timeout` after `ATA_DEFAULT_TIMEOUT` polls.
+    fn wait_reg(&self, reg: u32, ready: impl Fn(u32) -> bool) -> Result<u32, ATAError> {
+        for _ in 0..ATA_DEFAULT_TIMEOUT {
+            let value = mmio_read(self.reg(reg));
+            if ready(value) {
+                return Ok(value)
+            }
+            spin_loop();
+        }
+        Err(ATAError::Timeout)
+    }
+
+    /// Stop the command engine and the FIS receive engine of the port
+    fn stop(&self) -> Result<(), ATAError> {
+        let cmd = self.reg(PX_CMD);
+        mmio_write(cmd, mmio_read(cmd) & !CMD_ST);
+        self.wait_reg(PX_CMD, |cmd| cmd & CMD_CR == 0)?;
+        mmio_write(cmd, mmio_read(cmd) & !CMD_FRE);
+        self.wait_reg(PX_CMD, |cmd| cmd & CMD_FR == 0)?;
+        Ok(())
+    }
+
+    /// Rebase the port to our memory and start the engines
+    fn start(&self) -> Result<(), ATAError> {
+        self.stop()?;
+
+        let mem = unsafe { &mut *self.mem };
+        let table = &mem.cmd_table as *const AHCICmdTable as u32;
+        mem.cmd_list.iter_mut().for_each(|header| {
+            header.ctba = table;
+            header.ctbau = 0;
+        });
+        mmio_write(self.reg(PX_CLB), mem.cmd_list.as_ptr() as u32);
+        mmio_write(self.reg(PX_CLBU), 0);
+        mmio_write(self.reg(PX_FB), mem.fis.as_ptr() as u32);
+        mmio_write(self.reg(PX_FBU), 0);
+
+        // clear the error and interrupt bits, which are write 1 to clear
+        mmio_write(self.reg(PX_SERR), u32::MAX);
+        mmio_write(self.reg(PX_IS), u32::MAX);
+
+        let cmd = self.reg(PX_CMD);
+        mmio_write(cmd, mmio_read(cmd) | CMD_FRE);
+        self.wait_reg(PX_CMD, |cmd| cmd & CMD_FR != 0)?;
+        // ST must not be set while the device is busy, see AHCI 1.3.1, 10.3.1
+        self.wait_reg(PX_TFD, |tfd| tfd & (ATAStatus::BSY as u32 | ATAStatus::DRQ as u32) == 0)?;
+        mmio_write(cmd, mmio_read(cmd) | CMD_ST);
+        Ok(())
+    }
+
+    /// Fill the PRD table of the command table with a physically contiguous buffer.
+    /// Returns the number of entries used.
+    fn fill_prdt(table: &mut AHCICmdTable, buf: *const u8, len: usize) -> Result<u16, ATAError> {
+        let mut addr = buf as usize;
+        let end = addr + len;
+
+        if addr & 1 != 0 {
+            return Err(ATAError::BufferNotAligned)
+        }
+
+        let mut i = 0;
+        while addr < end {
+            if i == AHCI_PRDT_ENTRIES {
+                return Err(ATAError::BufferOverflow)
+            }
+            let size = (end - addr).min(PRD_MAX_BYTES);
+            table.prdt[i] = AHCIPRD {
+                dba: addr as u32,
+                dbau: 0,
+                _reserved: 0,
+                dbc: (size - 1) as u32
+            };
+            addr += size;
+            i += 1;
+        }
+        Ok(i as u16)
+    }
+
+    /// Issue an ATA command with command slot 0 and wait for the completion.
+    /// `count` is the value of the sector count register, `mode` tells whether `cmd`
+    /// is a LBA28 or a LBA48 (EXT) command.
+    fn exec(&self, mode: ATAPIOMode, cmd: u8, lba: u64, count: u16, buf: *const u8, len: usize, write: bool) -> Result<(), ATAError> {
+        // wait until the device is able to accept a new command
+        self.wait_reg(PX_TFD, |tfd| tfd & (ATAStatus::BSY as u32 | ATAStatus::DRQ as u32) == 0)?;
+
+        let mem = unsafe { &mut *self.mem };
+        let prdtl = Self::fill_prdt(&mut mem.cmd_table, buf, len)?;
+
+        let lba = lba.to_le_bytes();
+        let count = count.to_le_bytes();
+        let fis = &mut mem.cmd_table.cfis;
+        fis.fill(0);
+        fis[0] = FIS_TYPE_REG_H2D;
+        fis[1] = FIS_H2D_COMMAND;
+        fis[2] = cmd;
+        fis[4..7].copy_from_slice(&lba[0..3]);
+        fis[7] = match mode {
+            // the highest 4 bits of LBA28 are sent with the device register
+            ATAPIOMode::PIO28 => FIS_DEVICE_LBA | (lba[3] & 0x0f),
+            ATAPIOMode::PIO48 => FIS_DEVICE_LBA
+        };
+        if let ATAPIOMode::PIO48 = mode {
+            fis[8..11].copy_from_slice(&lba[3..6]);
+        }
+        fis[12..14].copy_from_slice(&count);
+
+        let header = &mut mem.cmd_list[0];
+        // a register H2D FIS is 5 dwords long
+        header.flags = 5 | if write { HDR_WRITE } else { 0 };
+        header.prdtl = prdtl;
+        header.prdbc = 0;
+
+        // the HBA must see the command table before the command is issued
+        compiler_fence(Ordering::SeqCst);
+        mmio_write(self.reg(PX_IS), u32::MAX);
+        mmio_write(self.reg(PX_CI), 1);
+
+        let done = self.wait_reg(PX_CI, |ci| {
+            ci & 1 == 0 || mmio_read(self.reg(PX_IS)) & IS_TFES != 0
+        });
+        compiler_fence(Ordering::SeqCst);
+        if let Err(e) = done {
+            // the command is still owned by the HBA, restarting the engine takes it back
+            self.start()?;
+            return Err(e)
+        }
+
+        let tfd = mmio_read(self.reg(PX_TFD));
+        if mmio_read(self.reg(PX_IS)) & IS_TFES != 0
+            || tfd & (ATAStatus::ERR as u32 | ATAStatus::DF as u32) != 0 {
+            // the engine must be restarted to clear the error, if that fails the
+            // port is dead and the next command times out
+            let _ = self.start();
+            return Err(ATAError::DeviceFault { status: tfd as u8, error: (tfd >> 8) as u8 })
+        }
+        Ok(())
+    }
+
+    /// Send IDENTIFY DEVICE, which returns the same data as with ATA PIO.
+    pub fn identify(&self) -> Result<ATADiskInfo, ATAError> {
+        let mut result: [u8; 512] = [0; 512];
+        self.exec(ATAPIOMode::PIO28, ATACommand::Identify as u8, 0, 0, result.as_mut_ptr(), result.len(), false)?;
+        Ok(unsafe { transmute(result) })
+    }
+
+    fn check(&self, mode: ATAPIOMode, lba: u64, buf_len: usize) -> Result<u64, ATAError> {
+        if !is_sector_aligned(buf_len) {
+            return Err(ATAError::BufferNotAligned)
+        }
+        let sec_num = size_to_lba(buf_len);
+        if lba + sec_num > mode.max_lba() {
+            return Err(ATAError::LBATooLarge)
+        }
+        Ok(sec_num)
+    }
+
+    /// The max number of sectors transferred by a single command
+    pub fn max_sectors_per_cmd(&self, mode: ATAPIOMode) -> u64 {
+        let by_prdt = size_to_lba(AHCI_PRDT_ENTRIES * PRD_MAX_BYTES);
+        mode.max_sectors_per_cmd().min(by_prdt)
+    }
+
+    /// Read `buf.len()` bytes from `lba` into `buf`. Large transfers are split into
+    /// several commands. The buffer must be physically contiguous.
+    pub fn read_sectors(&self, mode: ATAPIOMode, lba: u64, buf: &mut [u8]) -> Result<(), ATAError> {
+        self.check(mode, lba, buf.len())?;
+        let cmd = match mode {
+            ATAPIOMode::PIO28 => ATACommand::ReadDMA as u8,
+            ATAPIOMode::PIO48 => ATACommand::ReadDMAExt as u8
+        };
+        let chunk = lba_to_size(self.max_sectors_per_cmd(mode)) as usize;
+        for (i, part) in buf.chunks_mut(chunk).enumerate() {
+            // a sector count of 0 means the max sectors of a command
+            let count = size_to_lba(part.len()) as u16;
+            self.exec(mode, cmd, lba + i as u64 * size_to_lba(chunk), count, part.as_ptr(), part.len(), false)?;
+        }
+        Ok(())
+    }
+
+    /// Write `buf` to the disk from `lba`, then flush the write cache.
+    pub fn write_sectors(&self, mode: ATAPIOMode, lba: u64, buf: &[u8]) -> Result<(), ATAError> {
+        self.check(mode, lba, buf.len())?;
+        let cmd = match mode {
+            ATAPIOMode::PIO28 => ATACommand::WriteDMA as u8,
+            ATAPIOMode::PIO48 => ATACommand::WriteDMAExt as u8
+        };
+        let chunk = lba_to_size(self.max_sectors_per_cmd(mode)) as usize;
+        for (i, part) in buf.chunks(chunk).enumerate() {
+            let count = size_to_lba(part.len()) as u16;
+            self.exec(mode, cmd, lba + i as u64 * size_to_lba(chunk), count, part.as_ptr(), part.len(), true)?;
+        }
+        self.flush(mode)
+    }
+
+    /// Flush the write cache of the disk
+    pub fn flush(&self, mode: ATAPIOMode) -> Result<(), ATAError> {
+        let cmd = match mode {
+            ATAPIOMode::PIO28 => ATACommand::CacheFlush as u8,
+            ATAPIOMode::PIO48 => ATACommand::CacheFlushExt as u8
+        };
+        self.exec(mode, cmd, 0, 0, [].as_ptr(), 0, false)
+    }
+}
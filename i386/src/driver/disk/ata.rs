@@ -2,13 +2,12 @@
 //! See https://wiki.osdev.org/ATA_PIO_Mode
 
 pub mod pio;
+pub mod identify;
+pub mod dma;
+pub mod irq;
 
-#[cfg(feature = "alloc")]
-extern crate alloc;
-
-#[cfg(feature = "alloc")]
-use alloc::{format, string::String};
-use crate::instrs::inb;
+use core::{fmt, hint::spin_loop};
+use crate::{instrs::inb, driver::disk::block::DeviceError};
 
 
 #[repr(u8)]
@@ -20,8 +19,25 @@ enum ATADCR {
 }
 
 #[repr(u8)]
-enum ATACommand {
+pub(super) enum ATACommand {
+    /// READ SECTORS, 28-bit LBA
+    Read = 0x20,
+    /// READ SECTORS EXT, 48-bit LBA
     ReadExt = 0x24,
+    /// WRITE SECTORS, 28-bit LBA
+    Write = 0x30,
+    /// WRITE SECTORS EXT, 48-bit LBA
+    WriteExt = 0x34,
+    CacheFlush = 0xE7,
+    CacheFlushExt = 0xEA,
+    /// READ DMA, 28-bit LBA
+    ReadDMA = 0xC8,
+    /// READ DMA EXT, 48-bit LBA
+    ReadDMAExt = 0x25,
+    /// WRITE DMA, 28-bit LBA
+    WriteDMA = 0xCA,
+    /// WRITE DMA EXT, 48-bit LBA
+    WriteDMAExt = 0x35,
     Identify = 0xEC
 }
 
@@ -32,7 +48,7 @@ enum ATAFeature {
 
 #[allow(dead_code)]
 #[repr(u8)]
-enum ATAStatus {
+pub(super) enum ATAStatus {
     ERR = 0b00000001,
     DRQ = 0b00001000,
     DF = 0b00100000,
@@ -40,66 +56,224 @@ enum ATAStatus {
     BSY = 0b10000000
 }
 
+#[derive(Debug)]
 pub enum ATAError {
     BufferNotAligned,
     BufferOverflow,
     LBATooLarge,
-    DiskError(u8),
     DeviceNotExist,
-    NotATADevice
+    NotATADevice,
+    NotATAPIDevice,
+    /// the device supports neither LBA28 nor LBA48 addressing
+    LBANotSupported,
+    /// the device does not support DMA transfers
+    DMANotSupported,
+    /// the bus master status register reported an error
+    DMAError(u8),
+    /// the request queue of the channel is full
+    QueueFull,
+    /// the device did not complete the command in time, the channel has been reset
+    Timeout,
+    /// the device failed the command, usually with ERR or DF set in the status register
+    DeviceFault {
+        status: u8,
+        /// the contents of the error register
+        error: u8
+    }
 }
 
-#[cfg(feature = "alloc")]
-impl Into<String> for ATAError {
-    fn into(self) -> String {
+impl fmt::Display for ATAError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::DiskError(i) => format!("Disk Error: {}", i),
-            Self::BufferOverflow => "Disk Error: Overflow".into(),
-            Self::LBATooLarge => "Disk Error: LBA too large".into(),
-            Self::DeviceNotExist => "Disk Error: not found".into(),
-            Self::NotATADevice => "Disk Error: not ATA".into(),
-            Self::BufferNotAligned => "Disk Error: alignment".into(),
+            Self::BufferOverflow => write!(f, "Disk Error: overflow"),
+            Self::LBATooLarge => write!(f, "Disk Error: LBA too large"),
+            Self::DeviceNotExist => write!(f, "Disk Error: not found"),
+            Self::NotATADevice => write!(f, "Disk Error: not ATA"),
+            Self::NotATAPIDevice => write!(f, "Disk Error: not ATAPI"),
+            Self::BufferNotAligned => write!(f, "Disk Error: alignment"),
+            Self::LBANotSupported => write!(f, "Disk Error: LBA not supported"),
+            Self::DMANotSupported => write!(f, "Disk Error: DMA not supported"),
+            Self::DMAError(status) => write!(f, "Disk Error: DMA failed (bus master status {:#04x})", status),
+            Self::QueueFull => write!(f, "Disk Error: queue full"),
+            Self::Timeout => write!(f, "Disk Error: timeout"),
+            Self::DeviceFault { status, error } =>
+                write!(f, "Disk Error: device fault (status {:#04x}, error {:#04x})", status, error)
         }
     }
 }
 
-pub enum ATADriver {
-    PRIMARY,
-    SECONDARY
+impl DeviceError for ATAError {
+    /// The error register of a failed command, or the bus master status of a failed DMA transfer
+    fn status(&self) -> u8 {
+        match self {
+            Self::DeviceFault { error, .. } => *error,
+            Self::DMAError(status) => *status,
+            _ => 0
+        }
+    }
 }
 
-#[allow(dead_code)]
-impl ATADriver {
+/// An ATA channel. Each channel has its own I/O ports and can hold
+/// a master and a slave drive.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ATABus {
+    Primary,
+    Secondary
+}
+
+impl ATABus {
     const fn io_base(&self) -> u16 {
         match self {
-            &Self::PRIMARY => 0x1f0,
-            &Self::SECONDARY => 0x170
+            &Self::Primary => 0x1f0,
+            &Self::Secondary => 0x170
+        }
+    }
+
+    const fn ctrl_base(&self) -> u16 {
+        match self {
+            &Self::Primary => 0x3f6,
+            &Self::Secondary => 0x376
+        }
+    }
+}
+
+impl Into<&'static str> for ATABus {
+    fn into(self) -> &'static str {
+        match self {
+            ATABus::Primary => "Primary",
+            ATABus::Secondary => "Secondary",
+        }
+    }
+}
+
+/// A drive on an ATA channel
+#[derive(Clone, Copy)]
+pub enum ATADrive {
+    Master,
+    Slave
+}
+
+impl ATADrive {
+    /// the DRV bit (bit 4) of the drive / head register
+    pub(super) const fn select_bit(&self) -> u8 {
+        match self {
+            &Self::Master => 0,
+            &Self::Slave => 1 << 4
         }
     }
+}
 
-    const fn data_reg(&self) -> u16 { self.io_base() + 0 }
-    const fn feature_reg(&self) ->  u16 { self.io_base() + 1 }
+impl Into<&'static str> for ATADrive {
+    fn into(self) -> &'static str {
+        match self {
+            ATADrive::Master => "Master",
+            ATADrive::Slave => "Slave",
+        }
+    }
+}
+
+/// The maximum number of devices on the legacy IDE controller
+pub const ATA_MAX_DISKS: usize = 4;
+
+/// The default number of status register polls before a command times out.
+/// Each poll is an I/O port access, which takes about 1us on real hardware,
+/// so this is a few seconds, long enough for a disk to spin up.
+pub const ATA_DEFAULT_TIMEOUT: u32 = 1 << 22;
+/// The default number of times a failed command is retried after a reset
+pub const ATA_DEFAULT_RETRIES: u8 = 3;
+
+/// The driver of a single device, identified by its channel and drive.
+#[derive(Clone, Copy)]
+pub struct ATADriver {
+    pub bus: ATABus,
+    pub drive: ATADrive,
+    /// the max number of status polls of a wait, see `ATA_DEFAULT_TIMEOUT`
+    pub timeout: u32,
+    /// how many times a timed out or failed command is retried
+    pub retries: u8
+}
+
+#[allow(dead_code)]
+impl ATADriver {
+    pub const PRIMARY_MASTER: Self = Self::new(ATABus::Primary, ATADrive::Master);
+    pub const PRIMARY_SLAVE: Self = Self::new(ATABus::Primary, ATADrive::Slave);
+    pub const SECONDARY_MASTER: Self = Self::new(ATABus::Secondary, ATADrive::Master);
+    pub const SECONDARY_SLAVE: Self = Self::new(ATABus::Secondary, ATADrive::Slave);
+
+    /// All possible devices, in the order we probe them
+    pub const ALL: [Self; ATA_MAX_DISKS] = [
+        Self::PRIMARY_MASTER,
+        Self::PRIMARY_SLAVE,
+        Self::SECONDARY_MASTER,
+        Self::SECONDARY_SLAVE
+    ];
+
+    pub const fn new(bus: ATABus, drive: ATADrive) -> Self {
+        Self { bus, drive, timeout: ATA_DEFAULT_TIMEOUT, retries: ATA_DEFAULT_RETRIES }
+    }
+
+    /// Set the max number of status polls before a command fails with `ATAError::Timeout`
+    pub const fn with_timeout(self, timeout: u32) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Set how many times a failed command is retried after resetting the channel
+    pub const fn with_retries(self, retries: u8) -> Self {
+        Self { retries, ..self }
+    }
+
+    pub(super) const fn io_base(&self) -> u16 { self.bus.io_base() }
+
+    pub(super) const fn data_reg(&self) -> u16 { self.io_base() + 0 }
+    pub(super) const fn feature_reg(&self) ->  u16 { self.io_base() + 1 }
     /// for reading
-    const fn error_reg(&self) -> u16 { self.io_base() + 1 }
-    const fn sector_num_reg(&self) -> u16 { self.io_base() + 2 }
-    const fn lba_lo_reg(&self) -> u16 { self.io_base() + 3 }
-    const fn lba_mid_reg(&self) -> u16 { self.io_base() + 4 }
-    const fn lba_hi_reg(&self) -> u16 { self.io_base() + 5 }/// Used to select a drive and/or head.   Supports extra address/flag bits.
-    const fn drive_reg(&self) -> u16 { self.io_base() + 6 }/// for reading
-    const fn status_reg(&self) -> u16 { self.io_base() + 7 }/// for writing 
-    const fn command_reg(&self) -> u16 { self.io_base() + 7 }
-
-    const fn ctrl_base(&self) -> u16 { 0x376 }
-    const fn dcr_reg(&self) -> u16 { self.ctrl_base() + 0 }
-    const fn alt_status_reg(&self) -> u16 { self.ctrl_base() + 0 }
+    pub(super) const fn error_reg(&self) -> u16 { self.io_base() + 1 }
+    pub(super) const fn sector_num_reg(&self) -> u16 { self.io_base() + 2 }
+    pub(super) const fn lba_lo_reg(&self) -> u16 { self.io_base() + 3 }
+    pub(super) const fn lba_mid_reg(&self) -> u16 { self.io_base() + 4 }
+    pub(super) const fn lba_hi_reg(&self) -> u16 { self.io_base() + 5 }/// Used to select a drive and/or head.   Supports extra address/flag bits.
+    pub(super) const fn drive_reg(&self) -> u16 { self.io_base() + 6 }/// for reading
+    pub(super) const fn status_reg(&self) -> u16 { self.io_base() + 7 }/// for writing 
+    pub(super) const fn command_reg(&self) -> u16 { self.io_base() + 7 }
+
+    pub(super) const fn ctrl_base(&self) -> u16 { self.bus.ctrl_base() }
+    pub(super) const fn dcr_reg(&self) -> u16 { self.ctrl_base() + 0 }
+    pub(super) const fn alt_status_reg(&self) -> u16 { self.ctrl_base() + 0 }
     /// drive address register
-    const fn dar(&self) -> u16 { self.ctrl_base() + 1 }
+    pub(super) const fn dar(&self) -> u16 { self.ctrl_base() + 1 }
 
-    fn ata_delay_400ns(&self) {
+    pub(super) fn ata_delay_400ns(&self) {
         inb(self.status_reg());
         inb(self.status_reg());
         inb(self.status_reg());
         inb(self.status_reg());
     }
+
+    /// Poll the status register until `ready` returns true, and return the last status.
+    /// Fails with `ATAError::Timeout` after `self.timeout` polls.
+    pub(super) fn wait_status(&self, ready: impl Fn(u8) -> bool) -> Result<u8, ATAError> {
+        for _ in 0..self.timeout {
+            let status = inb(self.status_reg());
+            if ready(status) {
+                return Ok(status)
+            }
+            spin_loop();
+        }
+        Err(ATAError::Timeout)
+    }
+
+    /// Wait until BSY clears, and check the error bits of the status
+    pub(super) fn wait_not_busy(&self) -> Result<u8, ATAError> {
+        let status = self.wait_status(|status| status & ATAStatus::BSY as u8 == 0)?;
+        self.check_status(status)
+    }
+
+    /// Returns `ATAError::DeviceFault` if ERR or DF is set in `status`
+    pub(super) fn check_status(&self, status: u8) -> Result<u8, ATAError> {
+        if status & (ATAStatus::ERR as u8 | ATAStatus::DF as u8) != 0 {
+            return Err(ATAError::DeviceFault { status, error: inb(self.error_reg()) })
+        }
+        Ok(status)
+    }
 }
 
@@ -0,0 +1,294 @@
+//! This module contains bus master IDE (PIIX) DMA operations for protected mode disk access.
+//! With DMA, the controller moves data between the disk and memory by itself, the CPU
+//! only needs to build a PRD table and wait for the completion.
+//! See https://wiki.osdev.org/ATA/ATAPI_using_DMA
+
+use core::hint::spin_loop;
+use super::*;
+use super::pio::{ATADisk, ATAPIOMode};
+use crate::{
+    instrs::{inb, outb, outdw},
+    driver::pci::{self, PCIBar, PCIDevice, CMD_BUS_MASTER, CMD_IO_SPACE},
+    utils::disk::*
+};
+
+/// PCI class code of mass storage controllers
+const PCI_CLASS_STORAGE: u8 = 0x01;
+/// PCI subclass of IDE controllers
+const PCI_SUBCLASS_IDE: u8 = 0x01;
+/// prog if bit 7: the controller supports bus mastering
+const PCI_IDE_BUS_MASTER: u8 = 1 << 7;
+
+/// bus master command register bit 0: start / stop the transfer
+const BM_CMD_START: u8 = 1 << 0;
+/// bus master command register bit 3: set if the controller writes to memory,
+/// i.e. we are reading from the disk
+const BM_CMD_READ: u8 = 1 << 3;
+
+/// bus master status register bit 0: the transfer is in progress
+const BM_STATUS_ACTIVE: u8 = 1 << 0;
+/// bus master status register bit 1: the transfer failed, write 1 to clear
+const BM_STATUS_ERR: u8 = 1 << 1;
+/// bus master status register bit 2: the device raised its interrupt, write 1 to clear
+const BM_STATUS_IRQ: u8 = 1 << 2;
+
+/// set in the last entry of a PRD table
+const PRD_EOT: u16 = 1 << 15;
+/// a single PRD describes at most 64KiB, and must not cross a 64KiB boundary
+const PRD_MAX_BYTES: usize = 0x10000;
+/// the PRD table fills a 4KiB page, so it never crosses a 64KiB boundary
+pub const PRDT_MAX_ENTRIES: usize = 512;
+
+/// A physical region descriptor, describes a memory region for DMA transfers.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct PRD {
+    /// physical address of the region, must be even
+    addr: u32,
+    /// size of the region in bytes, 0 means 64KiB
+    count: u16,
+    /// bit 15 is set if this is the last entry in the table
+    flags: u16
+}
+
+/// The PRD table, which must be dword aligned and must not cross a 64KiB boundary.
+#[repr(C, align(4096))]
+pub struct PRDTable {
+    entries: [PRD; PRDT_MAX_ENTRIES]
+}
+
+impl PRDTable {
+    pub const fn new() -> Self {
+        Self { entries: [PRD { addr: 0, count: 0, flags: 0 }; PRDT_MAX_ENTRIES] }
+    }
+
+    /// Fill the table with a physically contiguous buffer. Since our kernel is
+    /// identity mapped, the address of the buffer is its physical address.
+    fn fill(&mut self, buf: *const u8, len: usize) -> Result<(), ATAError> {
+        let mut addr = buf as usize;
+        let end = addr + len;
+
+        if addr & 1 != 0 {
+            return Err(ATAError::BufferNotAligned)
+        }
+
+        let mut i = 0;
+        while addr < end {
+            if i == PRDT_MAX_ENTRIES {
+                return Err(ATAError::BufferOverflow)
+            }
+            // split the region at 64KiB boundaries
+            let boundary = (addr & !(PRD_MAX_BYTES - 1)) + PRD_MAX_BYTES;
+            let size = boundary.min(end) - addr;
+            self.entries[i] = PRD {
+                addr: addr as u32,
+                count: size as u16,
+                flags: 0
+            };
+            addr += size;
+            i += 1;
+        }
+
+        if i == 0 {
+            return Err(ATAError::BufferOverflow)
+        }
+        self.entries[i - 1].flags = PRD_EOT;
+        Ok(())
+    }
+}
+
+/// The PCI IDE controller with bus mastering capability.
+#[derive(Clone, Copy)]
+pub struct BusMasterIDE {
+    pub pci: PCIDevice,
+    /// I/O base of the bus master registers (BAR4)
+    base: u16
+}
+
+impl BusMasterIDE {
+    /// Find the IDE controller on the PCI bus and enable bus mastering
+    pub fn probe() -> Option<Self> {
+        let pci = pci::find_by_class(PCI_CLASS_STORAGE, PCI_SUBCLASS_IDE)?;
+        if pci.prog_if() & PCI_IDE_BUS_MASTER == 0 {
+            return None
+        }
+        let base = match pci.bar(4) {
+            PCIBar::Io(base) => base,
+            _ => return None
+        };
+        pci.enable(CMD_IO_SPACE | CMD_BUS_MASTER);
+        Some(Self { pci, base })
+    }
+
+    /// The bus master registers of the primary channel start at BAR4 + 0,
+    /// and those of the secondary channel start at BAR4 + 8.
+    const fn channel_base(&self, bus: ATABus) -> u16 {
+        match bus {
+            ATABus::Primary => self.base,
+            ATABus::Secondary => self.base + 8
+        }
+    }
+}
+
+/// The DMA driver of a single device.
+pub struct ATADMADriver<'a> {
+    driver: ATADriver,
+    mode: ATAPIOMode,
+    /// I/O base of the bus master registers of the channel
+    bm_base: u16,
+    prdt: &'a mut PRDTable
+}
+
+impl<'a> ATADMADriver<'a> {
+    /// The PRD table is provided by the caller, since it should live in memory
+    /// which is reachable by the controller.
+    pub fn new(controller: &BusMasterIDE, disk: &ATADisk, prdt: &'a mut PRDTable) -> Result<Self, ATAError> {
+        if !disk.info.dma_supported() {
+            return Err(ATAError::DMANotSupported)
+        }
+        let mode = disk.info.pio_mode()
+            .ok_or(ATAError::LBANotSupported)?;
+        Ok(Self {
+            driver: disk.driver,
+            mode,
+            bm_base: controller.channel_base(disk.driver.bus),
+            prdt
+        })
+    }
+
+    const fn bm_cmd_reg(&self) -> u16 { self.bm_base + 0 }
+    const fn bm_status_reg(&self) -> u16 { self.bm_base + 2 }
+    const fn bm_prdt_reg(&self) -> u16 { self.bm_base + 4 }
+
+    /// The max number of sectors transferred by a single command
+    pub fn max_sectors_per_cmd(&self) -> u64 {
+        // entries are split at 64KiB boundaries, so a buffer not starting at one
+        // takes an entry more than its size suggests
+        let by_prdt = size_to_lba((PRDT_MAX_ENTRIES - 1) * PRD_MAX_BYTES);
+        self.mode.max_sectors_per_cmd().min(by_prdt)
+    }
+
+    /// Start reading `buf.len()` bytes from `lba` into `buf`. The transfer completes
+    /// asynchronously, call `poll`, `wait` or `complete` (from the IRQ handler) to
+    /// finish it. The buffer must be physically contiguous and must not be touched
+    /// until the transfer finishes.
+    pub fn start_read(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), ATAError> {
+        let cmd = match self.mode {
+            ATAPIOMode::PIO28 => ATACommand::ReadDMA as u8,
+            ATAPIOMode::PIO48 => ATACommand::ReadDMAExt as u8
+        };
+        self.start(cmd, BM_CMD_READ, lba, buf.as_ptr(), buf.len())
+    }
+
+    /// Start writing `buf` to the disk from `lba`, see `start_read`.
+    pub fn start_write(&mut self, lba: u64, buf: &[u8]) -> Result<(), ATAError> {
+        let cmd = match self.mode {
+            ATAPIOMode::PIO28 => ATACommand::WriteDMA as u8,
+            ATAPIOMode::PIO48 => ATACommand::WriteDMAExt as u8
+        };
+        self.start(cmd, 0, lba, buf.as_ptr(), buf.len())
+    }
+
+    fn start(&mut self, cmd: u8, direction: u8, lba: u64, buf: *const u8, len: usize) -> Result<(), ATAError> {
+        if !is_sector_aligned(len) {
+            return Err(ATAError::BufferNotAligned)
+        }
+        let sec_num = size_to_lba(len);
+        if sec_num > self.max_sectors_per_cmd() {
+            return Err(ATAError::BufferOverflow)
+        }
+        if lba + sec_num > self.mode.max_lba() {
+            return Err(ATAError::LBATooLarge)
+        }
+
+        self.prdt.fill(buf, len)?;
+
+        // stop any previous transfer, then load the PRD table and the direction
+        outb(self.bm_cmd_reg(), 0);
+        outdw(self.bm_prdt_reg(), self.prdt as *const PRDTable as u32);
+        outb(self.bm_cmd_reg(), direction);
+        // clear the error and interrupt bits
+        outb(self.bm_status_reg(), inb(self.bm_status_reg()) | BM_STATUS_ERR | BM_STATUS_IRQ);
+
+        self.driver.send_command(self.mode, cmd, lba, sec_num);
+        outb(self.bm_cmd_reg(), direction | BM_CMD_START);
+        Ok(())
+    }
+
+    /// Check whether the transfer has finished. Returns None if the transfer is
+    /// still in progress.
+    pub fn poll(&mut self) -> Option<Result<(), ATAError>> {
+        let bm_status = inb(self.bm_status_reg());
+        if bm_status & BM_STATUS_ERR == 0 && bm_status & BM_STATUS_IRQ == 0 {
+            return None
+        }
+        if inb(self.driver.alt_status_reg()) & ATAStatus::BSY as u8 != 0 {
+            return None
+        }
+        Some(self.complete())
+    }
+
+    /// Finish the current transfer. This should be called by the IRQ14 / IRQ15 handler,
+    /// reading the status register acknowledges the interrupt of the device.
+    pub fn complete(&mut self) -> Result<(), ATAError> {
+        let bm_status = inb(self.bm_status_reg());
+        outb(self.bm_cmd_reg(), 0);
+        outb(self.bm_status_reg(), bm_status | BM_STATUS_ERR | BM_STATUS_IRQ);
+
+        let status = inb(self.driver.status_reg());
+        if bm_status & BM_STATUS_ERR != 0 {
+            return Err(ATAError::DMAError(bm_status))
+        }
+        if status & (ATAStatus::DF as u8 | ATAStatus::ERR as u8) != 0 {
+            return Err(ATAError::DeviceFault { status, error: inb(self.driver.error_reg()) })
+        }
+        Ok(())
+    }
+
+    /// Spin until the current transfer finishes. If it does not finish in
+    /// `driver.timeout` polls, the transfer is stopped and the channel is reset.
+    pub fn wait(&mut self) -> Result<(), ATAError> {
+        for _ in 0..self.driver.timeout {
+            if let Some(res) = self.poll() {
+                return res
+            }
+            spin_loop();
+        }
+        outb(self.bm_cmd_reg(), 0);
+        self.driver.pio_sftrst()?;
+        Err(ATAError::Timeout)
+    }
+
+    /// Returns true if the controller is still transferring data
+    pub fn is_active(&self) -> bool {
+        inb(self.bm_status_reg()) & BM_STATUS_ACTIVE != 0
+    }
+
+    /// Read sectors with DMA and wait for the completion by polling.
+    /// Large transfers are split into several commands.
+    pub fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), ATAError> {
+        if !is_sector_aligned(buf.len()) {
+            return Err(ATAError::BufferNotAligned)
+        }
+        let chunk = lba_to_size(self.max_sectors_per_cmd()) as usize;
+        for (i, part) in buf.chunks_mut(chunk).enumerate() {
+            self.start_read(lba + i as u64 * size_to_lba(chunk), part)?;
+            self.wait()?;
+        }
+        Ok(())
+    }
+
+    /// Write sectors with DMA and wait for the completion by polling.
+    /// The write cache is flushed after all sectors are written.
+    pub fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), ATAError> {
+        if !is_sector_aligned(buf.len()) {
+            return Err(ATAError::BufferNotAligned)
+        }
+        let chunk = lba_to_size(self.max_sectors_per_cmd()) as usize;
+        for (i, part) in buf.chunks(chunk).enumerate() {
+            self.start_write(lba + i as u64 * size_to_lba(chunk), part)?;
+            self.wait()?;
+        }
+        self.driver.pio_flush(self.mode)
+    }
+}
@@ -0,0 +1,235 @@
+//! Decoding of the 256 words returned by IDENTIFY DEVICE and IDENTIFY PACKET DEVICE.
+//! See ATA/ATAPI Command Set (ACS-3), 7.12.7
+
+use core::str;
+use super::pio::ATAPIOMode;
+
+/// general configuration (word 0) bit 15: set for ATAPI devices
+const GENERAL_NOT_ATA: u16 = 1 << 15;
+/// general configuration (word 0) bit 7: the media is removable
+const GENERAL_REMOVABLE: u16 = 1 << 7;
+
+/// capabilities (word 49) bit 8: DMA supported
+const CAP_DMA: u16 = 1 << 8;
+/// capabilities (word 49) bit 9: LBA supported
+const CAP_LBA: u16 = 1 << 9;
+/// capabilities (word 49) bit 11: IORDY supported
+const CAP_IORDY: u16 = 1 << 11;
+
+/// field validity (word 53) bit 2: word 88 is valid
+const VALID_UDMA: u16 = 1 << 2;
+
+/// command set supported (word 82) bit 5: volatile write cache supported
+const CMD_WRITE_CACHE: u16 = 1 << 5;
+/// command set supported (word 83) bit 10: 48-bit address feature set supported
+const CMD_LBA48: u16 = 1 << 10;
+/// command set supported (word 83) bit 12: FLUSH CACHE supported
+const CMD_FLUSH: u16 = 1 << 12;
+/// command set supported (word 83) bit 13: FLUSH CACHE EXT supported
+const CMD_FLUSH_EXT: u16 = 1 << 13;
+
+/// hardware reset result (word 93) bit 11: an 80-conductor cable is detected
+const CABLE_80PIN: u16 = 1 << 11;
+
+/// sector size (word 106) bit 12: the logical sector is longer than 256 words
+const SECTOR_LONG_LOGICAL: u16 = 1 << 12;
+/// sector size (word 106) bit 13: there are multiple logical sectors per physical sector
+const SECTOR_MULTI_LOGICAL: u16 = 1 << 13;
+
+/// An ATA string, two characters are packed in each word with the first one in
+/// the high byte. Strings are padded with spaces.
+#[derive(Clone, Copy)]
+pub struct ATAString<const LEN: usize> {
+    bytes: [u8; LEN]
+}
+
+impl<const LEN: usize> ATAString<LEN> {
+    fn from_words(words: &[u16]) -> Self {
+        let mut bytes = [0; LEN];
+        for (chars, word) in bytes.chunks_mut(2).zip(words) {
+            chars.copy_from_slice(&word.to_be_bytes());
+        }
+        Self { bytes }
+    }
+
+    /// The string without padding. Returns an empty string if the device
+    /// reports non ASCII characters.
+    pub fn as_str(&self) -> &str {
+        str::from_utf8(&self.bytes)
+            .map(|s| s.trim_matches(|c| c == ' ' || c == '\0'))
+            .unwrap_or("")
+    }
+}
+
+/// The disk information read with ATA IDENTIFY command
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ATADiskInfo {
+    words: [u16; 256]
+}
+
+impl ATADiskInfo {
+    pub const fn new(words: [u16; 256]) -> Self {
+        Self { words }
+    }
+
+    /// The raw IDENTIFY data
+    pub const fn raw(&self) -> &[u16; 256] {
+        &self.words
+    }
+
+    pub const fn word(&self, index: usize) -> u16 {
+        self.words[index]
+    }
+
+    /// two words holding a little endian dword, low word first
+    const fn dword(&self, index: usize) -> u32 {
+        self.words[index] as u32 | (self.words[index + 1] as u32) << 16
+    }
+
+    /// words 10-19
+    pub fn serial(&self) -> ATAString<20> {
+        ATAString::from_words(&self.words[10..20])
+    }
+
+    /// words 23-26
+    pub fn firmware(&self) -> ATAString<8> {
+        ATAString::from_words(&self.words[23..27])
+    }
+
+    /// words 27-46
+    pub fn model(&self) -> ATAString<40> {
+        ATAString::from_words(&self.words[27..47])
+    }
+
+    pub const fn is_ata(&self) -> bool {
+        self.words[0] & GENERAL_NOT_ATA == 0
+    }
+
+    pub const fn is_removable(&self) -> bool {
+        self.words[0] & GENERAL_REMOVABLE != 0
+    }
+
+    /// word 49
+    pub const fn capabilities(&self) -> u16 {
+        self.words[49]
+    }
+
+    pub const fn lba_supported(&self) -> bool {
+        self.capabilities() & CAP_LBA != 0
+    }
+
+    pub const fn dma_supported(&self) -> bool {
+        self.capabilities() & CAP_DMA != 0
+    }
+
+    pub const fn iordy_supported(&self) -> bool {
+        self.capabilities() & CAP_IORDY != 0
+    }
+
+    /// words 60-61, the number of sectors addressable with LBA28
+    pub const fn lba28_sectors(&self) -> u32 {
+        self.dword(60)
+    }
+
+    pub const fn lba48_supported(&self) -> bool {
+        self.words[83] & CMD_LBA48 != 0
+    }
+
+    /// words 100-103, the number of sectors addressable with LBA48
+    pub const fn lba48_sectors(&self) -> u64 {
+        self.dword(100) as u64 | (self.dword(102) as u64) << 32
+    }
+
+    /// Bitmap of supported UDMA modes, bit n is set if mode n is supported
+    pub const fn udma_supported(&self) -> u8 {
+        if self.words[53] & VALID_UDMA == 0 {
+            return 0
+        }
+        (self.words[88] & 0x7f) as u8
+    }
+
+    /// The UDMA mode currently selected
+    pub const fn udma_active(&self) -> Option<u8> {
+        if self.words[53] & VALID_UDMA == 0 {
+            return None
+        }
+        let selected = (self.words[88] >> 8) & 0x7f;
+        if selected == 0 {
+            None
+        } else {
+            Some(selected.trailing_zeros() as u8)
+        }
+    }
+
+    pub const fn cable_80pin(&self) -> bool {
+        self.words[93] & CABLE_80PIN != 0
+    }
+
+    pub const fn write_cache_supported(&self) -> bool {
+        self.words[82] & CMD_WRITE_CACHE != 0
+    }
+
+    /// word 85 mirrors word 82 with the features which are enabled
+    pub const fn write_cache_enabled(&self) -> bool {
+        self.words[85] & CMD_WRITE_CACHE != 0
+    }
+
+    pub const fn flush_supported(&self) -> bool {
+        self.words[83] & CMD_FLUSH != 0
+    }
+
+    pub const fn flush_ext_supported(&self) -> bool {
+        self.words[83] & CMD_FLUSH_EXT != 0
+    }
+
+    /// word 106 is valid if bit 14 is set and bit 15 is cleared
+    const fn sector_size_info(&self) -> Option<u16> {
+        match self.words[106] >> 14 {
+            0b01 => Some(self.words[106]),
+            _ => None
+        }
+    }
+
+    /// The size of a logical sector in bytes, which is the unit of LBA
+    pub const fn logical_sector_size(&self) -> u32 {
+        match self.sector_size_info() {
+            // words 117-118 is the size in words
+            Some(info) if info & SECTOR_LONG_LOGICAL != 0 => self.dword(117) * 2,
+            _ => 512
+        }
+    }
+
+    /// The size of a physical sector in bytes. Writes aligned to physical sectors
+    /// avoid read-modify-write inside the device.
+    pub const fn physical_sector_size(&self) -> u32 {
+        match self.sector_size_info() {
+            // bit 0:3 is log2 of logical sectors per physical sector
+            Some(info) if info & SECTOR_MULTI_LOGICAL != 0 =>
+                self.logical_sector_size() << (info & 0xf),
+            _ => self.logical_sector_size()
+        }
+    }
+
+    /// Choose the addressing mode for this device. LBA48 is preferred if the device
+    /// reports both the capability bit and a non-zero LBA48 sector count, otherwise
+    /// we fall back to LBA28. Returns None for CHS-only devices.
+    pub const fn pio_mode(&self) -> Option<ATAPIOMode> {
+        if self.lba48_supported() && self.lba48_sectors() != 0 {
+            Some(ATAPIOMode::PIO48)
+        } else if self.lba_supported() && self.lba28_sectors() != 0 {
+            Some(ATAPIOMode::PIO28)
+        } else {
+            None
+        }
+    }
+
+    /// The number of user addressable sectors under the selected addressing mode
+    pub const fn max_sectors(&self) -> u64 {
+        match self.pio_mode() {
+            Some(ATAPIOMode::PIO48) => self.lba48_sectors(),
+            Some(ATAPIOMode::PIO28) => self.lba28_sectors() as u64,
+            None => 0
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Interrupt driven ATA PIO transfers.
+//!
+//! Instead of spinning on the status port, requests are put into a per channel queue.
+//! The device raises IRQ14 (primary channel) or IRQ15 (secondary channel) whenever a
+//! sector is ready, and the IRQ handler of the kernel calls `ATAChannel::handle_irq`
+//! to move the data. The task which issued the request waits on its completion, and
+//! the timer handler calls `ATAChannel::check_timeout` to reset a wedged channel.
+//!
+//! The channel is not synchronized by itself, the kernel should wrap it in a lock and
+//! disable interrupts while holding the lock.
+
+use core::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering}
+};
+use super::*;
+use super::pio::{ATADisk, ATAPIOMode};
+use crate::{
+    instrs::inb,
+    utils::disk::*
+};
+
+#[derive(Clone, Copy, PartialEq)]
+enum ATADirection {
+    Read,
+    Write
+}
+
+/// Progress of a request, only modified by the channel
+struct ATARequestState {
+    /// sectors transferred so far
+    done_sec: u64,
+    /// end of the sectors covered by the command in flight
+    issued_sec: u64,
+    result: Result<(), ATAError>
+}
+
+/// A read or write request. The request must stay alive (and must not be moved)
+/// until it is finished, since the channel keeps a pointer to it.
+pub struct ATARequest<'a> {
+    driver: ATADriver,
+    mode: ATAPIOMode,
+    dir: ATADirection,
+    lba: u64,
+    sec_num: u64,
+    buf: *mut u8,
+    state: UnsafeCell<ATARequestState>,
+    finished: AtomicBool,
+    _buf: PhantomData<&'a mut [u8]>
+}
+
+impl<'a> ATARequest<'a> {
+    fn new(disk: &ATADisk, dir: ATADirection, lba: u64, buf: *mut u8, len: usize) -> Result<Self, ATAError> {
+        if !is_sector_aligned(len) || len == 0 {
+            return Err(ATAError::BufferNotAligned)
+        }
+        let mode = disk.info.pio_mode()
+            .ok_or(ATAError::LBANotSupported)?;
+        let sec_num = size_to_lba(len);
+        if lba + sec_num > mode.max_lba() {
+            return Err(ATAError::LBATooLarge)
+        }
+
+        Ok(Self {
+            driver: disk.driver,
+            mode,
+            dir,
+            lba,
+            sec_num,
+            buf,
+            state: UnsafeCell::new(ATARequestState {
+                done_sec: 0,
+                issued_sec: 0,
+                result: Ok(())
+            }),
+            finished: AtomicBool::new(false),
+            _buf: PhantomData
+        })
+    }
+
+    /// Create a request reading `buf.len()` bytes from `lba` into `buf`
+    pub fn read(disk: &ATADisk, lba: u64, buf: &'a mut [u8]) -> Result<Self, ATAError> {
+        Self::new(disk, ATADirection::Read, lba, buf.as_mut_ptr(), buf.len())
+    }
+
+    /// Create a request writing `buf` to the disk from `lba`
+    pub fn write(disk: &ATADisk, lba: u64, buf: &'a [u8]) -> Result<Self, ATAError> {
+        Self::new(disk, ATADirection::Write, lba, buf.as_ptr() as *mut u8, buf.len())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    /// Wait until the request is completed by the IRQ handler, or failed by
+    /// `ATAChannel::check_timeout`. `relax` is called while waiting, a scheduler can
+    /// switch to another task there.
+    pub fn wait(&self, mut relax: impl FnMut()) -> Result<(), ATAError> {
+        while !self.is_finished() {
+            relax();
+        }
+        // the channel never touches a finished request
+        unsafe { core::mem::replace(&mut (*self.state.get()).result, Ok(())) }
+    }
+
+    fn sector_ptr(&self, index: u64) -> *mut [u8; SECTOR_SIZE as usize] {
+        unsafe { self.buf.add(lba_to_size(index) as usize) as *mut [u8; SECTOR_SIZE as usize] }
+    }
+}
+
+/// The request queue of an ATA channel.
+pub struct ATAChannel<const N: usize> {
+    bus: ATABus,
+    queue: [Option<NonNull<ATARequest<'static>>>; N],
+    head: usize,
+    len: usize,
+    /// ticks before a command in flight is considered to be timed out
+    timeout: u64,
+    /// the tick at which the command in flight was issued
+    issued_at: u64
+}
+
+unsafe impl<const N: usize> Send for ATAChannel<N> {}
+
+impl<const N: usize> ATAChannel<N> {
+    /// `timeout` is measured in the ticks passed to `submit`, `handle_irq` and `check_timeout`
+    pub const fn new(bus: ATABus, timeout: u64) -> Self {
+        Self {
+            bus,
+            queue: [None; N],
+            head: 0,
+            len: 0,
+            timeout,
+            issued_at: 0
+        }
+    }
+
+    /// The IRQ line of this channel
+    pub const fn irq(&self) -> u8 {
+        match self.bus {
+            ATABus::Primary => 14,
+            ATABus::Secondary => 15
+        }
+    }
+
+    /// The request in flight. The reference is not bound to `self`, since the
+    /// request is owned by the waiting task rather than the channel.
+    fn current<'r>(&self) -> Option<&'r ATARequest<'static>> {
+        if self.len == 0 {
+            return None
+        }
+        self.queue[self.head].map(|req| unsafe { req.as_ref() })
+    }
+
+    /// Put a request into the queue, the request is started immediately if the
+    /// channel is idle.
+    ///
+    /// # Safety
+    ///
+    /// The request must not be moved or dropped until it is finished.
+    pub unsafe fn submit(&mut self, req: &ATARequest, now: u64) -> Result<(), ATAError> {
+        if req.driver.bus != self.bus {
+            return Err(ATAError::DeviceNotExist)
+        }
+        if self.len == N {
+            return Err(ATAError::QueueFull)
+        }
+
+        let ptr = NonNull::from(req).cast::<ATARequest<'static>>();
+        self.queue[(self.head + self.len) % N] = Some(ptr);
+        self.len += 1;
+
+        if self.len == 1 {
+            self.issue(now);
+        }
+        Ok(())
+    }
+
+    /// Issue the next command of the current request
+    fn issue(&mut self, now: u64) {
+        let req = match self.current() {
+            Some(req) => req,
+            None => return
+        };
+        let state = unsafe { &mut *req.state.get() };
+        let count = (req.sec_num - state.done_sec).min(req.mode.max_sectors_per_cmd());
+        let cmd = match (req.dir, req.mode) {
+            (ATADirection::Read, ATAPIOMode::PIO28) => ATACommand::Read,
+            (ATADirection::Read, ATAPIOMode::PIO48) => ATACommand::ReadExt,
+            (ATADirection::Write, ATAPIOMode::PIO28) => ATACommand::Write,
+            (ATADirection::Write, ATAPIOMode::PIO48) => ATACommand::WriteExt
+        };
+
+        self.issued_at = now;
+        state.issued_sec = state.done_sec + count;
+        req.driver.send_command(req.mode, cmd as u8, req.lba + state.done_sec, count);
+
+        // the device does not raise an interrupt for the first sector of a write
+        if req.dir == ATADirection::Write {
+            match req.driver.pio_wait_data() {
+                Ok(()) => {
+                    req.driver.pio_write_port(req.driver.data_reg(), unsafe { &*req.sector_ptr(state.done_sec) });
+                    state.done_sec += 1;
+                },
+                Err(e) => self.finish(Err(e), now)
+            }
+        }
+    }
+
+    /// Complete the current request and start the next one
+    fn finish(&mut self, result: Result<(), ATAError>, now: u64) {
+        if let Some(req) = self.current() {
+            unsafe { (*req.state.get()).result = result };
+            req.finished.store(true, Ordering::Release);
+        }
+        self.queue[self.head] = None;
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        self.issue(now);
+    }
+
+    /// Should be called by the IRQ handler of this channel (see `irq`).
+    /// Reading the status register acknowledges the interrupt.
+    pub fn handle_irq(&mut self, now: u64) {
+        let req = match self.current() {
+            Some(req) => req,
+            None => {
+                // spurious interrupt, acknowledge it by reading the status
+                inb(ATADriver::new(self.bus, ATADrive::Master).status_reg());
+                return
+            }
+        };
+        let driver = req.driver;
+        let status = inb(driver.status_reg());
+        let state = unsafe { &mut *req.state.get() };
+
+        if status & (ATAStatus::DF as u8 | ATAStatus::ERR as u8) != 0 {
+            let error = ATAError::DeviceFault { status, error: inb(driver.error_reg()) };
+            return self.finish(Err(error), now)
+        }
+        if status & ATAStatus::BSY as u8 != 0 {
+            return
+        }
+
+        match req.dir {
+            ATADirection::Read => {
+                if status & ATAStatus::DRQ as u8 == 0 {
+                    return
+                }
+                driver.pio_read_port(driver.data_reg(), unsafe { &mut *req.sector_ptr(state.done_sec) });
+                state.done_sec += 1;
+            },
+            ATADirection::Write => {
+                if state.done_sec < state.issued_sec {
+                    driver.pio_write_port(driver.data_reg(), unsafe { &*req.sector_ptr(state.done_sec) });
+                    state.done_sec += 1;
+                    return
+                }
+            }
+        }
+
+        if state.done_sec == req.sec_num {
+            self.finish(Ok(()), now)
+        } else if state.done_sec == state.issued_sec {
+            self.issue(now)
+        }
+    }
+
+    /// Should be called periodically, for example by the timer handler.
+    /// If the command in flight takes too long, the channel is reset with
+    /// `pio_sftrst` and the request fails with `ATAError::Timeout`.
+    pub fn check_timeout(&mut self, now: u64) {
+        let driver = match self.current() {
+            Some(req) => req.driver,
+            None => return
+        };
+        if now.wrapping_sub(self.issued_at) > self.timeout {
+            // the request fails with Timeout even if the reset fails
+            let _ = driver.pio_sftrst();
+            self.finish(Err(ATAError::Timeout), now)
+        }
+    }
+}
@@ -1,49 +1,83 @@
 //! This module contains ATA PIO mode operations for protected mode disk access.
 
 use core::{
-    hint::spin_loop, 
     intrinsics::{transmute, size_of},
     arch::asm
 };
 use super::*;
-use crate::{
-    instrs::{inb, outb}, 
-    utils::u8x::{Padding, uint}
-};
+use super::identify::ATADiskInfo;
+use crate::instrs::{inb, outb};
 use crate::utils::disk::*;
+use crate::driver::disk::block::BlockDevice;
+
+/// `ata_delay_400ns` rounds to hold SRST for more than 5us
+const SFTRST_DELAY: usize = 13;
+
+/// An ATA device detected on the IDE controller
+#[derive(Clone, Copy)]
+pub struct ATADisk {
+    pub driver: ATADriver,
+    pub info: ATADiskInfo
+}
 
-/// The disk information read with ATA IDENTIFY command
-#[repr(packed)]
-pub struct ATADiskInfo {
-    _pad0: Padding<{60 * 2}>,
-    /// 60th u16
-    pub lba28_sec: u32,
-    /// 62th u16
-    _pad1: Padding<{21 * 2}>,
-    /// 83th u16
-    pub mode: u16,
-    /// 84th u16
-    _pad2: Padding<{4 * 2}>,
-    /// 88th u16
-    pub umda: u16,
-    /// 89th u16
-    _pad3: Padding<{4 * 2}>,
-    /// 93th u16
-    pub cable: u16,
-    /// 94th u16
-    _pad4: Padding<{6 * 2}>,
-    /// 100th u16 
-    pub lba48_sec: uint<6>,
-    /// 103th u16
-    _pad5: Padding<{153 * 2}>
-    // 256th 
+impl ATADisk {
+    fn mode(&self) -> Result<ATAPIOMode, ATAError> {
+        self.info.pio_mode().ok_or(ATAError::LBANotSupported)
+    }
 }
 
+/// Disk access with ATA PIO, see `pio_read_sectors` for the performance issue.
+impl BlockDevice for ATADisk {
+    type Error = ATAError;
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE as usize
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info.max_sectors()
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), ATAError> {
+        let sec_num = size_to_lba(buf.len());
+        self.driver.pio_read_sectors(self.mode()?, lba, buf, sec_num)
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), ATAError> {
+        let sec_num = size_to_lba(buf.len());
+        self.driver.pio_write_sectors(self.mode()?, lba, buf, sec_num)
+    }
+
+    fn flush(&mut self) -> Result<(), ATAError> {
+        self.driver.pio_flush(self.mode()?)
+    }
+}
+
+#[derive(Clone, Copy)]
 pub enum ATAPIOMode {
     PIO28,
     PIO48
 }
 
+impl ATAPIOMode {
+    /// the first LBA which cannot be addressed in this mode
+    pub const fn max_lba(&self) -> u64 {
+        match self {
+            Self::PIO28 => 1 << 28,
+            Self::PIO48 => 1 << 48
+        }
+    }
+
+    /// The max sector count of a single command. The sector count register
+    /// treats 0 as the maximum value, so we can send this value truncated.
+    pub const fn max_sectors_per_cmd(&self) -> u64 {
+        match self {
+            Self::PIO28 => 1 << 8,
+            Self::PIO48 => 1 << 16
+        }
+    }
+}
+
 impl ATADriver {
 
     /// To use the IDENTIFY command, select a target drive by sending 0xA0 for the 
@@ -63,12 +97,8 @@ impl ATADriver {
     pub fn pio_identify(&self) -> Result<ATADiskInfo, ATAError> {
         let mut result: [u8; 512] = [0; 512];
 
-        let drive = match self {
-            ATADriver::PRIMARY => 0xA0,
-            ATADriver::SECONDARY => 0xB0
-        };
-
-        outb(self.drive_reg(), drive);
+        outb(self.drive_reg(), 0xA0 | self.drive.select_bit());
+        self.ata_delay_400ns();
         outb(self.sector_num_reg(), 0);
         outb(self.lba_lo_reg(), 0);
         outb(self.lba_mid_reg(), 0);
@@ -76,124 +106,221 @@ impl ATADriver {
 
         outb(self.command_reg(), ATACommand::Identify as u8);
 
-        let mut status = inb(self.status_reg());
-        if status == 0 {
+        if inb(self.status_reg()) == 0 {
             return Err(ATAError::DeviceNotExist)
         }
 
-        while status & (ATAStatus::BSY as u8) != 0 {
-            spin_loop();
-            status = inb(self.status_reg());
-        }
+        self.wait_status(|status| status & ATAStatus::BSY as u8 == 0)?;
 
         if inb(self.lba_mid_reg()) | inb(self.lba_hi_reg()) != 0 {
             return Err(ATAError::NotATADevice)
         }
 
-        loop {
-            if status & (ATAStatus::ERR as u8) != 0 {
-                return Err(ATAError::DiskError(inb(self.error_reg())))
-            } else if status & (ATAStatus::DRQ as u8) != 0 {
-                self.pio_read_port(self.data_reg(), &mut result);
-                return Ok(unsafe { transmute(result) })
+        let status = self.wait_status(|status| status & (ATAStatus::ERR as u8 | ATAStatus::DRQ as u8) != 0)?;
+        self.check_status(status)?;
+        self.pio_read_port(self.data_reg(), &mut result);
+        Ok(unsafe { transmute(result) })
+    }
+
+    /// Probe all four possible devices on both channels with IDENTIFY.
+    /// Devices which are absent or are not ATA devices are left as None.
+    pub fn enumerate() -> [Option<ATADisk>; ATA_MAX_DISKS] {
+        let mut disks = [None; ATA_MAX_DISKS];
+        for (slot, driver) in disks.iter_mut().zip(ATADriver::ALL) {
+            // a floating bus reads 0xff, which means there is no drive on this channel
+            if inb(driver.status_reg()) == 0xff {
+                continue
+            }
+            if let Ok(info) = driver.pio_identify() {
+                *slot = Some(ATADisk { driver, info })
             }
-            spin_loop();
-            status = inb(self.status_reg());
         }
+        disks
     }
 
-    pub fn pio_sftrst(&self) {
+    /// Reset both drives on the channel, then wait until this drive is ready.
+    pub fn pio_sftrst(&self) -> Result<(), ATAError> {
         outb(self.dcr_reg(), ATADCR::SFTRST as u8);
+        // SRST should be held for at least 5us
+        for _ in 0..SFTRST_DELAY {
+            self.ata_delay_400ns();
+        }
         outb(self.dcr_reg(), ATADCR::BUSRST as u8);
-    
         self.ata_delay_400ns();
-    
-        let mut status = inb(self.status_reg());
+
+        // the master is selected after a reset
+        self.wait_status(|status| status & ATAStatus::BSY as u8 == 0)?;
+        outb(self.drive_reg(), 0xA0 | self.drive.select_bit());
+        self.ata_delay_400ns();
+
         // spin wait until the BUSY flag is unset and READY flag is set
-        while status & ATAStatus::BSY as u8 != 0 || status & ATAStatus::RDY as u8 != 1{
-            spin_loop();
-            status = inb(self.status_reg());
+        self.wait_status(|status| {
+            status & ATAStatus::BSY as u8 == 0 && status & ATAStatus::RDY as u8 != 0
+        })?;
+        Ok(())
+    }
+
+    /// Run a command with `op`. If the device times out or reports an error,
+    /// the channel is reset and the command is retried at most `self.retries` times.
+    fn pio_retry(&self, mut op: impl FnMut() -> Result<(), ATAError>) -> Result<(), ATAError> {
+        let mut retries = self.retries;
+        loop {
+            match op() {
+                Err(ATAError::Timeout | ATAError::DeviceFault { .. }) if retries > 0 => {
+                    retries -= 1;
+                    self.pio_sftrst()?;
+                },
+                res => return res
+            }
         }
     }
 
-    pub fn pio_read_sectors(&self, lba: u64, buf: &mut [u8], sec_num: u64) -> Result<(), ATAError> {
-        if (buf.len() as u64) < lba_to_size(sec_num) {
+    /// Check the parameters of a transfer and reset the device if the previous
+    /// command left it in a bad state.
+    fn pio_prepare(&self, mode: ATAPIOMode, lba: u64, buf_len: usize, sec_num: u64) -> Result<(), ATAError> {
+        if (buf_len as u64) < lba_to_size(sec_num) {
             return Err(ATAError::BufferOverflow)
         }
-        
-        let status = inb(self.alt_status_reg());
-        // the previous sould have properly cleared BSY and DRQ
-        if status & (ATAStatus::BSY as u8 | ATAStatus::DRQ as u8) != 0 {
-            self.pio_sftrst();
+
+        if !is_sector_aligned(buf_len) {
+            return Err(ATAError::BufferNotAligned)
         }
 
-        if (lba + (buf.len() as u64 >> 9)) >> 33 == 0 {
-            self.pio48_read_sectors(lba, buf, sec_num)
-        } else {
+        if lba + sec_num > mode.max_lba() {
             return Err(ATAError::LBATooLarge)
         }
         
+        let status = inb(self.alt_status_reg());
+        // the previous sould have properly cleared BSY and DRQ
+        if status & (ATAStatus::BSY as u8 | ATAStatus::DRQ as u8) != 0 {
+            self.pio_sftrst()?;
+        }
+        Ok(())
     }
 
-    /// read sectors in PIO mode, this has huge performance issue. So it should only be used
+    /// Read sectors with READ SECTORS (LBA28) or READ SECTORS EXT (LBA48) according
+    /// to `mode`. Large transfers are split into several commands.
+    /// Reading in PIO mode has huge performance issue. So it should only be used
     /// in bootloader for loading kernel into memory.
-    fn pio48_read_sectors(&self, lba: u64, buf: &mut [u8], sec_num: u64) -> Result<(), ATAError> {
-        // now data is ready, we now read data from the data port
-        // the remainder must be empty since we had checked the alignment before
+    pub fn pio_read_sectors(&self, mode: ATAPIOMode, lba: u64, buf: &mut [u8], sec_num: u64) -> Result<(), ATAError> {
+        self.pio_prepare(mode, lba, buf.len(), sec_num)?;
+
         let sectors = slice_as_sectors(buf)
             .ok_or(ATAError::BufferNotAligned)?;
+        let cmd = match mode {
+            ATAPIOMode::PIO28 => ATACommand::Read as u8,
+            ATAPIOMode::PIO48 => ATACommand::ReadExt as u8
+        };
 
-        if sec_num > sectors.len() as u64 {
-            return Err(ATAError::BufferOverflow)
+        let mut done = 0;
+        while done < sec_num {
+            let count = (sec_num - done).min(mode.max_sectors_per_cmd());
+            let chunk = &mut sectors[done as usize..(done + count) as usize];
+            self.pio_retry(|| {
+                self.send_command(mode, cmd, lba + done, count);
+                for sector in chunk.iter_mut() {
+                    self.pio_wait_data()?;
+                    self.pio_read_port(self.data_reg(), sector);
+                }
+                Ok(())
+            })?;
+            done += count;
         }
+        
+        Ok(())
+    }
 
-        let drive = match self {
-            ATADriver::PRIMARY => 0x40,
-            ATADriver::SECONDARY => 0x50
+    /// Write sectors with WRITE SECTORS (LBA28) or WRITE SECTORS EXT (LBA48),
+    /// the write cache is flushed after all sectors are transferred.
+    pub fn pio_write_sectors(&self, mode: ATAPIOMode, lba: u64, buf: &[u8], sec_num: u64) -> Result<(), ATAError> {
+        self.pio_prepare(mode, lba, buf.len(), sec_num)?;
+
+        let (sectors, _) = buf.as_chunks::<{SECTOR_SIZE as usize}>();
+        let cmd = match mode {
+            ATAPIOMode::PIO28 => ATACommand::Write as u8,
+            ATAPIOMode::PIO48 => ATACommand::WriteExt as u8
         };
 
-        
-        // select drive
-        outb(self.drive_reg(), drive);
-        // set pio mode 
-        outb(self.feature_reg(), ATAFeature::PIO as u8);
-
-        // send parameters
-        outb(self.sector_num_reg(), ((sec_num >> 8) & 0xff) as u8);
-        outb(self.lba_lo_reg(), (lba >> 24 & 0xff) as u8);
-        outb(self.lba_mid_reg(), (lba >> 32 & 0xff) as u8);
-        outb(self.lba_hi_reg(), (lba >> 48 & 0xff) as u8);
-        outb(self.sector_num_reg(), ((sec_num >> 0) & 0xff) as u8);
-        outb(self.lba_lo_reg(), (lba >> 0 & 0xff) as u8);
-        outb(self.lba_mid_reg(), (lba >> 8 & 0xff) as u8);
-        outb(self.lba_hi_reg(), (lba >> 16 & 0xff) as u8);
-
-        // send read sector command
-        outb(self.command_reg(), ATACommand::ReadExt as u8);
-
-        for sector in &mut sectors[..sec_num as usize] {
-            // delay 400ns to wait ATA controller to set status registers
+        let mut done = 0;
+        while done < sec_num {
+            let count = (sec_num - done).min(mode.max_sectors_per_cmd());
+            let chunk = &sectors[done as usize..(done + count) as usize];
+            self.pio_retry(|| {
+                self.send_command(mode, cmd, lba + done, count);
+                for sector in chunk {
+                    self.pio_wait_data()?;
+                    self.pio_write_port(self.data_reg(), sector);
+                }
+                // the device is busy until the last sector is written
+                self.pio_wait_data()
+            })?;
+            done += count;
+        }
+
+        self.pio_flush(mode)
+    }
+
+    /// Flush the write cache of the device with CACHE FLUSH (EXT)
+    pub fn pio_flush(&self, mode: ATAPIOMode) -> Result<(), ATAError> {
+        let cmd = match mode {
+            ATAPIOMode::PIO28 => ATACommand::CacheFlush as u8,
+            ATAPIOMode::PIO48 => ATACommand::CacheFlushExt as u8
+        };
+        self.pio_retry(|| {
+            // a command may only be sent when BSY is clear
+            self.wait_not_busy()?;
+            outb(self.command_reg(), cmd);
             self.ata_delay_400ns();
+            self.wait_not_busy()?;
+            Ok(())
+        })
+    }
 
-            let mut status = inb(self.status_reg());
-            // spin wait until the BUSY flag is unset
-            while status & ATAStatus::BSY as u8 != 0 {
-                spin_loop();
-                status = inb(self.status_reg());
-            }
-        
-            // make a error checking
-            if status & (ATAStatus::DF as u8 | ATAStatus::ERR as u8) != 0 {
-                return Err(ATAError::DiskError(inb(self.error_reg())))
+    /// Select the drive and send a LBA28 or LBA48 command.
+    /// `count` must not exceed `mode.max_sectors_per_cmd()`.
+    pub(super) fn send_command(&self, mode: ATAPIOMode, cmd: u8, lba: u64, count: u64) {
+        match mode {
+            ATAPIOMode::PIO28 => {
+                // the highest 4 bits of LBA28 are sent with the drive select register
+                outb(self.drive_reg(), 0xE0 | self.drive.select_bit() | (lba >> 24 & 0x0f) as u8);
+                outb(self.feature_reg(), ATAFeature::PIO as u8);
+                outb(self.sector_num_reg(), (count & 0xff) as u8);
+                outb(self.lba_lo_reg(), (lba >> 0 & 0xff) as u8);
+                outb(self.lba_mid_reg(), (lba >> 8 & 0xff) as u8);
+                outb(self.lba_hi_reg(), (lba >> 16 & 0xff) as u8);
+            },
+            ATAPIOMode::PIO48 => {
+                // select drive
+                outb(self.drive_reg(), 0x40 | self.drive.select_bit());
+                // set pio mode
+                outb(self.feature_reg(), ATAFeature::PIO as u8);
+
+                // send parameters, high bytes first
+                outb(self.sector_num_reg(), ((count >> 8) & 0xff) as u8);
+                outb(self.lba_lo_reg(), (lba >> 24 & 0xff) as u8);
+                outb(self.lba_mid_reg(), (lba >> 32 & 0xff) as u8);
+                outb(self.lba_hi_reg(), (lba >> 40 & 0xff) as u8);
+                outb(self.sector_num_reg(), ((count >> 0) & 0xff) as u8);
+                outb(self.lba_lo_reg(), (lba >> 0 & 0xff) as u8);
+                outb(self.lba_mid_reg(), (lba >> 8 & 0xff) as u8);
+                outb(self.lba_hi_reg(), (lba >> 16 & 0xff) as u8);
             }
-            self.pio_read_port(self.data_reg(), sector);
         }
-        
+
+        outb(self.command_reg(), cmd);
+    }
+
+    /// Wait until the device is ready to transfer the next sector.
+    pub(super) fn pio_wait_data(&self) -> Result<(), ATAError> {
+        // delay 400ns to wait ATA controller to set status registers
+        self.ata_delay_400ns();
+        // spin wait until the BUSY flag is unset, and make a error checking
+        self.wait_not_busy()?;
         Ok(())
     }
 
     /// MAKE SURE SIZE IS EVEN!!!
-    fn pio_read_port<const SIZE: usize>(&self, port: u16, buf: &mut [u8; SIZE]) {
+    pub(super) fn pio_read_port<const SIZE: usize>(&self, port: u16, buf: &mut [u8; SIZE]) {
         unsafe {
             asm! {
                 "rep insw",
@@ -204,6 +331,18 @@ impl ATADriver {
             }
         }
     }
-}
 
+    /// MAKE SURE SIZE IS EVEN!!!
+    pub(super) fn pio_write_port<const SIZE: usize>(&self, port: u16, buf: &[u8; SIZE]) {
+        unsafe {
+            asm! {
+                "rep outsw",
+                // BE AWARE THAT REP MODIFIES ECX AND ESI!!!
+                inout("ecx") SIZE / size_of::<u16>() => _,
+                in("dx") port,
+                inout("esi") buf => _
+            }
+        }
+    }
+}
 
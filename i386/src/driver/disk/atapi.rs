@@ -0,0 +1,229 @@
+//! Support for ATAPI (packet interface) devices on the IDE controller, mainly CD-ROMs.
+//! ATAPI devices share the registers of ATA devices, but most operations are done
+//! by sending SCSI command packets with the PACKET command.
+//! See https://wiki.osdev.org/ATAPI
+
+use core::{arch::asm, intrinsics::transmute};
+use super::ata::{
+    ATADriver, ATAError, ATAStatus, ATA_MAX_DISKS,
+    identify::ATADiskInfo
+};
+use crate::instrs::{inb, inw, outb, outw};
+
+/// CD-ROMs always use 2048 bytes sectors
+pub const ATAPI_SECTOR_SIZE: usize = 2048;
+pub const ATAPI_SECTOR_ALIGN: u16 = 11;
+
+/// the max byte count of a single DRQ data block, must be even
+const MAX_BYTE_COUNT: u16 = 0xf800;
+
+#[repr(u8)]
+enum ATAPICommand {
+    Packet = 0xA0,
+    IdentifyPacket = 0xA1
+}
+
+/// SCSI operation codes sent in command packets
+#[repr(u8)]
+enum SCSICommand {
+    TestUnitReady = 0x00,
+    ReadCapacity = 0x25,
+    Read10 = 0x28,
+    Read12 = 0xA8
+}
+
+/// The capacity reported by READ CAPACITY
+#[derive(Clone, Copy)]
+pub struct ATAPICapacity {
+    /// number of blocks on the medium
+    pub blocks: u64,
+    /// size of a block in bytes, 2048 for CD-ROMs
+    pub block_size: u32
+}
+
+/// An ATAPI device detected on the IDE controller
+#[derive(Clone, Copy)]
+pub struct ATAPIDisk {
+    pub driver: ATAPIDriver,
+    pub info: ATADiskInfo
+}
+
+/// The driver of an ATAPI device, identified by its channel and drive.
+#[derive(Clone, Copy)]
+pub struct ATAPIDriver {
+    pub driver: ATADriver
+}
+
+impl ATAPIDriver {
+    pub const fn new(driver: ATADriver) -> Self {
+        Self { driver }
+    }
+
+    /// Probe all four possible devices on both channels with IDENTIFY PACKET DEVICE.
+    /// Devices which are absent or are not ATAPI devices are left as None.
+    pub fn enumerate() -> [Option<ATAPIDisk>; ATA_MAX_DISKS] {
+        let mut disks = [None; ATA_MAX_DISKS];
+        for (slot, driver) in disks.iter_mut().zip(ATADriver::ALL) {
+            // a floating bus reads 0xff, which means there is no drive on this channel
+            if inb(driver.status_reg()) == 0xff {
+                continue
+            }
+            let driver = ATAPIDriver::new(driver);
+            if let Ok(info) = driver.identify() {
+                *slot = Some(ATAPIDisk { driver, info })
+            }
+        }
+        disks
+    }
+
+    /// Send IDENTIFY PACKET DEVICE, which returns 256 words in the same layout
+    /// as ATA IDENTIFY. ATA devices abort this command.
+    pub fn identify(&self) -> Result<ATADiskInfo, ATAError> {
+        let mut result: [u8; 512] = [0; 512];
+        let ata = &self.driver;
+
+        outb(ata.drive_reg(), 0xA0 | ata.drive.select_bit());
+        ata.ata_delay_400ns();
+        outb(ata.command_reg(), ATAPICommand::IdentifyPacket as u8);
+
+        if inb(ata.status_reg()) == 0 {
+            return Err(ATAError::DeviceNotExist)
+        }
+
+        if !matches!(self.wait_drq(), Ok(true)) {
+            return Err(ATAError::NotATAPIDevice)
+        }
+        self.read_data(&mut result);
+        Ok(unsafe { transmute(result) })
+    }
+
+    /// Wait until BSY clears. Returns whether the device requests a data transfer.
+    fn wait_drq(&self) -> Result<bool, ATAError> {
+        let ata = &self.driver;
+        ata.ata_delay_400ns();
+
+        let status = ata.wait_not_busy()?;
+        Ok(status & ATAStatus::DRQ as u8 != 0)
+    }
+
+    /// Send a 12-byte SCSI command packet with the PACKET command, and read the
+    /// returned data into `buf`. Returns the number of bytes transferred.
+    fn packet(&self, packet: &[u8; 12], buf: &mut [u8]) -> Result<usize, ATAError> {
+        let ata = &self.driver;
+
+        outb(ata.drive_reg(), 0xA0 | ata.drive.select_bit());
+        ata.ata_delay_400ns();
+        // PIO mode, the max byte count of a DRQ block is sent with LBA mid and hi
+        outb(ata.feature_reg(), 0);
+        outb(ata.lba_mid_reg(), (MAX_BYTE_COUNT & 0xff) as u8);
+        outb(ata.lba_hi_reg(), (MAX_BYTE_COUNT >> 8) as u8);
+        outb(ata.command_reg(), ATAPICommand::Packet as u8);
+
+        // the device asks for the command packet first
+        if !self.wait_drq()? {
+            let status = inb(ata.alt_status_reg());
+            return Err(ATAError::DeviceFault { status, error: inb(ata.error_reg()) })
+        }
+        for word in packet.chunks(2) {
+            outw(ata.data_reg(), u16::from_le_bytes([word[0], word[1]]));
+        }
+
+        let mut done = 0;
+        while self.wait_drq()? {
+            let count = (inb(ata.lba_mid_reg()) as usize) | (inb(ata.lba_hi_reg()) as usize) << 8;
+            let len = count.min(buf.len() - done) & !1;
+            self.read_data(&mut buf[done..done + len]);
+            // drain the data which does not fit into the buffer
+            for _ in 0..(count - len + 1) / 2 {
+                inw(ata.data_reg());
+            }
+            done += len;
+        }
+        Ok(done)
+    }
+
+    /// MAKE SURE THE LENGTH OF BUF IS EVEN!!!
+    fn read_data(&self, buf: &mut [u8]) {
+        unsafe {
+            asm! {
+                "rep insw",
+                // BE AWARE THAT REP MODIFIES ECX AND EDI!!!
+                inout("ecx") buf.len() / 2 => _,
+                in("dx") self.driver.data_reg(),
+                inout("edi") buf.as_mut_ptr() => _
+            }
+        }
+    }
+
+    /// Check whether a medium is inserted and ready
+    pub fn test_unit_ready(&self) -> Result<(), ATAError> {
+        let packet = [SCSICommand::TestUnitReady as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        self.packet(&packet, &mut [])?;
+        Ok(())
+    }
+
+    pub fn read_capacity(&self) -> Result<ATAPICapacity, ATAError> {
+        let packet = [SCSICommand::ReadCapacity as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut data = [0_u8; 8];
+        if self.packet(&packet, &mut data)? != data.len() {
+            return Err(ATAError::BufferOverflow)
+        }
+        // the response is big endian, and contains the LBA of the last block
+        let last_lba = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        Ok(ATAPICapacity {
+            blocks: last_lba as u64 + 1,
+            block_size: u32::from_be_bytes([data[4], data[5], data[6], data[7]])
+        })
+    }
+
+    /// READ(10), reads at most 0xffff sectors
+    pub fn read10(&self, lba: u32, buf: &mut [u8]) -> Result<(), ATAError> {
+        let sec_num = self.sectors_of(buf, u16::MAX as usize)?;
+        let lba = lba.to_be_bytes();
+        let len = (sec_num as u16).to_be_bytes();
+        let packet = [
+            SCSICommand::Read10 as u8, 0,
+            lba[0], lba[1], lba[2], lba[3],
+            0, len[0], len[1],
+            0, 0, 0
+        ];
+        self.packet(&packet, buf)?;
+        Ok(())
+    }
+
+    /// READ(12), which has a 32-bit transfer length
+    pub fn read12(&self, lba: u32, buf: &mut [u8]) -> Result<(), ATAError> {
+        let sec_num = self.sectors_of(buf, u32::MAX as usize)?;
+        let lba = lba.to_be_bytes();
+        let len = (sec_num as u32).to_be_bytes();
+        let packet = [
+            SCSICommand::Read12 as u8, 0,
+            lba[0], lba[1], lba[2], lba[3],
+            len[0], len[1], len[2], len[3],
+            0, 0
+        ];
+        self.packet(&packet, buf)?;
+        Ok(())
+    }
+
+    /// Read 2048-byte sectors from `lba`, READ(12) is used only if READ(10) can
+    /// not express the transfer length.
+    pub fn read_sectors(&self, lba: u32, buf: &mut [u8]) -> Result<(), ATAError> {
+        if buf.len() >> ATAPI_SECTOR_ALIGN > u16::MAX as usize {
+            self.read12(lba, buf)
+        } else {
+            self.read10(lba, buf)
+        }
+    }
+
+    fn sectors_of(&self, buf: &[u8], max: usize) -> Result<usize, ATAError> {
+        if buf.len() & (ATAPI_SECTOR_SIZE - 1) != 0 {
+            return Err(ATAError::BufferNotAligned)
+        }
+        let sec_num = buf.len() >> ATAPI_SECTOR_ALIGN;
+        if sec_num > max {
+            return Err(ATAError::BufferOverflow)
+        }
+        Ok(sec_num)
+    }
+}
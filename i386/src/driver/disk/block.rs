@@ -0,0 +1,137 @@
+//! A common interface of disks which are accessed in fixed size blocks.
+//! File systems should be written against `BlockDevice`, so they work with
+//! BIOS disk services in real mode as well as ATA / AHCI disks in protected mode.
+
+/// The largest block size `ByteAdapter` can handle
+pub const MAX_BLOCK_SIZE: usize = 4096;
+
+/// A disk which is read and written in blocks (sectors).
+pub trait BlockDevice {
+    type Error: DeviceError;
+
+    /// The size of a block in bytes
+    fn block_size(&self) -> usize;
+    /// The number of blocks on the device
+    fn block_count(&self) -> u64;
+    /// Read `buf.len() / block_size()` blocks from `lba` into `buf`.
+    /// The length of `buf` must be a multiple of the block size.
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), Self::Error>;
+    /// Write `buf` to the device from `lba`.
+    /// The length of `buf` must be a multiple of the block size.
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), Self::Error>;
+    /// Make sure all written blocks reach the medium
+    fn flush(&mut self) -> Result<(), Self::Error>;
+}
+
+/// The error of a `BlockDevice`, which is reduced to a status byte when it is passed
+/// through the `FileSystem` trait
+pub trait DeviceError {
+    /// The status the device reported, like the ATA error register or the BIOS status
+    /// in AH, 0 if the driver detected the error itself
+    fn status(&self) -> u8;
+}
+
+/// Byte granular access to a block device. Unaligned heads and tails of a transfer
+/// go through a bounce buffer, the aligned middle part is transferred directly.
+pub struct ByteAdapter<D: BlockDevice> {
+    dev: D
+}
+
+impl<D: BlockDevice> ByteAdapter<D> {
+    /// Block devices with blocks larger than `MAX_BLOCK_SIZE` are not supported.
+    pub fn new(dev: D) -> Self {
+        assert!(dev.block_size() <= MAX_BLOCK_SIZE);
+        Self { dev }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.dev
+    }
+
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.dev
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+
+    /// The size of the device in bytes
+    pub fn size(&self) -> u64 {
+        self.dev.block_count().saturating_mul(self.dev.block_size() as u64)
+    }
+
+    /// Clamp a transfer to the end of the device, returns the length to transfer.
+    fn clamp(&self, offset: u64, len: usize) -> usize {
+        let size = self.size();
+        if offset >= size {
+            return 0
+        }
+        (size - offset).min(len as u64) as usize
+    }
+
+    /// Read `buf.len()` bytes from `offset`. Returns the number of bytes read,
+    /// which is less than `buf.len()` only at the end of the device.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, D::Error> {
+        let bs = self.dev.block_size();
+        let len = self.clamp(offset, buf.len());
+        let mut bounce = [0_u8; MAX_BLOCK_SIZE];
+        let bounce = &mut bounce[..bs];
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let lba = pos / bs as u64;
+            let skip = (pos % bs as u64) as usize;
+            let rest = len - done;
+
+            if skip == 0 && rest >= bs {
+                // the aligned middle part, read into the buffer directly
+                let aligned = rest - rest % bs;
+                self.dev.read_blocks(lba, &mut buf[done..done + aligned])?;
+                done += aligned;
+            } else {
+                let part = (bs - skip).min(rest);
+                self.dev.read_blocks(lba, bounce)?;
+                buf[done..done + part].copy_from_slice(&bounce[skip..skip + part]);
+                done += part;
+            }
+        }
+        Ok(len)
+    }
+
+    /// Write `buf` to `offset`. Partially written blocks are read first.
+    /// Returns the number of bytes written, which is less than `buf.len()`
+    /// only at the end of the device.
+    pub fn write(&mut self, offset: u64, buf: &[u8]) -> Result<usize, D::Error> {
+        let bs = self.dev.block_size();
+        let len = self.clamp(offset, buf.len());
+        let mut bounce = [0_u8; MAX_BLOCK_SIZE];
+        let bounce = &mut bounce[..bs];
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let lba = pos / bs as u64;
+            let skip = (pos % bs as u64) as usize;
+            let rest = len - done;
+
+            if skip == 0 && rest >= bs {
+                let aligned = rest - rest % bs;
+                self.dev.write_blocks(lba, &buf[done..done + aligned])?;
+                done += aligned;
+            } else {
+                let part = (bs - skip).min(rest);
+                self.dev.read_blocks(lba, bounce)?;
+                bounce[skip..skip + part].copy_from_slice(&buf[done..done + part]);
+                self.dev.write_blocks(lba, bounce)?;
+                done += part;
+            }
+        }
+        Ok(len)
+    }
+
+    pub fn flush(&mut self) -> Result<(), D::Error> {
+        self.dev.flush()
+    }
+}
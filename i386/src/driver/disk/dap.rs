@@ -1,13 +1,211 @@
-//! This module contains operations on DAP interface for real mode hard disk read.
+//! This module contains operations on BIOS disk services for real mode hard disk access.
+//! INT 13h extensions (EDD) are used if the BIOS supports them, otherwise we fall back
+//! to the legacy CHS interface with the geometry reported by BIOS.
+//! See https://en.wikipedia.org/wiki/INT_13H
 
-use crate::utils::disk::*;
-use core::arch::asm;
+use crate::{
+    utils::disk::*,
+    driver::disk::block::{BlockDevice, DeviceError}
+};
+use core::{arch::asm, fmt};
 
 const MAX_READ_BYTES: u32 = 0x10000;
 const MAX_READ_SECTORS: u16 = (MAX_READ_BYTES >> SECTOR_ALIGN as u32) as u16;
 
+/// AH=41h returns this in BX if extensions are installed
+const EDD_MAGIC: u16 = 0xaa55;
+/// AH=41h interface support bitmap (CX) bit 0: AH=42h-44h, 47h, 48h are supported
+const EDD_DAP_ACCESS: u16 = 1 << 0;
+
+/// INT 13h function numbers
+const CMD_RESET: u8 = 0x00;
+const CMD_READ_CHS: u8 = 0x02;
+const CMD_WRITE_CHS: u8 = 0x03;
+const CMD_GEOMETRY: u8 = 0x08;
+const CMD_CHECK_EXT: u8 = 0x41;
+const CMD_READ_EXT: u8 = 0x42;
+const CMD_WRITE_EXT: u8 = 0x43;
+const CMD_PARAMS_EXT: u8 = 0x48;
+
+#[derive(Debug)]
 pub enum DAPError {
-    DiskError
+    /// BIOS failed the request with the status in AH
+    DiskError(u8),
+    /// the LBA can not be addressed with CHS
+    OutOfRange
+}
+
+impl fmt::Display for DAPError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DiskError(status) => write!(f, "BIOS Disk Error: status {:#04x}", status),
+            Self::OutOfRange => write!(f, "BIOS Disk Error: out of range")
+        }
+    }
+}
+
+impl DeviceError for DAPError {
+    fn status(&self) -> u8 {
+        match self {
+            Self::DiskError(status) => *status,
+            Self::OutOfRange => 0
+        }
+    }
+}
+
+/// The disk geometry reported by INT 13h AH=08h
+#[derive(Clone, Copy)]
+pub struct CHSGeometry {
+    pub cylinders: u16,
+    pub heads: u16,
+    pub sectors_per_track: u8
+}
+
+impl CHSGeometry {
+    pub const fn total_sectors(&self) -> u64 {
+        self.cylinders as u64 * self.heads as u64 * self.sectors_per_track as u64
+    }
+
+    /// Translate a LBA into (cylinder, head, sector), sectors start from 1
+    const fn to_chs(&self, lba: u64) -> Option<(u16, u8, u8)> {
+        if lba >= self.total_sectors() {
+            return None
+        }
+        let spt = self.sectors_per_track as u64;
+        let track = lba / spt;
+        Some((
+            (track / self.heads as u64) as u16,
+            (track % self.heads as u64) as u8,
+            (lba % spt) as u8 + 1
+        ))
+    }
+}
+
+/// The drive parameters returned by INT 13h AH=48h
+/// https://en.wikipedia.org/wiki/INT_13H#INT_13h_AH=48h:_Extended_Read_Drive_Parameters
+#[repr(C, packed)]
+struct DriveParams {
+    /// the size of this buffer, set by the caller
+    self_size: u16,
+    flags: u16,
+    cylinders: u32,
+    heads: u32,
+    sectors_per_track: u32,
+    total_sectors: u64,
+    bytes_per_sector: u16
+}
+
+/// How the disk is accessed
+#[derive(Clone, Copy)]
+pub enum DAPAccess {
+    /// INT 13h extensions, with LBA in a disk address packet
+    Extended,
+    /// the legacy interface, addressed with cylinder, head and sector
+    CHS(CHSGeometry)
+}
+
+/// A disk accessed with BIOS disk services, identified by its BIOS drive number.
+#[derive(Clone, Copy)]
+pub struct DAPDisk {
+    drive: u8,
+    access: DAPAccess,
+    sectors: u64
+}
+
+impl DAPDisk {
+    /// Reset the disk system and detect how the disk can be accessed.
+    pub fn new(drive: u8) -> Result<Self, DAPError> {
+        reset_disk(drive)?;
+        if check_extensions(drive) {
+            // the parameters are optional, BIOS checks the LBA anyway
+            let sectors = match query_params(drive) {
+                Some(params) if params.bytes_per_sector == SECTOR_SIZE as u16
+                    && params.total_sectors != 0 => params.total_sectors,
+                _ => u64::MAX
+            };
+            Ok(Self { drive, access: DAPAccess::Extended, sectors })
+        } else {
+            let geometry = query_geometry(drive)?;
+            Ok(Self { drive, access: DAPAccess::CHS(geometry), sectors: geometry.total_sectors() })
+        }
+    }
+
+    pub const fn drive(&self) -> u8 {
+        self.drive
+    }
+
+    pub const fn access(&self) -> DAPAccess {
+        self.access
+    }
+
+    fn transfer(&self, cmd: (u8, u8), lba: u64, buffer: *const u8, len: usize) -> Result<(), DAPError> {
+        match self.access {
+            DAPAccess::Extended => {
+                DAP::new((self.drive, lba), far_ptr(buffer as usize), len).transfer(cmd.0)
+            },
+            DAPAccess::CHS(geometry) => {
+                transfer_chs(self.drive, cmd.1, &geometry, lba, buffer as usize, len)
+            }
+        }
+    }
+}
+
+impl BlockDevice for DAPDisk {
+    type Error = DAPError;
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE as usize
+    }
+
+    /// u64::MAX if BIOS does not report the size of an extended disk
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    #[inline(always)]
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), DAPError> {
+        self.transfer((CMD_READ_EXT, CMD_READ_CHS), lba, buf.as_ptr(), buf.len())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), DAPError> {
+        self.transfer((CMD_WRITE_EXT, CMD_WRITE_CHS), lba, buf.as_ptr(), buf.len())
+    }
+
+    /// BIOS writes are synchronous
+    fn flush(&mut self) -> Result<(), DAPError> {
+        Ok(())
+    }
+}
+
+/// The read path of the boot sector: reset the disk and read `buf` from `lba`, with a
+/// single AH=42h request if INT 13h extensions are available, or sector by sector
+/// with AH=02h otherwise. `buf` must not exceed 64KiB and must end below LBA 65536,
+/// so the CHS translation fits in 16 bits. Use `DAPDisk` anywhere else.
+#[inline(always)]
+pub fn boot_read(drive: u8, lba: u64, buf: &mut [u8]) -> Result<(), DAPError> {
+    reset_disk(drive)?;
+    if check_extensions(drive) {
+        let dap = DAP::new((drive, lba), far_ptr(buf.as_ptr() as usize), buf.len());
+        return extended_sectors(CMD_READ_EXT, drive, &dap as *const DAP)
+    }
+
+    // a single sector never crosses a track, which keeps this small enough for the boot sector
+    let geometry = query_geometry(drive)?;
+    let spt = geometry.sectors_per_track as u16;
+    let mut lba = lba as u16;
+    for sector in buf.chunks_mut(SECTOR_SIZE as usize) {
+        let track = lba / spt;
+        let chs = (track / geometry.heads, (track % geometry.heads) as u8, (lba % spt) as u8 + 1);
+        let addr = sector.as_ptr() as usize;
+        chs_sectors(CMD_READ_CHS, drive, chs, ((addr & 0xf) as u16, (addr >> 4) as u16), 1)?;
+        lba += 1;
+    }
+    Ok(())
+}
+
+/// Split a linear address into (offset, segment)
+const fn far_ptr(addr: usize) -> (u16, u16) {
+    (addr as u16, ((addr >> 16) as u16) << 12)
 }
 
 /// https://en.wikipedia.org/wiki/INT_13H#INT_13h_AH=42h:_Extended_Read_Sectors_From_Drive
@@ -18,15 +216,17 @@ struct DAP {
     reserved: u8,
     sector_num: u16,
     /// This pointer should obey the form segment:offset.
-    /// On x86, offset comes before segment. 
+    /// On x86, offset comes before segment.
     /// Address = Segment << 4 + Offset (real mode)
     buffer_ptr: (u16, u16),
     start_lba: u64,
     disk_id: u8
 }
 
+/// Issue AH=42h (read) or AH=43h (write) with the DAP at `dap_ptr`.
+/// AL=0 asks BIOS to write without verification.
 #[inline(always)]
-fn extended_read_sectors(disk: u8, dap_ptr: *const DAP) -> Result<(), DAPError> {
+fn extended_sectors(cmd: u8, disk: u8, dap_ptr: *const DAP) -> Result<(), DAPError> {
     let mut res: u16;
     unsafe {
         asm! {
@@ -35,43 +235,166 @@ fn extended_read_sectors(disk: u8, dap_ptr: *const DAP) -> Result<(), DAPError>
             "int 0x13",
             "pop si",
             si = in(reg) dap_ptr,
-            inout("ax") 0x4200_u16 => res,
+            inout("ax") (cmd as u16) << 8 => res,
+            in("dl") disk,
+        }
+    }
+    match (res >> 8) as u8 {
+        0 => Ok(()),
+        status => Err(DAPError::DiskError(status))
+    }
+}
+
+/// Check whether INT 13h extensions are available for `disk` with AH=41h.
+fn check_extensions(disk: u8) -> bool {
+    let mut res: u16;
+    let magic: u16;
+    let support: u16;
+    unsafe {
+        asm! {
+            "push bx",
+            "mov bx, 0x55aa",
+            "int 13h",
+            "mov di, bx",
+            "pop bx",
+            inout("ax") (CMD_CHECK_EXT as u16) << 8 => res,
+            out("di") magic,
+            out("cx") support,
+            inout("dx") disk as u16 => _,
+        }
+    }
+    res >> 8 == 0 && magic == EDD_MAGIC && support & EDD_DAP_ACCESS != 0
+}
+
+/// Query the size of an extended disk with AH=48h
+fn query_params(disk: u8) -> Option<DriveParams> {
+    let mut params = DriveParams {
+        self_size: core::mem::size_of::<DriveParams>() as u16,
+        flags: 0,
+        cylinders: 0,
+        heads: 0,
+        sectors_per_track: 0,
+        total_sectors: 0,
+        bytes_per_sector: 0
+    };
+    let mut res: u16;
+    unsafe {
+        asm! {
+            "push si",
+            "mov si, {si:x}",
+            "int 0x13",
+            "pop si",
+            si = in(reg) &mut params as *mut DriveParams,
+            inout("ax") (CMD_PARAMS_EXT as u16) << 8 => res,
             in("dl") disk,
         }
     }
     if res >> 8 == 0 {
-        Ok(())
+        Some(params)
     } else {
-        Err(DAPError::DiskError)
+        None
+    }
+}
+
+/// Query the CHS geometry with AH=08h.
+/// https://en.wikipedia.org/wiki/INT_13H#INT_13h_AH=08h:_Read_Drive_Parameters
+fn query_geometry(disk: u8) -> Result<CHSGeometry, DAPError> {
+    let mut res: u16;
+    let cx: u16;
+    let dx: u16;
+    unsafe {
+        // ES:DI is set to 0:0 to work around some buggy BIOSes, it is
+        // overwritten with the floppy parameter table. BL is the drive type.
+        asm! {
+            "push es",
+            "push bx",
+            "xor di, di",
+            "mov es, di",
+            "int 13h",
+            "pop bx",
+            "pop es",
+            inout("ax") (CMD_GEOMETRY as u16) << 8 => res,
+            out("cx") cx,
+            inout("dx") disk as u16 => dx,
+            out("di") _,
+        }
+    }
+    // CX: bits 0-5 are the max sector number, bits 6-7 are the high bits of the
+    // max cylinder number, whose low bits are in bits 8-15
+    let sectors_per_track = (cx & 0x3f) as u8;
+    if res >> 8 != 0 || sectors_per_track == 0 {
+        return Err(DAPError::DiskError((res >> 8) as u8))
+    }
+    Ok(CHSGeometry {
+        cylinders: ((cx >> 8) | (cx & 0xc0) << 2) + 1,
+        heads: (dx >> 8) + 1,
+        sectors_per_track
+    })
+}
+
+/// Issue AH=02h (read) or AH=03h (write) for `count` sectors in a track
+#[inline(always)]
+fn chs_sectors(cmd: u8, disk: u8, chs: (u16, u8, u8), buffer: (u16, u16), count: u8) -> Result<(), DAPError> {
+    let (cylinder, head, sector) = chs;
+    let cx = (cylinder << 8) | ((cylinder >> 2) & 0xc0) | sector as u16;
+    let mut res: u16;
+    unsafe {
+        asm! {
+            "push es",
+            "push bx",
+            "mov es, {seg:x}",
+            "mov bx, di",
+            "int 13h",
+            "pop bx",
+            "pop es",
+            seg = in(reg) buffer.1,
+            inout("ax") (cmd as u16) << 8 | count as u16 => res,
+            in("di") buffer.0,
+            in("cx") cx,
+            in("dx") (head as u16) << 8 | disk as u16,
+        }
+    }
+    match (res >> 8) as u8 {
+        0 => Ok(()),
+        status => Err(DAPError::DiskError(status))
     }
 }
 
-pub(crate) fn read_disk(addr: (u8, u64), buffer: &mut [u8]) -> Result<(), DAPError> {
-    let dest = buffer.as_ptr() as usize;
-    let dap = DAP::new(addr, (dest as u16, ((dest >> 16) as u16) << 12), buffer.len());
-    dap.read()?;
+/// Transfer `len` bytes from `lba` with the CHS interface. A request never crosses
+/// a track, since not all BIOSes support multitrack transfers.
+fn transfer_chs(disk: u8, cmd: u8, geometry: &CHSGeometry, lba: u64, buffer: usize, len: usize) -> Result<(), DAPError> {
+    let total = (len >> SECTOR_ALIGN) as u64;
+    let mut done = 0;
+    while done < total {
+        let chs = geometry.to_chs(lba + done)
+            .ok_or(DAPError::OutOfRange)?;
+        let count = ((geometry.sectors_per_track - chs.2 + 1) as u64).min(total - done);
+        // use a normalized segment:offset so the offset never wraps in a track
+        let addr = buffer + lba_to_size(done) as usize;
+        chs_sectors(cmd, disk, chs, ((addr & 0xf) as u16, (addr >> 4) as u16), count as u8)?;
+        done += count;
+    }
     Ok(())
 }
 
-pub(crate) fn reset_disk(disk_id: u8) -> Result<(), DAPError> {
+fn reset_disk(disk_id: u8) -> Result<(), DAPError> {
     let mut res: u16;
     unsafe {
         asm! {
             "int 13h",
             in("dl") disk_id,
-            inout("ax") 0_u16 => res
+            inout("ax") (CMD_RESET as u16) << 8 => res
         }
     }
-    if res >> 8 == 0 {
-        Ok(())
-    } else {
-        Err(DAPError::DiskError)
+    match (res >> 8) as u8 {
+        0 => Ok(()),
+        status => Err(DAPError::DiskError(status))
     }
 }
 
 impl DAP {
     /// - disk: (disk_id, start_lba)
-    /// - buffer: (segment, offset)
+    /// - buffer: (offset, segment)
     /// - len: length in bytes
     const fn new(disk: (u8, u64), buffer: (u16, u16), len: usize) -> Self {
         Self {
@@ -84,19 +407,20 @@ impl DAP {
         }
     }
 
+    /// Issue `cmd` (AH=42h or AH=43h), splitting transfers larger than 64KiB
     #[inline(always)]
-    fn read(mut self) -> Result<(), DAPError> {
+    fn transfer(mut self, cmd: u8) -> Result<(), DAPError> {
         if self.sector_num > MAX_READ_SECTORS {
             let remained_sectors = self.sector_num - MAX_READ_SECTORS;
             self.sector_num = MAX_READ_SECTORS as u16;
-            extended_read_sectors(self.disk_id, &self as *const DAP)?;
+            extended_sectors(cmd, self.disk_id, &self as *const DAP)?;
 
             self.buffer_ptr.1 += 0x1000;
             self.start_lba += MAX_READ_SECTORS as u64;
             self.sector_num = remained_sectors;
-            self.read()
+            self.transfer(cmd)
         } else {
-            extended_read_sectors(self.disk_id, &self as *const DAP)?;
+            extended_sectors(cmd, self.disk_id, &self as *const DAP)?;
             Ok(())
         }
     }
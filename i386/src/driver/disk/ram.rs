@@ -0,0 +1,132 @@
+//! A block device backed by memory, for example an initrd loaded by the bootloader
+//! or a heap buffer. File systems can be tested on it without any disk access.
+
+use core::fmt;
+use crate::{
+    driver::disk::block::{BlockDevice, DeviceError},
+    utils::disk::SECTOR_SIZE
+};
+
+#[derive(Debug)]
+pub enum RamDiskError {
+    /// the buffer is not a multiple of the block size
+    BufferNotAligned,
+    /// the transfer exceeds the end of the disk
+    OutOfRange,
+    /// the disk is read only
+    ReadOnly
+}
+
+impl fmt::Display for RamDiskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BufferNotAligned => write!(f, "RAM Disk Error: alignment"),
+            Self::OutOfRange => write!(f, "RAM Disk Error: out of range"),
+            Self::ReadOnly => write!(f, "RAM Disk Error: read only")
+        }
+    }
+}
+
+impl DeviceError for RamDiskError {
+    fn status(&self) -> u8 {
+        0
+    }
+}
+
+/// A block device on top of a byte buffer `S`, which is a `&'static mut [u8]` for
+/// a memory region, or an owned buffer like `Vec<u8>`. A trailing partial block
+/// of the buffer is not accessible.
+pub struct RamDisk<S: AsRef<[u8]> + AsMut<[u8]>> {
+    data: S,
+    block_size: usize,
+    read_only: bool
+}
+
+impl RamDisk<&'static mut [u8]> {
+    /// Use the memory region at physical address `base` as a disk.
+    ///
+    /// # Safety
+    /// The region must be mapped and must not be used by anything else,
+    /// for example the initrd loaded by the bootloader.
+    pub unsafe fn from_raw(base: usize, size: usize) -> Self {
+        Self::new(core::slice::from_raw_parts_mut(base as *mut u8, size))
+    }
+}
+
+impl<S: AsRef<[u8]> + AsMut<[u8]>> RamDisk<S> {
+    /// A writable disk with 512 bytes blocks
+    pub fn new(data: S) -> Self {
+        Self { data, block_size: SECTOR_SIZE as usize, read_only: false }
+    }
+
+    /// Use another block size, which must be a power of two
+    pub fn with_block_size(self, block_size: usize) -> Self {
+        assert!(block_size.is_power_of_two());
+        Self { block_size, ..self }
+    }
+
+    /// Reject all writes with `RamDiskError::ReadOnly`
+    pub fn read_only(self) -> Self {
+        Self { read_only: true, ..self }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// The whole buffer, including a trailing partial block
+    pub fn as_bytes(&self) -> &[u8] {
+        self.data.as_ref()
+    }
+
+    pub fn into_inner(self) -> S {
+        self.data
+    }
+
+    /// The byte range of a transfer of `len` bytes from `lba`
+    fn range(&self, lba: u64, len: usize) -> Result<core::ops::Range<usize>, RamDiskError> {
+        if len % self.block_size != 0 {
+            return Err(RamDiskError::BufferNotAligned)
+        }
+        let blocks = (len / self.block_size) as u64;
+        match lba.checked_add(blocks) {
+            Some(end) if end <= self.block_count() => {
+                let start = lba as usize * self.block_size;
+                Ok(start..start + len)
+            },
+            _ => Err(RamDiskError::OutOfRange)
+        }
+    }
+}
+
+impl<S: AsRef<[u8]> + AsMut<[u8]>> BlockDevice for RamDisk<S> {
+    type Error = RamDiskError;
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.as_ref().len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), RamDiskError> {
+        let range = self.range(lba, buf.len())?;
+        buf.copy_from_slice(&self.data.as_ref()[range]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), RamDiskError> {
+        if self.read_only {
+            return Err(RamDiskError::ReadOnly)
+        }
+        let range = self.range(lba, buf.len())?;
+        self.data.as_mut()[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    /// Writes reach the memory immediately
+    fn flush(&mut self) -> Result<(), RamDiskError> {
+        Ok(())
+    }
+}
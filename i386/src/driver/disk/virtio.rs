@@ -0,0 +1,285 @@
+//! The legacy virtio PCI transport, which is also implemented by transitional devices.
+//! The common registers are in the I/O space at BAR0, followed by the device specific
+//! configuration. Requests are passed to the device with split virtqueues, whose
+//! memory is provided by the driver and located by its page frame number.
+//! See https://wiki.osdev.org/Virtio and Virtual I/O Device (VIRTIO) Version 1.1, 4.1.4.8
+
+pub mod blk;
+
+use core::{
+    fmt,
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{compiler_fence, Ordering}
+};
+use crate::{
+    instrs::{inb, indw, inw, outb, outdw, outw},
+    driver::{pci::{PCIBar, PCIDevice, CMD_BUS_MASTER, CMD_IO_SPACE}, disk::block::DeviceError}
+};
+
+/// PCI vendor id of virtio devices
+pub const PCI_VENDOR_VIRTIO: u16 = 0x1af4;
+
+/// features offered by the device
+const REG_DEVICE_FEATURES: u16 = 0x00;
+/// features accepted by the driver
+const REG_GUEST_FEATURES: u16 = 0x04;
+/// page frame number of the selected queue, 0 disables the queue
+const REG_QUEUE_PFN: u16 = 0x08;
+/// size of the selected queue, read only for legacy devices
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+/// reading the ISR status acknowledges the interrupt
+const REG_ISR_STATUS: u16 = 0x13;
+/// the device specific configuration, if MSI-X is disabled
+const REG_DEVICE_CONFIG: u16 = 0x14;
+
+/// device status bit 0: the guest has found the device
+pub const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+/// device status bit 1: the guest knows how to drive the device
+pub const STATUS_DRIVER: u8 = 1 << 1;
+/// device status bit 2: the driver is ready
+pub const STATUS_DRIVER_OK: u8 = 1 << 2;
+/// device status bit 7: the guest has given up on the device
+pub const STATUS_FAILED: u8 = 1 << 7;
+
+/// legacy virtqueues are aligned to pages
+const VIRTQ_ALIGN: usize = 4096;
+/// The largest queue we support, the size of a legacy queue is chosen by the device
+pub const VIRTQ_MAX_SIZE: u16 = 256;
+/// The memory needed by a queue of `VIRTQ_MAX_SIZE` entries
+pub const VIRTQ_MAX_BYTES: usize = virtq_layout(VIRTQ_MAX_SIZE).2 + used_ring_size(VIRTQ_MAX_SIZE);
+
+/// descriptor flags bit 0: the buffer continues in the `next` descriptor
+pub const DESC_F_NEXT: u16 = 1 << 0;
+/// descriptor flags bit 1: the buffer is written by the device
+pub const DESC_F_WRITE: u16 = 1 << 1;
+/// available ring flags bit 0: we poll the used ring, so no interrupts are needed
+const AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+
+/// The default number of used ring polls before a request times out
+pub const VIRTIO_DEFAULT_TIMEOUT: u32 = 1 << 22;
+
+#[derive(Debug)]
+pub enum VirtIOError {
+    /// BAR0 is not an I/O port range
+    NoIOBar,
+    /// the queue does not exist or is larger than `VIRTQ_MAX_SIZE`
+    QueueUnavailable,
+    /// the queue memory is not aligned to a page
+    QueueNotAligned,
+    BufferNotAligned,
+    /// the transfer exceeds the end of the device
+    OutOfRange,
+    /// the device does not accept writes
+    ReadOnly,
+    /// the device failed the request with the status
+    IOError(u8),
+    /// the device did not complete the request in time, it has been marked as failed
+    Timeout
+}
+
+impl fmt::Display for VirtIOError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoIOBar => write!(f, "VirtIO Error: no I/O BAR"),
+            Self::QueueUnavailable => write!(f, "VirtIO Error: queue unavailable"),
+            Self::QueueNotAligned => write!(f, "VirtIO Error: queue alignment"),
+            Self::BufferNotAligned => write!(f, "VirtIO Error: alignment"),
+            Self::OutOfRange => write!(f, "VirtIO Error: out of range"),
+            Self::ReadOnly => write!(f, "VirtIO Error: read only"),
+            Self::IOError(status) => write!(f, "VirtIO Error: I/O error (status {:#04x})", status),
+            Self::Timeout => write!(f, "VirtIO Error: timeout")
+        }
+    }
+}
+
+impl DeviceError for VirtIOError {
+    fn status(&self) -> u8 {
+        match self {
+            Self::IOError(status) => *status,
+            _ => 0
+        }
+    }
+}
+
+/// The size of the used ring: flags, idx, `size` elements and avail_event
+const fn used_ring_size(size: u16) -> usize {
+    6 + 8 * size as usize
+}
+
+const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// The offsets of (descriptor table, available ring, used ring) in a legacy queue.
+/// The available ring follows the descriptors, the used ring starts at the next page.
+const fn virtq_layout(size: u16) -> (usize, usize, usize) {
+    let avail = 16 * size as usize;
+    // flags, idx, `size` elements and used_event
+    let avail_end = avail + 6 + 2 * size as usize;
+    (0, avail, align_up(avail_end, VIRTQ_ALIGN))
+}
+
+/// The memory of a split virtqueue, which must be page aligned.
+#[repr(C)]
+pub struct VirtQueueMemory {
+    bytes: [u8; VIRTQ_MAX_BYTES]
+}
+
+impl VirtQueueMemory {
+    pub const fn new() -> Self {
+        Self { bytes: [0; VIRTQ_MAX_BYTES] }
+    }
+}
+
+/// A split virtqueue shared with the device
+#[derive(Clone, Copy)]
+pub struct VirtQueue {
+    pub index: u16,
+    pub size: u16,
+    base: *mut u8
+}
+
+impl VirtQueue {
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        unsafe { self.base.add(offset) as *mut T }
+    }
+
+    fn avail(&self, offset: usize) -> *mut u16 {
+        self.ptr(virtq_layout(self.size).1 + offset)
+    }
+
+    fn used(&self, offset: usize) -> *mut u16 {
+        self.ptr(virtq_layout(self.size).2 + offset)
+    }
+
+    /// Fill the descriptor `index` with a buffer at physical address `addr`
+    pub fn set_desc(&self, index: u16, addr: u32, len: u32, flags: u16, next: u16) {
+        let desc = 16 * (index % self.size) as usize;
+        unsafe {
+            write_volatile(self.ptr::<u64>(desc), addr as u64);
+            write_volatile(self.ptr::<u32>(desc + 8), len);
+            write_volatile(self.ptr::<u16>(desc + 12), flags);
+            write_volatile(self.ptr::<u16>(desc + 14), next);
+        }
+    }
+
+    /// The number of buffers made available to the device, wraps around at 2^16
+    pub fn avail_idx(&self) -> u16 {
+        unsafe { read_volatile(self.avail(2)) }
+    }
+
+    /// The number of buffers used by the device, wraps around at 2^16
+    pub fn used_idx(&self) -> u16 {
+        unsafe { read_volatile(self.used(2)) }
+    }
+
+    /// Put the descriptor chain starting at `head` into the available ring.
+    /// The device only sees it after being notified.
+    pub fn push(&self, head: u16) {
+        let idx = self.avail_idx();
+        unsafe {
+            write_volatile(self.avail(4 + 2 * (idx % self.size) as usize), head);
+            // the entry must be visible before the index, x86 does not
+            // reorder stores, so a compiler fence is enough
+            compiler_fence(Ordering::SeqCst);
+            write_volatile(self.avail(2), idx.wrapping_add(1));
+        }
+    }
+
+    /// Returns true if the device has used every available buffer
+    pub fn is_idle(&self) -> bool {
+        self.used_idx() == self.avail_idx()
+    }
+}
+
+/// The legacy transport of a virtio device on the PCI bus
+#[derive(Clone, Copy)]
+pub struct VirtIOTransport {
+    pub pci: PCIDevice,
+    io_base: u16
+}
+
+impl VirtIOTransport {
+    /// Enable I/O space access and bus mastering of a legacy or transitional device
+    pub fn new(pci: PCIDevice) -> Result<Self, VirtIOError> {
+        let io_base = match pci.bar(0) {
+            PCIBar::Io(base) => base,
+            _ => return Err(VirtIOError::NoIOBar)
+        };
+        pci.enable(CMD_IO_SPACE | CMD_BUS_MASTER);
+        Ok(Self { pci, io_base })
+    }
+
+    /// Writing 0 to the status register resets the device
+    pub fn reset(&self) {
+        outb(self.io_base + REG_DEVICE_STATUS, 0);
+    }
+
+    pub fn status(&self) -> u8 {
+        inb(self.io_base + REG_DEVICE_STATUS)
+    }
+
+    /// Set bits in the device status, for example `STATUS_DRIVER_OK`
+    pub fn add_status(&self, bits: u8) {
+        outb(self.io_base + REG_DEVICE_STATUS, self.status() | bits)
+    }
+
+    /// Legacy devices only have 32 feature bits
+    pub fn device_features(&self) -> u32 {
+        indw(self.io_base + REG_DEVICE_FEATURES)
+    }
+
+    pub fn set_guest_features(&self, features: u32) {
+        outdw(self.io_base + REG_GUEST_FEATURES, features)
+    }
+
+    /// Read the ISR status, which also acknowledges the interrupt
+    pub fn isr_status(&self) -> u8 {
+        inb(self.io_base + REG_ISR_STATUS)
+    }
+
+    pub fn config_u8(&self, offset: u16) -> u8 {
+        inb(self.io_base + REG_DEVICE_CONFIG + offset)
+    }
+
+    pub fn config_u16(&self, offset: u16) -> u16 {
+        inw(self.io_base + REG_DEVICE_CONFIG + offset)
+    }
+
+    pub fn config_u32(&self, offset: u16) -> u32 {
+        indw(self.io_base + REG_DEVICE_CONFIG + offset)
+    }
+
+    /// 64-bit fields are read in two halves, low dword first
+    pub fn config_u64(&self, offset: u16) -> u64 {
+        self.config_u32(offset) as u64 | (self.config_u32(offset + 4) as u64) << 32
+    }
+
+    /// Hand `mem` over to the device as queue `index`
+    pub fn setup_queue(&self, index: u16, mem: &'static mut VirtQueueMemory) -> Result<VirtQueue, VirtIOError> {
+        let base = mem.bytes.as_mut_ptr();
+        if base as usize % VIRTQ_ALIGN != 0 {
+            return Err(VirtIOError::QueueNotAligned)
+        }
+
+        outw(self.io_base + REG_QUEUE_SELECT, index);
+        let size = inw(self.io_base + REG_QUEUE_SIZE);
+        if size == 0 || size > VIRTQ_MAX_SIZE {
+            return Err(VirtIOError::QueueUnavailable)
+        }
+
+        mem.bytes.fill(0);
+        let queue = VirtQueue { index, size, base };
+        unsafe { write_volatile(queue.avail(0), AVAIL_F_NO_INTERRUPT) };
+        outdw(self.io_base + REG_QUEUE_PFN, (base as usize / VIRTQ_ALIGN) as u32);
+        Ok(queue)
+    }
+
+    /// Tell the device that new buffers are available in `queue`
+    pub fn notify(&self, queue: &VirtQueue) {
+        outw(self.io_base + REG_QUEUE_NOTIFY, queue.index)
+    }
+}
@@ -0,0 +1,277 @@
+//! The virtio block device. Every request is a descriptor chain of a header, the
+//! data buffer and a status byte. We only keep one request in flight and wait for
+//! the completion by polling the used ring.
+//! See Virtual I/O Device (VIRTIO) Version 1.1, 5.2
+
+use core::{
+    hint::spin_loop,
+    ptr::{addr_of, addr_of_mut, read_volatile, write_volatile},
+    sync::atomic::{compiler_fence, Ordering}
+};
+use super::*;
+use crate::{
+    driver::{disk::block::BlockDevice, pci::PCIScan},
+    utils::disk::*
+};
+
+/// PCI device id of transitional block devices
+const PCI_DEVICE_BLK: u16 = 0x1001;
+
+/// feature bit 5: the device is read only
+const F_RO: u32 = 1 << 5;
+/// feature bit 6: the block size is in the configuration
+const F_BLK_SIZE: u32 = 1 << 6;
+/// feature bit 9: the device supports the flush command
+const F_FLUSH: u32 = 1 << 9;
+
+/// configuration: the capacity in 512 byte sectors
+const CONFIG_CAPACITY: u16 = 0x00;
+/// configuration: the optimal block size, valid with `F_BLK_SIZE`
+const CONFIG_BLK_SIZE: u16 = 0x14;
+
+/// request types
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+/// request status
+const S_OK: u8 = 0;
+/// set before submitting, so an untouched status is an error
+const S_PENDING: u8 = 0xff;
+
+/// the only queue of a block device
+const REQUEST_QUEUE: u16 = 0;
+/// The max sectors of a single request, so the data fits into one descriptor
+const MAX_SECTORS_PER_REQ: u64 = 256;
+
+/// The maximum number of block devices we can drive, each of them needs a `VirtIOBlkMemory`
+pub const VIRTIO_MAX_DISKS: usize = 4;
+
+/// The header of a request, read by the device
+#[repr(C)]
+struct VirtIOBlkReqHeader {
+    ty: u32,
+    reserved: u32,
+    sector: u64
+}
+
+/// The memory accessed by a block device: the request queue, then the header
+/// and the status byte of the request in flight.
+#[repr(C, align(4096))]
+pub struct VirtIOBlkMemory {
+    queue: VirtQueueMemory,
+    header: VirtIOBlkReqHeader,
+    status: u8
+}
+
+impl VirtIOBlkMemory {
+    pub const fn new() -> Self {
+        Self {
+            queue: VirtQueueMemory::new(),
+            header: VirtIOBlkReqHeader { ty: 0, reserved: 0, sector: 0 },
+            status: 0
+        }
+    }
+}
+
+/// The memory for all block devices we can drive. Since our kernel is identity mapped,
+/// it can be placed in any static variable below 4MiB, and must never be freed.
+pub struct VirtIOMemory {
+    disks: [VirtIOBlkMemory; VIRTIO_MAX_DISKS]
+}
+
+impl VirtIOMemory {
+    const EMPTY_DISK: VirtIOBlkMemory = VirtIOBlkMemory::new();
+
+    pub const fn new() -> Self {
+        Self { disks: [Self::EMPTY_DISK; VIRTIO_MAX_DISKS] }
+    }
+}
+
+/// A virtio block device
+#[derive(Clone, Copy)]
+pub struct VirtIOBlkDisk {
+    pub transport: VirtIOTransport,
+    queue: VirtQueue,
+    mem: *mut VirtIOBlkMemory,
+    /// the features accepted by us
+    features: u32,
+    /// the size of the disk in 512 byte sectors
+    pub capacity: u64,
+    /// the max number of used ring polls of a request
+    pub timeout: u32
+}
+
+/// The memory is owned by the device once the queue is set up, the driver
+/// only touches it while issuing a request.
+unsafe impl Send for VirtIOBlkDisk {}
+
+impl VirtIOBlkDisk {
+    /// Initialize every block device on the PCI bus, each of them gets a
+    /// `VirtIOBlkMemory` from `mem`. Devices beyond `VIRTIO_MAX_DISKS` are ignored.
+    pub fn enumerate(mem: &'static mut VirtIOMemory) -> [Option<Self>; VIRTIO_MAX_DISKS] {
+        let mut disks = [None; VIRTIO_MAX_DISKS];
+        let devices = PCIScan::new()
+            .filter(|dev| dev.vendor_id() == PCI_VENDOR_VIRTIO && dev.device_id() == PCI_DEVICE_BLK);
+
+        for ((slot, disk_mem), pci) in disks.iter_mut().zip(mem.disks.iter_mut()).zip(devices) {
+            *slot = Self::new(pci, disk_mem).ok();
+        }
+        disks
+    }
+
+    /// Reset the device, negotiate the features and set up the request queue.
+    pub fn new(pci: PCIDevice, mem: &'static mut VirtIOBlkMemory) -> Result<Self, VirtIOError> {
+        let transport = VirtIOTransport::new(pci)?;
+        transport.reset();
+        transport.add_status(STATUS_ACKNOWLEDGE);
+        transport.add_status(STATUS_DRIVER);
+
+        let features = transport.device_features() & (F_RO | F_BLK_SIZE | F_FLUSH);
+        transport.set_guest_features(features);
+
+        let mem_ptr = mem as *mut VirtIOBlkMemory;
+        let queue = match transport.setup_queue(REQUEST_QUEUE, &mut mem.queue) {
+            Ok(queue) => queue,
+            Err(e) => {
+                transport.add_status(STATUS_FAILED);
+                return Err(e)
+            }
+        };
+        transport.add_status(STATUS_DRIVER_OK);
+
+        Ok(Self {
+            transport,
+            queue,
+            mem: mem_ptr,
+            features,
+            capacity: transport.config_u64(CONFIG_CAPACITY),
+            timeout: VIRTIO_DEFAULT_TIMEOUT
+        })
+    }
+
+    pub const fn is_read_only(&self) -> bool {
+        self.features & F_RO != 0
+    }
+
+    pub const fn flush_supported(&self) -> bool {
+        self.features & F_FLUSH != 0
+    }
+
+    /// The optimal block size reported by the device. Requests are always
+    /// addressed in 512 byte sectors.
+    pub fn blk_size(&self) -> u32 {
+        if self.features & F_BLK_SIZE != 0 {
+            self.transport.config_u32(CONFIG_BLK_SIZE)
+        } else {
+            SECTOR_SIZE
+        }
+    }
+
+    /// Submit a request and wait for the completion. The data buffer is
+    /// written by the device if `device_writes` is set.
+    fn request(&self, ty: u32, sector: u64, buf: *const u8, len: usize, device_writes: bool) -> Result<(), VirtIOError> {
+        let mem = self.mem;
+        let header = unsafe { addr_of_mut!((*mem).header) };
+        let status = unsafe { addr_of_mut!((*mem).status) };
+        unsafe {
+            write_volatile(header, VirtIOBlkReqHeader { ty, reserved: 0, sector });
+            write_volatile(status, S_PENDING);
+        }
+
+        let q = &self.queue;
+        q.set_desc(0, header as u32, 16, DESC_F_NEXT, 1);
+        if len == 0 {
+            q.set_desc(1, status as u32, 1, DESC_F_WRITE, 0);
+        } else {
+            let flags = DESC_F_NEXT | if device_writes { DESC_F_WRITE } else { 0 };
+            q.set_desc(1, buf as u32, len as u32, flags, 2);
+            q.set_desc(2, status as u32, 1, DESC_F_WRITE, 0);
+        }
+        q.push(0);
+        self.transport.notify(q);
+
+        let mut polls = 0;
+        while !q.is_idle() {
+            if polls == self.timeout {
+                // the request may still be in flight, the descriptors can not be reused
+                self.transport.add_status(STATUS_FAILED);
+                return Err(VirtIOError::Timeout)
+            }
+            polls += 1;
+            spin_loop();
+        }
+        compiler_fence(Ordering::SeqCst);
+
+        match unsafe { read_volatile(addr_of!((*mem).status)) } {
+            S_OK => Ok(()),
+            err => Err(VirtIOError::IOError(err))
+        }
+    }
+
+    fn check(&self, lba: u64, buf_len: usize) -> Result<(), VirtIOError> {
+        if !is_sector_aligned(buf_len) {
+            return Err(VirtIOError::BufferNotAligned)
+        }
+        if lba + size_to_lba(buf_len) > self.capacity {
+            return Err(VirtIOError::OutOfRange)
+        }
+        Ok(())
+    }
+
+    /// Read `buf.len()` bytes from `lba` into `buf`. Large transfers are split into
+    /// several requests. The buffer must be physically contiguous.
+    pub fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), VirtIOError> {
+        self.check(lba, buf.len())?;
+        let chunk = lba_to_size(MAX_SECTORS_PER_REQ) as usize;
+        for (i, part) in buf.chunks_mut(chunk).enumerate() {
+            self.request(T_IN, lba + i as u64 * MAX_SECTORS_PER_REQ, part.as_ptr(), part.len(), true)?;
+        }
+        Ok(())
+    }
+
+    /// Write `buf` to the disk from `lba`.
+    pub fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), VirtIOError> {
+        if self.is_read_only() {
+            return Err(VirtIOError::ReadOnly)
+        }
+        self.check(lba, buf.len())?;
+        let chunk = lba_to_size(MAX_SECTORS_PER_REQ) as usize;
+        for (i, part) in buf.chunks(chunk).enumerate() {
+            self.request(T_OUT, lba + i as u64 * MAX_SECTORS_PER_REQ, part.as_ptr(), part.len(), false)?;
+        }
+        Ok(())
+    }
+
+    /// Flush the write cache of the device. Devices without `F_FLUSH` write through.
+    pub fn flush(&self) -> Result<(), VirtIOError> {
+        if !self.flush_supported() {
+            return Ok(())
+        }
+        self.request(T_FLUSH, 0, [].as_ptr(), 0, false)
+    }
+}
+
+impl BlockDevice for VirtIOBlkDisk {
+    type Error = VirtIOError;
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE as usize
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), VirtIOError> {
+        self.read_sectors(lba, buf)
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), VirtIOError> {
+        self.write_sectors(lba, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), VirtIOError> {
+        VirtIOBlkDisk::flush(self)
+    }
+}
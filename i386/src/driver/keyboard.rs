@@ -0,0 +1,106 @@
+//! PS/2 keyboard behind the 8042 controller, used by polling. The controller translates
+//! scancodes to set 1 by default, which are decoded to ASCII with a US layout.
+
+use crate::instrs::inb;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+
+/// a byte is waiting in the data port
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// the byte is from the mouse
+const STATUS_AUX: u8 = 1 << 5;
+
+/// the next scancode is an extended key
+const SC_EXTENDED: u8 = 0xe0;
+/// set in scancodes of key releases
+const SC_RELEASE: u8 = 1 << 7;
+const SC_CTRL: u8 = 0x1d;
+const SC_LSHIFT: u8 = 0x2a;
+const SC_RSHIFT: u8 = 0x36;
+const SC_CAPS_LOCK: u8 = 0x3a;
+const SC_ENTER: u8 = 0x1c;
+const SC_SLASH: u8 = 0x35;
+
+/// Characters of scancodes 0x00 - 0x39, 0 for keys without one
+const KEYMAP: &[u8; 58] =
+    b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const KEYMAP_SHIFT: &[u8; 58] =
+    b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+/// The state of the modifier keys
+pub struct Keyboard {
+    shift: bool,
+    ctrl: bool,
+    caps_lock: bool,
+    /// the last scancode was `SC_EXTENDED`
+    extended: bool
+}
+
+impl Keyboard {
+    pub const fn new() -> Self {
+        Self { shift: false, ctrl: false, caps_lock: false, extended: false }
+    }
+
+    /// The next scancode from the controller, None if there is none.
+    /// Bytes from the mouse are dropped.
+    pub fn scancode() -> Option<u8> {
+        let status = inb(STATUS_PORT);
+        if status & STATUS_OUTPUT_FULL == 0 {
+            return None
+        }
+        let code = inb(DATA_PORT);
+        match status & STATUS_AUX {
+            0 => Some(code),
+            _ => None
+        }
+    }
+
+    /// Decode scancodes until a key press gives a character,
+    /// None if the controller runs out of scancodes first
+    pub fn read_char(&mut self) -> Option<u8> {
+        while let Some(code) = Self::scancode() {
+            if let Some(c) = self.decode(code) {
+                return Some(c)
+            }
+        }
+        None
+    }
+
+    /// Update the modifiers with `code`, returns the character of a key press.
+    /// Ctrl with a letter gives the control character, e.g. 0x03 for Ctrl-C.
+    pub fn decode(&mut self, code: u8) -> Option<u8> {
+        if code == SC_EXTENDED {
+            self.extended = true;
+            return None
+        }
+        let extended = core::mem::replace(&mut self.extended, false);
+        let pressed = code & SC_RELEASE == 0;
+        let key = code & !SC_RELEASE;
+
+        match key {
+            SC_CTRL => self.ctrl = pressed,
+            SC_LSHIFT | SC_RSHIFT if !extended => self.shift = pressed,
+            SC_CAPS_LOCK if pressed => self.caps_lock = !self.caps_lock,
+            _ => ()
+        }
+        if !pressed {
+            return None
+        }
+        // the keypad enter and slash are the only extended keys with characters
+        if extended && key != SC_ENTER && key != SC_SLASH {
+            return None
+        }
+
+        let c = match self.shift && !extended {
+            true => *KEYMAP_SHIFT.get(key as usize)?,
+            false => *KEYMAP.get(key as usize)?
+        };
+        match c {
+            0 => None,
+            b'a'..=b'z' | b'A'..=b'Z' if self.ctrl => Some(c & 0x1f),
+            b'a'..=b'z' | b'A'..=b'Z' if self.caps_lock => Some(c ^ 0x20),
+            _ => Some(c)
+        }
+    }
+}
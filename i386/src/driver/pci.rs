@@ -0,0 +1,174 @@
+//! Access to PCI configuration space with configuration space access mechanism #1.
+//! See https://wiki.osdev.org/PCI
+
+use crate::instrs::{indw, outdw};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// command register bit 0: respond to I/O space accesses
+pub const CMD_IO_SPACE: u16 = 1 << 0;
+/// command register bit 1: respond to memory space accesses
+pub const CMD_MEM_SPACE: u16 = 1 << 1;
+/// command register bit 2: allow the device to behave as a bus master
+pub const CMD_BUS_MASTER: u16 = 1 << 2;
+/// command register bit 10: disable the INTx# signal
+pub const CMD_INT_DISABLE: u16 = 1 << 10;
+
+const MAX_BUS: u16 = 256;
+const MAX_SLOT: u8 = 32;
+const MAX_FUNC: u8 = 8;
+
+/// A function on the PCI bus
+#[derive(Clone, Copy)]
+pub struct PCIDevice {
+    pub bus: u8,
+    pub slot: u8,
+    pub func: u8
+}
+
+/// A decoded base address register
+#[derive(Clone, Copy)]
+pub enum PCIBar {
+    /// base of an I/O port range
+    Io(u16),
+    /// base of a 32-bit memory mapped range
+    Memory(u32),
+    /// the BAR is not implemented or is a 64-bit BAR above 4GiB
+    None
+}
+
+impl PCIDevice {
+    pub const fn new(bus: u8, slot: u8, func: u8) -> Self {
+        Self { bus, slot, func }
+    }
+
+    const fn address(&self, offset: u8) -> u32 {
+        (1 << 31)
+            | (self.bus as u32) << 16
+            | (self.slot as u32) << 11
+            | (self.func as u32) << 8
+            | (offset & 0xfc) as u32
+    }
+
+    pub fn read_dword(&self, offset: u8) -> u32 {
+        outdw(CONFIG_ADDRESS, self.address(offset));
+        indw(CONFIG_DATA)
+    }
+
+    pub fn write_dword(&self, offset: u8, data: u32) {
+        outdw(CONFIG_ADDRESS, self.address(offset));
+        outdw(CONFIG_DATA, data)
+    }
+
+    pub fn read_word(&self, offset: u8) -> u16 {
+        (self.read_dword(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn write_word(&self, offset: u8, data: u16) {
+        let shift = (offset & 2) * 8;
+        let orig = self.read_dword(offset) & !(0xffff << shift);
+        self.write_dword(offset, orig | (data as u32) << shift)
+    }
+
+    pub fn read_byte(&self, offset: u8) -> u8 {
+        (self.read_dword(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn vendor_id(&self) -> u16 { self.read_word(0x00) }
+    pub fn device_id(&self) -> u16 { self.read_word(0x02) }
+    pub fn command(&self) -> u16 { self.read_word(0x04) }
+    pub fn status(&self) -> u16 { self.read_word(0x06) }
+    pub fn prog_if(&self) -> u8 { self.read_byte(0x09) }
+    pub fn subclass(&self) -> u8 { self.read_byte(0x0a) }
+    pub fn class(&self) -> u8 { self.read_byte(0x0b) }
+    pub fn header_type(&self) -> u8 { self.read_byte(0x0e) }
+    pub fn interrupt_line(&self) -> u8 { self.read_byte(0x3c) }
+
+    pub fn exists(&self) -> bool {
+        self.vendor_id() != 0xffff
+    }
+
+    pub fn set_command(&self, cmd: u16) {
+        self.write_word(0x04, cmd)
+    }
+
+    /// Set bits in the command register, for example `CMD_BUS_MASTER`
+    pub fn enable(&self, bits: u16) {
+        self.set_command(self.command() | bits)
+    }
+
+    /// Read the raw value of the `index`th base address register
+    pub fn bar_raw(&self, index: u8) -> u32 {
+        self.read_dword(0x10 + index * 4)
+    }
+
+    /// Decode the `index`th base address register
+    pub fn bar(&self, index: u8) -> PCIBar {
+        let raw = self.bar_raw(index);
+        if raw & 1 != 0 {
+            return PCIBar::Io((raw & 0xfffc) as u16)
+        }
+        // bit 1:2 is the type of a memory BAR, 0b10 means 64-bit
+        let upper = if (raw >> 1) & 0b11 == 0b10 && index < 5 {
+            self.bar_raw(index + 1)
+        } else {
+            0
+        };
+        match raw & 0xffff_fff0 {
+            0 => PCIBar::None,
+            _ if upper != 0 => PCIBar::None,
+            base => PCIBar::Memory(base)
+        }
+    }
+}
+
+/// An iterator over all functions present on the PCI bus (brute force scan)
+pub struct PCIScan {
+    bus: u16,
+    slot: u8,
+    func: u8
+}
+
+impl PCIScan {
+    pub const fn new() -> Self {
+        Self { bus: 0, slot: 0, func: 0 }
+    }
+
+    fn advance(&mut self, multifunction: bool) {
+        if multifunction && self.func + 1 < MAX_FUNC {
+            self.func += 1;
+            return
+        }
+        self.func = 0;
+        self.slot += 1;
+        if self.slot == MAX_SLOT {
+            self.slot = 0;
+            self.bus += 1;
+        }
+    }
+}
+
+impl Iterator for PCIScan {
+    type Item = PCIDevice;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.bus < MAX_BUS {
+            let dev = PCIDevice::new(self.bus as u8, self.slot, self.func);
+            let exists = dev.exists();
+            // only scan other functions if function 0 is a multifunction device
+            let multifunction = self.func != 0
+                || (exists && dev.header_type() & 0x80 != 0);
+            self.advance(multifunction);
+            if exists {
+                return Some(dev)
+            }
+        }
+        None
+    }
+}
+
+/// Find the first device with the given class code and subclass
+pub fn find_by_class(class: u8, subclass: u8) -> Option<PCIDevice> {
+    PCIScan::new().find(|dev| dev.class() == class && dev.subclass() == subclass)
+}
@@ -1,20 +1,88 @@
 //! Provide some functions for displaying content on screen
 
 pub mod s80x25c16;
+pub mod crtc;
 
+use core::fmt;
+
+#[derive(Debug)]
 pub enum VideoError {
     BufferOverflow,
 }
 
+impl fmt::Display for VideoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BufferOverflow => write!(f, "Video Error: buffer overflow")
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Cursor(pub usize, pub usize);
 
+/// The 16 colors of VGA text mode
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Color {
+    Black = 0,
+    Blue = 1,
+    Green = 2,
+    Cyan = 3,
+    Red = 4,
+    Magenta = 5,
+    Brown = 6,
+    LightGray = 7,
+    DarkGray = 8,
+    LightBlue = 9,
+    LightGreen = 10,
+    LightCyan = 11,
+    LightRed = 12,
+    Pink = 13,
+    Yellow = 14,
+    White = 15
+}
+
+/// The attribute byte of a character: bits 0-3 are the foreground, bits 4-6 the
+/// background and bit 7 blinks the character. Only the first 8 colors can be
+/// used as background, since bit 7 is taken by blinking.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(transparent)]
+pub struct ColorCode(pub u8);
+
+/// blink bit of the attribute byte
+const ATTR_BLINK: u8 = 1 << 7;
+
+impl ColorCode {
+    /// The bright bit of `bg` is dropped
+    pub const fn new(fg: Color, bg: Color) -> Self {
+        Self((fg as u8) | ((bg as u8) & 0x7) << 4)
+    }
+
+    pub const fn blink(self) -> Self {
+        Self(self.0 | ATTR_BLINK)
+    }
+
+    pub const fn with_fg(self, fg: Color) -> Self {
+        Self((self.0 & 0xf0) | fg as u8)
+    }
+
+    pub const fn with_bg(self, bg: Color) -> Self {
+        Self((self.0 & !0x70) | ((bg as u8) & 0x7) << 4)
+    }
+}
+
+/// Light gray on black, the color BIOS leaves the screen in
+pub const DEFAULT_COLOR: ColorCode = ColorCode::new(Color::LightGray, Color::Black);
+
 pub struct Screen<'a, T> 
 where
     T: VideoBuf
 {
     /// The cursor always points to the location after the previous writing
     pub cursor: Cursor,
+    /// The attribute of characters printed from now on
+    pub color: ColorCode,
     pub buf: &'a mut T
 }
 
@@ -52,7 +120,7 @@ impl<'a, T: VideoBuf> Screen<'a, T> {
     pub fn newline(&mut self) {
         if !self.cursor_down() {
             // scroll up by 1 line
-            self.buf.up();
+            self.buf.up(self.color);
         }
         self.cursor.1 = 0;
     }
@@ -60,25 +128,47 @@ impl<'a, T: VideoBuf> Screen<'a, T> {
     pub fn set_cursor(&mut self, row: usize, col: usize) {
         self.cursor.0 = row;
         self.cursor.1 = col;
+        self.buf.sync_cursor(self.cursor);
     }
 
     pub fn clear(&mut self) {
-        self.buf.clear();
+        self.buf.clear(self.color);
         self.set_cursor(0, 0);
     }
+
+    pub fn set_color(&mut self, color: ColorCode) {
+        self.color = color;
+    }
+
+    pub fn set_fg(&mut self, fg: Color) {
+        self.color = self.color.with_fg(fg);
+    }
+
+    pub fn set_bg(&mut self, bg: Color) {
+        self.color = self.color.with_bg(bg);
+    }
+
+    pub fn reset_color(&mut self) {
+        self.color = DEFAULT_COLOR;
+    }
 }
 
 pub trait VideoBuf {
     type Item;
     /// Note that row number comes
     fn get_shape(&self) -> (usize, usize);
-    fn clear(&mut self);
-    fn up(&mut self);
-    fn down(&mut self);
+    /// Blank cells are filled with `color`, so a background color covers the screen
+    fn clear(&mut self, color: ColorCode);
+    /// Scroll up by a line, the new last line is blank in `color`
+    fn up(&mut self, color: ColorCode);
+    /// Scroll down by a line, the new first line is blank in `color`
+    fn down(&mut self, color: ColorCode);
     /// get the byte sequence to write to video buffer for the specified char
-    fn get_charseq(&self, ch: u8) -> Self::Item;
+    fn get_charseq(&self, ch: u8, color: ColorCode) -> Self::Item;
     /// get the location of cursor
     fn set_at(&mut self, cur: Cursor, data: Self::Item);
+    /// move the hardware cursor, if the device has one, to `cur`
+    fn sync_cursor(&mut self, _cur: Cursor) {}
 }
 
 pub trait Printable {
@@ -108,9 +198,10 @@ impl<'a, T: VideoBuf> Printable for Screen<'a, T> {
                 self.newline();
                 continue;
             }
-            let item = self.buf.get_charseq(ch);
+            let item = self.buf.get_charseq(ch, self.color);
             let cur = self.next().unwrap();
             self.buf.set_at(cur, item);
         }
+        self.buf.sync_cursor(self.cursor);
     }
 }
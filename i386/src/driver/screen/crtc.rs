@@ -0,0 +1,53 @@
+//! The hardware text cursor, programmed through the CRT controller of VGA.
+//! Registers are selected by writing their index to the address port and
+//! accessed through the data port.
+//! See https://wiki.osdev.org/Text_Mode_Cursor
+
+use crate::instrs::{inb, outb};
+
+const CRTC_ADDR: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+
+/// bits 0-4: the first scanline of the cursor, bit 5: the cursor is hidden
+const REG_CURSOR_START: u8 = 0x0a;
+/// bits 0-4: the last scanline of the cursor
+const REG_CURSOR_END: u8 = 0x0b;
+/// the high byte of the character offset of the cursor
+const REG_CURSOR_HIGH: u8 = 0x0e;
+/// the low byte of the character offset of the cursor
+const REG_CURSOR_LOW: u8 = 0x0f;
+
+const CURSOR_DISABLE: u8 = 1 << 5;
+const SCANLINE_MASK: u8 = 0x1f;
+
+/// The underline cursor of 80x25 mode, whose characters are 16 scanlines high
+pub const CURSOR_UNDERLINE: (u8, u8) = (14, 15);
+/// A cursor covering the whole character
+pub const CURSOR_BLOCK: (u8, u8) = (0, 15);
+
+fn read(reg: u8) -> u8 {
+    outb(CRTC_ADDR, reg);
+    inb(CRTC_DATA)
+}
+
+fn write(reg: u8, data: u8) {
+    outb(CRTC_ADDR, reg);
+    outb(CRTC_DATA, data);
+}
+
+/// Move the cursor to the character at `offset`, which is `row * width + col`
+pub fn move_cursor(offset: u16) {
+    write(REG_CURSOR_LOW, (offset & 0xff) as u8);
+    write(REG_CURSOR_HIGH, (offset >> 8) as u8);
+}
+
+/// Show the cursor from scanline `start` to `end` of a character, this also sets its shape
+pub fn show_cursor(start: u8, end: u8) {
+    // keep the reserved high bits of the registers
+    write(REG_CURSOR_START, (read(REG_CURSOR_START) & 0xc0) | (start & SCANLINE_MASK));
+    write(REG_CURSOR_END, (read(REG_CURSOR_END) & 0xe0) | (end & SCANLINE_MASK));
+}
+
+pub fn hide_cursor() {
+    write(REG_CURSOR_START, read(REG_CURSOR_START) | CURSOR_DISABLE);
+}
@@ -1,4 +1,4 @@
-use super::VideoBuf;
+use super::{VideoBuf, ColorCode, Cursor, crtc::move_cursor};
 
 /// 80x25 16 color mode BIOS int 10h mode number
 pub const BIOS_80X25_16_COLOR: u8 = 3;
@@ -8,6 +8,12 @@ pub const WIDTH: usize = 80;
 
 pub type Buffer = [[u16; WIDTH]; HEIGHT];
 
+/// An empty cell in `color`. It holds a space rather than 0, the hardware cursor
+/// is drawn in the foreground color of its cell and must stay visible there.
+const fn blank(color: ColorCode) -> u16 {
+    (color.0 as u16) << 8 | b' ' as u16
+}
+
 impl VideoBuf for Buffer {
     type Item = u16;
 
@@ -15,29 +21,33 @@ impl VideoBuf for Buffer {
         (HEIGHT, WIDTH)
     }
 
-    fn clear(&mut self) {
-        self.fill([0; WIDTH]);
+    fn clear(&mut self, color: ColorCode) {
+        self.fill([blank(color); WIDTH]);
     }
 
-    fn up(&mut self) {
+    fn up(&mut self, color: ColorCode) {
         for i in 0..HEIGHT - 2 {
             self[i] = self[i + 1]
         }
-        self[HEIGHT - 1].fill(0);
+        self[HEIGHT - 1].fill(blank(color));
     }
 
-    fn down(&mut self) {
+    fn down(&mut self, color: ColorCode) {
         for i in 2..HEIGHT - 1 {
             self[i] = self[i - 1]
         }
-        self[0].fill(0);
+        self[0].fill(blank(color));
     }
 
-    fn get_charseq(&self, ch: u8) -> Self::Item {
-        (0x0c << 8) | ch as u16
+    fn get_charseq(&self, ch: u8, color: ColorCode) -> Self::Item {
+        (color.0 as u16) << 8 | ch as u16
     }
 
-    fn set_at(&mut self, cur: super::Cursor, data: Self::Item) {
+    fn set_at(&mut self, cur: Cursor, data: Self::Item) {
         self[cur.0][cur.1] = data
     }
+
+    fn sync_cursor(&mut self, cur: Cursor) {
+        move_cursor((cur.0 * WIDTH + cur.1) as u16)
+    }
 }
@@ -0,0 +1,94 @@
+//! 16550 UART serial ports (COM1 - COM4), used by polling the line status register.
+
+use core::hint::spin_loop;
+use crate::instrs::{inb, outb};
+
+/// The I/O base ports of COM1 - COM4
+pub const SERIAL_PORTS: [u16; SERIAL_MAX_PORTS] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+pub const SERIAL_MAX_PORTS: usize = 4;
+
+const REG_DATA: u16 = 0;
+/// interrupt enable, the high byte of the divisor when DLAB is set
+const REG_IER: u16 = 1;
+/// FIFO control
+const REG_FCR: u16 = 2;
+/// line control
+const REG_LCR: u16 = 3;
+/// modem control
+const REG_MCR: u16 = 4;
+/// line status
+const REG_LSR: u16 = 5;
+
+/// divisor latch access bit, REG_DATA and REG_IER hold the baud rate divisor when set
+const LCR_DLAB: u8 = 1 << 7;
+/// 8 data bits, no parity, 1 stop bit
+const LCR_8N1: u8 = 0x03;
+/// enable and clear both FIFOs, interrupt at 14 bytes
+const FCR_ENABLE: u8 = 0xc7;
+/// DTR, RTS and OUT2
+const MCR_NORMAL: u8 = 0x0b;
+/// loopback mode, used to test the port
+const MCR_LOOPBACK: u8 = 0x1e;
+/// received data ready
+const LSR_DR: u8 = 1 << 0;
+/// transmitter holding register empty
+const LSR_THRE: u8 = 1 << 5;
+
+/// The baud rate divisor of 115200 baud
+const DIVISOR: u16 = 1;
+/// The max number of status polls while sending a byte. A byte takes about 87us
+/// at 115200 baud and each poll about 1us, so only a stuck port runs out of it,
+/// e.g. one whose flow control never lets it send.
+const WRITE_TIMEOUT: u32 = 1 << 16;
+
+#[derive(Clone, Copy)]
+pub struct SerialPort {
+    pub base: u16
+}
+
+impl SerialPort {
+    /// Set the port at `base` to 115200 baud 8N1. Returns None if no UART
+    /// sends a byte back in loopback mode.
+    pub fn init(base: u16) -> Option<Self> {
+        outb(base + REG_IER, 0);
+        outb(base + REG_LCR, LCR_DLAB);
+        outb(base + REG_DATA, (DIVISOR & 0xff) as u8);
+        outb(base + REG_IER, (DIVISOR >> 8) as u8);
+        outb(base + REG_LCR, LCR_8N1);
+        outb(base + REG_FCR, FCR_ENABLE);
+
+        outb(base + REG_MCR, MCR_LOOPBACK);
+        outb(base + REG_DATA, 0xae);
+        if inb(base + REG_DATA) != 0xae {
+            return None
+        }
+        outb(base + REG_MCR, MCR_NORMAL);
+        Some(Self { base })
+    }
+
+    /// Initialize every COM port, absent ports are None
+    pub fn enumerate() -> [Option<Self>; SERIAL_MAX_PORTS] {
+        SERIAL_PORTS.map(Self::init)
+    }
+
+    /// Send `byte`, waiting until the transmitter can take it.
+    /// Returns false if it does not within `WRITE_TIMEOUT` polls, the byte is dropped.
+    pub fn write_byte(&self, byte: u8) -> bool {
+        for _ in 0..WRITE_TIMEOUT {
+            if inb(self.base + REG_LSR) & LSR_THRE != 0 {
+                outb(self.base + REG_DATA, byte);
+                return true
+            }
+            spin_loop();
+        }
+        false
+    }
+
+    /// The next received byte, None if nothing was received
+    pub fn read_byte(&self) -> Option<u8> {
+        match inb(self.base + REG_LSR) & LSR_DR {
+            0 => None,
+            _ => Some(inb(self.base + REG_DATA))
+        }
+    }
+}
@@ -1,10 +1,28 @@
 pub mod nofs;
+pub mod part;
+pub mod fat;
+pub mod ext2;
 
+use core::fmt;
+use crate::driver::disk::block::DeviceError;
+
+/// The error of file system drivers, which keeps the error of the device
+#[derive(Debug)]
 pub enum FSError<E> {
     UnknownError,
     NoEnoughSpace,
     FileNotFound,
     NotImplemented,
+    /// the file system is not recognized or its metadata is corrupted
+    InvalidFileSystem,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    /// the name is empty, too long or contains characters not allowed
+    InvalidName,
+    /// the file system does not accept writes
+    ReadOnly,
     DiskError(E)
 }
 
@@ -14,13 +32,201 @@ impl<T> From<T> for FSError<T> {
     }
 }
 
-/// A file system should have these traits.
-/// P is an identifier for a file/directory
-pub trait FileSystem<I, E> {
-    fn alloc(&mut self, size: usize) -> Result<I, FSError<E>>;
-    fn extend(&mut self, orig: I, new_size: usize) -> Result<I, FSError<E>>;
-    fn delete(&mut self, id: I) -> Result<(), FSError<E>>;
-    fn write(&mut self, id: I, src: &[u8]) -> Result<usize, FSError<E>>;
-    fn read(&self, id: I, dest: &mut [u8]) -> Result<usize, FSError<E>>;
+/// The error of the `FileSystem` trait. The error of the device is reduced to its status
+/// in `Io`, so file systems on different devices can be used through the same trait object.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    /// a component of the path is empty, too long or contains characters not allowed,
+    /// or a directory would be moved into itself
+    InvalidPath,
+    NoSpace,
+    ReadOnly,
+    /// the operation is not supported by the file system or the file
+    NotSupported,
+    /// the file system is not recognized or its metadata is corrupted
+    InvalidFileSystem,
+    /// the handle does not refer to a file of the file system
+    BadHandle,
+    /// the device failed with the status, see `DeviceError::status`
+    Io(u8)
+}
+
+impl<E: DeviceError> From<FSError<E>> for FileError {
+    fn from(e: FSError<E>) -> Self {
+        match e {
+            FSError::NoEnoughSpace => Self::NoSpace,
+            FSError::FileNotFound => Self::NotFound,
+            FSError::NotImplemented => Self::NotSupported,
+            FSError::InvalidFileSystem => Self::InvalidFileSystem,
+            FSError::NotADirectory => Self::NotADirectory,
+            FSError::IsADirectory => Self::IsADirectory,
+            FSError::AlreadyExists => Self::AlreadyExists,
+            FSError::DirectoryNotEmpty => Self::DirectoryNotEmpty,
+            FSError::InvalidName => Self::InvalidPath,
+            FSError::ReadOnly => Self::ReadOnly,
+            FSError::DiskError(e) => Self::Io(e.status()),
+            FSError::UnknownError => Self::Io(0)
+        }
+    }
+}
+
+/// An open file or directory. What it refers to is up to the file system, e.g. an
+/// inode number, and it stays valid until the file is removed. File systems without
+/// inode numbers may also invalidate it when the file is renamed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FileHandle(pub u64);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket
+}
+
+/// What `FileSystem::stat` returns
+#[derive(Clone, Copy)]
+pub struct Metadata {
+    pub file_type: FileType,
+    /// the size in bytes
+    pub size: u64,
+    /// the permission bits, like 0o755
+    pub perm: u16,
+    /// the number of names of the file
+    pub links: u32,
+    /// a number identifying the file in its file system
+    pub ino: u64
+}
+
+/// The longest name of a `DirEntry`, a FAT long name of 255 UTF-16 units
+/// takes up to 765 bytes in UTF-8.
+pub const NAME_MAX: usize = 765;
+
+/// An entry passed to the callback of `FileSystem::readdir`
+pub struct DirEntry {
+    name: [u8; NAME_MAX],
+    len: usize,
+    pub file_type: FileType,
+    /// the same as `Metadata::ino` of the file
+    pub ino: u64
+}
+
+impl DirEntry {
+    /// An entry with an empty name
+    pub const fn new(file_type: FileType, ino: u64) -> Self {
+        Self { name: [0; NAME_MAX], len: 0, file_type, ino }
+    }
+
+    /// Append `bytes` to the name, returns false if it does not fit
+    pub fn push(&mut self, bytes: &[u8]) -> bool {
+        if self.len + bytes.len() > NAME_MAX {
+            return false
+        }
+        self.name[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        true
+    }
+
+    /// The name, which is usually but not necessarily UTF-8
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.len]
+    }
+}
+
+/// A file system with directories. Paths like `/boot/kernel.bin` are relative to
+/// the root of the file system, the leading `/` is optional.
+/// Symbolic links are not followed, the caller resolves them with `readlink`.
+pub trait FileSystem {
+    /// Look up the file or directory at `path`
+    fn open(&self, path: &str) -> Result<FileHandle, FileError>;
+    /// Create an empty file at `path`, its parent directory must exist
+    fn create(&mut self, path: &str) -> Result<FileHandle, FileError>;
+    /// Read from `offset` of the file into `buf`, returns the number of bytes
+    /// read, which is less than `buf.len()` at the end of the file.
+    fn read_at(&self, file: FileHandle, offset: u64, buf: &mut [u8]) -> Result<usize, FileError>;
+    /// Write `buf` to `offset` of the file, which grows if needed
+    fn write_at(&mut self, file: FileHandle, offset: u64, buf: &[u8]) -> Result<usize, FileError>;
+    fn stat(&self, file: FileHandle) -> Result<Metadata, FileError>;
+    /// Change the size of the file, new contents are zeros
+    fn truncate(&mut self, file: FileHandle, size: u64) -> Result<(), FileError>;
+    /// Call `f` with every entry of the directory, except `.` and `..`
+    fn readdir(&self, dir: FileHandle, f: &mut dyn FnMut(&DirEntry)) -> Result<(), FileError>;
+    fn mkdir(&mut self, path: &str) -> Result<(), FileError>;
+    /// Remove a file or an empty directory
+    fn unlink(&mut self, path: &str) -> Result<(), FileError>;
+    /// Move the file or directory at `from` to `to`, a file at `to` is replaced
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FileError>;
+    /// Create a symbolic link at `path` pointing to `target`
+    fn symlink(&mut self, _path: &str, _target: &str) -> Result<(), FileError> {
+        Err(FileError::NotSupported)
+    }
+    /// Read the target of the symbolic link into `buf`, returns its length
+    fn readlink(&self, _link: FileHandle, _buf: &mut [u8]) -> Result<usize, FileError> {
+        Err(FileError::NotSupported)
+    }
+    /// Write cached data to the device
+    fn sync(&mut self) -> Result<(), FileError> {
+        Ok(())
+    }
+}
+
+/// Split `path` into the path of its parent and its last component,
+/// e.g. `/boot/kernel.bin` into `/boot` and `kernel.bin`.
+pub fn split_path(path: &str) -> Result<(&str, &str), FileError> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path)
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FileError::InvalidPath)
+    }
+    Ok((parent, name))
+}
+
+impl<E: fmt::Display> fmt::Display for FSError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FSError::DiskError(e) => write!(f, "{}", e),
+            FSError::UnknownError => write!(f, "FS Error: unknown error"),
+            FSError::NoEnoughSpace => write!(f, "FS Error: no enough space"),
+            FSError::FileNotFound => write!(f, "FS Error: file not found"),
+            FSError::NotImplemented => write!(f, "FS Error: not implemented"),
+            FSError::InvalidFileSystem => write!(f, "FS Error: invalid file system"),
+            FSError::NotADirectory => write!(f, "FS Error: not a directory"),
+            FSError::IsADirectory => write!(f, "FS Error: is a directory"),
+            FSError::AlreadyExists => write!(f, "FS Error: already exists"),
+            FSError::DirectoryNotEmpty => write!(f, "FS Error: directory not empty"),
+            FSError::InvalidName => write!(f, "FS Error: invalid name"),
+            FSError::ReadOnly => write!(f, "FS Error: read only")
+        }
+    }
 }
 
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            FileError::Io(status) => return write!(f, "File Error: device error (status {:#04x})", status),
+            FileError::NotFound => "File Error: not found",
+            FileError::AlreadyExists => "File Error: already exists",
+            FileError::NotADirectory => "File Error: not a directory",
+            FileError::IsADirectory => "File Error: is a directory",
+            FileError::DirectoryNotEmpty => "File Error: directory not empty",
+            FileError::InvalidPath => "File Error: invalid path",
+            FileError::NoSpace => "File Error: no space left",
+            FileError::ReadOnly => "File Error: read only",
+            FileError::NotSupported => "File Error: not supported",
+            FileError::InvalidFileSystem => "File Error: invalid file system",
+            FileError::BadHandle => "File Error: bad handle"
+        };
+        f.write_str(msg)
+    }
+}
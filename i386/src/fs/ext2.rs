@@ -0,0 +1,1037 @@
+//! The second extended file system. The volume is split into block groups, each of
+//! them has a block bitmap, an inode bitmap and an inode table, which are located
+//! by the group descriptor table following the superblock.
+//! See https://wiki.osdev.org/Ext2 and https://www.nongnu.org/ext2-doc/ext2.html
+
+pub mod inode;
+pub mod dir;
+
+use crate::{
+    driver::disk::block::{BlockDevice, ByteAdapter, MAX_BLOCK_SIZE},
+    fs::{FSError, FileSystem, FileError, FileHandle, FileType, Metadata, DirEntry, split_path}
+};
+pub use inode::Ext2Inode;
+pub use dir::Ext2DirEntry;
+use inode::*;
+
+/// the superblock is always 1024 bytes from the start of the volume
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT2_MAGIC: u16 = 0xef53;
+/// The inode of the root directory
+pub const ROOT_INO: u32 = 2;
+/// revision 0 file systems have fixed inode sizes and reserved inodes
+const GOOD_OLD_FIRST_INO: u32 = 11;
+const GOOD_OLD_INODE_SIZE: u16 = 128;
+/// the size of a group descriptor
+const GROUP_DESC_SIZE: u64 = 32;
+/// the magic of the header of an extended attribute block
+const XATTR_MAGIC: u32 = 0xea02_0000;
+
+/// incompatible feature: directory entries have a file type,
+/// we refuse to mount file systems with other incompatible features
+pub(super) const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// read only compatible features we support, file systems with
+/// other ones are mounted read only
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+const RO_COMPAT_BTREE_DIR: u32 = 0x0004;
+
+/// The fields of the superblock we use
+#[derive(Clone, Copy)]
+pub struct Superblock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    /// the block containing the superblock, 1 for 1KiB blocks and 0 otherwise
+    pub first_data_block: u32,
+    /// the block size is `1024 << log_block_size`
+    pub log_block_size: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    /// the last write time
+    pub wtime: u32,
+    pub rev_level: u32,
+    /// the first inode not reserved
+    pub first_ino: u32,
+    pub inode_size: u16,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    pub volume_name: [u8; 16]
+}
+
+impl Superblock {
+    fn parse(raw: &[u8]) -> Option<Self> {
+        let u16_at = |i: usize| u16::from_le_bytes([raw[i], raw[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([raw[i], raw[i + 1], raw[i + 2], raw[i + 3]]);
+        if u16_at(56) != EXT2_MAGIC {
+            return None
+        }
+
+        let rev_level = u32_at(76);
+        let dynamic = rev_level != 0;
+        let mut volume_name = [0; 16];
+        volume_name.copy_from_slice(&raw[120..136]);
+        let sb = Self {
+            inodes_count: u32_at(0),
+            blocks_count: u32_at(4),
+            free_blocks_count: u32_at(12),
+            free_inodes_count: u32_at(16),
+            first_data_block: u32_at(20),
+            log_block_size: u32_at(24),
+            blocks_per_group: u32_at(32),
+            inodes_per_group: u32_at(40),
+            wtime: u32_at(48),
+            rev_level,
+            first_ino: if dynamic { u32_at(84) } else { GOOD_OLD_FIRST_INO },
+            inode_size: if dynamic { u16_at(88) } else { GOOD_OLD_INODE_SIZE },
+            feature_compat: if dynamic { u32_at(92) } else { 0 },
+            feature_incompat: if dynamic { u32_at(96) } else { 0 },
+            feature_ro_compat: if dynamic { u32_at(100) } else { 0 },
+            volume_name
+        };
+
+        let valid = sb.blocks_per_group != 0
+            && sb.inodes_per_group != 0
+            && sb.log_block_size <= 6
+            && sb.inode_size >= GOOD_OLD_INODE_SIZE
+            && sb.inode_size.is_power_of_two()
+            && sb.first_data_block < sb.blocks_count;
+        if valid { Some(sb) } else { None }
+    }
+
+    pub const fn block_size(&self) -> u32 {
+        1024 << self.log_block_size
+    }
+}
+
+/// A block group descriptor
+#[derive(Clone, Copy)]
+struct GroupDesc {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    used_dirs: u16
+}
+
+/// A mounted ext2 volume on a block device. Files are identified by their inode numbers.
+pub struct Ext2<D: BlockDevice> {
+    dev: ByteAdapter<D>,
+    pub sb: Superblock,
+    block_size: u32,
+    groups: u32,
+    /// the volume uses features we can not keep consistent
+    read_only: bool
+}
+
+impl<D: BlockDevice> Ext2<D> {
+    /// Read the superblock and mount the volume. Volumes with unknown incompatible
+    /// features are rejected, and those with unknown read only features are read only.
+    pub fn new(dev: D) -> Result<Self, FSError<D::Error>> {
+        if dev.block_size() > MAX_BLOCK_SIZE {
+            return Err(FSError::InvalidFileSystem)
+        }
+        let dev = ByteAdapter::new(dev);
+        let mut raw = [0_u8; SUPERBLOCK_SIZE];
+        if dev.read(SUPERBLOCK_OFFSET, &mut raw)? != raw.len() {
+            return Err(FSError::InvalidFileSystem)
+        }
+        let sb = Superblock::parse(&raw).ok_or(FSError::InvalidFileSystem)?;
+
+        let block_size = sb.block_size();
+        // our buffers hold a block at most
+        if block_size as usize > MAX_BLOCK_SIZE || sb.feature_incompat & !INCOMPAT_FILETYPE != 0 {
+            return Err(FSError::NotImplemented)
+        }
+        let supported = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_BTREE_DIR;
+        let groups = (sb.blocks_count - sb.first_data_block + sb.blocks_per_group - 1) / sb.blocks_per_group;
+
+        Ok(Self {
+            dev,
+            sb,
+            block_size,
+            groups,
+            read_only: sb.feature_ro_compat & !supported != 0
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// The number of block groups
+    pub fn groups(&self) -> u32 {
+        self.groups
+    }
+
+    pub fn device(&self) -> &D {
+        self.dev.inner()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev.into_inner()
+    }
+
+    pub fn flush(&mut self) -> Result<(), FSError<D::Error>> {
+        Ok(self.dev.flush()?)
+    }
+
+    /// The root directory
+    pub fn root(&self) -> u32 {
+        ROOT_INO
+    }
+
+    fn check_writable(&self) -> Result<(), FSError<D::Error>> {
+        if self.read_only { Err(FSError::ReadOnly) } else { Ok(()) }
+    }
+
+    /// We have no clock, new inodes get the last write time of the superblock
+    fn now(&self) -> u32 {
+        self.sb.wtime
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.block_size as u64
+    }
+
+    fn read_exact(&self, offset: u64, buf: &mut [u8]) -> Result<(), FSError<D::Error>> {
+        match self.dev.read(offset, buf)? == buf.len() {
+            true => Ok(()),
+            false => Err(FSError::InvalidFileSystem)
+        }
+    }
+
+    fn write_exact(&mut self, offset: u64, buf: &[u8]) -> Result<(), FSError<D::Error>> {
+        match self.dev.write(offset, buf)? == buf.len() {
+            true => Ok(()),
+            false => Err(FSError::InvalidFileSystem)
+        }
+    }
+
+    fn read_u32(&self, offset: u64) -> Result<u32, FSError<D::Error>> {
+        let mut bytes = [0; 4];
+        self.read_exact(offset, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn write_u32(&mut self, offset: u64, value: u32) -> Result<(), FSError<D::Error>> {
+        self.write_exact(offset, &value.to_le_bytes())
+    }
+
+    /// Read the block `block` into `buf`, which is at least a block long
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<(), FSError<D::Error>> {
+        self.read_exact(self.block_offset(block), &mut buf[..self.block_size as usize])
+    }
+
+    fn write_block(&mut self, block: u32, buf: &[u8]) -> Result<(), FSError<D::Error>> {
+        self.write_exact(self.block_offset(block), &buf[..self.block_size as usize])
+    }
+
+    /// Write the free counters and features back to the superblock
+    fn write_superblock(&mut self) -> Result<(), FSError<D::Error>> {
+        self.write_u32(SUPERBLOCK_OFFSET + 12, self.sb.free_blocks_count)?;
+        self.write_u32(SUPERBLOCK_OFFSET + 16, self.sb.free_inodes_count)?;
+        if self.sb.rev_level != 0 {
+            self.write_u32(SUPERBLOCK_OFFSET + 100, self.sb.feature_ro_compat)?;
+        }
+        Ok(())
+    }
+
+    /// The group descriptor table follows the block of the superblock
+    fn group_desc_offset(&self, group: u32) -> u64 {
+        self.block_offset(self.sb.first_data_block + 1) + group as u64 * GROUP_DESC_SIZE
+    }
+
+    fn group_desc(&self, group: u32) -> Result<GroupDesc, FSError<D::Error>> {
+        let mut raw = [0_u8; GROUP_DESC_SIZE as usize];
+        self.read_exact(self.group_desc_offset(group), &mut raw)?;
+        let u16_at = |i: usize| u16::from_le_bytes([raw[i], raw[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([raw[i], raw[i + 1], raw[i + 2], raw[i + 3]]);
+        Ok(GroupDesc {
+            block_bitmap: u32_at(0),
+            inode_bitmap: u32_at(4),
+            inode_table: u32_at(8),
+            free_blocks: u16_at(12),
+            free_inodes: u16_at(14),
+            used_dirs: u16_at(16)
+        })
+    }
+
+    /// Write the counters of a group descriptor back
+    fn write_group_desc(&mut self, group: u32, desc: &GroupDesc) -> Result<(), FSError<D::Error>> {
+        let mut raw = [0_u8; 6];
+        raw[0..2].copy_from_slice(&desc.free_blocks.to_le_bytes());
+        raw[2..4].copy_from_slice(&desc.free_inodes.to_le_bytes());
+        raw[4..6].copy_from_slice(&desc.used_dirs.to_le_bytes());
+        self.write_exact(self.group_desc_offset(group) + 12, &raw)
+    }
+
+    /// The number of blocks in `group`, the last group may be smaller
+    fn blocks_in_group(&self, group: u32) -> u32 {
+        let start = self.sb.first_data_block + group * self.sb.blocks_per_group;
+        (self.sb.blocks_count - start).min(self.sb.blocks_per_group)
+    }
+
+    /// Find a clear bit from `from` below `count` in the bitmap `bitmap` and set it
+    fn alloc_bit(&mut self, bitmap: u32, from: u32, count: u32) -> Result<Option<u32>, FSError<D::Error>> {
+        let mut buf = [0_u8; MAX_BLOCK_SIZE];
+        self.read_block(bitmap, &mut buf)?;
+        let count = count.min(self.block_size * 8);
+        let bit = match (from..count).find(|&bit| buf[bit as usize / 8] & 1 << (bit % 8) == 0) {
+            Some(bit) => bit,
+            None => return Ok(None)
+        };
+        let byte = buf[bit as usize / 8] | 1 << (bit % 8);
+        self.write_exact(self.block_offset(bitmap) + bit as u64 / 8, &[byte])?;
+        Ok(Some(bit))
+    }
+
+    /// Clear a bit in the bitmap `bitmap`, returns false if it was clear already
+    fn free_bit(&mut self, bitmap: u32, bit: u32) -> Result<bool, FSError<D::Error>> {
+        let offset = self.block_offset(bitmap) + bit as u64 / 8;
+        let mut byte = [0];
+        self.read_exact(offset, &mut byte)?;
+        if byte[0] & 1 << (bit % 8) == 0 {
+            return Ok(false)
+        }
+        byte[0] &= !(1 << (bit % 8));
+        self.write_exact(offset, &byte)?;
+        Ok(true)
+    }
+
+    /// Allocate a zeroed block, preferring the block group `goal`
+    fn alloc_block(&mut self, goal: u32) -> Result<u32, FSError<D::Error>> {
+        for i in 0..self.groups {
+            let group = (goal + i) % self.groups;
+            let mut desc = self.group_desc(group)?;
+            if desc.free_blocks == 0 {
+                continue
+            }
+            let count = self.blocks_in_group(group);
+            if let Some(bit) = self.alloc_bit(desc.block_bitmap, 0, count)? {
+                desc.free_blocks -= 1;
+                self.write_group_desc(group, &desc)?;
+                self.sb.free_blocks_count = self.sb.free_blocks_count.saturating_sub(1);
+                self.write_superblock()?;
+
+                let block = self.sb.first_data_block + group * self.sb.blocks_per_group + bit;
+                let zeros = [0_u8; MAX_BLOCK_SIZE];
+                self.write_block(block, &zeros)?;
+                return Ok(block)
+            }
+        }
+        Err(FSError::NoEnoughSpace)
+    }
+
+    fn free_block(&mut self, block: u32) -> Result<(), FSError<D::Error>> {
+        if block < self.sb.first_data_block || block >= self.sb.blocks_count {
+            return Err(FSError::InvalidFileSystem)
+        }
+        let rel = block - self.sb.first_data_block;
+        let group = rel / self.sb.blocks_per_group;
+        let mut desc = self.group_desc(group)?;
+        if self.free_bit(desc.block_bitmap, rel % self.sb.blocks_per_group)? {
+            desc.free_blocks += 1;
+            self.write_group_desc(group, &desc)?;
+            self.sb.free_blocks_count += 1;
+            self.write_superblock()?;
+        }
+        Ok(())
+    }
+
+    /// The block group of an inode
+    fn inode_group(&self, ino: u32) -> u32 {
+        (ino - 1) / self.sb.inodes_per_group
+    }
+
+    fn inode_offset(&self, ino: u32) -> Result<u64, FSError<D::Error>> {
+        if ino == 0 || ino > self.sb.inodes_count {
+            return Err(FSError::FileNotFound)
+        }
+        let desc = self.group_desc(self.inode_group(ino))?;
+        let index = (ino - 1) % self.sb.inodes_per_group;
+        Ok(self.block_offset(desc.inode_table) + index as u64 * self.sb.inode_size as u64)
+    }
+
+    /// Read the inode `ino`
+    pub fn inode(&self, ino: u32) -> Result<Ext2Inode, FSError<D::Error>> {
+        let mut raw = [0_u8; GOOD_OLD_INODE_SIZE as usize];
+        self.read_exact(self.inode_offset(ino)?, &mut raw)?;
+        Ok(Ext2Inode::parse(&raw))
+    }
+
+    fn write_inode(&mut self, ino: u32, inode: &Ext2Inode) -> Result<(), FSError<D::Error>> {
+        let offset = self.inode_offset(ino)?;
+        let mut raw = [0_u8; GOOD_OLD_INODE_SIZE as usize];
+        self.read_exact(offset, &mut raw)?;
+        inode.store(&mut raw);
+        self.write_exact(offset, &raw)
+    }
+
+    /// Allocate an inode with `mode`, preferring the block group `goal`
+    fn new_inode(&mut self, goal: u32, mode: u16) -> Result<(u32, Ext2Inode), FSError<D::Error>> {
+        for i in 0..self.groups {
+            let group = (goal + i) % self.groups;
+            let mut desc = self.group_desc(group)?;
+            if desc.free_inodes == 0 {
+                continue
+            }
+            // the first inodes are reserved
+            let first = group * self.sb.inodes_per_group + 1;
+            let from = self.sb.first_ino.saturating_sub(first);
+            if let Some(bit) = self.alloc_bit(desc.inode_bitmap, from, self.sb.inodes_per_group)? {
+                desc.free_inodes -= 1;
+                if mode & S_IFMT == S_IFDIR {
+                    desc.used_dirs += 1;
+                }
+                self.write_group_desc(group, &desc)?;
+                self.sb.free_inodes_count = self.sb.free_inodes_count.saturating_sub(1);
+                self.write_superblock()?;
+
+                let ino = first + bit;
+                let offset = self.inode_offset(ino)?;
+                self.zero(offset, self.sb.inode_size as u64)?;
+                let inode = Ext2Inode::new(mode, self.now());
+                self.write_inode(ino, &inode)?;
+                return Ok((ino, inode))
+            }
+        }
+        Err(FSError::NoEnoughSpace)
+    }
+
+    /// Free the blocks and the inode `ino`, which has no links
+    fn release_inode(&mut self, ino: u32, inode: &mut Ext2Inode) -> Result<(), FSError<D::Error>> {
+        if !inode.is_fast_symlink(self.block_size) {
+            self.truncate_blocks(ino, inode, 0)?;
+        }
+        if inode.file_acl != 0 {
+            self.release_xattr_block(inode.file_acl)?;
+            inode.file_acl = 0;
+            inode.sectors = 0;
+        }
+        inode.size = 0;
+        inode.links_count = 0;
+        inode.dtime = self.now().max(1);
+        self.write_inode(ino, inode)?;
+
+        let group = self.inode_group(ino);
+        let mut desc = self.group_desc(group)?;
+        if self.free_bit(desc.inode_bitmap, (ino - 1) % self.sb.inodes_per_group)? {
+            desc.free_inodes += 1;
+            if inode.is_dir() {
+                desc.used_dirs = desc.used_dirs.saturating_sub(1);
+            }
+            self.write_group_desc(group, &desc)?;
+            self.sb.free_inodes_count += 1;
+            self.write_superblock()?;
+        }
+        Ok(())
+    }
+
+    /// Drop a reference to the extended attribute block `block`, which may be shared
+    /// by several inodes. It is freed with its last reference.
+    fn release_xattr_block(&mut self, block: u32) -> Result<(), FSError<D::Error>> {
+        let offset = self.block_offset(block);
+        let mut header = [0_u8; 8];
+        self.read_exact(offset, &mut header)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let refcount = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if magic != XATTR_MAGIC {
+            return Err(FSError::InvalidFileSystem)
+        }
+        if refcount > 1 {
+            self.write_exact(offset + 4, &(refcount - 1).to_le_bytes())
+        } else {
+            self.free_block(block)
+        }
+    }
+
+    fn zero(&mut self, offset: u64, len: u64) -> Result<(), FSError<D::Error>> {
+        let zeros = [0_u8; 512];
+        let mut done = 0;
+        while done < len {
+            let part = (len - done).min(zeros.len() as u64) as usize;
+            self.write_exact(offset + done, &zeros[..part])?;
+            done += part as u64;
+        }
+        Ok(())
+    }
+
+    /// The number of block pointers in an indirect block
+    fn ptrs_per_block(&self) -> u64 {
+        self.block_size as u64 / 4
+    }
+
+    /// The sectors counted in `i_blocks` for each block
+    fn sectors_per_block(&self) -> u32 {
+        self.block_size / 512
+    }
+
+    /// The way to the logical block `lbn`: the index in the block pointers of the inode,
+    /// the number of indirect blocks and the index in each of them.
+    fn block_path(&self, lbn: u64) -> Result<(usize, usize, [u64; 3]), FSError<D::Error>> {
+        let p = self.ptrs_per_block();
+        let direct = N_DIRECT as u64;
+        if lbn < direct {
+            return Ok((lbn as usize, 0, [0; 3]))
+        }
+        let lbn = lbn - direct;
+        if lbn < p {
+            return Ok((N_DIRECT, 1, [lbn, 0, 0]))
+        }
+        let lbn = lbn - p;
+        if lbn < p * p {
+            return Ok((N_DIRECT + 1, 2, [lbn / p, lbn % p, 0]))
+        }
+        let lbn = lbn - p * p;
+        if lbn < p * p * p {
+            return Ok((N_DIRECT + 2, 3, [lbn / (p * p), lbn / p % p, lbn % p]))
+        }
+        Err(FSError::NoEnoughSpace)
+    }
+
+    /// The block holding the logical block `lbn` of `inode`, 0 for a hole
+    fn bmap(&self, inode: &Ext2Inode, lbn: u64) -> Result<u32, FSError<D::Error>> {
+        let (root, depth, path) = self.block_path(lbn)?;
+        let mut block = inode.block[root];
+        for &index in &path[..depth] {
+            if block == 0 {
+                return Ok(0)
+            }
+            if block >= self.sb.blocks_count {
+                return Err(FSError::InvalidFileSystem)
+            }
+            block = self.read_u32(self.block_offset(block) + index * 4)?;
+        }
+        if block >= self.sb.blocks_count {
+            return Err(FSError::InvalidFileSystem)
+        }
+        Ok(block)
+    }
+
+    /// Like `bmap`, but allocate the block and the indirect blocks leading to it.
+    /// The caller writes `inode` back.
+    fn bmap_alloc(&mut self, ino: u32, inode: &mut Ext2Inode, lbn: u64) -> Result<u32, FSError<D::Error>> {
+        let (root, depth, path) = self.block_path(lbn)?;
+        let goal = self.inode_group(ino);
+        if inode.block[root] == 0 {
+            inode.block[root] = self.alloc_block(goal)?;
+            inode.sectors += self.sectors_per_block();
+        }
+        let mut block = inode.block[root];
+        for &index in &path[..depth] {
+            let entry = self.block_offset(block) + index * 4;
+            let mut next = self.read_u32(entry)?;
+            if next == 0 {
+                next = self.alloc_block(goal)?;
+                inode.sectors += self.sectors_per_block();
+                self.write_u32(entry, next)?;
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// Free the blocks of `inode` from the logical block `keep`
+    fn truncate_blocks(&mut self, ino: u32, inode: &mut Ext2Inode, keep: u64) -> Result<(), FSError<D::Error>> {
+        let spb = self.sectors_per_block();
+        for i in (keep.min(N_DIRECT as u64) as usize)..N_DIRECT {
+            if inode.block[i] != 0 {
+                self.free_block(inode.block[i])?;
+                inode.sectors = inode.sectors.saturating_sub(spb);
+                inode.block[i] = 0;
+            }
+        }
+
+        let p = self.ptrs_per_block();
+        let mut base = N_DIRECT as u64;
+        let mut span = p;
+        for (root, level) in [(N_DIRECT, 1), (N_DIRECT + 1, 2), (N_DIRECT + 2, 3)] {
+            let kept = keep.saturating_sub(base);
+            let block = inode.block[root];
+            if block != 0 && kept < span && self.truncate_indirect(inode, block, level, kept)? {
+                self.free_block(block)?;
+                inode.sectors = inode.sectors.saturating_sub(spb);
+                inode.block[root] = 0;
+            }
+            base += span;
+            span *= p;
+        }
+        self.write_inode(ino, inode)
+    }
+
+    /// Free the blocks below the `level` indirect block `block` from the `keep`th
+    /// data block it maps, returns true if `block` is not needed anymore.
+    fn truncate_indirect(&mut self, inode: &mut Ext2Inode, block: u32, level: u32, keep: u64) -> Result<bool, FSError<D::Error>> {
+        let p = self.ptrs_per_block();
+        let span = p.pow(level - 1);
+        for i in 0..p {
+            let start = i * span;
+            if start + span <= keep {
+                continue
+            }
+            let entry = self.block_offset(block) + i * 4;
+            let child = self.read_u32(entry)?;
+            if child == 0 {
+                continue
+            }
+            if level == 1 || self.truncate_indirect(inode, child, level - 1, keep.saturating_sub(start))? {
+                self.free_block(child)?;
+                inode.sectors = inode.sectors.saturating_sub(self.sectors_per_block());
+                self.write_u32(entry, 0)?;
+            }
+        }
+        Ok(keep == 0)
+    }
+
+    /// Read from `offset` of the inode `ino` into `buf`, returns the number of bytes
+    /// read, which is less than `buf.len()` at the end of the file. Holes read as zeros.
+    pub fn read_at(&self, ino: u32, offset: u64, buf: &mut [u8]) -> Result<usize, FSError<D::Error>> {
+        let inode = self.inode(ino)?;
+        if offset >= inode.size {
+            return Ok(0)
+        }
+        let len = (inode.size - offset).min(buf.len() as u64) as usize;
+        if inode.is_fast_symlink(self.block_size) {
+            if inode.size > FAST_SYMLINK_MAX as u64 {
+                return Err(FSError::InvalidFileSystem)
+            }
+            let data = inode.inline_data();
+            let start = offset as usize;
+            buf[..len].copy_from_slice(&data[start..start + len]);
+            return Ok(len)
+        }
+
+        let bs = self.block_size as u64;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let skip = pos % bs;
+            let part = ((bs - skip) as usize).min(len - done);
+            match self.bmap(&inode, pos / bs)? {
+                0 => buf[done..done + part].fill(0),
+                block => self.read_exact(self.block_offset(block) + skip, &mut buf[done..done + part])?
+            }
+            done += part;
+        }
+        Ok(len)
+    }
+
+    /// Write `buf` to `offset` of the regular file `ino`, blocks are allocated as needed.
+    pub fn write_at(&mut self, ino: u32, offset: u64, buf: &[u8]) -> Result<usize, FSError<D::Error>> {
+        self.check_writable()?;
+        let mut inode = self.inode(ino)?;
+        if inode.is_dir() {
+            return Err(FSError::IsADirectory)
+        }
+        if !inode.is_file() {
+            return Err(FSError::NotImplemented)
+        }
+
+        let bs = self.block_size as u64;
+        let mut done = 0;
+        let result = loop {
+            if done == buf.len() {
+                break Ok(done)
+            }
+            let pos = offset + done as u64;
+            let skip = pos % bs;
+            let part = ((bs - skip) as usize).min(buf.len() - done);
+            let block = match self.bmap_alloc(ino, &mut inode, pos / bs) {
+                Ok(block) => block,
+                Err(e) => break Err(e)
+            };
+            if let Err(e) = self.write_exact(self.block_offset(block) + skip, &buf[done..done + part]) {
+                break Err(e)
+            }
+            done += part;
+        };
+
+        // keep the blocks allocated so far, even if the write failed
+        let end = offset + done as u64;
+        if end > inode.size {
+            inode.size = end;
+            self.set_large_file(end)?;
+        }
+        inode.mtime = self.now();
+        self.write_inode(ino, &inode)?;
+        result
+    }
+
+    /// Files of 2GiB and more need the large file feature
+    fn set_large_file(&mut self, size: u64) -> Result<(), FSError<D::Error>> {
+        if size > i32::MAX as u64 && self.sb.feature_ro_compat & RO_COMPAT_LARGE_FILE == 0 {
+            self.sb.feature_ro_compat |= RO_COMPAT_LARGE_FILE;
+            self.write_superblock()?;
+        }
+        Ok(())
+    }
+
+    /// Change the size of the regular file `ino`. Blocks beyond the end are freed,
+    /// growing leaves a hole which reads as zeros.
+    pub fn set_size(&mut self, ino: u32, size: u64) -> Result<(), FSError<D::Error>> {
+        self.check_writable()?;
+        let mut inode = self.inode(ino)?;
+        if inode.is_dir() {
+            return Err(FSError::IsADirectory)
+        }
+        if !inode.is_file() {
+            return Err(FSError::NotImplemented)
+        }
+        // the largest file the block map can address
+        let p = self.ptrs_per_block();
+        if size > (N_DIRECT as u64 + p + p * p + p * p * p) * self.block_size as u64 {
+            return Err(FSError::NoEnoughSpace)
+        }
+
+        let bs = self.block_size as u64;
+        if size < inode.size {
+            self.truncate_blocks(ino, &mut inode, (size + bs - 1) / bs)?;
+            // the tail of the last block must read as zeros if the file grows again
+            if size % bs != 0 {
+                let block = self.bmap(&inode, size / bs)?;
+                if block != 0 {
+                    self.zero(self.block_offset(block) + size % bs, bs - size % bs)?;
+                }
+            }
+        }
+        self.set_large_file(size)?;
+        inode.size = size;
+        inode.mtime = self.now();
+        self.write_inode(ino, &inode)
+    }
+
+    /// Look up `name` in the directory `dir` without following symbolic links
+    pub fn lookup(&self, dir: u32, name: &str) -> Result<u32, FSError<D::Error>> {
+        dir::find(self, dir, name.as_bytes()).map(|(entry, _)| entry.inode)
+    }
+
+    /// Look up a path like `/etc/passwd` from the root directory, symbolic links
+    /// are followed.
+    pub fn open(&self, path: &str) -> Result<u32, FSError<D::Error>> {
+        dir::resolve(self, ROOT_INO, path, 0)
+    }
+
+    /// Call `f` with every entry of the directory `dir`, except `.` and `..`
+    pub fn read_dir(&self, dir: u32, mut f: impl FnMut(&Ext2DirEntry)) -> Result<(), FSError<D::Error>> {
+        dir::scan(self, dir, |entry, _| {
+            if !entry.is_dot() {
+                f(entry);
+            }
+            None::<()>
+        }).map(|_| ())
+    }
+
+    /// Read the target of the symbolic link `ino` into `buf`, returns its length
+    pub fn read_link(&self, ino: u32, buf: &mut [u8]) -> Result<usize, FSError<D::Error>> {
+        if !self.inode(ino)?.is_symlink() {
+            return Err(FSError::InvalidName)
+        }
+        self.read_at(ino, 0, buf)
+    }
+
+    /// Create an empty regular file called `name` in `dir`
+    pub fn create(&mut self, dir: u32, name: &str) -> Result<u32, FSError<D::Error>> {
+        self.check_writable()?;
+        dir::check_new(self, dir, name.as_bytes())?;
+        let (ino, mut inode) = self.new_inode(self.inode_group(dir), S_IFREG | 0o644)?;
+        self.add_link(dir, name, ino, &mut inode)?;
+        Ok(ino)
+    }
+
+    /// Create an empty directory called `name` in `dir`
+    pub fn mkdir(&mut self, dir: u32, name: &str) -> Result<u32, FSError<D::Error>> {
+        self.check_writable()?;
+        dir::check_new(self, dir, name.as_bytes())?;
+        let (ino, mut inode) = self.new_inode(self.inode_group(dir), S_IFDIR | 0o755)?;
+        dir::init_dir(self, ino, &mut inode, dir)?;
+        if let Err(e) = dir::add_entry(self, dir, name.as_bytes(), ino, &inode) {
+            self.release_inode(ino, &mut inode)?;
+            return Err(e)
+        }
+        // `..` of the new directory
+        let mut parent = self.inode(dir)?;
+        parent.links_count += 1;
+        self.write_inode(dir, &parent)?;
+        Ok(ino)
+    }
+
+    /// Create a symbolic link called `name` in `dir` pointing to `target`
+    pub fn symlink(&mut self, dir: u32, name: &str, target: &str) -> Result<u32, FSError<D::Error>> {
+        self.check_writable()?;
+        if target.is_empty() || target.len() > self.block_size as usize {
+            return Err(FSError::InvalidName)
+        }
+        dir::check_new(self, dir, name.as_bytes())?;
+        let (ino, mut inode) = self.new_inode(self.inode_group(dir), S_IFLNK | 0o777)?;
+        if target.len() < FAST_SYMLINK_MAX {
+            inode.set_inline_data(target.as_bytes());
+        } else {
+            let block = self.bmap_alloc(ino, &mut inode, 0)?;
+            self.write_exact(self.block_offset(block), target.as_bytes())?;
+        }
+        inode.size = target.len() as u64;
+        self.add_link(dir, name, ino, &mut inode)?;
+        Ok(ino)
+    }
+
+    /// Add another name for the file `ino` in `dir`. Directories can not be linked.
+    pub fn link(&mut self, dir: u32, name: &str, ino: u32) -> Result<(), FSError<D::Error>> {
+        self.check_writable()?;
+        let mut inode = self.inode(ino)?;
+        if inode.is_dir() {
+            return Err(FSError::IsADirectory)
+        }
+        dir::check_new(self, dir, name.as_bytes())?;
+        self.add_link(dir, name, ino, &mut inode)
+    }
+
+    fn add_link(&mut self, dir: u32, name: &str, ino: u32, inode: &mut Ext2Inode) -> Result<(), FSError<D::Error>> {
+        if let Err(e) = dir::add_entry(self, dir, name.as_bytes(), ino, inode) {
+            if inode.links_count == 0 {
+                self.release_inode(ino, inode)?;
+            }
+            return Err(e)
+        }
+        inode.links_count += 1;
+        inode.ctime = self.now();
+        self.write_inode(ino, inode)
+    }
+
+    /// Remove `name` from `dir`. The file is freed with its last link,
+    /// directories must be empty.
+    pub fn remove(&mut self, dir: u32, name: &str) -> Result<(), FSError<D::Error>> {
+        self.check_writable()?;
+        let (entry, _) = dir::find(self, dir, name.as_bytes())?;
+        if entry.is_dot() {
+            return Err(FSError::InvalidName)
+        }
+        let ino = entry.inode;
+        let mut inode = self.inode(ino)?;
+        if inode.is_dir() && !dir::is_empty(self, ino)? {
+            return Err(FSError::DirectoryNotEmpty)
+        }
+        dir::remove_entry(self, dir, name.as_bytes())?;
+
+        if inode.is_dir() {
+            let mut parent = self.inode(dir)?;
+            parent.links_count = parent.links_count.saturating_sub(1);
+            self.write_inode(dir, &parent)?;
+            return self.release_inode(ino, &mut inode)
+        }
+        inode.links_count = inode.links_count.saturating_sub(1);
+        if inode.links_count == 0 {
+            return self.release_inode(ino, &mut inode)
+        }
+        inode.ctime = self.now();
+        self.write_inode(ino, &inode)
+    }
+
+    /// Move the entry `name` of `dir` to `new_name` in `new_dir`,
+    /// a file called `new_name` there is replaced.
+    pub fn rename(&mut self, dir: u32, name: &str, new_dir: u32, new_name: &str) -> Result<(), FSError<D::Error>> {
+        self.check_writable()?;
+        let (entry, _) = dir::find(self, dir, name.as_bytes())?;
+        if entry.is_dot() {
+            return Err(FSError::InvalidName)
+        }
+        let ino = entry.inode;
+        let mut inode = self.inode(ino)?;
+        if !self.inode(new_dir)?.is_dir() {
+            return Err(FSError::NotADirectory)
+        }
+        if inode.is_dir() && self.is_within(new_dir, ino)? {
+            return Err(FSError::InvalidName)
+        }
+
+        match dir::find(self, new_dir, new_name.as_bytes()) {
+            // both names are links of the same file
+            Ok((target, _)) if target.inode == ino => return Ok(()),
+            Ok((target, _)) => {
+                if target.is_dot() || inode.is_dir() || self.inode(target.inode)?.is_dir() {
+                    return Err(FSError::AlreadyExists)
+                }
+                self.remove(new_dir, new_name)?;
+            },
+            Err(FSError::FileNotFound) => (),
+            Err(e) => return Err(e)
+        }
+        dir::check_new(self, new_dir, new_name.as_bytes())?;
+        dir::add_entry(self, new_dir, new_name.as_bytes(), ino, &inode)?;
+        dir::remove_entry(self, dir, name.as_bytes())?;
+
+        // `..` of a directory moves to the new parent
+        if inode.is_dir() && dir != new_dir {
+            dir::set_parent(self, ino, new_dir)?;
+            let mut parent = self.inode(dir)?;
+            parent.links_count = parent.links_count.saturating_sub(1);
+            self.write_inode(dir, &parent)?;
+            let mut parent = self.inode(new_dir)?;
+            parent.links_count += 1;
+            self.write_inode(new_dir, &parent)?;
+        }
+        inode.ctime = self.now();
+        self.write_inode(ino, &inode)
+    }
+
+    /// Returns true if the directory `dir` is `ancestor` or inside it
+    fn is_within(&self, dir: u32, ancestor: u32) -> Result<bool, FSError<D::Error>> {
+        let mut current = dir;
+        // a corrupted file system may have a loop of `..` entries
+        for _ in 0..self.sb.inodes_count {
+            if current == ancestor {
+                return Ok(true)
+            }
+            if current == ROOT_INO {
+                return Ok(false)
+            }
+            current = self.lookup(current, "..")?;
+        }
+        Err(FSError::InvalidFileSystem)
+    }
+
+    /// The inode of `file`, which must be in use
+    fn inode_of(&self, file: FileHandle) -> Result<(u32, Ext2Inode), FileError> {
+        let ino = u32::try_from(file.0).or(Err(FileError::BadHandle))?;
+        match self.inode(ino) {
+            Ok(inode) if inode.mode != 0 && inode.dtime == 0 => Ok((ino, inode)),
+            Ok(_) | Err(FSError::FileNotFound) => Err(FileError::BadHandle),
+            Err(e) => Err(e.into())
+        }
+    }
+}
+
+/// The file type of an inode with `mode`
+const fn mode_type(mode: u16) -> FileType {
+    match mode & S_IFMT {
+        S_IFDIR => FileType::Directory,
+        S_IFLNK => FileType::Symlink,
+        S_IFCHR => FileType::CharDevice,
+        S_IFBLK => FileType::BlockDevice,
+        S_IFIFO => FileType::Fifo,
+        S_IFSOCK => FileType::Socket,
+        _ => FileType::File
+    }
+}
+
+/// Files are identified by their inode number
+impl<D: BlockDevice> FileSystem for Ext2<D> {
+    fn open(&self, path: &str) -> Result<FileHandle, FileError> {
+        let mut ino = ROOT_INO;
+        for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+            ino = self.lookup(ino, name)?;
+        }
+        Ok(FileHandle(ino as u64))
+    }
+
+    fn create(&mut self, path: &str) -> Result<FileHandle, FileError> {
+        let (parent, name) = split_path(path)?;
+        let dir = FileSystem::open(self, parent)?.0 as u32;
+        Ok(FileHandle(Ext2::create(self, dir, name)? as u64))
+    }
+
+    fn read_at(&self, file: FileHandle, offset: u64, buf: &mut [u8]) -> Result<usize, FileError> {
+        let (ino, inode) = self.inode_of(file)?;
+        if inode.is_dir() {
+            return Err(FileError::IsADirectory)
+        }
+        Ok(Ext2::read_at(self, ino, offset, buf)?)
+    }
+
+    fn write_at(&mut self, file: FileHandle, offset: u64, buf: &[u8]) -> Result<usize, FileError> {
+        let (ino, _) = self.inode_of(file)?;
+        Ok(Ext2::write_at(self, ino, offset, buf)?)
+    }
+
+    fn stat(&self, file: FileHandle) -> Result<Metadata, FileError> {
+        let (_, inode) = self.inode_of(file)?;
+        Ok(Metadata {
+            file_type: mode_type(inode.mode),
+            size: inode.size,
+            perm: inode.perm(),
+            links: inode.links_count as u32,
+            ino: file.0
+        })
+    }
+
+    fn truncate(&mut self, file: FileHandle, size: u64) -> Result<(), FileError> {
+        let (ino, _) = self.inode_of(file)?;
+        Ok(self.set_size(ino, size)?)
+    }
+
+    fn readdir(&self, dir: FileHandle, f: &mut dyn FnMut(&DirEntry)) -> Result<(), FileError> {
+        let (ino, inode) = self.inode_of(dir)?;
+        if !inode.is_dir() {
+            return Err(FileError::NotADirectory)
+        }
+        let mut result = Ok(());
+        self.read_dir(ino, |entry| {
+            let file_type = match entry.file_type {
+                dir::FT_REG_FILE => FileType::File,
+                dir::FT_DIR => FileType::Directory,
+                dir::FT_CHRDEV => FileType::CharDevice,
+                dir::FT_BLKDEV => FileType::BlockDevice,
+                dir::FT_FIFO => FileType::Fifo,
+                dir::FT_SOCK => FileType::Socket,
+                dir::FT_SYMLINK => FileType::Symlink,
+                // entries without a file type, the inode has it
+                _ => match self.inode(entry.inode) {
+                    Ok(inode) => mode_type(inode.mode),
+                    Err(e) => {
+                        result = Err(e.into());
+                        return
+                    }
+                }
+            };
+            let mut dirent = DirEntry::new(file_type, entry.inode as u64);
+            dirent.push(entry.name());
+            f(&dirent);
+        })?;
+        result
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), FileError> {
+        let (parent, name) = split_path(path)?;
+        let dir = FileSystem::open(self, parent)?.0 as u32;
+        Ext2::mkdir(self, dir, name)?;
+        Ok(())
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FileError> {
+        let (parent, name) = split_path(path)?;
+        let dir = FileSystem::open(self, parent)?.0 as u32;
+        Ok(self.remove(dir, name)?)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FileError> {
+        let (parent, name) = split_path(from)?;
+        let (new_parent, new_name) = split_path(to)?;
+        let dir = FileSystem::open(self, parent)?.0 as u32;
+        let new_dir = FileSystem::open(self, new_parent)?.0 as u32;
+        Ok(Ext2::rename(self, dir, name, new_dir, new_name)?)
+    }
+
+    fn symlink(&mut self, path: &str, target: &str) -> Result<(), FileError> {
+        let (parent, name) = split_path(path)?;
+        let dir = FileSystem::open(self, parent)?.0 as u32;
+        Ext2::symlink(self, dir, name, target)?;
+        Ok(())
+    }
+
+    fn readlink(&self, link: FileHandle, buf: &mut [u8]) -> Result<usize, FileError> {
+        let (ino, _) = self.inode_of(link)?;
+        Ok(self.read_link(ino, buf)?)
+    }
+
+    fn sync(&mut self) -> Result<(), FileError> {
+        Ok(self.flush()?)
+    }
+}
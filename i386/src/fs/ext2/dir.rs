@@ -0,0 +1,302 @@
+//! Directories of the ext2 file system. A directory is a file of variable length
+//! entries which never cross a block, each entry points to the next with `rec_len`.
+//! A deleted entry is merged into the previous entry of its block.
+//! See https://www.nongnu.org/ext2-doc/ext2.html#linked-directories
+
+use core::fmt;
+use super::{inode::*, Ext2, INCOMPAT_FILETYPE, ROOT_INO};
+use crate::{
+    driver::disk::block::{BlockDevice, MAX_BLOCK_SIZE},
+    fs::FSError
+};
+
+/// The max length of a name in bytes
+pub const NAME_MAX: usize = 255;
+/// inode, rec_len, name_len and file_type
+const HEADER_SIZE: usize = 8;
+/// the max number of symbolic links followed while resolving a path
+const SYMLINK_MAX_DEPTH: usize = 8;
+/// the longest symbolic link target we follow
+const SYMLINK_TARGET_MAX: usize = 1024;
+
+/// file types in directory entries, with the filetype feature
+pub const FT_UNKNOWN: u8 = 0;
+pub const FT_REG_FILE: u8 = 1;
+pub const FT_DIR: u8 = 2;
+pub const FT_CHRDEV: u8 = 3;
+pub const FT_BLKDEV: u8 = 4;
+pub const FT_FIFO: u8 = 5;
+pub const FT_SOCK: u8 = 6;
+pub const FT_SYMLINK: u8 = 7;
+
+/// The file type of an entry pointing to an inode with `mode`
+const fn file_type(mode: u16) -> u8 {
+    match mode & S_IFMT {
+        S_IFREG => FT_REG_FILE,
+        S_IFDIR => FT_DIR,
+        S_IFCHR => FT_CHRDEV,
+        S_IFBLK => FT_BLKDEV,
+        S_IFIFO => FT_FIFO,
+        S_IFSOCK => FT_SOCK,
+        S_IFLNK => FT_SYMLINK,
+        _ => FT_UNKNOWN
+    }
+}
+
+/// The space taken by an entry with a name of `name_len` bytes, entries are 4 byte aligned
+const fn entry_len(name_len: usize) -> usize {
+    (HEADER_SIZE + name_len + 3) & !3
+}
+
+/// An entry returned by `Ext2::read_dir`
+#[derive(Clone, Copy)]
+pub struct Ext2DirEntry {
+    pub inode: u32,
+    /// one of the `FT_*` values, always `FT_UNKNOWN` without the filetype feature
+    pub file_type: u8,
+    name: [u8; NAME_MAX],
+    name_len: usize
+}
+
+impl Ext2DirEntry {
+    /// The name, which is usually but not necessarily UTF-8
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+
+    /// Returns true for the `.` and `..` entries
+    pub(super) fn is_dot(&self) -> bool {
+        self.name() == b"." || self.name() == b".."
+    }
+}
+
+impl fmt::Display for Ext2DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match core::str::from_utf8(self.name()) {
+            Ok(name) => f.write_str(name),
+            Err(_) => self.name().iter().try_for_each(|&c| {
+                let c = if c.is_ascii() { c as char } else { char::REPLACEMENT_CHARACTER };
+                fmt::Write::write_char(f, c)
+            })
+        }
+    }
+}
+
+/// Where an entry is: its block, its offset in the block and
+/// the offset of the previous entry in the same block
+#[derive(Clone, Copy)]
+pub(super) struct EntryPos {
+    block: u32,
+    offset: usize,
+    prev: Option<usize>
+}
+
+/// The (inode, rec_len, name_len, file_type) of the entry at the start of `raw`
+fn parse_header<D: BlockDevice>(fs: &Ext2<D>, raw: &[u8]) -> (u32, usize, usize, u8) {
+    let file_type = if fs.sb.feature_incompat & INCOMPAT_FILETYPE != 0 { raw[7] } else { FT_UNKNOWN };
+    (
+        u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]),
+        u16::from_le_bytes([raw[4], raw[5]]) as usize,
+        raw[6] as usize,
+        file_type
+    )
+}
+
+fn write_header<D: BlockDevice>(fs: &Ext2<D>, raw: &mut [u8], ino: u32, rec_len: usize, name: &[u8], mode: u16) {
+    raw[0..4].copy_from_slice(&ino.to_le_bytes());
+    raw[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+    raw[6] = name.len() as u8;
+    raw[7] = if fs.sb.feature_incompat & INCOMPAT_FILETYPE != 0 { file_type(mode) } else { 0 };
+    raw[HEADER_SIZE..HEADER_SIZE + name.len()].copy_from_slice(name);
+}
+
+/// Call `f` with every entry of `dir` and its position, until `f` returns something
+pub(super) fn scan<D: BlockDevice, T>(
+    fs: &Ext2<D>, dir: u32,
+    mut f: impl FnMut(&Ext2DirEntry, EntryPos) -> Option<T>
+) -> Result<Option<T>, FSError<D::Error>> {
+    let inode = fs.inode(dir)?;
+    if !inode.is_dir() {
+        return Err(FSError::NotADirectory)
+    }
+
+    let bs = fs.block_size as usize;
+    let mut buf = [0_u8; MAX_BLOCK_SIZE];
+    for lbn in 0..inode.size / bs as u64 {
+        let block = match fs.bmap(&inode, lbn)? {
+            // directories have no holes
+            0 => return Err(FSError::InvalidFileSystem),
+            block => block
+        };
+        fs.read_block(block, &mut buf)?;
+
+        let mut offset = 0;
+        let mut prev = None;
+        while offset < bs {
+            let (ino, rec_len, name_len, file_type) = parse_header(fs, &buf[offset..]);
+            if rec_len < HEADER_SIZE || rec_len % 4 != 0 || offset + rec_len > bs || HEADER_SIZE + name_len > rec_len {
+                return Err(FSError::InvalidFileSystem)
+            }
+            if ino != 0 {
+                let mut entry = Ext2DirEntry { inode: ino, file_type, name: [0; NAME_MAX], name_len };
+                entry.name[..name_len].copy_from_slice(&buf[offset + HEADER_SIZE..offset + HEADER_SIZE + name_len]);
+                if let Some(result) = f(&entry, EntryPos { block, offset, prev }) {
+                    return Ok(Some(result))
+                }
+            }
+            prev = Some(offset);
+            offset += rec_len;
+        }
+    }
+    Ok(None)
+}
+
+/// Find the entry called `name` in `dir`, names are case sensitive
+pub(super) fn find<D: BlockDevice>(fs: &Ext2<D>, dir: u32, name: &[u8]) -> Result<(Ext2DirEntry, EntryPos), FSError<D::Error>> {
+    if name.is_empty() || name.len() > NAME_MAX {
+        return Err(FSError::InvalidName)
+    }
+    scan(fs, dir, |entry, pos| if entry.name() == name { Some((*entry, pos)) } else { None })?
+        .ok_or(FSError::FileNotFound)
+}
+
+/// Returns true if `dir` only contains `.` and `..`
+pub(super) fn is_empty<D: BlockDevice>(fs: &Ext2<D>, dir: u32) -> Result<bool, FSError<D::Error>> {
+    Ok(scan(fs, dir, |entry, _| if entry.is_dot() { None } else { Some(()) })?.is_none())
+}
+
+/// Make sure `name` is a valid name which does not exist in `dir` yet
+pub(super) fn check_new<D: BlockDevice>(fs: &Ext2<D>, dir: u32, name: &[u8]) -> Result<(), FSError<D::Error>> {
+    let valid = !name.is_empty() && name.len() <= NAME_MAX
+        && name != b"." && name != b".."
+        && !name.iter().any(|&c| c == b'/' || c == 0);
+    if !valid {
+        return Err(FSError::InvalidName)
+    }
+    match find(fs, dir, name) {
+        Ok(_) => Err(FSError::AlreadyExists),
+        Err(FSError::FileNotFound) => Ok(()),
+        Err(e) => Err(e)
+    }
+}
+
+/// Add an entry called `name` for the inode `ino` to `dir`. The entry goes into
+/// the free space of an existing block if possible, otherwise a block is appended.
+pub(super) fn add_entry<D: BlockDevice>(fs: &mut Ext2<D>, dir: u32, name: &[u8], ino: u32, inode: &Ext2Inode) -> Result<(), FSError<D::Error>> {
+    let mut dir_inode = fs.inode(dir)?;
+    let bs = fs.block_size as usize;
+    let need = entry_len(name.len());
+    let mut buf = [0_u8; MAX_BLOCK_SIZE];
+    let blocks = dir_inode.size / bs as u64;
+
+    let mut found = None;
+    'blocks: for lbn in 0..blocks {
+        let block = match fs.bmap(&dir_inode, lbn)? {
+            0 => return Err(FSError::InvalidFileSystem),
+            block => block
+        };
+        fs.read_block(block, &mut buf)?;
+        let mut offset = 0;
+        while offset < bs {
+            let (entry_ino, rec_len, name_len, _) = parse_header(fs, &buf[offset..]);
+            if rec_len < HEADER_SIZE || offset + rec_len > bs {
+                return Err(FSError::InvalidFileSystem)
+            }
+            let used = if entry_ino == 0 { 0 } else { entry_len(name_len) };
+            if rec_len >= used + need {
+                if used != 0 {
+                    // split the free space off the entry
+                    buf[offset + 4..offset + 6].copy_from_slice(&(used as u16).to_le_bytes());
+                }
+                found = Some((block, offset + used, rec_len - used));
+                break 'blocks
+            }
+            offset += rec_len;
+        }
+    }
+
+    let (block, offset, rec_len) = match found {
+        Some(found) => found,
+        None => {
+            let block = fs.bmap_alloc(dir, &mut dir_inode, blocks)?;
+            dir_inode.size += bs as u64;
+            buf[..bs].fill(0);
+            (block, 0, bs)
+        }
+    };
+    write_header(fs, &mut buf[offset..], ino, rec_len, name, inode.mode);
+    fs.write_block(block, &buf)?;
+
+    // the hash index is not maintained, so the directory becomes a linear one
+    dir_inode.flags &= !FL_INDEX;
+    dir_inode.mtime = fs.now();
+    fs.write_inode(dir, &dir_inode)
+}
+
+/// Remove the entry called `name` from `dir`
+pub(super) fn remove_entry<D: BlockDevice>(fs: &mut Ext2<D>, dir: u32, name: &[u8]) -> Result<(), FSError<D::Error>> {
+    let (_, pos) = find(fs, dir, name)?;
+    let mut buf = [0_u8; MAX_BLOCK_SIZE];
+    fs.read_block(pos.block, &mut buf)?;
+    match pos.prev {
+        Some(prev) => {
+            let (_, prev_len, _, _) = parse_header(fs, &buf[prev..]);
+            let (_, rec_len, _, _) = parse_header(fs, &buf[pos.offset..]);
+            buf[prev + 4..prev + 6].copy_from_slice(&((prev_len + rec_len) as u16).to_le_bytes());
+        },
+        // the first entry of a block can not be merged
+        None => buf[pos.offset..pos.offset + 4].fill(0)
+    }
+    fs.write_block(pos.block, &buf)?;
+
+    let mut dir_inode = fs.inode(dir)?;
+    dir_inode.mtime = fs.now();
+    fs.write_inode(dir, &dir_inode)
+}
+
+/// Give the new directory `ino` its first block with `.` and `..`
+pub(super) fn init_dir<D: BlockDevice>(fs: &mut Ext2<D>, ino: u32, inode: &mut Ext2Inode, parent: u32) -> Result<(), FSError<D::Error>> {
+    let bs = fs.block_size as usize;
+    let block = fs.bmap_alloc(ino, inode, 0)?;
+    let mut buf = [0_u8; MAX_BLOCK_SIZE];
+    let dot_len = entry_len(1);
+    write_header(fs, &mut buf, ino, dot_len, b".", S_IFDIR);
+    write_header(fs, &mut buf[dot_len..], parent, bs - dot_len, b"..", S_IFDIR);
+    fs.write_block(block, &buf)?;
+
+    inode.size = bs as u64;
+    // the entry in the parent and `.`
+    inode.links_count = 2;
+    fs.write_inode(ino, inode)
+}
+
+/// Point the `..` entry of the directory `dir` to `parent`
+pub(super) fn set_parent<D: BlockDevice>(fs: &mut Ext2<D>, dir: u32, parent: u32) -> Result<(), FSError<D::Error>> {
+    let (_, pos) = find(fs, dir, b"..")?;
+    fs.write_u32(fs.block_offset(pos.block) + pos.offset as u64, parent)
+}
+
+/// Resolve `path` from the directory `start`, following symbolic links.
+/// `depth` is the number of symbolic links followed so far.
+pub(super) fn resolve<D: BlockDevice>(fs: &Ext2<D>, start: u32, path: &str, depth: usize) -> Result<u32, FSError<D::Error>> {
+    let mut current = if path.starts_with('/') { ROOT_INO } else { start };
+    for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+        let dir = current;
+        current = fs.lookup(dir, name)?;
+        let inode = fs.inode(current)?;
+        if !inode.is_symlink() {
+            continue
+        }
+
+        // too many levels of symbolic links
+        if depth == SYMLINK_MAX_DEPTH || inode.size > SYMLINK_TARGET_MAX as u64 {
+            return Err(FSError::InvalidName)
+        }
+        let mut target = [0_u8; SYMLINK_TARGET_MAX];
+        let len = fs.read_at(current, 0, &mut target)?;
+        let target = core::str::from_utf8(&target[..len]).map_err(|_| FSError::InvalidName)?;
+        // a relative target starts from the directory containing the link
+        current = resolve(fs, dir, target, depth + 1)?;
+    }
+    Ok(current)
+}
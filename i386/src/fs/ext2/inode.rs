@@ -0,0 +1,149 @@
+//! Inodes of the ext2 file system. Every file, directory and symbolic link is
+//! described by an inode, which maps its contents to blocks with 12 direct pointers
+//! and a single, double and triple indirect block.
+//! See https://www.nongnu.org/ext2-doc/ext2.html#inode-table
+
+/// the file type bits of the mode
+pub const S_IFMT: u16 = 0xf000;
+pub const S_IFSOCK: u16 = 0xc000;
+pub const S_IFLNK: u16 = 0xa000;
+pub const S_IFREG: u16 = 0x8000;
+pub const S_IFBLK: u16 = 0x6000;
+pub const S_IFDIR: u16 = 0x4000;
+pub const S_IFCHR: u16 = 0x2000;
+pub const S_IFIFO: u16 = 0x1000;
+
+/// the directory is indexed with a hashed b-tree
+pub(super) const FL_INDEX: u32 = 0x1000;
+
+/// the number of block pointers in an inode
+pub(super) const N_BLOCKS: usize = 15;
+/// the number of direct block pointers, followed by the indirect ones
+pub(super) const N_DIRECT: usize = 12;
+/// the pointers of a fast symbolic link hold the target
+pub(super) const FAST_SYMLINK_MAX: usize = N_BLOCKS * 4;
+
+/// The fields of an on disk inode we use, the rest is kept as is
+#[derive(Clone, Copy)]
+pub struct Ext2Inode {
+    pub mode: u16,
+    pub uid: u16,
+    /// the size in bytes, the high half is only used by regular files
+    pub size: u64,
+    pub atime: u32,
+    pub ctime: u32,
+    pub mtime: u32,
+    /// the time the inode was deleted
+    pub dtime: u32,
+    pub gid: u16,
+    pub links_count: u16,
+    /// the number of 512 byte sectors used, including indirect blocks
+    pub sectors: u32,
+    pub flags: u32,
+    pub block: [u32; N_BLOCKS],
+    /// the block holding extended attributes
+    pub file_acl: u32
+}
+
+impl Ext2Inode {
+    /// A new inode with no blocks
+    pub(super) const fn new(mode: u16, time: u32) -> Self {
+        Self {
+            mode, uid: 0, size: 0,
+            atime: time, ctime: time, mtime: time, dtime: 0,
+            gid: 0, links_count: 0, sectors: 0, flags: 0,
+            block: [0; N_BLOCKS],
+            file_acl: 0
+        }
+    }
+
+    pub(super) fn parse(raw: &[u8]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([raw[i], raw[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([raw[i], raw[i + 1], raw[i + 2], raw[i + 3]]);
+        let mode = u16_at(0);
+        let mut block = [0; N_BLOCKS];
+        block.iter_mut().enumerate().for_each(|(i, b)| *b = u32_at(40 + i * 4));
+        // i_dir_acl of directories in revision 0
+        let size_high = if mode & S_IFMT == S_IFREG { u32_at(108) as u64 } else { 0 };
+
+        Self {
+            mode,
+            uid: u16_at(2),
+            size: u32_at(4) as u64 | size_high << 32,
+            atime: u32_at(8),
+            ctime: u32_at(12),
+            mtime: u32_at(16),
+            dtime: u32_at(20),
+            gid: u16_at(24),
+            links_count: u16_at(26),
+            sectors: u32_at(28),
+            flags: u32_at(32),
+            block,
+            file_acl: u32_at(104)
+        }
+    }
+
+    /// Store the fields into the first 128 bytes of an on disk inode
+    pub(super) fn store(&self, raw: &mut [u8]) {
+        let mut put = |i: usize, bytes: &[u8]| raw[i..i + bytes.len()].copy_from_slice(bytes);
+        put(0, &self.mode.to_le_bytes());
+        put(2, &self.uid.to_le_bytes());
+        put(4, &(self.size as u32).to_le_bytes());
+        put(8, &self.atime.to_le_bytes());
+        put(12, &self.ctime.to_le_bytes());
+        put(16, &self.mtime.to_le_bytes());
+        put(20, &self.dtime.to_le_bytes());
+        put(24, &self.gid.to_le_bytes());
+        put(26, &self.links_count.to_le_bytes());
+        put(28, &self.sectors.to_le_bytes());
+        put(32, &self.flags.to_le_bytes());
+        for (i, b) in self.block.iter().enumerate() {
+            put(40 + i * 4, &b.to_le_bytes());
+        }
+        put(104, &self.file_acl.to_le_bytes());
+        if self.is_file() {
+            put(108, &((self.size >> 32) as u32).to_le_bytes());
+        }
+    }
+
+    pub const fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub const fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub const fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    /// The permission bits
+    pub const fn perm(&self) -> u16 {
+        self.mode & !S_IFMT
+    }
+
+    /// The target of a fast symbolic link is stored in the block pointers,
+    /// it has no blocks except an extended attribute block.
+    pub(super) fn is_fast_symlink(&self, block_size: u32) -> bool {
+        let acl_sectors = if self.file_acl != 0 { block_size / 512 } else { 0 };
+        self.is_symlink() && self.sectors == acl_sectors
+    }
+
+    /// The block pointers as bytes, which is the target of a fast symbolic link
+    pub(super) fn inline_data(&self) -> [u8; FAST_SYMLINK_MAX] {
+        let mut data = [0; FAST_SYMLINK_MAX];
+        for (chunk, b) in data.chunks_mut(4).zip(self.block.iter()) {
+            chunk.copy_from_slice(&b.to_le_bytes());
+        }
+        data
+    }
+
+    pub(super) fn set_inline_data(&mut self, data: &[u8]) {
+        let mut bytes = [0; FAST_SYMLINK_MAX];
+        bytes[..data.len()].copy_from_slice(data);
+        for (b, chunk) in self.block.iter_mut().zip(bytes.chunks(4)) {
+            *b = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+    }
+}
@@ -0,0 +1,872 @@
+//! The FAT file system in its three flavours FAT12, FAT16 and FAT32, which are told
+//! apart by the number of clusters. The volume starts with the BIOS parameter block,
+//! followed by the reserved sectors, the file allocation tables, the fixed root
+//! directory (FAT12 / FAT16 only) and the data region divided into clusters.
+//! See https://wiki.osdev.org/FAT and the Microsoft FAT Specification
+
+pub mod dir;
+
+use crate::{
+    driver::disk::block::{BlockDevice, ByteAdapter, MAX_BLOCK_SIZE},
+    fs::{FSError, FileSystem, FileError, FileHandle, FileType, Metadata, DirEntry, split_path}
+};
+pub use dir::{FATDirEntry, FATName, FATNode};
+use dir::{ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_VOLUME_ID, ENTRY_SIZE};
+
+/// the boot sector signature at offset 510
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// FAT12 volumes have less clusters than this
+const FAT12_MAX_CLUSTERS: u32 = 4085;
+/// FAT16 volumes have less clusters than this
+const FAT16_MAX_CLUSTERS: u32 = 65525;
+/// the number of the first cluster in the data region
+const FIRST_CLUSTER: u32 = 2;
+/// a free cluster in the FAT
+const FREE_CLUSTER: u32 = 0;
+
+/// FSInfo signatures, at offset 0, 484 and 508
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUC_SIG: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIG: u32 = 0xaa55_0000;
+/// the free cluster count and the next free hint in FSInfo
+const FSINFO_FREE_COUNT: u64 = 488;
+/// an unknown free count or next free cluster
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FATType {
+    FAT12,
+    FAT16,
+    FAT32
+}
+
+impl FATType {
+    /// Entries at or above this value mark the end of a cluster chain
+    const fn eoc(&self) -> u32 {
+        match self {
+            Self::FAT12 => 0xff8,
+            Self::FAT16 => 0xfff8,
+            Self::FAT32 => 0x0fff_fff8
+        }
+    }
+
+    /// The end of chain marker we write
+    const fn eoc_mark(&self) -> u32 {
+        match self {
+            Self::FAT12 => 0xfff,
+            Self::FAT16 => 0xffff,
+            Self::FAT32 => 0x0fff_ffff
+        }
+    }
+
+    /// The number of bits of a FAT entry
+    const fn entry_bits(&self) -> u64 {
+        match self {
+            Self::FAT12 => 12,
+            Self::FAT16 => 16,
+            Self::FAT32 => 32
+        }
+    }
+}
+
+impl Into<&'static str> for FATType {
+    fn into(self) -> &'static str {
+        match self {
+            FATType::FAT12 => "FAT12",
+            FATType::FAT16 => "FAT16",
+            FATType::FAT32 => "FAT32",
+        }
+    }
+}
+
+/// The fields of the BIOS parameter block we use
+#[derive(Clone, Copy)]
+pub struct BPB {
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub num_fats: u8,
+    /// the number of entries in the fixed root directory, 0 on FAT32
+    pub root_entries: u16,
+    pub total_sectors: u32,
+    /// the size of a FAT in sectors
+    pub fat_sectors: u32,
+    /// the first cluster of the root directory, FAT32 only
+    pub root_cluster: u32,
+    /// the sector of the FSInfo structure, FAT32 only
+    pub fsinfo_sector: u16
+}
+
+impl BPB {
+    fn parse(sector: &[u8]) -> Option<Self> {
+        let u16_at = |i: usize| u16::from_le_bytes([sector[i], sector[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([sector[i], sector[i + 1], sector[i + 2], sector[i + 3]]);
+
+        if sector[510..512] != BOOT_SIGNATURE {
+            return None
+        }
+
+        let fat16_sectors = u16_at(22) as u32;
+        let total16 = u16_at(19) as u32;
+        let fat32 = fat16_sectors == 0;
+        let bpb = Self {
+            bytes_per_sector: u16_at(11),
+            sectors_per_cluster: sector[13],
+            reserved_sectors: u16_at(14),
+            num_fats: sector[16],
+            root_entries: u16_at(17),
+            total_sectors: if total16 != 0 { total16 } else { u32_at(32) },
+            fat_sectors: if fat32 { u32_at(36) } else { fat16_sectors },
+            root_cluster: if fat32 { u32_at(44) } else { 0 },
+            fsinfo_sector: if fat32 { u16_at(48) } else { 0 }
+        };
+
+        let valid = matches!(bpb.bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            && bpb.sectors_per_cluster.is_power_of_two()
+            && bpb.reserved_sectors != 0
+            && bpb.num_fats != 0
+            && bpb.total_sectors != 0
+            && bpb.fat_sectors != 0
+            // FAT32 has no fixed root directory
+            && (bpb.root_entries == 0) == fat32;
+        if valid { Some(bpb) } else { None }
+    }
+}
+
+/// A mounted FAT volume on a block device, usually a `PartitionDevice`.
+/// Files and directories are identified by `FATNode`s.
+pub struct FAT<D: BlockDevice> {
+    dev: ByteAdapter<D>,
+    pub bpb: BPB,
+    ty: FATType,
+    /// byte offsets of the first FAT, the fixed root directory and the data region
+    fat_start: u64,
+    root_start: u64,
+    data_start: u64,
+    cluster_size: u32,
+    cluster_count: u32,
+    /// where the search for a free cluster starts
+    next_free: u32,
+    /// the free count in FSInfo has been marked as unknown
+    fsinfo_invalidated: bool
+}
+
+impl<D: BlockDevice> FAT<D> {
+    /// Parse the BIOS parameter block and mount the volume
+    pub fn new(dev: D) -> Result<Self, FSError<D::Error>> {
+        if dev.block_size() > MAX_BLOCK_SIZE {
+            return Err(FSError::InvalidFileSystem)
+        }
+        let dev = ByteAdapter::new(dev);
+        let mut sector = [0_u8; 512];
+        if dev.read(0, &mut sector)? != sector.len() {
+            return Err(FSError::InvalidFileSystem)
+        }
+        let bpb = BPB::parse(&sector).ok_or(FSError::InvalidFileSystem)?;
+
+        let bps = bpb.bytes_per_sector as u64;
+        let root_sectors = (bpb.root_entries as u64 * ENTRY_SIZE + bps - 1) / bps;
+        let meta_sectors = bpb.reserved_sectors as u64
+            + bpb.num_fats as u64 * bpb.fat_sectors as u64
+            + root_sectors;
+        if meta_sectors >= bpb.total_sectors as u64 {
+            return Err(FSError::InvalidFileSystem)
+        }
+        let data_sectors = bpb.total_sectors as u64 - meta_sectors;
+        let cluster_count = (data_sectors / bpb.sectors_per_cluster as u64) as u32;
+
+        let ty = if cluster_count < FAT12_MAX_CLUSTERS {
+            FATType::FAT12
+        } else if cluster_count < FAT16_MAX_CLUSTERS {
+            FATType::FAT16
+        } else {
+            FATType::FAT32
+        };
+        if (ty == FATType::FAT32) != (bpb.root_entries == 0) {
+            return Err(FSError::InvalidFileSystem)
+        }
+        // clusters not covered by the FAT can not be used
+        let fat_entries = bpb.fat_sectors as u64 * bps * 8 / ty.entry_bits();
+        let cluster_count = (cluster_count as u64).min(fat_entries.saturating_sub(FIRST_CLUSTER as u64)) as u32;
+
+        let fat_start = bpb.reserved_sectors as u64 * bps;
+        let root_start = fat_start + bpb.num_fats as u64 * bpb.fat_sectors as u64 * bps;
+        let fs = Self {
+            dev,
+            bpb,
+            ty,
+            fat_start,
+            root_start,
+            data_start: root_start + root_sectors * bps,
+            cluster_size: bpb.sectors_per_cluster as u32 * bpb.bytes_per_sector as u32,
+            cluster_count,
+            next_free: FIRST_CLUSTER,
+            fsinfo_invalidated: false
+        };
+        if ty == FATType::FAT32 && !fs.is_valid_cluster(bpb.root_cluster) {
+            return Err(FSError::InvalidFileSystem)
+        }
+        Ok(fs)
+    }
+
+    pub fn fat_type(&self) -> FATType {
+        self.ty
+    }
+
+    /// The size of a cluster in bytes
+    pub fn cluster_size(&self) -> u32 {
+        self.cluster_size
+    }
+
+    /// The number of clusters in the data region
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    pub fn device(&self) -> &D {
+        self.dev.inner()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev.into_inner()
+    }
+
+    pub fn flush(&mut self) -> Result<(), FSError<D::Error>> {
+        Ok(self.dev.flush()?)
+    }
+
+    /// The root directory, on FAT12 / FAT16 it is not in a cluster
+    pub fn root(&self) -> FATNode {
+        FATNode::root(self.root_cluster())
+    }
+
+    /// The first cluster of the root directory, 0 for the fixed root directory
+    pub(super) fn root_cluster(&self) -> u32 {
+        match self.ty {
+            FATType::FAT32 => self.bpb.root_cluster,
+            _ => 0
+        }
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && cluster - FIRST_CLUSTER < self.cluster_count
+    }
+
+    /// The byte offset of a cluster on the device
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.cluster_size as u64
+    }
+
+    /// Read exactly `buf.len()` bytes from `offset`
+    fn read_exact(&self, offset: u64, buf: &mut [u8]) -> Result<(), FSError<D::Error>> {
+        match self.dev.read(offset, buf)? == buf.len() {
+            true => Ok(()),
+            false => Err(FSError::InvalidFileSystem)
+        }
+    }
+
+    fn write_exact(&mut self, offset: u64, buf: &[u8]) -> Result<(), FSError<D::Error>> {
+        match self.dev.write(offset, buf)? == buf.len() {
+            true => Ok(()),
+            false => Err(FSError::InvalidFileSystem)
+        }
+    }
+
+    /// The byte offset of the entry of `cluster` in the first FAT
+    fn fat_offset(&self, cluster: u32) -> u64 {
+        let cluster = cluster as u64;
+        self.fat_start + match self.ty {
+            // 12-bit entries, two entries share three bytes
+            FATType::FAT12 => cluster + cluster / 2,
+            FATType::FAT16 => cluster * 2,
+            FATType::FAT32 => cluster * 4
+        }
+    }
+
+    /// Read the FAT entry of `cluster`
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FSError<D::Error>> {
+        let offset = self.fat_offset(cluster);
+        Ok(match self.ty {
+            FATType::FAT12 => {
+                let mut bytes = [0; 2];
+                self.read_exact(offset, &mut bytes)?;
+                let pair = u16::from_le_bytes(bytes) as u32;
+                if cluster & 1 == 0 { pair & 0xfff } else { pair >> 4 }
+            },
+            FATType::FAT16 => {
+                let mut bytes = [0; 2];
+                self.read_exact(offset, &mut bytes)?;
+                u16::from_le_bytes(bytes) as u32
+            },
+            FATType::FAT32 => {
+                let mut bytes = [0; 4];
+                self.read_exact(offset, &mut bytes)?;
+                // the high 4 bits are reserved
+                u32::from_le_bytes(bytes) & 0x0fff_ffff
+            }
+        })
+    }
+
+    /// Set the FAT entry of `cluster` in every FAT
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), FSError<D::Error>> {
+        let fat_size = self.bpb.fat_sectors as u64 * self.bpb.bytes_per_sector as u64;
+        for i in 0..self.bpb.num_fats as u64 {
+            let offset = self.fat_offset(cluster) + i * fat_size;
+            match self.ty {
+                FATType::FAT12 => {
+                    let mut bytes = [0; 2];
+                    self.read_exact(offset, &mut bytes)?;
+                    let pair = u16::from_le_bytes(bytes);
+                    let value = value as u16 & 0xfff;
+                    let pair = if cluster & 1 == 0 {
+                        (pair & 0xf000) | value
+                    } else {
+                        (pair & 0x000f) | value << 4
+                    };
+                    self.write_exact(offset, &pair.to_le_bytes())?;
+                },
+                FATType::FAT16 => self.write_exact(offset, &(value as u16).to_le_bytes())?,
+                FATType::FAT32 => {
+                    let mut bytes = [0; 4];
+                    self.read_exact(offset, &mut bytes)?;
+                    let reserved = u32::from_le_bytes(bytes) & 0xf000_0000;
+                    self.write_exact(offset, &(reserved | value & 0x0fff_ffff).to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, None at the end of the chain
+    pub(super) fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FSError<D::Error>> {
+        match self.fat_entry(cluster)? {
+            next if next >= self.ty.eoc() => Ok(None),
+            next if self.is_valid_cluster(next) => Ok(Some(next)),
+            // free, bad or reserved clusters can not be part of a chain
+            _ => Err(FSError::InvalidFileSystem)
+        }
+    }
+
+    /// The `n`th cluster of the chain starting at `first`
+    fn nth_cluster(&self, first: u32, n: u32) -> Result<Option<u32>, FSError<D::Error>> {
+        if !self.is_valid_cluster(first) {
+            return Err(FSError::InvalidFileSystem)
+        }
+        let mut cluster = first;
+        for _ in 0..n {
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None => return Ok(None)
+            };
+        }
+        Ok(Some(cluster))
+    }
+
+    /// The number of clusters in the chain starting at `first`, 0 for an empty chain
+    fn chain_len(&self, first: u32) -> Result<u32, FSError<D::Error>> {
+        if first == 0 {
+            return Ok(0)
+        }
+        if !self.is_valid_cluster(first) {
+            return Err(FSError::InvalidFileSystem)
+        }
+        let mut len = 1;
+        let mut cluster = first;
+        while let Some(next) = self.next_cluster(cluster)? {
+            len += 1;
+            // a chain can not be longer than the volume, so it must contain a loop
+            if len > self.cluster_count {
+                return Err(FSError::InvalidFileSystem)
+            }
+            cluster = next;
+        }
+        Ok(len)
+    }
+
+    /// Mark the free cluster count in FSInfo as unknown before changing the FAT,
+    /// since we do not keep track of it.
+    fn invalidate_fsinfo(&mut self) -> Result<(), FSError<D::Error>> {
+        let sector = self.bpb.fsinfo_sector;
+        if self.ty != FATType::FAT32 || self.fsinfo_invalidated || sector == 0 || sector == 0xffff {
+            return Ok(())
+        }
+        self.fsinfo_invalidated = true;
+
+        let offset = sector as u64 * self.bpb.bytes_per_sector as u64;
+        let mut info = [0_u8; 512];
+        self.read_exact(offset, &mut info)?;
+        let u32_at = |i: usize| u32::from_le_bytes([info[i], info[i + 1], info[i + 2], info[i + 3]]);
+        if u32_at(0) != FSINFO_LEAD_SIG || u32_at(484) != FSINFO_STRUC_SIG || u32_at(508) != FSINFO_TRAIL_SIG {
+            return Ok(())
+        }
+        let mut unknown = [0_u8; 8];
+        unknown[..4].copy_from_slice(&FSINFO_UNKNOWN.to_le_bytes());
+        unknown[4..].copy_from_slice(&FSINFO_UNKNOWN.to_le_bytes());
+        self.write_exact(offset + FSINFO_FREE_COUNT, &unknown)
+    }
+
+    /// Fill `len` bytes from `offset` with zeros
+    fn zero(&mut self, offset: u64, len: u64) -> Result<(), FSError<D::Error>> {
+        let zeros = [0_u8; 512];
+        let mut done = 0;
+        while done < len {
+            let part = (len - done).min(zeros.len() as u64) as usize;
+            self.write_exact(offset + done, &zeros[..part])?;
+            done += part as u64;
+        }
+        Ok(())
+    }
+
+    /// Allocate a zeroed cluster and append it to the chain ending at `prev`
+    fn alloc_cluster(&mut self, prev: Option<u32>) -> Result<u32, FSError<D::Error>> {
+        self.invalidate_fsinfo()?;
+        let start = if self.is_valid_cluster(self.next_free) { self.next_free } else { FIRST_CLUSTER };
+        let mut cluster = start;
+        loop {
+            if self.fat_entry(cluster)? == FREE_CLUSTER {
+                break
+            }
+            cluster += 1;
+            if !self.is_valid_cluster(cluster) {
+                cluster = FIRST_CLUSTER;
+            }
+            if cluster == start {
+                return Err(FSError::NoEnoughSpace)
+            }
+        }
+
+        self.set_fat_entry(cluster, self.ty.eoc_mark())?;
+        self.zero(self.cluster_offset(cluster), self.cluster_size as u64)?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, cluster)?;
+        }
+        self.next_free = cluster + 1;
+        Ok(cluster)
+    }
+
+    /// Free every cluster of the chain starting at `first`
+    fn free_chain(&mut self, first: u32) -> Result<(), FSError<D::Error>> {
+        if first == 0 {
+            return Ok(())
+        }
+        if !self.is_valid_cluster(first) {
+            return Err(FSError::InvalidFileSystem)
+        }
+        self.invalidate_fsinfo()?;
+        let mut cluster = Some(first);
+        let mut freed = 0;
+        while let Some(current) = cluster {
+            cluster = self.next_cluster(current)?;
+            self.set_fat_entry(current, FREE_CLUSTER)?;
+            freed += 1;
+            if freed > self.cluster_count {
+                return Err(FSError::InvalidFileSystem)
+            }
+        }
+        self.next_free = self.next_free.min(first);
+        Ok(())
+    }
+
+    /// Make the chain starting at `first` exactly `clusters` long, new clusters are zeroed.
+    /// Returns the new first cluster, which is 0 for an empty chain.
+    fn resize_chain(&mut self, first: u32, clusters: u32) -> Result<u32, FSError<D::Error>> {
+        if clusters == 0 {
+            self.free_chain(first)?;
+            return Ok(0)
+        }
+        if clusters > self.cluster_count {
+            return Err(FSError::NoEnoughSpace)
+        }
+
+        let first = if first == 0 { self.alloc_cluster(None)? } else { first };
+        let mut last = first;
+        for _ in 1..clusters {
+            last = match self.next_cluster(last)? {
+                Some(next) => next,
+                None => self.alloc_cluster(Some(last))?
+            };
+        }
+        // cut off the rest of the chain
+        if let Some(rest) = self.next_cluster(last)? {
+            self.set_fat_entry(last, self.ty.eoc_mark())?;
+            self.free_chain(rest)?;
+        }
+        Ok(first)
+    }
+
+    /// The number of clusters needed for `size` bytes
+    fn clusters_for(&self, size: u64) -> u64 {
+        (size + self.cluster_size as u64 - 1) / self.cluster_size as u64
+    }
+
+    /// The size of `node` in bytes. Directories have no size in their entry,
+    /// so it is the length of the cluster chain.
+    pub fn size_of(&self, node: &FATNode) -> Result<u64, FSError<D::Error>> {
+        if !node.is_dir() {
+            return Ok(node.size as u64)
+        }
+        if node.cluster == 0 {
+            return Ok(self.bpb.root_entries as u64 * ENTRY_SIZE)
+        }
+        Ok(self.chain_len(node.cluster)? as u64 * self.cluster_size as u64)
+    }
+
+    /// Read from `offset` of `node` into `buf`, returns the number of bytes read,
+    /// which is less than `buf.len()` at the end of the file.
+    pub fn read_at(&self, node: &FATNode, offset: u64, buf: &mut [u8]) -> Result<usize, FSError<D::Error>> {
+        let node = self.refresh(node)?;
+        let size = self.size_of(&node)?;
+        if offset >= size || buf.is_empty() {
+            return Ok(0)
+        }
+        let len = (size - offset).min(buf.len() as u64) as usize;
+        let mut cursor = Cursor::new(self, &node, offset)?;
+        let mut done = 0;
+        while done < len {
+            let (pos, part) = cursor.piece(self, len - done)?;
+            self.read_exact(pos, &mut buf[done..done + part])?;
+            done += part;
+        }
+        Ok(len)
+    }
+
+    /// Write `buf` to `offset` of the file `node`, the file grows if needed and a gap
+    /// before `offset` is filled with zeros. `node` and its directory entry are updated.
+    pub fn write_at(&mut self, node: &mut FATNode, offset: u64, buf: &[u8]) -> Result<usize, FSError<D::Error>> {
+        *node = self.refresh(node)?;
+        if node.is_dir() {
+            return Err(FSError::IsADirectory)
+        }
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        let end = offset + buf.len() as u64;
+        if end > u32::MAX as u64 {
+            return Err(FSError::NoEnoughSpace)
+        }
+        if end > node.size as u64 {
+            self.set_size(node, end as u32)?;
+        }
+
+        let mut cursor = Cursor::new(self, node, offset)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let (pos, part) = cursor.piece(self, buf.len() - done)?;
+            self.write_exact(pos, &buf[done..done + part])?;
+            done += part;
+        }
+        Ok(done)
+    }
+
+    /// Change the size of the file `node` to `size`, allocating or freeing clusters.
+    /// New contents are zeros.
+    pub fn set_size(&mut self, node: &mut FATNode, size: u32) -> Result<(), FSError<D::Error>> {
+        *node = self.refresh(node)?;
+        if node.is_dir() {
+            return Err(FSError::IsADirectory)
+        }
+        let old_clusters = self.clusters_for(node.size as u64);
+        let clusters = self.clusters_for(size as u64) as u32;
+        // the tail of the last cluster may contain stale data
+        if size > node.size && old_clusters > 0 {
+            let tail = old_clusters * self.cluster_size as u64 - node.size as u64;
+            let tail = tail.min((size - node.size) as u64);
+            if tail > 0 {
+                let (pos, _) = Cursor::new(self, node, node.size as u64)?.piece(self, tail as usize)?;
+                self.zero(pos, tail)?;
+            }
+        }
+        node.cluster = self.resize_chain(node.cluster, clusters)?;
+        node.size = size;
+        dir::update_entry(self, node)
+    }
+
+    /// Read the current state of `node` from its directory entry, so a copy of
+    /// a node taken before a write is still usable.
+    fn refresh(&self, node: &FATNode) -> Result<FATNode, FSError<D::Error>> {
+        dir::reload(self, node)
+    }
+
+    /// Look up `name` in the directory `dir`. Names are compared case insensitively.
+    pub fn lookup(&self, dir: &FATNode, name: &str) -> Result<FATNode, FSError<D::Error>> {
+        dir::find(self, dir, name).map(|(entry, _)| entry.node)
+    }
+
+    /// Look up a path like `/boot/kernel.bin` from the root directory
+    pub fn open(&self, path: &str) -> Result<FATNode, FSError<D::Error>> {
+        let mut node = self.root();
+        for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+            node = self.lookup(&node, name)?;
+        }
+        Ok(node)
+    }
+
+    /// Call `f` with every entry of the directory `dir`, except `.` and `..`
+    pub fn read_dir(&self, dir: &FATNode, mut f: impl FnMut(&FATDirEntry)) -> Result<(), FSError<D::Error>> {
+        dir::scan(self, dir, |entry, _| {
+            if !entry.is_dot() {
+                f(entry);
+            }
+            None::<()>
+        }).map(|_| ())
+    }
+
+    /// Create an empty file called `name` in `dir`
+    pub fn create(&mut self, dir: &FATNode, name: &str) -> Result<FATNode, FSError<D::Error>> {
+        let mut node = FATNode::new(0, 0, ATTR_ARCHIVE);
+        dir::link(self, dir, name, &mut node)?;
+        Ok(node)
+    }
+
+    /// Create an empty directory called `name` in `dir`
+    pub fn mkdir(&mut self, dir: &FATNode, name: &str) -> Result<FATNode, FSError<D::Error>> {
+        let dir = self.refresh(dir)?;
+        let cluster = self.alloc_cluster(None)?;
+        let mut node = FATNode::new(cluster, 0, ATTR_DIRECTORY);
+        if let Err(e) = dir::link(self, &dir, name, &mut node) {
+            self.free_chain(cluster)?;
+            return Err(e)
+        }
+        if let Err(e) = dir::init_dir(self, &node, &dir) {
+            // the entry is useless without `.` and `..`
+            let (_, slots) = dir::find(self, &dir, name)?;
+            dir::free_slots(self, slots.as_slice())?;
+            self.free_chain(cluster)?;
+            return Err(e)
+        }
+        Ok(node)
+    }
+
+    /// Move the entry `name` of `dir` to `new_name` in `new_dir`,
+    /// a file called `new_name` there is replaced.
+    pub fn rename(&mut self, dir: &FATNode, name: &str, new_dir: &FATNode, new_name: &str) -> Result<(), FSError<D::Error>> {
+        let (entry, slots) = dir::find(self, dir, name)?;
+        if entry.is_dot() {
+            return Err(FSError::InvalidName)
+        }
+        let new_dir = self.refresh(new_dir)?;
+        if !new_dir.is_dir() {
+            return Err(FSError::NotADirectory)
+        }
+        let mut node = entry.node;
+        if node.is_dir() && self.is_within(&new_dir, &node)? {
+            return Err(FSError::InvalidName)
+        }
+
+        match dir::find(self, &new_dir, new_name) {
+            // only the case of the name changes
+            Ok((target, _)) if target.node.location == node.location => {
+                dir::free_slots(self, slots.as_slice())?;
+                return dir::link(self, &new_dir, new_name, &mut node)
+            },
+            Ok((target, target_slots)) => {
+                if target.is_dot() || target.node.is_dir() || node.is_dir() {
+                    return Err(FSError::AlreadyExists)
+                }
+                self.unlink(&target.node, target_slots.as_slice())?;
+            },
+            Err(FSError::FileNotFound) => (),
+            Err(e) => return Err(e)
+        }
+        dir::link(self, &new_dir, new_name, &mut node)?;
+        dir::free_slots(self, slots.as_slice())?;
+        if node.is_dir() {
+            dir::set_parent(self, &node, &new_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the directory `dir` is `ancestor` or inside it
+    fn is_within(&self, dir: &FATNode, ancestor: &FATNode) -> Result<bool, FSError<D::Error>> {
+        let mut cluster = dir.cluster;
+        // a corrupted file system may have a loop of `..` entries
+        for _ in 0..self.cluster_count {
+            if cluster == ancestor.cluster {
+                return Ok(true)
+            }
+            if cluster == 0 || cluster == self.root_cluster() {
+                return Ok(false)
+            }
+            cluster = dir::find(self, &FATNode::root(cluster), "..")?.0.node.cluster;
+        }
+        Err(FSError::InvalidFileSystem)
+    }
+
+    /// Remove the file or empty directory `name` from `dir` and free its clusters
+    pub fn remove(&mut self, dir: &FATNode, name: &str) -> Result<(), FSError<D::Error>> {
+        let (entry, slots) = dir::find(self, dir, name)?;
+        if entry.is_dot() {
+            return Err(FSError::InvalidName)
+        }
+        self.unlink(&entry.node, slots.as_slice())
+    }
+
+    /// The handle of `node` for the `FileSystem` trait
+    fn handle_of(&self, node: &FATNode) -> FileHandle {
+        node.location.map_or(ROOT_HANDLE, FileHandle)
+    }
+
+    /// The node of `file`, which is the root directory or a short name entry
+    fn node_of(&self, file: FileHandle) -> Result<FATNode, FileError> {
+        if file == ROOT_HANDLE {
+            return Ok(self.root())
+        }
+        if file.0 < self.root_start || file.0 % ENTRY_SIZE != 0 || file.0 >= self.dev.size() {
+            return Err(FileError::BadHandle)
+        }
+        let node = self.refresh(&FATNode::at(file.0))?;
+        // a long name entry or the volume label
+        if node.attr & ATTR_VOLUME_ID != 0 {
+            return Err(FileError::BadHandle)
+        }
+        Ok(node)
+    }
+
+    /// Free the clusters of `node` and mark its directory entries in `slots` as free
+    fn unlink(&mut self, node: &FATNode, slots: &[u64]) -> Result<(), FSError<D::Error>> {
+        if node.is_dir() && !dir::is_empty(self, node)? {
+            return Err(FSError::DirectoryNotEmpty)
+        }
+        dir::free_slots(self, slots)?;
+        self.free_chain(node.cluster)
+    }
+}
+
+/// A position in the contents of a node, which follows its cluster chain
+struct Cursor {
+    /// the cluster containing `pos`, 0 in the fixed root directory
+    cluster: u32,
+    pos: u64,
+    /// `pos` has reached the end of `cluster`, the next cluster is looked up lazily,
+    /// so a cursor can stop at the end of a chain
+    at_boundary: bool
+}
+
+impl Cursor {
+    fn new<D: BlockDevice>(fs: &FAT<D>, node: &FATNode, pos: u64) -> Result<Self, FSError<D::Error>> {
+        let cluster = match node.cluster {
+            0 => 0,
+            first => fs.nth_cluster(first, (pos / fs.cluster_size as u64) as u32)?
+                .ok_or(FSError::InvalidFileSystem)?
+        };
+        Ok(Self { cluster, pos, at_boundary: false })
+    }
+
+    /// The device offset of the cursor and the length of the contiguous piece there,
+    /// which is at most `len`. The cursor moves to the end of the piece.
+    fn piece<D: BlockDevice>(&mut self, fs: &FAT<D>, len: usize) -> Result<(u64, usize), FSError<D::Error>> {
+        if self.cluster == 0 {
+            let pos = fs.root_start + self.pos;
+            self.pos += len as u64;
+            return Ok((pos, len))
+        }
+        if self.at_boundary {
+            self.cluster = fs.next_cluster(self.cluster)?.ok_or(FSError::InvalidFileSystem)?;
+            self.at_boundary = false;
+        }
+        let cs = fs.cluster_size as u64;
+        let skip = self.pos % cs;
+        let part = ((cs - skip) as usize).min(len);
+        self.pos += part as u64;
+        self.at_boundary = self.pos % cs == 0;
+        Ok((fs.cluster_offset(self.cluster) + skip, part))
+    }
+}
+
+/// The root directory, other files are identified by the device offset of their entry.
+/// FAT has no inode numbers, so a handle names the slot rather than the file: it goes
+/// stale when the file is renamed or removed. A freed slot is reported as `NotFound`,
+/// but a slot reused by a later file refers to that file.
+const ROOT_HANDLE: FileHandle = FileHandle(0);
+
+impl<D: BlockDevice> FileSystem for FAT<D> {
+    fn open(&self, path: &str) -> Result<FileHandle, FileError> {
+        let node = FAT::open(self, path)?;
+        Ok(self.handle_of(&node))
+    }
+
+    fn create(&mut self, path: &str) -> Result<FileHandle, FileError> {
+        let (parent, name) = split_path(path)?;
+        let dir = FAT::open(self, parent)?;
+        let node = FAT::create(self, &dir, name)?;
+        Ok(self.handle_of(&node))
+    }
+
+    fn read_at(&self, file: FileHandle, offset: u64, buf: &mut [u8]) -> Result<usize, FileError> {
+        let node = self.node_of(file)?;
+        if node.is_dir() {
+            return Err(FileError::IsADirectory)
+        }
+        Ok(FAT::read_at(self, &node, offset, buf)?)
+    }
+
+    fn write_at(&mut self, file: FileHandle, offset: u64, buf: &[u8]) -> Result<usize, FileError> {
+        let mut node = self.node_of(file)?;
+        Ok(FAT::write_at(self, &mut node, offset, buf)?)
+    }
+
+    fn stat(&self, file: FileHandle) -> Result<Metadata, FileError> {
+        let node = self.node_of(file)?;
+        let (file_type, perm) = match node.is_dir() {
+            true => (FileType::Directory, 0o755),
+            false => (FileType::File, 0o644)
+        };
+        Ok(Metadata {
+            file_type,
+            size: self.size_of(&node)?,
+            perm: if node.is_read_only() { perm & !0o222 } else { perm },
+            links: 1,
+            ino: file.0
+        })
+    }
+
+    /// Files are at most 4GiB - 1 on FAT
+    fn truncate(&mut self, file: FileHandle, size: u64) -> Result<(), FileError> {
+        if size > u32::MAX as u64 {
+            return Err(FileError::NoSpace)
+        }
+        let mut node = self.node_of(file)?;
+        Ok(self.set_size(&mut node, size as u32)?)
+    }
+
+    fn readdir(&self, dir: FileHandle, f: &mut dyn FnMut(&DirEntry)) -> Result<(), FileError> {
+        let dir = self.node_of(dir)?;
+        if !dir.is_dir() {
+            return Err(FileError::NotADirectory)
+        }
+        Ok(self.read_dir(&dir, |entry| {
+            let file_type = if entry.node.is_dir() { FileType::Directory } else { FileType::File };
+            let mut dirent = DirEntry::new(file_type, self.handle_of(&entry.node).0);
+            let mut utf8 = [0_u8; 4];
+            for c in entry.name.chars() {
+                dirent.push(c.encode_utf8(&mut utf8).as_bytes());
+            }
+            f(&dirent);
+        })?)
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), FileError> {
+        let (parent, name) = split_path(path)?;
+        let dir = FAT::open(self, parent)?;
+        FAT::mkdir(self, &dir, name)?;
+        Ok(())
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FileError> {
+        let (parent, name) = split_path(path)?;
+        let dir = FAT::open(self, parent)?;
+        Ok(self.remove(&dir, name)?)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FileError> {
+        let (parent, name) = split_path(from)?;
+        let (new_parent, new_name) = split_path(to)?;
+        let dir = FAT::open(self, parent)?;
+        let new_dir = FAT::open(self, new_parent)?;
+        Ok(FAT::rename(self, &dir, name, &new_dir, new_name)?)
+    }
+
+    fn sync(&mut self) -> Result<(), FileError> {
+        Ok(self.flush()?)
+    }
+}
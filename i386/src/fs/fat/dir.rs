@@ -0,0 +1,595 @@
+//! Directories of the FAT file system. A directory is an array of 32 byte entries,
+//! each file has an 8.3 short name entry, which may be preceded by VFAT long name
+//! entries holding 13 UTF-16 characters each, in reverse order.
+//! See https://wiki.osdev.org/FAT#Directories
+
+use core::{char::decode_utf16, fmt};
+use super::FAT;
+use crate::{driver::disk::block::BlockDevice, fs::FSError};
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// the attributes of a long name entry
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// the size of a directory entry
+pub(super) const ENTRY_SIZE: u64 = 32;
+/// the first byte of a free entry, all following entries are free as well
+const ENTRY_END: u8 = 0x00;
+/// the first byte of a deleted entry
+const ENTRY_FREE: u8 = 0xe5;
+/// a short name starting with 0xe5 is stored as 0x05
+const ENTRY_E5: u8 = 0x05;
+
+/// NTRES bits used by Windows NT for short names in lower case
+const NTRES_LOWER_BASE: u8 = 0x08;
+const NTRES_LOWER_EXT: u8 = 0x10;
+
+/// the sequence number of the last long name entry (stored first) is or-ed with this
+const LFN_LAST: u8 = 0x40;
+/// the characters in each long name entry
+const LFN_CHARS: usize = 13;
+/// the byte offsets of the characters in a long name entry
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// The max length of a long name in UTF-16 code units
+pub const NAME_MAX: usize = 255;
+/// the long name entries needed by the longest name
+const LFN_MAX_ENTRIES: usize = (NAME_MAX + LFN_CHARS - 1) / LFN_CHARS;
+
+/// characters not allowed in long names, besides control characters
+const INVALID_CHARS: &str = "\"*/:<>?\\|";
+
+/// 1980-01-01, we have no real time clock yet
+const DEFAULT_DATE: u16 = (0 << 9) | (1 << 5) | 1;
+
+/// The name of a directory entry, which is its long name if it has one
+#[derive(Clone, Copy)]
+pub struct FATName {
+    chars: [u16; NAME_MAX],
+    len: usize
+}
+
+impl FATName {
+    const fn empty() -> Self {
+        Self { chars: [0; NAME_MAX], len: 0 }
+    }
+
+    fn push(&mut self, c: u16) {
+        if self.len < NAME_MAX {
+            self.chars[self.len] = c;
+            self.len += 1;
+        }
+    }
+
+    /// Build the name from a short name entry, like `KERNEL  BIN` => `KERNEL.BIN`
+    fn from_short(raw: &[u8], ntres: u8) -> Self {
+        let mut name = Self::empty();
+        let lower = |c: u8, bit: u8| if ntres & bit != 0 { c.to_ascii_lowercase() } else { c };
+        for (i, &c) in raw[..8].iter().enumerate().filter(|&(_, &c)| c != b' ') {
+            let c = if i == 0 && c == ENTRY_E5 { ENTRY_FREE } else { c };
+            name.push(lower(c, NTRES_LOWER_BASE) as u16);
+        }
+        if raw[8..11].iter().any(|&c| c != b' ') {
+            name.push(b'.' as u16);
+            raw[8..11].iter().filter(|&&c| c != b' ')
+                .for_each(|&c| name.push(lower(c, NTRES_LOWER_EXT) as u16));
+        }
+        name
+    }
+
+    pub fn as_utf16(&self) -> &[u16] {
+        &self.chars[..self.len]
+    }
+
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        decode_utf16(self.as_utf16().iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    /// FAT names are case insensitive, we only fold ASCII letters
+    pub fn eq_ignore_case(&self, name: &str) -> bool {
+        let mut chars = self.chars();
+        name.chars().all(|c| chars.next().map_or(false, |d| c.eq_ignore_ascii_case(&d)))
+            && chars.next().is_none()
+    }
+}
+
+impl fmt::Display for FATName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.chars().try_for_each(|c| fmt::Write::write_char(f, c))
+    }
+}
+
+/// A file or directory. The first cluster and the size are also stored in its
+/// directory entry, which is updated when the file changes.
+#[derive(Clone, Copy)]
+pub struct FATNode {
+    /// the first cluster, 0 for an empty file and the fixed root directory
+    pub cluster: u32,
+    /// the size in bytes, always 0 for directories
+    pub size: u32,
+    pub attr: u8,
+    /// the byte offset of the short name entry on the device, None for the
+    /// root directory and files which are not linked into a directory yet
+    pub(super) location: Option<u64>
+}
+
+impl FATNode {
+    pub(super) const fn new(cluster: u32, size: u32, attr: u8) -> Self {
+        Self { cluster, size, attr, location: None }
+    }
+
+    /// The node whose short name entry is at `location`, it is read with `reload`
+    pub(super) const fn at(location: u64) -> Self {
+        Self { cluster: 0, size: 0, attr: 0, location: Some(location) }
+    }
+
+    pub(super) const fn root(cluster: u32) -> Self {
+        Self::new(cluster, 0, ATTR_DIRECTORY)
+    }
+
+    /// The first cluster must be 0 or a cluster of the volume
+    fn parse<D: BlockDevice>(fs: &FAT<D>, raw: &[u8], location: u64) -> Result<Self, FSError<D::Error>> {
+        let u16_at = |i: usize| u16::from_le_bytes([raw[i], raw[i + 1]]) as u32;
+        let node = Self {
+            cluster: u16_at(20) << 16 | u16_at(26),
+            size: u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]),
+            attr: raw[11],
+            location: Some(location)
+        };
+        if node.cluster != 0 && !fs.is_valid_cluster(node.cluster) {
+            return Err(FSError::InvalidFileSystem)
+        }
+        Ok(node)
+    }
+
+    pub const fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    pub const fn is_read_only(&self) -> bool {
+        self.attr & ATTR_READ_ONLY != 0
+    }
+}
+
+/// An entry returned by `FAT::read_dir`
+#[derive(Clone, Copy)]
+pub struct FATDirEntry {
+    pub name: FATName,
+    pub node: FATNode,
+    /// the raw 8.3 name
+    short: [u8; 11]
+}
+
+impl FATDirEntry {
+    /// The 8.3 name, which is an alias of the long name
+    pub fn short_name(&self) -> FATName {
+        FATName::from_short(&self.short, 0)
+    }
+
+    /// Returns true for the `.` and `..` entries
+    pub(super) fn is_dot(&self) -> bool {
+        self.short[0] == b'.'
+    }
+}
+
+/// The offsets of the entries of a file, long name entries first
+#[derive(Clone, Copy)]
+pub(super) struct Slots {
+    offsets: [u64; LFN_MAX_ENTRIES + 1],
+    len: usize
+}
+
+impl Slots {
+    const fn new() -> Self {
+        Self { offsets: [0; LFN_MAX_ENTRIES + 1], len: 0 }
+    }
+
+    fn push(&mut self, offset: u64) {
+        self.offsets[self.len] = offset;
+        self.len += 1;
+    }
+
+    pub(super) fn as_slice(&self) -> &[u64] {
+        &self.offsets[..self.len]
+    }
+}
+
+/// Walks the entries of a directory, following its cluster chain
+struct DirCursor {
+    /// the current cluster, 0 in the fixed root directory
+    cluster: u32,
+    /// the index of the next entry in the cluster or the fixed root directory
+    index: u64
+}
+
+impl DirCursor {
+    const fn new(dir: &FATNode) -> Self {
+        Self { cluster: dir.cluster, index: 0 }
+    }
+
+    /// The device offset of the next entry, None at the end of the directory.
+    /// Calling it again after the directory has been extended continues there.
+    fn next<D: BlockDevice>(&mut self, fs: &FAT<D>) -> Result<Option<u64>, FSError<D::Error>> {
+        if self.cluster == 0 {
+            if self.index >= fs.bpb.root_entries as u64 {
+                return Ok(None)
+            }
+            self.index += 1;
+            return Ok(Some(fs.root_start + (self.index - 1) * ENTRY_SIZE))
+        }
+
+        if self.index == fs.cluster_size as u64 / ENTRY_SIZE {
+            match fs.next_cluster(self.cluster)? {
+                Some(next) => {
+                    self.cluster = next;
+                    self.index = 0;
+                },
+                None => return Ok(None)
+            }
+        }
+        self.index += 1;
+        Ok(Some(fs.cluster_offset(self.cluster) + (self.index - 1) * ENTRY_SIZE))
+    }
+}
+
+/// The checksum of a short name, stored in its long name entries
+fn checksum(short: &[u8]) -> u8 {
+    short[..11].iter().fold(0_u8, |sum, &c| (sum >> 1 | sum << 7).wrapping_add(c))
+}
+
+/// Call `f` with every entry of `dir` except volume labels, along with the offsets of
+/// its slots, until `f` returns something.
+pub(super) fn scan<D: BlockDevice, T>(
+    fs: &FAT<D>, dir: &FATNode,
+    mut f: impl FnMut(&FATDirEntry, &Slots) -> Option<T>
+) -> Result<Option<T>, FSError<D::Error>> {
+    if !dir.is_dir() {
+        return Err(FSError::NotADirectory)
+    }
+
+    let mut cursor = DirCursor::new(dir);
+    let mut long = [0_u16; LFN_MAX_ENTRIES * LFN_CHARS];
+    // the sequence number of the next long name entry, Some(0) if the name is complete
+    let mut expect: Option<u8> = None;
+    let mut sum = 0;
+    let mut slots = Slots::new();
+    let mut raw = [0_u8; ENTRY_SIZE as usize];
+
+    while let Some(offset) = cursor.next(fs)? {
+        fs.read_exact(offset, &mut raw)?;
+        match raw[0] {
+            ENTRY_END => break,
+            ENTRY_FREE => {
+                expect = None;
+                continue
+            },
+            _ => ()
+        }
+
+        if raw[11] & 0x3f == ATTR_LONG_NAME {
+            let seq = raw[0] & !LFN_LAST;
+            if raw[0] & LFN_LAST != 0 {
+                // the first entry of a new long name
+                expect = Some(seq);
+                sum = raw[13];
+                slots = Slots::new();
+                long.fill(0xffff);
+            }
+            match expect {
+                Some(e) if e == seq && seq != 0 && seq as usize <= LFN_MAX_ENTRIES && raw[13] == sum => {
+                    let start = (seq as usize - 1) * LFN_CHARS;
+                    for (i, &at) in LFN_OFFSETS.iter().enumerate() {
+                        long[start + i] = u16::from_le_bytes([raw[at], raw[at + 1]]);
+                    }
+                    slots.push(offset);
+                    expect = Some(seq - 1);
+                },
+                _ => expect = None
+            }
+            continue
+        }
+
+        if raw[11] & ATTR_VOLUME_ID != 0 {
+            expect = None;
+            continue
+        }
+
+        let mut short = [0_u8; 11];
+        short.copy_from_slice(&raw[..11]);
+        let name = if expect == Some(0) && checksum(&short) == sum {
+            let mut name = FATName::empty();
+            long.iter().take_while(|&&c| c != 0 && c != 0xffff).for_each(|&c| name.push(c));
+            name
+        } else {
+            slots = Slots::new();
+            FATName::from_short(&short, raw[12])
+        };
+        expect = None;
+
+        let mut node = FATNode::parse(fs, &raw, offset)?;
+        if node.is_dir() && node.cluster == 0 {
+            // `..` of a directory in the root directory
+            node = fs.root();
+        }
+        slots.push(offset);
+        if let Some(result) = f(&FATDirEntry { name, node, short }, &slots) {
+            return Ok(Some(result))
+        }
+        slots = Slots::new();
+    }
+    Ok(None)
+}
+
+/// Find the entry called `name` in `dir`, matching both long and short names
+pub(super) fn find<D: BlockDevice>(fs: &FAT<D>, dir: &FATNode, name: &str) -> Result<(FATDirEntry, Slots), FSError<D::Error>> {
+    if name.is_empty() {
+        return Err(FSError::InvalidName)
+    }
+    let dir = fs.refresh(dir)?;
+    scan(fs, &dir, |entry, slots| {
+        if entry.name.eq_ignore_case(name) || entry.short_name().eq_ignore_case(name) {
+            Some((*entry, *slots))
+        } else {
+            None
+        }
+    })?.ok_or(FSError::FileNotFound)
+}
+
+/// Returns true if `dir` only contains `.` and `..`
+pub(super) fn is_empty<D: BlockDevice>(fs: &FAT<D>, dir: &FATNode) -> Result<bool, FSError<D::Error>> {
+    Ok(scan(fs, dir, |entry, _| if entry.is_dot() { None } else { Some(()) })?.is_none())
+}
+
+/// Read the entry of a linked node again
+pub(super) fn reload<D: BlockDevice>(fs: &FAT<D>, node: &FATNode) -> Result<FATNode, FSError<D::Error>> {
+    let location = match node.location {
+        Some(location) => location,
+        None => return Ok(*node)
+    };
+    let mut raw = [0_u8; ENTRY_SIZE as usize];
+    fs.read_exact(location, &mut raw)?;
+    if raw[0] == ENTRY_END || raw[0] == ENTRY_FREE {
+        return Err(FSError::FileNotFound)
+    }
+    FATNode::parse(fs, &raw, location)
+}
+
+/// Write the first cluster and the size of `node` to its entry
+pub(super) fn update_entry<D: BlockDevice>(fs: &mut FAT<D>, node: &FATNode) -> Result<(), FSError<D::Error>> {
+    let location = match node.location {
+        Some(location) => location,
+        None => return Ok(())
+    };
+    let mut raw = [0_u8; ENTRY_SIZE as usize];
+    fs.read_exact(location, &mut raw)?;
+    set_cluster(&mut raw, node.cluster);
+    raw[28..32].copy_from_slice(&node.size.to_le_bytes());
+    raw[24..26].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    raw[18..20].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    fs.write_exact(location, &raw)
+}
+
+/// Mark the entries at `slots` as deleted
+pub(super) fn free_slots<D: BlockDevice>(fs: &mut FAT<D>, slots: &[u64]) -> Result<(), FSError<D::Error>> {
+    for &offset in slots {
+        fs.write_exact(offset, &[ENTRY_FREE])?;
+    }
+    Ok(())
+}
+
+fn set_cluster(raw: &mut [u8], cluster: u32) {
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// A short name entry for `node`
+fn short_entry(short: &[u8; 11], node: &FATNode) -> [u8; ENTRY_SIZE as usize] {
+    let mut raw = [0_u8; ENTRY_SIZE as usize];
+    raw[..11].copy_from_slice(short);
+    raw[11] = node.attr;
+    for at in [16, 18, 24] {
+        raw[at..at + 2].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    }
+    set_cluster(&mut raw, node.cluster);
+    if !node.is_dir() {
+        raw[28..32].copy_from_slice(&node.size.to_le_bytes());
+    }
+    raw
+}
+
+/// Write the `.` and `..` entries of the new directory `dir` in `parent`
+pub(super) fn init_dir<D: BlockDevice>(fs: &mut FAT<D>, dir: &FATNode, parent: &FATNode) -> Result<(), FSError<D::Error>> {
+    let dot = short_entry(b".          ", dir);
+    fs.write_exact(fs.cluster_offset(dir.cluster), &dot)?;
+    set_parent(fs, dir, parent)
+}
+
+/// Point the `..` entry of the directory `dir` to `parent`
+pub(super) fn set_parent<D: BlockDevice>(fs: &mut FAT<D>, dir: &FATNode, parent: &FATNode) -> Result<(), FSError<D::Error>> {
+    // `..` points to cluster 0 if the parent is the root directory
+    let parent_cluster = if parent.cluster == fs.root_cluster() { 0 } else { parent.cluster };
+    let dotdot = short_entry(b"..         ", &FATNode::root(parent_cluster));
+    fs.write_exact(fs.cluster_offset(dir.cluster) + ENTRY_SIZE, &dotdot)
+}
+
+fn is_short_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// The short name entry of `name` if it is a valid upper case 8.3 name,
+/// which needs no long name entries
+fn exact_short(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.split_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, "")
+    };
+    let valid = (1..=8).contains(&base.len()) && ext.len() <= 3
+        && !(name.ends_with('.'))
+        && base.chars().chain(ext.chars()).all(is_short_char);
+    if !valid {
+        return None
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+/// The basis of a generated short name: the upper case base name and extension with
+/// invalid characters replaced by `_`. Returns the short name and the length of the base.
+fn short_basis(name: &str) -> ([u8; 11], usize) {
+    let name = name.trim_start_matches(|c| c == '.' || c == ' ');
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, "")
+    };
+    let convert = |c: char| match c.to_ascii_uppercase() {
+        c if is_short_char(c) => Some(c as u8),
+        ' ' | '.' => None,
+        _ => Some(b'_')
+    };
+
+    let mut short = [b' '; 11];
+    let mut len = 0;
+    for c in base.chars().filter_map(convert).take(8) {
+        short[len] = c;
+        len += 1;
+    }
+    for (i, c) in ext.chars().filter_map(convert).take(3).enumerate() {
+        short[8 + i] = c;
+    }
+    if len == 0 {
+        short[0] = b'_';
+        len = 1;
+    }
+    (short, len)
+}
+
+/// Add the numeric tail `~n` to a short name basis
+fn numeric_tail(basis: &[u8; 11], len: usize, n: u32) -> [u8; 11] {
+    let mut digits = [0_u8; 10];
+    let mut count = 0;
+    let mut rest = n;
+    loop {
+        digits[count] = b'0' + (rest % 10) as u8;
+        count += 1;
+        rest /= 10;
+        if rest == 0 {
+            break
+        }
+    }
+
+    let mut short = *basis;
+    let start = len.min(8 - count - 1);
+    short[start..8].fill(b' ');
+    short[start] = b'~';
+    for i in 0..count {
+        short[start + 1 + i] = digits[count - 1 - i];
+    }
+    short
+}
+
+/// Check a long name, and convert it to UTF-16
+fn encode_name(name: &str) -> Option<FATName> {
+    let valid = !name.is_empty() && name != "." && name != ".."
+        && !name.ends_with(|c| c == '.' || c == ' ')
+        && !name.chars().any(|c| c < ' ' || INVALID_CHARS.contains(c));
+    if !valid || name.encode_utf16().count() > NAME_MAX {
+        return None
+    }
+    let mut encoded = FATName::empty();
+    name.encode_utf16().for_each(|c| encoded.push(c));
+    Some(encoded)
+}
+
+/// Find `count` consecutive free entries in `dir`, which is extended by a cluster
+/// if there are not enough of them.
+fn alloc_slots<D: BlockDevice>(fs: &mut FAT<D>, dir: &FATNode, count: usize) -> Result<Slots, FSError<D::Error>> {
+    let mut cursor = DirCursor::new(dir);
+    let mut slots = Slots::new();
+    let mut first = [0_u8];
+    loop {
+        let offset = match cursor.next(fs)? {
+            Some(offset) => offset,
+            // the fixed root directory can not grow
+            None if dir.cluster == 0 => return Err(FSError::NoEnoughSpace),
+            None => {
+                fs.alloc_cluster(Some(cursor.cluster))?;
+                continue
+            }
+        };
+        fs.read_exact(offset, &mut first)?;
+        if first[0] == ENTRY_END || first[0] == ENTRY_FREE {
+            slots.push(offset);
+            if slots.len == count {
+                return Ok(slots)
+            }
+        } else {
+            slots = Slots::new();
+        }
+    }
+}
+
+/// Create the entries of `node` called `name` in `dir`, and update the location of `node`
+pub(super) fn link<D: BlockDevice>(fs: &mut FAT<D>, dir: &FATNode, name: &str, node: &mut FATNode) -> Result<(), FSError<D::Error>> {
+    let dir = fs.refresh(dir)?;
+    if !dir.is_dir() {
+        return Err(FSError::NotADirectory)
+    }
+    let long = encode_name(name).ok_or(FSError::InvalidName)?;
+    match find(fs, &dir, name) {
+        Ok(_) => return Err(FSError::AlreadyExists),
+        Err(FSError::FileNotFound) => (),
+        Err(e) => return Err(e)
+    }
+
+    let (short, lfn_count) = match exact_short(name) {
+        Some(short) => (short, 0),
+        None => {
+            let (basis, len) = short_basis(name);
+            let mut n = 1;
+            let short = loop {
+                let short = numeric_tail(&basis, len, n);
+                if scan(fs, &dir, |entry, _| if entry.short == short { Some(()) } else { None })?.is_none() {
+                    break short
+                }
+                n += 1;
+                if n > 999_999 {
+                    return Err(FSError::AlreadyExists)
+                }
+            };
+            (short, (long.len + LFN_CHARS - 1) / LFN_CHARS)
+        }
+    };
+
+    let slots = alloc_slots(fs, &dir, lfn_count + 1)?;
+    let sum = checksum(&short);
+    for (i, &offset) in slots.as_slice()[..lfn_count].iter().enumerate() {
+        let seq = (lfn_count - i) as u8;
+        let mut raw = [0_u8; ENTRY_SIZE as usize];
+        raw[0] = if i == 0 { seq | LFN_LAST } else { seq };
+        raw[11] = ATTR_LONG_NAME;
+        raw[13] = sum;
+        let start = (seq as usize - 1) * LFN_CHARS;
+        for (j, &at) in LFN_OFFSETS.iter().enumerate() {
+            // the name is terminated by 0 and padded with 0xffff
+            let c = match start + j {
+                k if k < long.len => long.chars[k],
+                k if k == long.len => 0,
+                _ => 0xffff
+            };
+            raw[at..at + 2].copy_from_slice(&c.to_le_bytes());
+        }
+        fs.write_exact(offset, &raw)?;
+    }
+
+    let location = slots.as_slice()[lfn_count];
+    fs.write_exact(location, &short_entry(&short, node))?;
+    node.location = Some(location);
+    Ok(())
+}
@@ -1,12 +1,119 @@
-//! The simple file system we use in our bootloader (and even in kernel currently), 
-//! which is no file system.
+//! The simple file system we use in our bootloader (and even in kernel currently),
+//! which is no file system. The whole disk is a single file called `disk` in the
+//! root directory, and images are read from their byte offsets in it.
 
-pub mod real;
+use crate::{
+    fs::{FileSystem, FileError, FileHandle, FileType, Metadata, DirEntry},
+    driver::disk::block::{BlockDevice, ByteAdapter, DeviceError}
+};
 
-#[cfg(feature = "alloc")]
-pub mod protected;
+/// The name of the only file
+pub const FILE_NAME: &str = "disk";
 
-/// The sector LBA for the file
-type NoFSIdent = u64;
+const ROOT: FileHandle = FileHandle(0);
+const FILE: FileHandle = FileHandle(1);
 
+/// The nofs driver. It works with any block device, e.g. BIOS disk services
+/// in real mode and ATA / AHCI disks in protected mode.
+pub struct NoFS<D: BlockDevice> {
+    dev: ByteAdapter<D>
+}
 
+impl<D: BlockDevice> NoFS<D> {
+    pub fn new(dev: D) -> Self {
+        Self { dev: ByteAdapter::new(dev) }
+    }
+
+    pub fn device(&self) -> &D {
+        self.dev.inner()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev.into_inner()
+    }
+
+    fn check_file(&self, file: FileHandle) -> Result<(), FileError> {
+        match file {
+            FILE => Ok(()),
+            ROOT => Err(FileError::IsADirectory),
+            _ => Err(FileError::BadHandle)
+        }
+    }
+}
+
+impl<D: BlockDevice> FileSystem for NoFS<D> {
+    fn open(&self, path: &str) -> Result<FileHandle, FileError> {
+        match path.trim_matches('/') {
+            "" | "." => Ok(ROOT),
+            FILE_NAME => Ok(FILE),
+            _ => Err(FileError::NotFound)
+        }
+    }
+
+    fn create(&mut self, path: &str) -> Result<FileHandle, FileError> {
+        match self.open(path) {
+            Ok(_) => Err(FileError::AlreadyExists),
+            Err(_) => Err(FileError::NotSupported)
+        }
+    }
+
+    /// Read from the byte `offset` of the disk, the read stops at the end of the disk.
+    /// Partial sectors at either end go through the bounce sector of `ByteAdapter`,
+    /// so any offset and length can be read.
+    fn read_at(&self, file: FileHandle, offset: u64, buf: &mut [u8]) -> Result<usize, FileError> {
+        self.check_file(file)?;
+        self.dev.read(offset, buf).map_err(|e| FileError::Io(e.status()))
+    }
+
+    /// Write to the byte `offset` of the disk, the disk can not grow.
+    fn write_at(&mut self, file: FileHandle, offset: u64, buf: &[u8]) -> Result<usize, FileError> {
+        self.check_file(file)?;
+        if offset.saturating_add(buf.len() as u64) > self.dev.size() {
+            return Err(FileError::NoSpace)
+        }
+        self.dev.write(offset, buf).map_err(|e| FileError::Io(e.status()))
+    }
+
+    fn stat(&self, file: FileHandle) -> Result<Metadata, FileError> {
+        let (file_type, size, perm) = match file {
+            ROOT => (FileType::Directory, 0, 0o555),
+            FILE => (FileType::File, self.dev.size(), 0o644),
+            _ => return Err(FileError::BadHandle)
+        };
+        Ok(Metadata { file_type, size, perm, links: 1, ino: file.0 })
+    }
+
+    fn truncate(&mut self, file: FileHandle, _size: u64) -> Result<(), FileError> {
+        self.check_file(file)?;
+        Err(FileError::NotSupported)
+    }
+
+    fn readdir(&self, dir: FileHandle, f: &mut dyn FnMut(&DirEntry)) -> Result<(), FileError> {
+        match dir {
+            ROOT => {
+                let mut entry = DirEntry::new(FileType::File, FILE.0);
+                entry.push(FILE_NAME.as_bytes());
+                f(&entry);
+                Ok(())
+            },
+            FILE => Err(FileError::NotADirectory),
+            _ => Err(FileError::BadHandle)
+        }
+    }
+
+    fn mkdir(&mut self, _path: &str) -> Result<(), FileError> {
+        Err(FileError::NotSupported)
+    }
+
+    fn unlink(&mut self, _path: &str) -> Result<(), FileError> {
+        Err(FileError::NotSupported)
+    }
+
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), FileError> {
+        Err(FileError::NotSupported)
+    }
+
+    fn sync(&mut self) -> Result<(), FileError> {
+        self.dev.flush().map_err(|e| FileError::Io(e.status()))
+    }
+}
@@ -0,0 +1,104 @@
+//! Partition tables, which split a disk into several block devices.
+
+pub mod mbr;
+pub mod gpt;
+
+use core::fmt;
+use crate::driver::disk::block::{BlockDevice, DeviceError};
+
+#[derive(Debug)]
+pub enum PartitionError<E> {
+    /// the block device does not use 512 bytes sectors
+    BlockSize,
+    /// there is no valid partition table on the disk
+    InvalidTable,
+    /// the CRC32 of the partition table does not match
+    Checksum,
+    /// the transfer exceeds the end of the partition
+    OutOfRange,
+    DiskError(E)
+}
+
+impl<E> From<E> for PartitionError<E> {
+    fn from(e: E) -> Self {
+        Self::DiskError(e)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for PartitionError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BlockSize => write!(f, "Partition Error: block size is not 512 bytes"),
+            Self::InvalidTable => write!(f, "Partition Error: invalid partition table"),
+            Self::Checksum => write!(f, "Partition Error: checksum mismatch"),
+            Self::OutOfRange => write!(f, "Partition Error: out of range"),
+            Self::DiskError(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl<E: DeviceError> DeviceError for PartitionError<E> {
+    fn status(&self) -> u8 {
+        match self {
+            Self::DiskError(e) => e.status(),
+            _ => 0
+        }
+    }
+}
+
+/// A partition exposed as a block device, LBA 0 is the first block of the partition.
+pub struct PartitionDevice<D: BlockDevice> {
+    dev: D,
+    start: u64,
+    count: u64
+}
+
+impl<D: BlockDevice> PartitionDevice<D> {
+    /// `start` and `count` are in blocks of the underlying device
+    pub const fn new(dev: D, start: u64, count: u64) -> Self {
+        Self { dev, start, count }
+    }
+
+    pub const fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+
+    /// Translate a partition relative LBA, the whole transfer must lie in the partition
+    fn translate(&self, lba: u64, len: usize) -> Result<u64, PartitionError<D::Error>> {
+        let blocks = (len / self.dev.block_size()) as u64;
+        if lba.checked_add(blocks).map_or(true, |end| end > self.count) {
+            return Err(PartitionError::OutOfRange)
+        }
+        Ok(self.start + lba)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for PartitionDevice<D> {
+    type Error = PartitionError<D::Error>;
+
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let lba = self.translate(lba, buf.len())?;
+        Ok(self.dev.read_blocks(lba, buf)?)
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), Self::Error> {
+        let lba = self.translate(lba, buf.len())?;
+        Ok(self.dev.write_blocks(lba, buf)?)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(self.dev.flush()?)
+    }
+}
@@ -0,0 +1,319 @@
+//! The GUID partition table. The primary header is at LBA 1 and is followed by
+//! the partition entry array, a backup of both is kept at the end of the disk.
+//! LBA 0 holds a protective MBR, so legacy tools see the disk as fully used.
+//! See https://wiki.osdev.org/GPT and UEFI Specification 2.9, chapter 5
+
+use core::{char::decode_utf16, fmt};
+use crate::{
+    driver::disk::block::{BlockDevice, MAX_BLOCK_SIZE},
+    utils::crc32::Crc32
+};
+use super::{PartitionDevice, PartitionError, mbr};
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+/// the size of the header defined in the specification
+const MIN_HEADER_SIZE: usize = 92;
+/// the smallest partition entry, entries must be 128 * 2^n bytes
+const MIN_ENTRY_SIZE: usize = 128;
+/// the largest entry array we read, tools create 128 entries
+const MAX_ENTRIES: u32 = 1024;
+/// UTF-16 code units in the name of a partition
+const NAME_LEN: usize = 36;
+
+/// The max number of partitions we keep, entries after this are ignored
+pub const GPT_MAX_PARTITIONS: usize = 32;
+
+/// A GUID as stored on disk: the first three fields are little endian
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct GUID(pub [u8; 16]);
+
+impl GUID {
+    pub const fn new(d1: u32, d2: u16, d3: u16, d4: [u8; 8]) -> Self {
+        let a = d1.to_le_bytes();
+        let b = d2.to_le_bytes();
+        let c = d3.to_le_bytes();
+        Self([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1],
+            d4[0], d4[1], d4[2], d4[3], d4[4], d4[5], d4[6], d4[7]
+        ])
+    }
+
+    const fn from_slice(bytes: &[u8]) -> Self {
+        let mut guid = [0; 16];
+        let mut i = 0;
+        while i < 16 {
+            guid[i] = bytes[i];
+            i += 1;
+        }
+        Self(guid)
+    }
+
+    pub const fn is_zero(&self) -> bool {
+        u128::from_le_bytes(self.0) == 0
+    }
+}
+
+impl fmt::Display for GUID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let g = &self.0;
+        write!(f, "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]]),
+            g[8], g[9])?;
+        g[10..].iter().try_for_each(|b| write!(f, "{:02X}", b))
+    }
+}
+
+pub const GUID_EFI_SYSTEM: GUID = GUID::new(0xc12a7328, 0xf81f, 0x11d2, [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b]);
+pub const GUID_BIOS_BOOT: GUID = GUID::new(0x21686148, 0x6449, 0x6e6f, [0x74, 0x4e, 0x65, 0x65, 0x64, 0x45, 0x46, 0x49]);
+pub const GUID_BASIC_DATA: GUID = GUID::new(0xebd0a0a2, 0xb9e5, 0x4433, [0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7]);
+pub const GUID_LINUX_FS: GUID = GUID::new(0x0fc63daf, 0x8483, 0x4772, [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4]);
+pub const GUID_LINUX_SWAP: GUID = GUID::new(0x0657fd6d, 0xa4ab, 0x43c4, [0x84, 0xe5, 0x09, 0x33, 0xc8, 0x4b, 0x4f, 0x4f]);
+
+/// The name of a partition, UTF-16LE padded with zeros
+#[derive(Clone, Copy)]
+pub struct GPTName([u16; NAME_LEN]);
+
+impl GPTName {
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        let len = self.0.iter().position(|&c| c == 0).unwrap_or(NAME_LEN);
+        decode_utf16(self.0[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+impl fmt::Display for GPTName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.chars().try_for_each(|c| fmt::Write::write_char(f, c))
+    }
+}
+
+/// An entry of the partition entry array
+#[derive(Clone, Copy)]
+pub struct GPTPartition {
+    /// the index in the partition entry array, starting from 1
+    pub number: u32,
+    pub type_guid: GUID,
+    pub unique_guid: GUID,
+    pub start_lba: u64,
+    /// the last LBA of the partition (inclusive)
+    pub end_lba: u64,
+    pub attributes: u64,
+    pub name: GPTName
+}
+
+impl GPTPartition {
+    fn parse(number: u32, entry: &[u8]) -> Self {
+        let u64_at = |i: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&entry[i..i + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let mut name = [0_u16; NAME_LEN];
+        name.iter_mut().zip(entry[56..56 + NAME_LEN * 2].chunks(2))
+            .for_each(|(c, b)| *c = u16::from_le_bytes([b[0], b[1]]));
+
+        Self {
+            number,
+            type_guid: GUID::from_slice(&entry[0..16]),
+            unique_guid: GUID::from_slice(&entry[16..32]),
+            start_lba: u64_at(32),
+            end_lba: u64_at(40),
+            attributes: u64_at(48),
+            name: GPTName(name)
+        }
+    }
+
+    /// 0 if the entry ends before it starts
+    pub const fn sectors(&self) -> u64 {
+        match self.end_lba.checked_sub(self.start_lba) {
+            Some(last) => last.saturating_add(1),
+            None => 0
+        }
+    }
+
+    /// Expose this partition as a block device on `dev`
+    pub fn device<D: BlockDevice>(&self, dev: D) -> PartitionDevice<D> {
+        PartitionDevice::new(dev, self.start_lba, self.sectors())
+    }
+}
+
+impl Into<&'static str> for GPTPartition {
+    fn into(self) -> &'static str {
+        match self.type_guid {
+            GUID_EFI_SYSTEM => "EFI System",
+            GUID_BIOS_BOOT => "BIOS Boot",
+            GUID_BASIC_DATA => "Basic Data",
+            GUID_LINUX_FS => "Linux",
+            GUID_LINUX_SWAP => "Linux Swap",
+            _ => "Unknown"
+        }
+    }
+}
+
+/// The fields of a GPT header we use
+#[derive(Clone, Copy)]
+pub struct GPTHeader {
+    /// the LBA of this header
+    pub my_lba: u64,
+    /// the LBA of the other header
+    pub alternate_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: GUID,
+    pub entries_lba: u64,
+    pub entry_num: u32,
+    pub entry_size: u32,
+    entries_crc32: u32
+}
+
+impl GPTHeader {
+    /// Parse and verify the header in `block`, which is read from `lba`
+    fn parse(block: &[u8], lba: u64) -> Option<Self> {
+        let u32_at = |i: usize| u32::from_le_bytes([block[i], block[i + 1], block[i + 2], block[i + 3]]);
+        let u64_at = |i: usize| u32_at(i) as u64 | (u32_at(i + 4) as u64) << 32;
+
+        if &block[0..8] != SIGNATURE {
+            return None
+        }
+        let header_size = u32_at(12) as usize;
+        if header_size < MIN_HEADER_SIZE || header_size > block.len() {
+            return None
+        }
+        // the CRC is calculated with the CRC field itself zeroed
+        let mut crc = Crc32::new();
+        crc.update(&block[..16]);
+        crc.update(&[0; 4]);
+        crc.update(&block[20..header_size]);
+        if crc.finish() != u32_at(16) {
+            return None
+        }
+
+        let header = Self {
+            my_lba: u64_at(24),
+            alternate_lba: u64_at(32),
+            first_usable_lba: u64_at(40),
+            last_usable_lba: u64_at(48),
+            disk_guid: GUID::from_slice(&block[56..72]),
+            entries_lba: u64_at(72),
+            entry_num: u32_at(80),
+            entry_size: u32_at(84),
+            entries_crc32: u32_at(88)
+        };
+        let entry_size = header.entry_size as usize;
+        if header.my_lba != lba || entry_size < MIN_ENTRY_SIZE || !entry_size.is_power_of_two()
+            || header.entry_num > MAX_ENTRIES {
+            return None
+        }
+        Some(header)
+    }
+}
+
+/// The partitions found on a GPT disk
+pub struct GPTable {
+    pub header: GPTHeader,
+    /// true if the primary header or entries are damaged and the backup is used
+    pub from_backup: bool,
+    partitions: [Option<GPTPartition>; GPT_MAX_PARTITIONS],
+    len: usize
+}
+
+impl GPTable {
+    pub fn get_partitions(&self) -> &[Option<GPTPartition>] {
+        &self.partitions[..self.len]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &GPTPartition> {
+        self.get_partitions().iter().flatten()
+    }
+
+    /// Find a partition by its index in the entry array
+    pub fn get(&self, number: u32) -> Option<GPTPartition> {
+        self.iter().find(|part| part.number == number).copied()
+    }
+
+    /// Read the header at `lba` and its partition entries, the entries are
+    /// verified with the CRC in the header.
+    fn read_at<D: BlockDevice>(dev: &D, lba: u64, from_backup: bool) -> Result<Self, PartitionError<D::Error>> {
+        let bs = dev.block_size();
+        let mut block = [0_u8; MAX_BLOCK_SIZE];
+        let block = &mut block[..bs];
+
+        dev.read_blocks(lba, block)?;
+        let header = GPTHeader::parse(block, lba)
+            .ok_or(PartitionError::InvalidTable)?;
+        // both are powers of two, so entries never cross blocks
+        if header.entry_size as usize > bs {
+            return Err(PartitionError::InvalidTable)
+        }
+        if header.first_usable_lba > header.last_usable_lba || header.last_usable_lba >= dev.block_count() {
+            return Err(PartitionError::InvalidTable)
+        }
+
+        let mut table = Self {
+            header,
+            from_backup,
+            partitions: [None; GPT_MAX_PARTITIONS],
+            len: 0
+        };
+
+        // the entry array may be larger than our table, so it is streamed
+        // block by block through the CRC
+        let entry_size = header.entry_size as usize;
+        let total = (header.entry_num as usize).checked_mul(entry_size)
+            .ok_or(PartitionError::InvalidTable)?;
+        let mut crc = Crc32::new();
+        let mut done = 0;
+        let mut entries_lba = header.entries_lba;
+        while done < total {
+            dev.read_blocks(entries_lba, block)?;
+            let len = (total - done).min(bs);
+            crc.update(&block[..len]);
+
+            for entry in block[..len].chunks_exact(entry_size) {
+                done += entry_size;
+                let part = GPTPartition::parse((done / entry_size) as u32, entry);
+                if part.type_guid.is_zero() || table.len == GPT_MAX_PARTITIONS {
+                    continue
+                }
+                // a damaged entry would expose blocks outside the usable area, or beyond the disk
+                if part.end_lba < part.start_lba || part.start_lba < header.first_usable_lba
+                    || part.end_lba > header.last_usable_lba {
+                    continue
+                }
+                table.partitions[table.len] = Some(part);
+                table.len += 1;
+            }
+            entries_lba += 1;
+        }
+
+        if crc.finish() != header.entries_crc32 {
+            return Err(PartitionError::Checksum)
+        }
+        Ok(table)
+    }
+
+    /// Read the GPT of a disk. The primary header at LBA 1 is tried first, the
+    /// backup header at the last LBA is used if the primary one is damaged.
+    pub fn read<D: BlockDevice>(dev: &D) -> Result<Self, PartitionError<D::Error>> {
+        if dev.block_size() > MAX_BLOCK_SIZE {
+            return Err(PartitionError::BlockSize)
+        }
+        match Self::read_at(dev, 1, false) {
+            Ok(table) => Ok(table),
+            Err(PartitionError::InvalidTable) | Err(PartitionError::Checksum) => {
+                Self::read_at(dev, dev.block_count().saturating_sub(1), true)
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Check the protective MBR before reading the GPT
+    pub fn detect<D: BlockDevice>(dev: &D) -> Result<Self, PartitionError<D::Error>> {
+        if !mbr::PartitionTable::read(dev)?.is_protective() {
+            return Err(PartitionError::InvalidTable)
+        }
+        Self::read(dev)
+    }
+}
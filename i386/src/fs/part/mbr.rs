@@ -0,0 +1,194 @@
+//! The MBR partition table, with four primary partitions in the boot sector.
+//! One of them can be an extended partition, which holds a chain of EBRs
+//! describing logical partitions.
+//! See https://wiki.osdev.org/Partition_Table and
+//! https://en.wikipedia.org/wiki/Extended_boot_record
+
+use crate::{
+    driver::disk::block::BlockDevice,
+    utils::disk::SECTOR_SIZE
+};
+use super::{PartitionDevice, PartitionError};
+
+/// the partition table starts at byte 446 of the MBR and EBRs
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const PRIMARY_ENTRIES: usize = 4;
+/// bytes 510 and 511 of the MBR and EBRs
+const SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// the status byte of an active (bootable) partition
+const STATUS_ACTIVE: u8 = 0x80;
+const STATUS_INACTIVE: u8 = 0x00;
+
+/// partition type of unused entries
+const TYPE_EMPTY: u8 = 0x00;
+/// partition type of a protective MBR in front of a GPT
+pub const TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+/// The max number of partitions we keep, logical partitions after this are ignored
+pub const MBR_MAX_PARTITIONS: usize = 16;
+
+/// An entry of the partition table. Logical partitions have been made
+/// relative to the start of the disk.
+#[derive(Clone, Copy)]
+pub struct MBRPartition {
+    /// 1-4 for primary partitions, logical partitions start from 5
+    pub number: u8,
+    pub bootable: bool,
+    /// the system id, e.g. 0x83 for Linux and 0x0c for FAT32 (LBA)
+    pub ty: u8,
+    pub start_lba: u64,
+    pub sectors: u64
+}
+
+impl MBRPartition {
+    /// The extended partition types of DOS (0x05), Windows (0x0f) and Linux (0x85)
+    pub const fn is_extended(&self) -> bool {
+        matches!(self.ty, 0x05 | 0x0f | 0x85)
+    }
+
+    /// Expose this partition as a block device on `dev`
+    pub fn device<D: BlockDevice>(&self, dev: D) -> PartitionDevice<D> {
+        PartitionDevice::new(dev, self.start_lba, self.sectors)
+    }
+}
+
+impl Into<&'static str> for MBRPartition {
+    fn into(self) -> &'static str {
+        match self.ty {
+            0x01 => "FAT12",
+            0x04 | 0x06 | 0x0e => "FAT16",
+            0x0b | 0x0c => "FAT32",
+            0x07 => "NTFS",
+            0x05 | 0x0f | 0x85 => "Extended",
+            0x82 => "Linux Swap",
+            0x83 => "Linux",
+            TYPE_GPT_PROTECTIVE => "GPT",
+            _ => "Unknown"
+        }
+    }
+}
+
+/// Parse an entry, returns None for unused entries
+fn parse_entry(sector: &[u8; SECTOR_SIZE as usize], index: usize) -> Result<Option<MBRPartition>, ()> {
+    let entry = &sector[TABLE_OFFSET + index * ENTRY_SIZE..][..ENTRY_SIZE];
+    let ty = entry[4];
+    let start_lba = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as u64;
+    let sectors = u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) as u64;
+
+    // boot code which happens to end with the signature has random bytes here
+    if entry[0] != STATUS_ACTIVE && entry[0] != STATUS_INACTIVE {
+        return Err(())
+    }
+    if ty == TYPE_EMPTY || sectors == 0 {
+        return Ok(None)
+    }
+    Ok(Some(MBRPartition {
+        number: index as u8 + 1,
+        bootable: entry[0] == STATUS_ACTIVE,
+        ty,
+        start_lba,
+        sectors
+    }))
+}
+
+/// The partitions found on a disk, primary partitions come first.
+pub struct PartitionTable {
+    partitions: [Option<MBRPartition>; MBR_MAX_PARTITIONS],
+    len: usize
+}
+
+impl PartitionTable {
+    fn push(&mut self, part: MBRPartition) -> bool {
+        if self.len == MBR_MAX_PARTITIONS {
+            return false
+        }
+        self.partitions[self.len] = Some(part);
+        self.len += 1;
+        true
+    }
+
+    pub fn get_partitions(&self) -> &[Option<MBRPartition>] {
+        &self.partitions[..self.len]
+    }
+
+    /// Iterate over all partitions, including the extended partition itself
+    pub fn iter(&self) -> impl Iterator<Item = &MBRPartition> {
+        self.get_partitions().iter().flatten()
+    }
+
+    /// Find a partition by its number
+    pub fn get(&self, number: u8) -> Option<MBRPartition> {
+        self.iter().find(|part| part.number == number).copied()
+    }
+
+    /// Returns true if the disk uses GPT, the MBR only protects it from legacy tools.
+    pub fn is_protective(&self) -> bool {
+        self.iter().any(|part| part.ty == TYPE_GPT_PROTECTIVE)
+    }
+
+    fn read_sector<D: BlockDevice>(dev: &D, lba: u64) -> Result<[u8; SECTOR_SIZE as usize], PartitionError<D::Error>> {
+        let mut sector = [0_u8; SECTOR_SIZE as usize];
+        dev.read_blocks(lba, &mut sector)?;
+        if sector[510..512] != SIGNATURE {
+            return Err(PartitionError::InvalidTable)
+        }
+        Ok(sector)
+    }
+
+    /// Read the MBR and follow the EBR chain of the extended partition
+    pub fn read<D: BlockDevice>(dev: &D) -> Result<Self, PartitionError<D::Error>> {
+        if dev.block_size() != SECTOR_SIZE as usize {
+            return Err(PartitionError::BlockSize)
+        }
+
+        let mut table = Self { partitions: [None; MBR_MAX_PARTITIONS], len: 0 };
+        let mbr = Self::read_sector(dev, 0)?;
+        let mut extended = None;
+        for i in 0..PRIMARY_ENTRIES {
+            let part = parse_entry(&mbr, i)
+                .map_err(|_| PartitionError::InvalidTable)?;
+            if let Some(part) = part {
+                if part.is_extended() && extended.is_none() {
+                    extended = Some(part);
+                }
+                table.push(part);
+            }
+        }
+
+        let extended = match extended {
+            Some(part) => part,
+            None => return Ok(table)
+        };
+
+        // the first entry of an EBR is relative to the EBR itself, the second
+        // entry points to the next EBR, relative to the extended partition.
+        // A broken chain ends the list instead of failing the whole table,
+        // and the number of EBRs is bounded in case the chain is a loop.
+        let mut ebr_lba = extended.start_lba;
+        let mut number = PRIMARY_ENTRIES as u8 + 1;
+        for _ in 0..MBR_MAX_PARTITIONS {
+            let ebr = match Self::read_sector(dev, ebr_lba) {
+                Ok(ebr) => ebr,
+                Err(PartitionError::InvalidTable) => break,
+                Err(e) => return Err(e)
+            };
+            if let Ok(Some(mut logical)) = parse_entry(&ebr, 0) {
+                logical.number = number;
+                logical.start_lba += ebr_lba;
+                if !table.push(logical) {
+                    break
+                }
+                number += 1;
+            }
+            match parse_entry(&ebr, 1) {
+                Ok(Some(next)) if next.start_lba != 0 => {
+                    ebr_lba = extended.start_lba + next.start_lba
+                },
+                _ => break
+            }
+        }
+        Ok(table)
+    }
+}
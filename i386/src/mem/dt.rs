@@ -3,12 +3,27 @@ pub mod packers;
 pub mod gdt;
 pub mod consts;
 
+use core::fmt;
+
+#[derive(Debug)]
 pub enum DTError {
+    /// the table is full
     Overflow,
+    /// the first entry of the GDT is reserved and must be null
     ErrorReservedEntry,
     EmptyTable
 }
 
+impl fmt::Display for DTError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Overflow => write!(f, "Descriptor Table Error: overflow"),
+            Self::ErrorReservedEntry => write!(f, "Descriptor Table Error: reserved entry is not null"),
+            Self::EmptyTable => write!(f, "Descriptor Table Error: empty table")
+        }
+    }
+}
+
 /// Descriptor table type, GDT or LDT
 #[repr(u8)]
 pub enum DTType {
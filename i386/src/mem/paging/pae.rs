@@ -7,7 +7,7 @@ use crate::{
     instrs::{CR0_PG, CR4_PAE},
     mem::{PhysAddr, MemRange, VirtAddr}
 };
-use core::arch::asm;
+use core::{arch::asm, fmt};
 use super::{Paging, PATMemoryType};
 
 /// The number of PDPTEs in Page Directory Pointer Table, according to 
@@ -182,10 +182,19 @@ impl_page_table!(PTable, PTEntry, PTE_NUM);
 impl_page_table!(PDTable, PDEntry, PDE_NUM);
 impl_page_table!(PDPTable, PDPTEntry, PDPTE_NUM);
 
+#[derive(Debug)]
 pub enum PagingError {
     MapError
 }
 
+impl fmt::Display for PagingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MapError => write!(f, "Paging Error: map failed")
+        }
+    }
+}
+
 pub struct PAEPaging<'a> {
     page_table: &'a PDPTable
 }
@@ -2,3 +2,4 @@ pub mod addr;
 pub mod bitwise;
 pub mod u8x;
 pub mod disk;
+pub mod crc32;
@@ -0,0 +1,40 @@
+//! CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320), as used by GPT.
+
+const POLY: u32 = 0xedb88320;
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0_u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static TABLE: [u32; 256] = make_table();
+
+/// Incremental CRC-32, for data which is not in memory at once.
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self(0xffffffff)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0 = data.iter().fold(self.0, |crc, &byte| {
+            TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+        });
+    }
+
+    pub const fn finish(&self) -> u32 {
+        !self.0
+    }
+}
@@ -43,6 +43,26 @@ pub fn build() -> Vec<PathBuf> {
      .to_vec()
 }
 
+/// the distance between two addresses in the kernel stage of the layout
+fn kernel_region(start: &str, end: &str) -> usize {
+    let layout = std::fs::read_to_string(&*LAYOUT_CONF).unwrap();
+    let layout: Layout = serde_yaml::from_str(&layout).unwrap();
+    let kernel = layout.stages.iter()
+        .find(|x| x.name == "kernel")
+        .expect("No kernel in layout");
+    (kernel.meta[end] - kernel.meta[start]) as usize
+}
+
+/// the space reserved for the kernel image in the layout, stage 3 always reads all of it
+pub fn kernel_size() -> usize {
+    kernel_region("start", "end")
+}
+
+/// the space reserved for the initrd archive in the layout
+pub fn initrd_size() -> usize {
+    kernel_region("initrd", "initrd_end")
+}
+
 fn apply_template(temp_path: &Path, apply_file: &PathBuf) {
     let temp = std::fs::read_to_string(temp_path).unwrap();
     let temp: Layout = serde_yaml::from_str(&temp).unwrap();
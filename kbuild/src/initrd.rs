@@ -0,0 +1,76 @@
+//! Packs a host directory into the initrd, a newc cpio archive which the kernel
+//! unpacks into its root file system at boot.
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::Path
+};
+
+/// the header sector in front of the archive, see `shared::initrd`
+const INITRD_MAGIC: &[u8; 8] = b"ORUSTSRD";
+pub const INITRD_HEADER_SIZE: usize = 512;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+fn pad4(archive: &mut Vec<u8>) {
+    archive.resize((archive.len() + 3) & !3, 0);
+}
+
+/// append a newc entry, owned by root
+fn push_entry(archive: &mut Vec<u8>, ino: u32, name: &str, mode: u32, data: &[u8]) {
+    // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor,
+    // rdevmajor, rdevminor, namesize, check
+    let fields = [ino, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+    archive.extend_from_slice(b"070701");
+    fields.iter().for_each(|field| archive.extend_from_slice(format!("{:08x}", field).as_bytes()));
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    pad4(archive);
+    archive.extend_from_slice(data);
+    pad4(archive);
+}
+
+/// append every file under `dir`, directories come before their content
+fn pack_dir(archive: &mut Vec<u8>, dir: &Path, prefix: &str, ino: &mut u32) {
+    let mut entries: Vec<_> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap())
+        .collect();
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = prefix.to_string() + entry.file_name().to_str().expect("File name is not valid UTF-8");
+        let meta = fs::symlink_metadata(entry.path()).unwrap();
+        let perm = meta.permissions().mode() & 0o7777;
+        *ino += 1;
+
+        if meta.file_type().is_symlink() {
+            let target = fs::read_link(entry.path()).unwrap();
+            let target = target.to_str().expect("Link target is not valid UTF-8");
+            push_entry(archive, *ino, &name, S_IFLNK | 0o777, target.as_bytes());
+        } else if meta.is_dir() {
+            push_entry(archive, *ino, &name, S_IFDIR | perm, &[]);
+            pack_dir(archive, &entry.path(), &(name + "/"), ino);
+        } else if meta.is_file() {
+            push_entry(archive, *ino, &name, S_IFREG | perm, &fs::read(entry.path()).unwrap());
+        } else {
+            println!("Skipping {}, not a file, directory or link", name);
+        }
+    }
+}
+
+/// pack `dir` into the header sector and the archive, padded to whole sectors
+pub fn pack(dir: &Path) -> Vec<u8> {
+    let mut archive = vec![];
+    pack_dir(&mut archive, dir, "", &mut 0);
+    push_entry(&mut archive, 0, "TRAILER!!!", 0, &[]);
+
+    let mut res = vec![0; INITRD_HEADER_SIZE];
+    res[..8].copy_from_slice(INITRD_MAGIC);
+    res[8..12].copy_from_slice(&(archive.len() as u32).to_le_bytes());
+    res.extend_from_slice(&archive);
+    res.resize((res.len() + 511) & !511, 0);
+    res
+}
@@ -1,6 +1,7 @@
 mod bootloader;
 mod kernel;
 mod config;
+mod initrd;
 
 #[macro_use]
 extern crate lazy_static;
@@ -27,49 +28,94 @@ fn read_to_bytes(path: &PathBuf) -> Vec<u8> {
 }
 
 
-fn build(target: &Path) {
+/// build the disk image, the initrd is packed from the host directory `initrd`
+fn build(target: &Path, initrd: Option<&str>) {
     let target = File::create(target).unwrap();
-    let mut wrote_bytes = 0;
+    let mut kernel_bytes = 0;
     
     let mut f = bootloader::build()
         .iter()
-        .chain(kernel::build().iter())
         .map(|x| read_to_bytes(x))
         .fold(target, |mut f, bin| {
-            wrote_bytes += bin.len();
             println!("Size is {}", bin.len());
             f.write(&bin).unwrap(); 
             f 
         });
+
+    kernel::build()
+        .iter()
+        .map(|x| read_to_bytes(x))
+        .for_each(|bin| {
+            kernel_bytes += bin.len();
+            println!("Size is {}", bin.len());
+            f.write_all(&bin).unwrap();
+        });
     
-    let padding = vec![0; (1 << 9) - (((1 << 9) - 1) & wrote_bytes)];
-    f.write(&padding).unwrap();
+    // pad the kernel to the space reserved for it, the initrd comes right after
+    let kernel_size = bootloader::kernel_size();
+    assert!(kernel_bytes <= kernel_size, "Kernel image too large!");
+    f.write_all(&vec![0; kernel_size - kernel_bytes]).unwrap();
+
+    if let Some(dir) = initrd {
+        let initrd = initrd::pack(Path::new(dir));
+        println!("Initrd size is {}", initrd.len());
+        // the header sector is not loaded
+        assert!(initrd.len() - initrd::INITRD_HEADER_SIZE <= bootloader::initrd_size(), "Initrd too large!");
+        f.write_all(&initrd).unwrap();
+    }
 }
 
-fn run(target: &Path) {
-    if !target.is_file() {
-        build(target);
+/// attach an ISO image as the CD-ROM on the secondary master
+fn attach_cdrom<'a>(qemu: &'a mut Command, cdrom: Option<&str>) -> &'a mut Command {
+    match cdrom {
+        Some(iso) => qemu.arg("-cdrom").arg(iso),
+        None => qemu
+    }
+}
+
+/// select the emulated machine, e.g. `q35` for a machine with an AHCI controller
+fn select_machine<'a>(qemu: &'a mut Command, machine: Option<&str>) -> &'a mut Command {
+    match machine {
+        Some(machine) => qemu.arg("-machine").arg(machine),
+        None => qemu
+    }
+}
+
+/// the `-drive` option of the boot image, `interface` is the qemu interface
+/// type of the disk, `ide` by default or `virtio` for a virtio block device
+fn boot_drive(target: &Path, interface: Option<&str>) -> String {
+    format!("format=raw,index=0,if={},media=disk,file={}",
+        interface.unwrap_or("ide"), target.to_str().unwrap())
+}
+
+fn run(target: &Path, cdrom: Option<&str>, machine: Option<&str>, interface: Option<&str>, initrd: Option<&str>) {
+    // a new initrd has to be packed into the image
+    if !target.is_file() || initrd.is_some() {
+        build(target, initrd);
     }
     // qemu-system-i386 -d int -no-reboot -drive format=raw,index=0,media=disk,file=bootloader.bin -vga std
-    Command::new("qemu-system-i386")
+    let mut qemu = Command::new("qemu-system-i386");
+    attach_cdrom(select_machine(&mut qemu, machine), cdrom)
         .args(["-d", "int"])
         .arg("-no-reboot")
         .arg("-drive")
-        .arg(&format!("format=raw,index=0,if=ide,media=disk,file={}", target.to_str().unwrap()))
+        .arg(&boot_drive(target, interface))
         .args(["-vga", "std"])
         .spawn().unwrap();
 }
 
-fn debug(target: &Path) {
-    if !target.is_file() {
-        build(target);
+fn debug(target: &Path, cdrom: Option<&str>, machine: Option<&str>, interface: Option<&str>, initrd: Option<&str>) {
+    // a new initrd has to be packed into the image
+    if !target.is_file() || initrd.is_some() {
+        build(target, initrd);
     }
     // qemu-system-i386 -d int -no-reboot -drive format=raw,index=0,media=disk,file=bootloader.bin -vga std
-    Command::new("qemu-system-i386")
+    let mut qemu = Command::new("qemu-system-i386");
+    attach_cdrom(select_machine(&mut qemu, machine), cdrom)
         .args(["-d", "int"])
         .arg("-no-reboot")
         .arg("-drive")
-        .arg(&format!("format=raw,index=0,if=ide,media=disk,file={}", target.to_str().unwrap()))
+        .arg(&boot_drive(target, interface))
         .args(["-vga", "std", "-s", "-S"])
         .spawn().unwrap();
 }
@@ -98,15 +144,25 @@ fn main() {
         .version("1.0.0")
         .arg(Arg::from_usage("<type> 'The type to use'")
             .possible_values(&["build", "run", "debug"]))
+        .arg(Arg::from_usage("--cdrom [ISO] 'ISO image attached as CD-ROM when running'"))
+        .arg(Arg::from_usage("--machine [MACHINE] 'machine emulated by qemu, use q35 for AHCI'"))
+        .arg(Arg::from_usage("--disk [INTERFACE] 'interface of the boot disk, ide or virtio'")
+            .possible_values(&["ide", "virtio"]))
+        .arg(Arg::from_usage("--initrd [DIR] 'host directory packed into the initrd'"))
         .get_matches();
 
     let ty = value_t!(matches, "type", Choice)
         .unwrap_or_else(|e| e.exit());
 
+    let cdrom = matches.value_of("cdrom");
+    let machine = matches.value_of("machine");
+    let interface = matches.value_of("disk");
+    let initrd = matches.value_of("initrd");
+
     match ty {
-        Choice::Build => build(&ROOT_PROJ.join("target").join("orusts")),
-        Choice::Run => run(&ROOT_PROJ.join("target").join("orusts")),
-        Choice::Debug => debug(&ROOT_PROJ.join("target").join("orusts"))
+        Choice::Build => build(&ROOT_PROJ.join("target").join("orusts"), initrd),
+        Choice::Run => run(&ROOT_PROJ.join("target").join("orusts"), cdrom, machine, interface, initrd),
+        Choice::Debug => debug(&ROOT_PROJ.join("target").join("orusts"), cdrom, machine, interface, initrd)
     }
 
     println!("Build Done.");
@@ -0,0 +1,412 @@
+//! The block buffer cache, which sits between file systems and disk drivers.
+//! Blocks are keyed by (device, LBA) and evicted in LRU order. Writes only touch
+//! the cache (write-back), dirty blocks reach the disk when they are evicted or
+//! when `sync` is called. A miss right after the previous block of the same device
+//! reads the following blocks ahead.
+
+use i386::driver::disk::{
+    ahci::{AHCIDisk, AHCI_MAX_DISKS},
+    ata::{ATAError, ATA_MAX_DISKS, pio::ATADisk},
+    virtio::{VirtIOError, blk::{VirtIOBlkDisk, VIRTIO_MAX_DISKS}},
+    block::{BlockDevice, DeviceError}
+};
+use core::{fmt, ptr::addr_of_mut};
+use spin::Mutex;
+
+/// Every cached block has the size of an ATA sector
+pub const CACHE_BLOCK_SIZE: usize = 512;
+/// The number of blocks in the kernel block cache
+pub const CACHE_BLOCKS: usize = 64;
+/// The max number of devices registered to a cache
+pub const CACHE_MAX_DEVICES: usize = ATA_MAX_DISKS + AHCI_MAX_DISKS + VIRTIO_MAX_DISKS;
+/// Blocks read at once when a sequential access misses
+const READ_AHEAD: usize = 8;
+
+pub type CacheBlock = [u8; CACHE_BLOCK_SIZE];
+/// The index of a device registered to a cache
+pub type DeviceId = usize;
+
+#[derive(Debug)]
+pub enum CacheError<E> {
+    /// the device is not registered
+    NoDevice,
+    /// all device slots are taken
+    TooManyDevices,
+    /// the block size of the device is not `CACHE_BLOCK_SIZE`
+    BlockSize,
+    /// the buffer is not a multiple of the block size
+    BufferNotAligned,
+    /// the transfer exceeds the end of the device
+    OutOfRange,
+    DiskError(E)
+}
+
+impl<E> From<E> for CacheError<E> {
+    fn from(e: E) -> Self {
+        Self::DiskError(e)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for CacheError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoDevice => write!(f, "Cache Error: no such device"),
+            Self::TooManyDevices => write!(f, "Cache Error: too many devices"),
+            Self::BlockSize => write!(f, "Cache Error: unsupported block size"),
+            Self::BufferNotAligned => write!(f, "Cache Error: alignment"),
+            Self::OutOfRange => write!(f, "Cache Error: out of range"),
+            Self::DiskError(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl<E: DeviceError> DeviceError for CacheError<E> {
+    fn status(&self) -> u8 {
+        match self {
+            Self::DiskError(e) => e.status(),
+            _ => 0
+        }
+    }
+}
+
+/// A disk detected by the bootloader
+#[derive(Clone, Copy)]
+pub enum KernelDisk {
+    ATA(ATADisk),
+    AHCI(AHCIDisk),
+    VirtIO(VirtIOBlkDisk)
+}
+
+/// The errors of the drivers behind `KernelDisk`
+#[derive(Debug)]
+pub enum KernelDiskError {
+    /// ATA and AHCI disks
+    ATA(ATAError),
+    VirtIO(VirtIOError)
+}
+
+impl From<ATAError> for KernelDiskError {
+    fn from(e: ATAError) -> Self {
+        Self::ATA(e)
+    }
+}
+
+impl From<VirtIOError> for KernelDiskError {
+    fn from(e: VirtIOError) -> Self {
+        Self::VirtIO(e)
+    }
+}
+
+impl fmt::Display for KernelDiskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ATA(e) => write!(f, "{}", e),
+            Self::VirtIO(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl DeviceError for KernelDiskError {
+    fn status(&self) -> u8 {
+        match self {
+            Self::ATA(e) => e.status(),
+            Self::VirtIO(e) => e.status()
+        }
+    }
+}
+
+impl BlockDevice for KernelDisk {
+    type Error = KernelDiskError;
+
+    fn block_size(&self) -> usize {
+        match self {
+            Self::ATA(disk) => disk.block_size(),
+            Self::AHCI(disk) => disk.block_size(),
+            Self::VirtIO(disk) => disk.block_size()
+        }
+    }
+
+    fn block_count(&self) -> u64 {
+        match self {
+            Self::ATA(disk) => disk.block_count(),
+            Self::AHCI(disk) => disk.block_count(),
+            Self::VirtIO(disk) => disk.block_count()
+        }
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), KernelDiskError> {
+        match self {
+            Self::ATA(disk) => Ok(disk.read_blocks(lba, buf)?),
+            Self::AHCI(disk) => Ok(disk.read_blocks(lba, buf)?),
+            Self::VirtIO(disk) => Ok(disk.read_blocks(lba, buf)?)
+        }
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), KernelDiskError> {
+        match self {
+            Self::ATA(disk) => Ok(disk.write_blocks(lba, buf)?),
+            Self::AHCI(disk) => Ok(disk.write_blocks(lba, buf)?),
+            Self::VirtIO(disk) => Ok(disk.write_blocks(lba, buf)?)
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), KernelDiskError> {
+        match self {
+            Self::ATA(disk) => Ok(disk.flush()?),
+            Self::AHCI(disk) => Ok(disk.flush()?),
+            Self::VirtIO(disk) => Ok(disk.flush()?)
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CacheEntry {
+    dev: DeviceId,
+    lba: u64,
+    valid: bool,
+    dirty: bool,
+    /// the value of the LRU clock at the last access
+    last_used: u64
+}
+
+impl CacheEntry {
+    const fn empty() -> Self {
+        Self { dev: 0, lba: 0, valid: false, dirty: false, last_used: 0 }
+    }
+}
+
+pub struct BlockCache<D: BlockDevice + Copy, const N: usize> {
+    devices: [Option<D>; CACHE_MAX_DEVICES],
+    /// the last block read from each device, for detecting sequential access
+    last_read: [Option<u64>; CACHE_MAX_DEVICES],
+    entries: [CacheEntry; N],
+    /// the blocks are provided by the caller, so they can live in a static
+    /// variable instead of the stack
+    data: &'static mut [CacheBlock; N],
+    clock: u64
+}
+
+#[allow(dead_code)]
+impl<D: BlockDevice + Copy, const N: usize> BlockCache<D, N> {
+    pub fn new(data: &'static mut [CacheBlock; N]) -> Self {
+        Self {
+            devices: [None; CACHE_MAX_DEVICES],
+            last_read: [None; CACHE_MAX_DEVICES],
+            entries: [CacheEntry::empty(); N],
+            data,
+            clock: 0
+        }
+    }
+
+    /// Add a device to the cache, returns the id used to access it
+    pub fn register(&mut self, dev: D) -> Result<DeviceId, CacheError<D::Error>> {
+        if dev.block_size() != CACHE_BLOCK_SIZE {
+            return Err(CacheError::BlockSize)
+        }
+        let id = self.devices.iter().position(|d| d.is_none())
+            .ok_or(CacheError::TooManyDevices)?;
+        self.devices[id] = Some(dev);
+        self.last_read[id] = None;
+        Ok(id)
+    }
+
+    pub fn device(&self, id: DeviceId) -> Option<&D> {
+        self.devices.get(id)?.as_ref()
+    }
+
+    fn device_mut(&mut self, id: DeviceId) -> Result<&mut D, CacheError<D::Error>> {
+        self.devices.get_mut(id)
+            .and_then(|d| d.as_mut())
+            .ok_or(CacheError::NoDevice)
+    }
+
+    fn lookup(&self, dev: DeviceId, lba: u64) -> Option<usize> {
+        self.entries.iter().position(|e| e.valid && e.dev == dev && e.lba == lba)
+    }
+
+    fn touch(&mut self, index: usize) {
+        self.clock += 1;
+        self.entries[index].last_used = self.clock;
+    }
+
+    /// Write a dirty block back to its device
+    fn write_back(&mut self, index: usize) -> Result<(), CacheError<D::Error>> {
+        let entry = self.entries[index];
+        if !entry.valid || !entry.dirty {
+            return Ok(())
+        }
+        let mut dev = *self.device(entry.dev).ok_or(CacheError::NoDevice)?;
+        dev.write_blocks(entry.lba, &self.data[index])?;
+        self.entries[index].dirty = false;
+        Ok(())
+    }
+
+    /// Find a free slot, or evict the least recently used block
+    fn evict(&mut self) -> Result<usize, CacheError<D::Error>> {
+        if let Some(index) = self.entries.iter().position(|e| !e.valid) {
+            return Ok(index)
+        }
+        let (index, _) = self.entries.iter().enumerate()
+            .min_by_key(|(_, e)| e.last_used)
+            .ok_or(CacheError::OutOfRange)?;
+        self.write_back(index)?;
+        self.entries[index].valid = false;
+        Ok(index)
+    }
+
+    /// Put a block which is not cached yet into the cache
+    fn insert(&mut self, dev: DeviceId, lba: u64, block: &[u8]) -> Result<usize, CacheError<D::Error>> {
+        let index = self.evict()?;
+        self.data[index].copy_from_slice(block);
+        self.entries[index] = CacheEntry { dev, lba, valid: true, dirty: false, last_used: 0 };
+        self.touch(index);
+        Ok(index)
+    }
+
+    /// Make sure the block is cached and return its slot
+    fn fetch(&mut self, dev: DeviceId, lba: u64) -> Result<usize, CacheError<D::Error>> {
+        let sequential = self.last_read[dev] == Some(lba.wrapping_sub(1));
+        self.last_read[dev] = Some(lba);
+
+        if let Some(index) = self.lookup(dev, lba) {
+            self.touch(index);
+            return Ok(index)
+        }
+
+        let disk = *self.device(dev).ok_or(CacheError::NoDevice)?;
+        let ahead = if sequential { READ_AHEAD.min(N) } else { 1 };
+        let count = (ahead as u64).min(disk.block_count() - lba) as usize;
+
+        let mut buf = [0_u8; READ_AHEAD * CACHE_BLOCK_SIZE];
+        let buf = &mut buf[..count * CACHE_BLOCK_SIZE];
+        disk.read_blocks(lba, buf)?;
+
+        let mut blocks = buf.chunks_exact(CACHE_BLOCK_SIZE);
+        let index = self.insert(dev, lba, blocks.next().unwrap())?;
+        // blocks read ahead never replace cached blocks, which may be dirty
+        for (i, block) in blocks.enumerate() {
+            let ahead_lba = lba + 1 + i as u64;
+            if self.lookup(dev, ahead_lba).is_none() {
+                self.insert(dev, ahead_lba, block)?;
+            }
+        }
+        // the requested block stays the most recently used one
+        self.touch(index);
+        Ok(index)
+    }
+
+    fn check(&self, dev: DeviceId, lba: u64, len: usize) -> Result<(), CacheError<D::Error>> {
+        let disk = self.device(dev).ok_or(CacheError::NoDevice)?;
+        if len % CACHE_BLOCK_SIZE != 0 {
+            return Err(CacheError::BufferNotAligned)
+        }
+        if lba + (len / CACHE_BLOCK_SIZE) as u64 > disk.block_count() {
+            return Err(CacheError::OutOfRange)
+        }
+        Ok(())
+    }
+
+    /// Read `buf.len()` bytes from `lba` of a device through the cache
+    pub fn read(&mut self, dev: DeviceId, lba: u64, buf: &mut [u8]) -> Result<(), CacheError<D::Error>> {
+        self.check(dev, lba, buf.len())?;
+        for (i, block) in buf.chunks_exact_mut(CACHE_BLOCK_SIZE).enumerate() {
+            let index = self.fetch(dev, lba + i as u64)?;
+            block.copy_from_slice(&self.data[index]);
+        }
+        Ok(())
+    }
+
+    /// Write `buf` to `lba` of a device. Only the cache is updated,
+    /// the blocks are written to the disk later.
+    pub fn write(&mut self, dev: DeviceId, lba: u64, buf: &[u8]) -> Result<(), CacheError<D::Error>> {
+        self.check(dev, lba, buf.len())?;
+        for (i, block) in buf.chunks_exact(CACHE_BLOCK_SIZE).enumerate() {
+            let block_lba = lba + i as u64;
+            // the whole block is overwritten, so there is no need to read it first
+            let index = match self.lookup(dev, block_lba) {
+                Some(index) => index,
+                None => self.insert(dev, block_lba, block)?
+            };
+            self.data[index].copy_from_slice(block);
+            self.entries[index].dirty = true;
+            self.touch(index);
+        }
+        Ok(())
+    }
+
+    /// Write all dirty blocks of a device back and flush its write cache
+    pub fn sync_device(&mut self, dev: DeviceId) -> Result<(), CacheError<D::Error>> {
+        for index in 0..N {
+            if self.entries[index].dev == dev {
+                self.write_back(index)?;
+            }
+        }
+        self.device_mut(dev)?.flush()?;
+        Ok(())
+    }
+
+    /// Write all dirty blocks back to their devices
+    pub fn sync(&mut self) -> Result<(), CacheError<D::Error>> {
+        for dev in 0..CACHE_MAX_DEVICES {
+            if self.devices[dev].is_some() {
+                self.sync_device(dev)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop all cached blocks of a device without writing them back,
+    /// for example when the medium is changed.
+    pub fn invalidate(&mut self, dev: DeviceId) {
+        self.entries.iter_mut()
+            .filter(|e| e.dev == dev)
+            .for_each(|e| e.valid = false);
+        self.last_read[dev] = None;
+    }
+}
+
+/// A device accessed through a shared cache, file systems should be built on this.
+pub struct CachedDisk<'a, D: BlockDevice + Copy, const N: usize> {
+    cache: &'a Mutex<BlockCache<D, N>>,
+    dev: DeviceId,
+    block_count: u64
+}
+
+impl<'a, D: BlockDevice + Copy, const N: usize> CachedDisk<'a, D, N> {
+    pub fn new(cache: &'a Mutex<BlockCache<D, N>>, dev: DeviceId) -> Option<Self> {
+        let block_count = cache.lock().device(dev)?.block_count();
+        Some(Self { cache, dev, block_count })
+    }
+}
+
+impl<'a, D: BlockDevice + Copy, const N: usize> BlockDevice for CachedDisk<'a, D, N> {
+    type Error = CacheError<D::Error>;
+
+    fn block_size(&self) -> usize {
+        CACHE_BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.cache.lock().read(self.dev, lba, buf)
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), Self::Error> {
+        self.cache.lock().write(self.dev, lba, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.cache.lock().sync_device(self.dev)
+    }
+}
+
+static mut CACHE_DATA: [CacheBlock; CACHE_BLOCKS] = [[0; CACHE_BLOCK_SIZE]; CACHE_BLOCKS];
+
+lazy_static! {
+    /// The block cache of all disks detected by the bootloader
+    pub static ref BLOCK_CACHE: Mutex<BlockCache<KernelDisk, CACHE_BLOCKS>> =
+        Mutex::new(BlockCache::new(unsafe { &mut *addr_of_mut!(CACHE_DATA) }));
+}
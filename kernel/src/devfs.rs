@@ -0,0 +1,366 @@
+//! devfs, the file system on `/dev`. Drivers are registered as nodes, and reads and
+//! writes of a node go to its driver, so programs reach the hardware through the file API.
+//! Block nodes (`hda`, `hda1`, ...) go through the block cache, character nodes are
+//! polled, a read waits for the first byte and returns the bytes already received.
+
+use core::fmt::{self, Write};
+use i386::{
+    driver::{
+        keyboard::Keyboard,
+        serial::{SerialPort, SERIAL_MAX_PORTS},
+        screen::Printable,
+        disk::block::{BlockDevice, ByteAdapter, DeviceError}
+    },
+    fs::{
+        FileSystem, FileError, FileHandle, FileType, Metadata, DirEntry,
+        part::{PartitionDevice, mbr::PartitionTable, gpt::GPTable}
+    }
+};
+use spin::Mutex;
+
+use crate::{
+    cache::{BLOCK_CACHE, CACHE_BLOCKS, CACHE_MAX_DEVICES, CachedDisk, DeviceId, KernelDisk},
+    display::SCREEN,
+    vfs::{FsContext, MountId, VfsError, VFS}
+};
+
+/// The number of nodes in devfs
+pub const DEVFS_MAX_NODES: usize = 64;
+/// The longest node name
+pub const DEV_NAME_MAX: usize = 16;
+
+const ROOT: FileHandle = FileHandle(0);
+
+/// The keyboard is shared by `/dev/console` and `/dev/kbd`
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new());
+
+/// A disk of the block cache, a partition is a window of it
+type CachedDevice = PartitionDevice<CachedDisk<'static, KernelDisk, CACHE_BLOCKS>>;
+
+/// The driver behind a node
+#[derive(Clone, Copy)]
+pub enum Device {
+    /// reads nothing, discards writes
+    Null,
+    /// reads zeros, discards writes
+    Zero,
+    /// writes to the screen, reads from the keyboard (without echo)
+    Console,
+    Serial(SerialPort),
+    Keyboard,
+    /// the sectors `[start, start + sectors)` of a disk in the block cache
+    Block {
+        dev: DeviceId,
+        start: u64,
+        sectors: u64
+    }
+}
+
+impl Device {
+    fn file_type(&self) -> FileType {
+        match self {
+            Self::Block { .. } => FileType::BlockDevice,
+            _ => FileType::CharDevice
+        }
+    }
+}
+
+/// The name of a node, which can be built with `write!`
+#[derive(Clone, Copy)]
+pub struct DevName {
+    buf: [u8; DEV_NAME_MAX],
+    len: usize
+}
+
+impl DevName {
+    pub const fn new() -> Self {
+        Self { buf: [0; DEV_NAME_MAX], len: 0 }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only built from `&str`
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for DevName {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.len + s.len() > DEV_NAME_MAX {
+            return Err(fmt::Error)
+        }
+        self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    name: DevName,
+    device: Device
+}
+
+/// Handles are the index of the node plus 1, the root directory is 0
+pub struct DevFS {
+    nodes: [Option<Node>; DEVFS_MAX_NODES]
+}
+
+impl DevFS {
+    pub const fn new() -> Self {
+        Self { nodes: [None; DEVFS_MAX_NODES] }
+    }
+
+    /// Add the node `name` for `device`
+    pub fn register(&mut self, name: &str, device: Device) -> Result<(), FileError> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(FileError::InvalidPath)
+        }
+        if self.find(name).is_some() {
+            return Err(FileError::AlreadyExists)
+        }
+        let mut node_name = DevName::new();
+        node_name.write_str(name).or(Err(FileError::InvalidPath))?;
+        let slot = self.nodes.iter_mut()
+            .find(|node| node.is_none())
+            .ok_or(FileError::NoSpace)?;
+        *slot = Some(Node { name: node_name, device });
+        Ok(())
+    }
+
+    /// Register the devices found at boot: `null`, `zero`, `console`, `kbd`,
+    /// the serial ports (`ttyS0` - `ttyS3`) and every disk of the block cache
+    /// with its partitions. ATA disks are `hd*`, AHCI disks `sd*` and virtio disks `vd*`,
+    /// lettered in the order of the cache. A device which can not be registered is
+    /// skipped, the first error is returned at the end.
+    pub fn register_devices(&mut self) -> Result<(), FileError> {
+        let mut result = self.register("null", Device::Null)
+            .and(self.register("zero", Device::Zero))
+            .and(self.register("console", Device::Console))
+            .and(self.register("kbd", Device::Keyboard));
+
+        let ports: [Option<SerialPort>; SERIAL_MAX_PORTS] = SerialPort::enumerate();
+        for (i, port) in ports.iter().enumerate() {
+            if let Some(port) = port {
+                result = result.and(self.register_numbered("ttyS", i, Device::Serial(*port)));
+            }
+        }
+
+        let mut letters = [b'a'; 3];
+        for dev in 0..CACHE_MAX_DEVICES {
+            // the lock must be released before the partition tables are read through the cache
+            let disk = match BLOCK_CACHE.lock().device(dev) {
+                Some(disk) => *disk,
+                None => continue
+            };
+            let kind = match disk {
+                KernelDisk::ATA(_) => 0,
+                KernelDisk::AHCI(_) => 1,
+                KernelDisk::VirtIO(_) => 2
+            };
+            let mut name = DevName::new();
+            let _ = write!(name, "{}{}", ["hd", "sd", "vd"][kind], letters[kind] as char);
+            letters[kind] += 1;
+            result = result.and(self.register_disk(name, dev, disk.block_count()));
+        }
+        result
+    }
+
+    fn register_numbered(&mut self, prefix: &str, number: usize, device: Device) -> Result<(), FileError> {
+        let mut name = DevName::new();
+        write!(name, "{}{}", prefix, number).or(Err(FileError::InvalidPath))?;
+        self.register(name.as_str(), device)
+    }
+
+    /// Register the disk `dev` as `name`, and its MBR or GPT partitions
+    /// as `name` followed by the partition number. Like `register_devices`, the other
+    /// partitions are still registered after an error, the first one is returned.
+    fn register_disk(&mut self, name: DevName, dev: DeviceId, sectors: u64) -> Result<(), FileError> {
+        let mut result = self.register(name.as_str(), Device::Block { dev, start: 0, sectors });
+        let disk = match CachedDisk::new(&BLOCK_CACHE, dev) {
+            Some(disk) => disk,
+            None => return result
+        };
+
+        let table = match PartitionTable::read(&disk) {
+            Ok(table) => table,
+            // a disk without partitions
+            Err(_) => return result
+        };
+        if table.is_protective() {
+            let gpt = match GPTable::read(&disk) {
+                Ok(gpt) => gpt,
+                Err(_) => return result
+            };
+            for part in gpt.iter() {
+                let device = Device::Block { dev, start: part.start_lba, sectors: part.sectors() };
+                result = result.and(self.register_numbered(name.as_str(), part.number as usize, device));
+            }
+        } else {
+            for part in table.iter() {
+                let device = Device::Block { dev, start: part.start_lba, sectors: part.sectors };
+                result = result.and(self.register_numbered(name.as_str(), part.number as usize, device));
+            }
+        }
+        result
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| match node {
+            Some(node) => node.name.as_str() == name,
+            None => false
+        })
+    }
+
+    fn device(&self, file: FileHandle) -> Result<Device, FileError> {
+        if file == ROOT {
+            return Err(FileError::IsADirectory)
+        }
+        let index = (file.0 as usize).wrapping_sub(1);
+        match self.nodes.get(index) {
+            Some(Some(node)) => Ok(node.device),
+            _ => Err(FileError::BadHandle)
+        }
+    }
+}
+
+/// The bytes of a block node, through the block cache
+fn block(dev: DeviceId, start: u64, sectors: u64) -> Result<ByteAdapter<CachedDevice>, FileError> {
+    let disk = CachedDisk::new(&BLOCK_CACHE, dev).ok_or(FileError::BadHandle)?;
+    Ok(ByteAdapter::new(PartitionDevice::new(disk, start, sectors)))
+}
+
+/// Wait until `next` gives the first byte, then take the bytes it already has
+fn read_polled(buf: &mut [u8], mut next: impl FnMut() -> Option<u8>) -> usize {
+    let mut len = 0;
+    while len < buf.len() {
+        match next() {
+            Some(c) => {
+                buf[len] = c;
+                len += 1;
+            },
+            None if len > 0 => break,
+            None => ()
+        }
+    }
+    len
+}
+
+impl FileSystem for DevFS {
+    fn open(&self, path: &str) -> Result<FileHandle, FileError> {
+        let mut file = ROOT;
+        for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+            if file != ROOT {
+                return Err(FileError::NotADirectory)
+            }
+            file = match name {
+                ".." => ROOT,
+                _ => FileHandle(self.find(name).ok_or(FileError::NotFound)? as u64 + 1)
+            };
+        }
+        Ok(file)
+    }
+
+    fn create(&mut self, path: &str) -> Result<FileHandle, FileError> {
+        match self.open(path) {
+            Ok(_) => Err(FileError::AlreadyExists),
+            Err(_) => Err(FileError::NotSupported)
+        }
+    }
+
+    fn read_at(&self, file: FileHandle, offset: u64, buf: &mut [u8]) -> Result<usize, FileError> {
+        match self.device(file)? {
+            Device::Null => Ok(0),
+            Device::Zero => {
+                buf.fill(0);
+                Ok(buf.len())
+            },
+            Device::Console | Device::Keyboard => {
+                Ok(read_polled(buf, || KEYBOARD.lock().read_char()))
+            },
+            Device::Serial(port) => Ok(read_polled(buf, || port.read_byte())),
+            Device::Block { dev, start, sectors } => {
+                block(dev, start, sectors)?.read(offset, buf).map_err(|e| FileError::Io(e.status()))
+            }
+        }
+    }
+
+    fn write_at(&mut self, file: FileHandle, offset: u64, buf: &[u8]) -> Result<usize, FileError> {
+        match self.device(file)? {
+            Device::Null | Device::Zero => Ok(buf.len()),
+            Device::Console => {
+                SCREEN.lock().print_raw(buf);
+                Ok(buf.len())
+            },
+            Device::Serial(port) => {
+                // a stuck port fails the write unless some bytes were sent
+                match buf.iter().take_while(|&&c| port.write_byte(c)).count() {
+                    0 if !buf.is_empty() => Err(FileError::Io(0)),
+                    len => Ok(len)
+                }
+            },
+            Device::Keyboard => Err(FileError::NotSupported),
+            Device::Block { dev, start, sectors } => {
+                block(dev, start, sectors)?.write(offset, buf).map_err(|e| FileError::Io(e.status()))
+            }
+        }
+    }
+
+    fn stat(&self, file: FileHandle) -> Result<Metadata, FileError> {
+        let (file_type, size, perm) = match self.device(file) {
+            Err(FileError::IsADirectory) => (FileType::Directory, 0, 0o755),
+            Err(e) => return Err(e),
+            Ok(Device::Block { sectors, .. }) => (FileType::BlockDevice, sectors * 512, 0o660),
+            Ok(Device::Keyboard) => (FileType::CharDevice, 0, 0o444),
+            Ok(device) => (device.file_type(), 0, 0o666)
+        };
+        Ok(Metadata { file_type, size, perm, links: 1, ino: file.0 })
+    }
+
+    /// Devices have no size to change, truncating them does nothing
+    fn truncate(&mut self, file: FileHandle, _size: u64) -> Result<(), FileError> {
+        self.device(file).map(|_| ())
+    }
+
+    fn readdir(&self, dir: FileHandle, f: &mut dyn FnMut(&DirEntry)) -> Result<(), FileError> {
+        match self.device(dir) {
+            Err(FileError::IsADirectory) => (),
+            Err(e) => return Err(e),
+            Ok(_) => return Err(FileError::NotADirectory)
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let Some(node) = node {
+                let mut entry = DirEntry::new(node.device.file_type(), i as u64 + 1);
+                entry.push(node.name.as_str().as_bytes());
+                f(&entry);
+            }
+        }
+        Ok(())
+    }
+
+    fn mkdir(&mut self, _path: &str) -> Result<(), FileError> {
+        Err(FileError::NotSupported)
+    }
+
+    fn unlink(&mut self, _path: &str) -> Result<(), FileError> {
+        Err(FileError::NotSupported)
+    }
+
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), FileError> {
+        Err(FileError::NotSupported)
+    }
+
+    fn sync(&mut self) -> Result<(), FileError> {
+        BLOCK_CACHE.lock().sync().map_err(|e| FileError::Io(e.status()))
+    }
+}
+
+/// Mount `devfs` on `/dev`, which is created if needed
+pub fn mount_devfs(devfs: &'static mut DevFS) -> Result<MountId, VfsError> {
+    let mut vfs = VFS.lock();
+    let ctx = FsContext::new();
+    match vfs.mkdir(&ctx, "/dev") {
+        Ok(()) | Err(VfsError::File(FileError::AlreadyExists)) => (),
+        Err(e) => return Err(e)
+    }
+    vfs.mount(&ctx, "/dev", devfs)
+}
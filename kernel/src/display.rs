@@ -2,27 +2,52 @@ use core::{
     fmt::{Arguments, Write}, 
     intrinsics::transmute
 };
-use i386::driver::screen::{Cursor, Screen, s80x25c16::Buffer};
+use i386::driver::screen::{
+    Color, ColorCode, Cursor, Screen, DEFAULT_COLOR,
+    s80x25c16::Buffer,
+    crtc::{show_cursor, CURSOR_UNDERLINE}
+};
 use shared::layout::VIDEO_START;
 use spin::Mutex;
 
 lazy_static! {
     pub static ref SCREEN: Mutex<Screen<'static, Buffer>> = Mutex::new(Screen {
         cursor: Cursor(0, 0),
+        color: DEFAULT_COLOR,
         buf: unsafe {
             transmute::<usize, &mut Buffer>(VIDEO_START)
         }
     });
 }
 
+/// clear the screen and show the cursor at the top left corner
 pub fn scr_clear() {
-    SCREEN.lock().clear()
+    SCREEN.lock().clear();
+    let (start, end) = CURSOR_UNDERLINE;
+    show_cursor(start, end)
 }
 
+/// the color of `[INFO]` messages
+pub const INFO_COLOR: ColorCode = ColorCode::new(Color::LightGreen, Color::Black);
+/// the color of `[WARN]` messages
+pub const WARN_COLOR: ColorCode = ColorCode::new(Color::Yellow, Color::Black);
+/// the color of panics
+pub const ERROR_COLOR: ColorCode = ColorCode::new(Color::LightRed, Color::Black);
+
 pub fn _print(s: Arguments) -> core::fmt::Result {
     SCREEN.lock().write_fmt(s)
 }
 
+/// print in `color`, then go back to the previous color
+pub fn _print_color(color: ColorCode, s: Arguments) -> core::fmt::Result {
+    let mut screen = SCREEN.lock();
+    let prev = screen.color;
+    screen.set_color(color);
+    let res = screen.write_fmt(s);
+    screen.set_color(prev);
+    res
+}
+
 /// print with format string 
 #[macro_export]
 macro_rules! print {
@@ -35,3 +60,16 @@ macro_rules! println {
     () => (print!("\n"));
     ($($arg:tt)*) => (print!("{}\n", format_args!($($arg)*)));
 }
+
+/// print with format string in a color
+#[macro_export]
+macro_rules! cprint {
+    ($color:expr, $($arg:tt)*) => ($crate::display::_print_color($color, format_args!($($arg)*)).unwrap());
+}
+
+/// print with format string with newline in a color
+#[macro_export]
+macro_rules! cprintln {
+    ($color:expr) => (print!("\n"));
+    ($color:expr, $($arg:tt)*) => (cprint!($color, "{}\n", format_args!($($arg)*)));
+}
@@ -0,0 +1,14 @@
+//! The kernel heap. It takes the memory after the kernel image up to the end of
+//! the identity mapped region, see `KERNEL_HEAP_START` in the layout.
+
+use linked_list_allocator::LockedHeap;
+use shared::layout::{KERNEL_HEAP_START, KERNEL_HEAP_SIZE};
+
+#[global_allocator]
+static HEAP: LockedHeap = LockedHeap::empty();
+
+/// Hand the heap region to the allocator, nothing can be allocated before this
+pub fn init_heap() {
+    unsafe { HEAP.lock().init(KERNEL_HEAP_START as *mut u8, KERNEL_HEAP_SIZE) }
+}
+
@@ -0,0 +1,116 @@
+//! The initial ramdisk. Stage 3 loads the archive kbuild put after the kernel image,
+//! and it is unpacked into the root file system (a tmpfs) at boot.
+//! USTAR (`tar --format=ustar`) and newc cpio (`cpio -o -H newc`) archives are supported.
+
+mod cpio;
+mod ustar;
+
+use core::fmt;
+use i386::fs::{FileError, split_path};
+
+use crate::vfs::{Fd, FsContext, PathBuf, Vfs, VfsError, VFS, O_WRITE, O_CREATE, O_TRUNC};
+
+#[derive(Debug)]
+pub enum InitrdError {
+    /// the archive is neither USTAR nor newc cpio
+    UnknownFormat,
+    /// a header of the archive is damaged
+    BadHeader,
+    /// the archive ends in the middle of an entry
+    Truncated,
+    Vfs(VfsError)
+}
+
+impl From<VfsError> for InitrdError {
+    fn from(e: VfsError) -> Self {
+        Self::Vfs(e)
+    }
+}
+
+impl fmt::Display for InitrdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownFormat => write!(f, "Initrd Error: unknown archive format"),
+            Self::BadHeader => write!(f, "Initrd Error: damaged header"),
+            Self::Truncated => write!(f, "Initrd Error: truncated"),
+            Self::Vfs(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+pub enum EntryKind<'a> {
+    File,
+    Directory,
+    /// a symbolic link to the path
+    Symlink(&'a str),
+    /// hard links, devices and other entries, which are skipped
+    Other
+}
+
+/// An entry of an archive
+pub struct Entry<'a> {
+    /// the directory part of long USTAR names, empty for cpio
+    pub prefix: &'a str,
+    pub name: &'a str,
+    pub kind: EntryKind<'a>,
+    /// the content of files
+    pub data: &'a [u8]
+}
+
+/// Unpack `archive` into the root directory, returns the number of entries created
+pub fn unpack(archive: &[u8]) -> Result<usize, InitrdError> {
+    let mut vfs = VFS.lock();
+    let mut ctx = FsContext::new();
+    let mut count = 0;
+    let mut f = |entry: Entry| {
+        if add_entry(&mut vfs, &mut ctx, &entry)? {
+            count += 1;
+        }
+        Ok(())
+    };
+
+    if cpio::is_cpio(archive) {
+        cpio::for_each(archive, &mut f)?;
+    } else if ustar::is_ustar(archive) {
+        ustar::for_each(archive, &mut f)?;
+    } else {
+        return Err(InitrdError::UnknownFormat)
+    }
+    Ok(count)
+}
+
+/// Create `entry`, returns false if it is skipped
+fn add_entry(vfs: &mut Vfs, ctx: &mut FsContext, entry: &Entry) -> Result<bool, InitrdError> {
+    let path = PathBuf::concat(&["/", entry.prefix, "/", entry.name])?;
+    let path = path.as_str();
+    // the root directory itself, e.g. `.` in archives of `find .`
+    if split_path(path).is_err() {
+        return Ok(false)
+    }
+
+    match entry.kind {
+        EntryKind::File => {
+            let fd = vfs.open(ctx, path, O_WRITE | O_CREATE | O_TRUNC)?;
+            let written = write_all(vfs, ctx, fd, entry.data);
+            vfs.close(ctx, fd)?;
+            written?;
+        },
+        EntryKind::Directory => match vfs.mkdir(ctx, path) {
+            Ok(()) | Err(VfsError::File(FileError::AlreadyExists)) => (),
+            Err(e) => return Err(e.into())
+        },
+        EntryKind::Symlink(target) => vfs.symlink(ctx, target, path)?,
+        EntryKind::Other => return Ok(false)
+    }
+    Ok(true)
+}
+
+fn write_all(vfs: &mut Vfs, ctx: &FsContext, fd: Fd, mut data: &[u8]) -> Result<(), VfsError> {
+    while !data.is_empty() {
+        match vfs.write(ctx, fd, data)? {
+            0 => return Err(VfsError::File(FileError::NoSpace)),
+            len => data = &data[len..]
+        }
+    }
+    Ok(())
+}
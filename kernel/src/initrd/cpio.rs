@@ -0,0 +1,67 @@
+//! newc cpio archives, which kbuild packs. Every entry is a 110 byte header of
+//! hexadecimal fields, the NUL terminated name and the data, the name and the data
+//! are padded to 4 bytes. The entry named `TRAILER!!!` ends the archive.
+
+use super::{Entry, EntryKind, InitrdError};
+
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+/// the indexes of the header fields used, each field takes 8 bytes after the magic
+const FIELD_MODE: usize = 1;
+const FIELD_FILESIZE: usize = 6;
+const FIELD_NAMESIZE: usize = 11;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// `070702` is the same format with checksums of the data, which are not checked
+pub fn is_cpio(archive: &[u8]) -> bool {
+    matches!(archive.get(..6), Some(b"070701") | Some(b"070702"))
+}
+
+fn hex(header: &[u8], index: usize) -> Result<usize, InitrdError> {
+    let start = 6 + index * 8;
+    core::str::from_utf8(&header[start..start + 8]).ok()
+        .and_then(|field| usize::from_str_radix(field, 16).ok())
+        .ok_or(InitrdError::BadHeader)
+}
+
+const fn align4(pos: usize) -> usize {
+    (pos + 3) & !3
+}
+
+/// Call `f` with every entry of `archive`
+pub fn for_each(archive: &[u8], f: &mut dyn FnMut(Entry) -> Result<(), InitrdError>) -> Result<(), InitrdError> {
+    let mut pos = 0;
+    loop {
+        let header = archive.get(pos..pos + HEADER_SIZE).ok_or(InitrdError::Truncated)?;
+        if !is_cpio(header) {
+            return Err(InitrdError::BadHeader)
+        }
+        let mode = hex(header, FIELD_MODE)? as u32;
+        let size = hex(header, FIELD_FILESIZE)?;
+        let name_size = hex(header, FIELD_NAMESIZE)?;
+
+        let name_start = pos + HEADER_SIZE;
+        let name = archive.get(name_start..name_start + name_size).ok_or(InitrdError::Truncated)?;
+        let name = name.split(|&c| c == 0).next().unwrap_or(name);
+        let name = core::str::from_utf8(name).or(Err(InitrdError::BadHeader))?;
+        if name == TRAILER {
+            return Ok(())
+        }
+
+        let data_start = align4(name_start + name_size);
+        let data = archive.get(data_start..data_start + size).ok_or(InitrdError::Truncated)?;
+        let kind = match mode & S_IFMT {
+            S_IFREG => EntryKind::File,
+            S_IFDIR => EntryKind::Directory,
+            S_IFLNK => EntryKind::Symlink(core::str::from_utf8(data).or(Err(InitrdError::BadHeader))?),
+            _ => EntryKind::Other
+        };
+        f(Entry { prefix: "", name, kind, data })?;
+        pos = align4(data_start + size);
+    }
+}
@@ -0,0 +1,116 @@
+//! USTAR archives. Every entry is a 512 byte header followed by its data padded to
+//! 512 bytes, and zero blocks end the archive. Numbers in headers are octal text.
+//! Names longer than the header fields come in an extra entry before the entry:
+//! a GNU long name (`L`, `K`) or a pax extended header (`x`).
+
+use super::{Entry, EntryKind, InitrdError};
+
+const BLOCK_SIZE: usize = 512;
+
+/// the magic, which GNU tar follows with spaces instead of the version
+const MAGIC: &[u8] = b"ustar";
+const MAGIC_OFFSET: usize = 257;
+
+pub fn is_ustar(archive: &[u8]) -> bool {
+    archive.get(MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()) == Some(MAGIC)
+}
+
+/// A field of the header, which ends at the first NUL if it is shorter
+fn field(header: &[u8], start: usize, len: usize) -> &[u8] {
+    let field = &header[start..start + len];
+    let end = field.iter().position(|&c| c == 0).unwrap_or(len);
+    &field[..end]
+}
+
+fn text(field: &[u8]) -> Result<&str, InitrdError> {
+    core::str::from_utf8(field).or(Err(InitrdError::BadHeader))
+}
+
+/// An octal number, padded with spaces or NULs
+fn octal(field: &[u8]) -> Result<usize, InitrdError> {
+    field.iter()
+        .filter(|&&c| c != b' ' && c != 0)
+        .try_fold(0_usize, |n, &c| match c {
+            b'0'..=b'7' => n.checked_mul(8).map(|n| n + (c - b'0') as usize),
+            _ => None
+        })
+        .ok_or(InitrdError::BadHeader)
+}
+
+/// The checksum is the sum of the header bytes, with the checksum field taken as spaces
+fn check(header: &[u8]) -> Result<(), InitrdError> {
+    let sum = header.iter().enumerate()
+        .map(|(i, &c)| if (148..156).contains(&i) { b' ' as usize } else { c as usize })
+        .sum::<usize>();
+    match octal(field(header, 148, 8))? == sum {
+        true => Ok(()),
+        false => Err(InitrdError::BadHeader)
+    }
+}
+
+/// The records of a pax extended header look like `<length> <key>=<value>\n`,
+/// returns the values of `path` and `linkpath`
+fn pax_names(mut data: &[u8]) -> Result<(Option<&str>, Option<&str>), InitrdError> {
+    let (mut path, mut link) = (None, None);
+    while !data.is_empty() {
+        let space = data.iter().position(|&c| c == b' ').ok_or(InitrdError::BadHeader)?;
+        let len = text(&data[..space])?.parse::<usize>().or(Err(InitrdError::BadHeader))?;
+        let record = data.get(space + 1..len).ok_or(InitrdError::BadHeader)?;
+        let record = text(record.strip_suffix(b"\n").unwrap_or(record))?;
+        match record.split_once('=') {
+            Some(("path", value)) => path = Some(value),
+            Some(("linkpath", value)) => link = Some(value),
+            _ => ()
+        }
+        data = &data[len..];
+    }
+    Ok((path, link))
+}
+
+/// Call `f` with every entry of `archive`
+pub fn for_each(archive: &[u8], f: &mut dyn FnMut(Entry) -> Result<(), InitrdError>) -> Result<(), InitrdError> {
+    let mut pos = 0;
+    // the names from the entry before, which replace the names in the header
+    let mut long_name = None;
+    let mut long_link = None;
+    while pos < archive.len() {
+        let header = archive.get(pos..pos + BLOCK_SIZE).ok_or(InitrdError::Truncated)?;
+        if header.iter().all(|&c| c == 0) {
+            return Ok(())
+        }
+        check(header)?;
+
+        let size = octal(field(header, 124, 12))?;
+        let data_start = pos + BLOCK_SIZE;
+        let data = archive.get(data_start..data_start + size).ok_or(InitrdError::Truncated)?;
+        pos = data_start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+
+        let kind = match header[156] {
+            b'0' | b'7' | 0 => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            b'2' => EntryKind::Symlink(long_link.take().map_or_else(|| text(field(header, 157, 100)), Ok)?),
+            b'L' => {
+                long_name = Some(text(field(data, 0, size))?);
+                continue
+            },
+            b'K' => {
+                long_link = Some(text(field(data, 0, size))?);
+                continue
+            },
+            b'x' => {
+                let (path, link) = pax_names(data)?;
+                long_name = path.or(long_name);
+                long_link = link.or(long_link);
+                continue
+            },
+            _ => EntryKind::Other
+        };
+        let (prefix, name) = match long_name.take() {
+            Some(name) => ("", name),
+            None => (text(field(header, 345, 155))?, text(field(header, 0, 100))?)
+        };
+        long_link = None;
+        f(Entry { prefix, name, kind, data })?;
+    }
+    Ok(())
+}
@@ -1,31 +1,78 @@
 #![no_std]
 #![no_main]
+#![feature(alloc_error_handler)]
 #![feature(panic_info_message)]
 
 mod display;
+mod cache;
+mod heap;
+/// not used until the kernel has processes and syscalls
+#[allow(dead_code)]
+mod vfs;
+mod tmpfs;
+mod initrd;
+mod devfs;
 
 #[macro_use]
 extern crate lazy_static;
+extern crate alloc;
+
+use alloc::boxed::Box;
 
 use core::{
+    alloc::Layout,
     panic::PanicInfo,
     arch::asm
 };
-use i386::utils::u8x::CastUp;
+use i386::{
+    driver::disk::ata::{identify::ATADiskInfo, pio::ATAPIOMode},
+    fs::part::{mbr::PartitionTable, gpt::GPTable}
+};
 use shared::kctx::KernelContext;
-use crate::display::scr_clear;
+use crate::{
+    display::{scr_clear, INFO_COLOR, WARN_COLOR, ERROR_COLOR},
+    cache::{BLOCK_CACHE, KernelDisk},
+    heap::init_heap,
+    tmpfs::mount_tmpfs,
+    devfs::{DevFS, mount_devfs},
+    initrd::unpack
+};
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     if let Some(msg) = info.message() {
-        println!("Error: {}", msg);
+        cprintln!(ERROR_COLOR, "Error: {}", msg);
     } else {
-        println!("Unknown Error.");
+        cprintln!(ERROR_COLOR, "Unknown Error.");
     }
     unsafe { asm!("hlt") }
     loop {}
 }
 
+#[alloc_error_handler]
+fn oom(_layout: Layout) -> ! {
+    cprintln!(ERROR_COLOR, "Alloc Error.");
+    unsafe { asm!("hlt") }
+    loop {}
+}
+
+fn mode_name(info: &ATADiskInfo) -> &'static str {
+    match info.pio_mode() {
+        Some(ATAPIOMode::PIO48) => "LBA48",
+        Some(ATAPIOMode::PIO28) => "LBA28",
+        None => "CHS"
+    }
+}
+
+/// print the identity and features of a disk below its row in the disk table
+fn show_disk_details(info: &ATADiskInfo) {
+    println!("        Model: {} Serial: {} Firmware: {}",
+        info.model().as_str(), info.serial().as_str(), info.firmware().as_str());
+    println!("        Sector: {}/{} UDMA: {:#04x} Write Cache: {} Flush: {}",
+        info.logical_sector_size(), info.physical_sector_size(), info.udma_supported(),
+        info.write_cache_enabled(), info.flush_supported());
+}
+
 /// log some hardware information on screen
 fn show_info(ctx: &KernelContext<>) {
     // show memory information
@@ -37,17 +84,129 @@ fn show_info(ctx: &KernelContext<>) {
     });
 
     println!("\n\nDisk Information: \n");
-    let max_lba48: u64 = ctx.disk_info.lba48_sec.cast_le();
-    println!("    MAX ATA LBA48 SECTORS: {}", max_lba48);
+    println!("    {:<12}{:<12}{:<12}{:<12}", "Bus", "Drive", "Mode", "Sectors");
+    ctx.disks.iter().flatten().for_each(|disk| {
+        let bus: &'static str = disk.driver.bus.into();
+        let drive: &'static str = disk.driver.drive.into();
+        println!("    {:<12}{:<12}{:<12}{:<12}", bus, drive, mode_name(&disk.info), disk.info.max_sectors());
+        show_disk_details(&disk.info)
+    });
+    ctx.sata_disks.iter().flatten().for_each(|disk| {
+        println!("    {:<12}{:<12}{:<12}{:<12}", "AHCI", disk.driver.port, mode_name(&disk.info), disk.info.max_sectors());
+        show_disk_details(&disk.info)
+    });
+    ctx.virtio_disks.iter().flatten().for_each(|disk| {
+        let mode = if disk.is_read_only() { "VirtIO RO" } else { "VirtIO" };
+        println!("    {:<12}{:<12}{:<12}{:<12}", "VirtIO", disk.transport.pci.slot, mode, disk.capacity);
+        println!("        Block Size: {} Flush: {}", disk.blk_size(), disk.flush_supported());
+    });
+
+    println!("\n\nCD-ROM Information: \n");
+    println!("    {:<12}{:<12}{:<12}{:<12}", "Bus", "Drive", "Blocks", "Block Size");
+    ctx.cdroms.iter().flatten().for_each(|cdrom| {
+        let bus: &'static str = cdrom.driver.driver.bus.into();
+        let drive: &'static str = cdrom.driver.driver.drive.into();
+        let capacity = cdrom.driver.test_unit_ready()
+            .and_then(|_| cdrom.driver.read_capacity());
+        match capacity {
+            Ok(cap) => println!("    {:<12}{:<12}{:<12}{:<12}", bus, drive, cap.blocks, cap.block_size),
+            Err(_) => println!("    {:<12}{:<12}{:<12}", bus, drive, "No Medium")
+        }
+    });
     println!("\n\n");
 }
 
+/// every disk detected by the bootloader, ATA disks come first, then AHCI
+/// and virtio disks
+fn kernel_disks(ctx: &KernelContext) -> impl Iterator<Item = KernelDisk> + '_ {
+    ctx.disks.iter().flatten().map(|disk| KernelDisk::ATA(*disk))
+        .chain(ctx.sata_disks.iter().flatten().map(|disk| KernelDisk::AHCI(*disk)))
+        .chain(ctx.virtio_disks.iter().flatten().map(|disk| KernelDisk::VirtIO(*disk)))
+}
+
+/// list the MBR partitions of every disk, disks are numbered in the order
+/// of `kernel_disks`
+fn show_partitions(ctx: &KernelContext) {
+    println!("\n\nPartition Information: \n");
+    println!("    {:<8}{:<8}{:<12}{:<8}{:<12}{:<12}", "Disk", "No.", "Type", "Boot", "Start", "Sectors");
+    kernel_disks(ctx).enumerate().for_each(|(i, disk)| {
+        let table = match PartitionTable::read(&disk) {
+            Ok(table) => table,
+            Err(_) => return
+        };
+        if table.is_protective() {
+            let gpt = match GPTable::read(&disk) {
+                Ok(gpt) => gpt,
+                Err(e) => {
+                    cprintln!(WARN_COLOR, "    {:<8}[WARN] Damaged GPT: {}", i, e);
+                    return
+                }
+            };
+            if gpt.from_backup {
+                cprintln!(WARN_COLOR, "    {:<8}[WARN] Primary GPT damaged, using backup.", i);
+            }
+            gpt.iter().for_each(|part| {
+                let ty: &'static str = (*part).into();
+                println!("    {:<8}{:<8}{:<12}{:<8}{:<12}{:<12}{}",
+                    i, part.number, ty, "-", part.start_lba, part.sectors(), part.name)
+            });
+            return
+        }
+        table.iter().for_each(|part| {
+            let ty: &'static str = (*part).into();
+            println!("    {:<8}{:<8}{:<12}{:<8}{:<12}{:<12}",
+                i, part.number, ty, part.bootable, part.start_lba, part.sectors)
+        });
+    });
+}
+
+/// unpack the initrd loaded by stage 3 into the root file system
+fn load_initrd(ctx: &KernelContext) {
+    let initrd = match ctx.initrd {
+        Some(initrd) => initrd,
+        None => return
+    };
+    let archive = unsafe {
+        core::slice::from_raw_parts(initrd.start as *const u8, initrd.size)
+    };
+    match unpack(archive) {
+        Ok(count) => cprintln!(INFO_COLOR, "[INFO] {} files unpacked from initrd.", count),
+        Err(e) => cprintln!(WARN_COLOR, "[WARN] Damaged initrd: {}", e)
+    }
+}
+
+/// register every disk detected by the bootloader to the block cache
+fn init_cache(ctx: &KernelContext) {
+    let mut cache = BLOCK_CACHE.lock();
+    kernel_disks(ctx)
+        .for_each(|disk| {
+            if let Err(e) = cache.register(disk) {
+                cprintln!(WARN_COLOR, "[WARN] Disk not cached: {}", e);
+            }
+        });
+}
+
 #[link_section = ".startup"]
 #[no_mangle]
 fn main(ctx: KernelContext) {
     scr_clear();
-    println!("[INFO] Kernel Entered.");
+    cprintln!(INFO_COLOR, "[INFO] Kernel Entered.");
     show_info(&ctx);
+    show_partitions(&ctx);
+    init_cache(&ctx);
+    init_heap();
+    // the root file system until the kernel can mount one from disk
+    if let Err(e) = mount_tmpfs("/") {
+        cprintln!(WARN_COLOR, "[WARN] Root file system not mounted: {}", e);
+    }
+    let devfs = Box::leak(Box::new(DevFS::new()));
+    if let Err(e) = devfs.register_devices() {
+        cprintln!(WARN_COLOR, "[WARN] Some devices not registered: {}", e);
+    }
+    if let Err(e) = mount_devfs(devfs) {
+        cprintln!(WARN_COLOR, "[WARN] /dev not mounted: {}", e);
+    }
+    load_initrd(&ctx);
 
     loop {}
 }
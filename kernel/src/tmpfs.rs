@@ -0,0 +1,401 @@
+//! tmpfs, a file system living in the kernel heap. It is mounted on `/` before any
+//! disk file system is available, and on `/tmp` after one becomes the root.
+//! Its size is limited, so a full tmpfs fails with `NoSpace` instead of
+//! running the kernel out of memory.
+
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+use i386::fs::{FileSystem, FileError, FileHandle, FileType, Metadata, DirEntry, split_path};
+use shared::layout::{KERNEL_HEAP_SIZE, INITRD_SIZE};
+
+use crate::vfs::{FsContext, MountId, VfsError, VFS};
+
+/// The index of a node, which is also its `FileHandle`
+type Ino = usize;
+
+const ROOT_INO: Ino = 0;
+/// The longest name in a directory
+pub const TMPFS_NAME_MAX: usize = 255;
+/// The data of a mounted tmpfs takes at most a quarter of the heap
+pub const TMPFS_MAX_SIZE: usize = KERNEL_HEAP_SIZE / 4;
+// the initrd is unpacked into the root tmpfs, its archive is larger than the contents
+const _: () = assert!(INITRD_SIZE <= TMPFS_MAX_SIZE);
+/// The number of files in a mounted tmpfs
+pub const TMPFS_MAX_NODES: usize = 256;
+
+enum NodeKind {
+    File(Vec<u8>),
+    Dir {
+        entries: BTreeMap<String, Ino>,
+        /// the directory `..` refers to, the root is its own parent
+        parent: Ino
+    },
+    Symlink(Vec<u8>)
+}
+
+struct Node {
+    kind: NodeKind,
+    /// entries referring to the node, and `..` of subdirectories
+    links: u32
+}
+
+impl Node {
+    fn file_type(&self) -> FileType {
+        match self.kind {
+            NodeKind::File(_) => FileType::File,
+            NodeKind::Dir { .. } => FileType::Directory,
+            NodeKind::Symlink(_) => FileType::Symlink
+        }
+    }
+}
+
+pub struct TmpFS {
+    /// freed nodes are None and reused
+    nodes: Vec<Option<Node>>,
+    /// bytes of file data, link targets and names
+    used: usize,
+    /// the number of nodes in use
+    count: usize,
+    max_size: usize,
+    max_nodes: usize
+}
+
+impl TmpFS {
+    /// An empty file system with at most `max_nodes` files and `max_size` bytes
+    /// of data in them
+    pub fn new(max_size: usize, max_nodes: usize) -> Self {
+        let root = Node {
+            kind: NodeKind::Dir { entries: BTreeMap::new(), parent: ROOT_INO },
+            links: 2
+        };
+        let mut nodes = Vec::new();
+        nodes.push(Some(root));
+        Self { nodes, used: 0, count: 1, max_size, max_nodes }
+    }
+
+    fn node(&self, ino: Ino) -> Result<&Node, FileError> {
+        self.nodes.get(ino).and_then(Option::as_ref).ok_or(FileError::BadHandle)
+    }
+
+    fn node_mut(&mut self, ino: Ino) -> Result<&mut Node, FileError> {
+        self.nodes.get_mut(ino).and_then(Option::as_mut).ok_or(FileError::BadHandle)
+    }
+
+    fn entries(&self, dir: Ino) -> Result<&BTreeMap<String, Ino>, FileError> {
+        match &self.node(dir)?.kind {
+            NodeKind::Dir { entries, .. } => Ok(entries),
+            _ => Err(FileError::NotADirectory)
+        }
+    }
+
+    fn entries_mut(&mut self, dir: Ino) -> Result<&mut BTreeMap<String, Ino>, FileError> {
+        match &mut self.node_mut(dir)?.kind {
+            NodeKind::Dir { entries, .. } => Ok(entries),
+            _ => Err(FileError::NotADirectory)
+        }
+    }
+
+    fn parent(&self, dir: Ino) -> Result<Ino, FileError> {
+        match self.node(dir)?.kind {
+            NodeKind::Dir { parent, .. } => Ok(parent),
+            _ => Err(FileError::NotADirectory)
+        }
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, FileError> {
+        match name {
+            "." => Ok(dir),
+            ".." => self.parent(dir),
+            _ => self.entries(dir)?.get(name).copied().ok_or(FileError::NotFound)
+        }
+    }
+
+    fn open_path(&self, path: &str) -> Result<Ino, FileError> {
+        let mut ino = ROOT_INO;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            ino = self.lookup(ino, name)?;
+        }
+        Ok(ino)
+    }
+
+    /// Take `size` bytes from the space left
+    fn reserve(&mut self, size: usize) -> Result<(), FileError> {
+        match self.used.checked_add(size) {
+            Some(used) if used <= self.max_size => {
+                self.used = used;
+                Ok(())
+            },
+            _ => Err(FileError::NoSpace)
+        }
+    }
+
+    /// Add `node` as `name` in the directory `dir`
+    fn add(&mut self, dir: Ino, name: &str, node: Node) -> Result<Ino, FileError> {
+        if name.len() > TMPFS_NAME_MAX {
+            return Err(FileError::InvalidPath)
+        }
+        if self.entries(dir)?.contains_key(name) {
+            return Err(FileError::AlreadyExists)
+        }
+        if self.count >= self.max_nodes {
+            return Err(FileError::NoSpace)
+        }
+        self.reserve(name.len())?;
+
+        let ino = match self.nodes.iter().position(Option::is_none) {
+            Some(ino) => ino,
+            None => {
+                self.nodes.push(None);
+                self.nodes.len() - 1
+            }
+        };
+        self.nodes[ino] = Some(node);
+        self.count += 1;
+        self.entries_mut(dir)?.insert(String::from(name), ino);
+        Ok(ino)
+    }
+
+    /// Drop a link to `ino`, the node is freed with its last link
+    fn release(&mut self, ino: Ino) -> Result<(), FileError> {
+        let node = self.node_mut(ino)?;
+        node.links = node.links.saturating_sub(1);
+        let last = match node.kind {
+            // `.` of a directory is not a real link
+            NodeKind::Dir { .. } => node.links <= 1,
+            _ => node.links == 0
+        };
+        if last {
+            if let Some(Node { kind: NodeKind::File(data) | NodeKind::Symlink(data), .. }) = self.nodes[ino].take() {
+                self.used -= data.len();
+            }
+            self.count -= 1;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the directory `dir` is `ancestor` or inside it
+    fn is_within(&self, dir: Ino, ancestor: Ino) -> Result<bool, FileError> {
+        let mut current = dir;
+        while current != ancestor {
+            if current == ROOT_INO {
+                return Ok(false)
+            }
+            current = self.parent(current)?;
+        }
+        Ok(true)
+    }
+
+    fn file_data(&mut self, file: FileHandle) -> Result<&mut Vec<u8>, FileError> {
+        match &mut self.node_mut(file.0 as Ino)?.kind {
+            NodeKind::File(data) => Ok(data),
+            NodeKind::Dir { .. } => Err(FileError::IsADirectory),
+            NodeKind::Symlink(_) => Err(FileError::NotSupported)
+        }
+    }
+
+    /// Resize `file` to `size` bytes, new bytes are 0
+    fn resize(&mut self, file: FileHandle, size: usize) -> Result<(), FileError> {
+        let len = self.file_data(file)?.len();
+        if size > len {
+            self.reserve(size - len)?;
+            let data = self.file_data(file)?;
+            if data.try_reserve_exact(size - len).is_err() {
+                self.used -= size - len;
+                return Err(FileError::NoSpace)
+            }
+            data.resize(size, 0);
+        } else {
+            let data = self.file_data(file)?;
+            data.truncate(size);
+            data.shrink_to_fit();
+            self.used -= len - size;
+        }
+        Ok(())
+    }
+
+    /// Move the entry `name` of `dir` to `new_name` in `new_dir`,
+    /// replacing a file already there
+    fn move_entry(&mut self, dir: Ino, name: &str, new_dir: Ino, new_name: &str) -> Result<(), FileError> {
+        let ino = self.lookup(dir, name)?;
+        let is_dir = self.node(ino)?.file_type() == FileType::Directory;
+        if self.node(new_dir)?.file_type() != FileType::Directory {
+            return Err(FileError::NotADirectory)
+        }
+        if new_name.len() > TMPFS_NAME_MAX {
+            return Err(FileError::InvalidPath)
+        }
+        if is_dir && self.is_within(new_dir, ino)? {
+            return Err(FileError::InvalidPath)
+        }
+
+        let replaced = match self.lookup(new_dir, new_name) {
+            // both names are the same entry
+            Ok(target) if target == ino => return Ok(()),
+            Ok(target) => {
+                if is_dir || self.node(target)?.file_type() == FileType::Directory {
+                    return Err(FileError::AlreadyExists)
+                }
+                Some(target)
+            },
+            Err(FileError::NotFound) => None,
+            Err(e) => return Err(e)
+        };
+
+        // the new name may be longer, the old one is given back below.
+        // Reserve it before the target is released, so a full tmpfs leaves both files.
+        self.reserve(new_name.len())?;
+        if let Some(target) = replaced {
+            self.entries_mut(new_dir)?.remove(new_name);
+            self.used -= new_name.len();
+            self.release(target)?;
+        }
+        self.entries_mut(dir)?.remove(name);
+        self.used -= name.len();
+        self.entries_mut(new_dir)?.insert(String::from(new_name), ino);
+
+        // `..` of a directory moves to the new parent
+        if is_dir && dir != new_dir {
+            if let NodeKind::Dir { parent, .. } = &mut self.node_mut(ino)?.kind {
+                *parent = new_dir;
+            }
+            self.node_mut(dir)?.links -= 1;
+            self.node_mut(new_dir)?.links += 1;
+        }
+        Ok(())
+    }
+}
+
+impl FileSystem for TmpFS {
+    fn open(&self, path: &str) -> Result<FileHandle, FileError> {
+        Ok(FileHandle(self.open_path(path)? as u64))
+    }
+
+    fn create(&mut self, path: &str) -> Result<FileHandle, FileError> {
+        let (parent, name) = split_path(path)?;
+        let dir = self.open_path(parent)?;
+        let ino = self.add(dir, name, Node { kind: NodeKind::File(Vec::new()), links: 1 })?;
+        Ok(FileHandle(ino as u64))
+    }
+
+    fn read_at(&self, file: FileHandle, offset: u64, buf: &mut [u8]) -> Result<usize, FileError> {
+        let data = match &self.node(file.0 as Ino)?.kind {
+            NodeKind::File(data) => data,
+            NodeKind::Dir { .. } => return Err(FileError::IsADirectory),
+            NodeKind::Symlink(_) => return Err(FileError::NotSupported)
+        };
+        let start = match usize::try_from(offset) {
+            Ok(offset) => data.len().min(offset),
+            // beyond any file in memory
+            Err(_) => return Ok(0)
+        };
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    /// Write at `offset`, a gap after the end of the file is filled with 0.
+    fn write_at(&mut self, file: FileHandle, offset: u64, buf: &[u8]) -> Result<usize, FileError> {
+        let end = usize::try_from(offset).ok()
+            .and_then(|offset| offset.checked_add(buf.len()))
+            .ok_or(FileError::NoSpace)?;
+        if end > self.file_data(file)?.len() {
+            self.resize(file, end)?;
+        }
+        self.file_data(file)?[end - buf.len()..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn stat(&self, file: FileHandle) -> Result<Metadata, FileError> {
+        let node = self.node(file.0 as Ino)?;
+        let (size, perm) = match &node.kind {
+            NodeKind::File(data) => (data.len(), 0o644),
+            NodeKind::Dir { entries, .. } => (entries.len(), 0o755),
+            NodeKind::Symlink(target) => (target.len(), 0o777)
+        };
+        Ok(Metadata {
+            file_type: node.file_type(),
+            size: size as u64,
+            perm,
+            links: node.links,
+            ino: file.0
+        })
+    }
+
+    fn truncate(&mut self, file: FileHandle, size: u64) -> Result<(), FileError> {
+        let size = usize::try_from(size).or(Err(FileError::NoSpace))?;
+        self.resize(file, size)
+    }
+
+    fn readdir(&self, dir: FileHandle, f: &mut dyn FnMut(&DirEntry)) -> Result<(), FileError> {
+        for (name, ino) in self.entries(dir.0 as Ino)? {
+            let mut entry = DirEntry::new(self.node(*ino)?.file_type(), *ino as u64);
+            entry.push(name.as_bytes());
+            f(&entry);
+        }
+        Ok(())
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), FileError> {
+        let (parent, name) = split_path(path)?;
+        let dir = self.open_path(parent)?;
+        let node = Node {
+            kind: NodeKind::Dir { entries: BTreeMap::new(), parent: dir },
+            links: 2
+        };
+        self.add(dir, name, node)?;
+        self.node_mut(dir)?.links += 1;
+        Ok(())
+    }
+
+    /// Remove a file, a symbolic link or an empty directory
+    fn unlink(&mut self, path: &str) -> Result<(), FileError> {
+        let (parent, name) = split_path(path)?;
+        let dir = self.open_path(parent)?;
+        let ino = self.lookup(dir, name)?;
+        if let NodeKind::Dir { entries, .. } = &self.node(ino)?.kind {
+            if !entries.is_empty() {
+                return Err(FileError::DirectoryNotEmpty)
+            }
+            self.node_mut(dir)?.links -= 1;
+        }
+        self.entries_mut(dir)?.remove(name);
+        self.used -= name.len();
+        self.release(ino)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FileError> {
+        let (parent, name) = split_path(from)?;
+        let (new_parent, new_name) = split_path(to)?;
+        let dir = self.open_path(parent)?;
+        let new_dir = self.open_path(new_parent)?;
+        self.move_entry(dir, name, new_dir, new_name)
+    }
+
+    fn symlink(&mut self, path: &str, target: &str) -> Result<(), FileError> {
+        let (parent, name) = split_path(path)?;
+        let dir = self.open_path(parent)?;
+        self.reserve(target.len())?;
+        let node = Node { kind: NodeKind::Symlink(Vec::from(target.as_bytes())), links: 1 };
+        if let Err(e) = self.add(dir, name, node) {
+            self.used -= target.len();
+            return Err(e)
+        }
+        Ok(())
+    }
+
+    fn readlink(&self, link: FileHandle, buf: &mut [u8]) -> Result<usize, FileError> {
+        match &self.node(link.0 as Ino)?.kind {
+            NodeKind::Symlink(target) => {
+                let len = buf.len().min(target.len());
+                buf[..len].copy_from_slice(&target[..len]);
+                Ok(len)
+            },
+            _ => Err(FileError::InvalidPath)
+        }
+    }
+}
+
+/// Mount a new tmpfs on the directory `path`
+pub fn mount_tmpfs(path: &str) -> Result<MountId, VfsError> {
+    let fs = Box::leak(Box::new(TmpFS::new(TMPFS_MAX_SIZE, TMPFS_MAX_NODES)));
+    VFS.lock().mount(&FsContext::new(), path, fs)
+}
@@ -0,0 +1,559 @@
+//! The virtual file system, which joins the file systems mounted on directories
+//! into a single tree. Paths are resolved here component by component, so `..`
+//! and symbolic links work across mount points, and a file system only sees paths
+//! without links relative to its own root.
+//! Every call takes the `FsContext` of the calling process for its working directory
+//! and file descriptors, so syscalls map to the methods of `Vfs` directly.
+
+pub mod path;
+pub mod file;
+
+use core::fmt;
+use i386::fs::{FileSystem, FileError, FileType, Metadata, DirEntry, FileHandle, split_path};
+use spin::Mutex;
+
+pub use path::{PathBuf, PATH_MAX};
+pub use file::*;
+
+/// The number of file systems mounted at the same time
+pub const MAX_MOUNTS: usize = 8;
+/// The number of symbolic links followed when resolving a path
+const SYMLINK_MAX_DEPTH: usize = 8;
+
+/// The index of a file system in the mount table
+pub type MountId = usize;
+/// The file system mounted on `/`
+const ROOT_MOUNT: MountId = 0;
+
+#[derive(Debug)]
+pub enum VfsError {
+    /// an error of the file system
+    File(FileError),
+    /// the file descriptor is not open, or not open for the operation
+    BadFd,
+    /// all file descriptors of the process are taken
+    TooManyOpenFiles,
+    /// the open file table of the system is full
+    FileTableFull,
+    /// the mount table is full
+    TooManyMounts,
+    /// no file system is mounted there, or on `/`
+    NotMounted,
+    /// the file system or the mount point is in use
+    Busy,
+    /// a path is longer than `PATH_MAX`
+    NameTooLong,
+    /// more than `SYMLINK_MAX_DEPTH` symbolic links, or a link where none is allowed
+    TooManyLinks,
+    /// renaming a file to another file system
+    CrossDevice,
+    /// seeking before the start of the file
+    InvalidSeek
+}
+
+impl From<FileError> for VfsError {
+    fn from(e: FileError) -> Self {
+        Self::File(e)
+    }
+}
+
+impl VfsError {
+    /// The error number of Linux, which syscalls return negated
+    pub const fn errno(&self) -> i32 {
+        match self {
+            Self::File(e) => match e {
+                FileError::NotFound => 2,
+                FileError::Io(_) | FileError::InvalidFileSystem => 5,
+                FileError::AlreadyExists => 17,
+                FileError::NotADirectory => 20,
+                FileError::IsADirectory => 21,
+                FileError::InvalidPath => 22,
+                FileError::NoSpace => 28,
+                FileError::ReadOnly => 30,
+                FileError::DirectoryNotEmpty => 39,
+                FileError::NotSupported => 95,
+                FileError::BadHandle => 116
+            },
+            Self::BadFd => 9,
+            Self::TooManyMounts => 12,
+            Self::Busy => 16,
+            Self::CrossDevice => 18,
+            Self::NotMounted | Self::InvalidSeek => 22,
+            Self::FileTableFull => 23,
+            Self::TooManyOpenFiles => 24,
+            Self::NameTooLong => 36,
+            Self::TooManyLinks => 40
+        }
+    }
+}
+
+impl fmt::Display for VfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Self::File(e) => return write!(f, "{}", e),
+            Self::BadFd => "VFS Error: bad file descriptor",
+            Self::TooManyOpenFiles => "VFS Error: too many open files",
+            Self::FileTableFull => "VFS Error: file table full",
+            Self::TooManyMounts => "VFS Error: mount table full",
+            Self::NotMounted => "VFS Error: not mounted",
+            Self::Busy => "VFS Error: busy",
+            Self::NameTooLong => "VFS Error: path too long",
+            Self::TooManyLinks => "VFS Error: too many symbolic links",
+            Self::CrossDevice => "VFS Error: cross device link",
+            Self::InvalidSeek => "VFS Error: invalid seek"
+        };
+        f.write_str(msg)
+    }
+}
+
+/// A file system mounted on a directory
+struct Mount {
+    fs: &'static mut (dyn FileSystem + Send),
+    /// the mount and the path of the directory covered, None for the root file system
+    point: Option<(MountId, PathBuf)>,
+    /// the absolute path of the mount point
+    abs: PathBuf
+}
+
+/// A resolved file, the path in its file system has no `.`, `..` or links
+#[derive(Clone, Copy)]
+struct Location {
+    mount: MountId,
+    path: PathBuf
+}
+
+impl Location {
+    const fn root() -> Self {
+        Self { mount: ROOT_MOUNT, path: PathBuf::new() }
+    }
+}
+
+const NO_MOUNT: Option<Mount> = None;
+
+pub struct Vfs {
+    mounts: [Option<Mount>; MAX_MOUNTS],
+    /// the open file descriptions of every process
+    files: [Option<OpenFile>; MAX_OPEN_FILES]
+}
+
+impl Vfs {
+    pub const fn new() -> Self {
+        Self {
+            mounts: [NO_MOUNT; MAX_MOUNTS],
+            files: [None; MAX_OPEN_FILES]
+        }
+    }
+
+    fn fs(&self, mount: MountId) -> Result<&(dyn FileSystem + Send), VfsError> {
+        match &self.mounts[mount] {
+            Some(m) => Ok(&*m.fs),
+            None => Err(VfsError::NotMounted)
+        }
+    }
+
+    fn fs_mut(&mut self, mount: MountId) -> Result<&mut (dyn FileSystem + Send), VfsError> {
+        match &mut self.mounts[mount] {
+            Some(m) => Ok(&mut *m.fs),
+            None => Err(VfsError::NotMounted)
+        }
+    }
+
+    /// The mount covering the directory at `loc`
+    fn mounted_on(&self, loc: &Location) -> Option<MountId> {
+        self.mounts.iter().position(|m| match m {
+            Some(Mount { point: Some((mount, path)), .. }) => *mount == loc.mount && *path == loc.path,
+            _ => false
+        })
+    }
+
+    /// Move `loc` to the root of the file systems mounted on it
+    fn cross(&self, loc: &mut Location) {
+        while let Some(mount) = self.mounted_on(loc) {
+            *loc = Location { mount, path: PathBuf::new() };
+        }
+    }
+
+    /// Move `loc` to its parent directory. The parent of the root of a file system
+    /// is the parent of its mount point.
+    fn parent(&self, loc: &mut Location) {
+        while loc.path.is_root() {
+            match self.mounts[loc.mount].as_ref().and_then(|m| m.point) {
+                Some((mount, path)) => *loc = Location { mount, path },
+                None => return
+            }
+        }
+        loc.path.pop();
+        self.cross(loc);
+    }
+
+    fn open_at(&self, loc: &Location) -> Result<(FileHandle, Metadata), VfsError> {
+        let fs = self.fs(loc.mount)?;
+        let file = fs.open(loc.path.as_str())?;
+        Ok((file, fs.stat(file)?))
+    }
+
+    /// Resolve `path` from the working directory of `ctx`. A symbolic link
+    /// in the last component is followed if `follow` is true.
+    fn resolve(&self, ctx: &FsContext, path: &str, follow: bool) -> Result<Location, VfsError> {
+        self.fs(ROOT_MOUNT)?;
+        let mut buf = match path.starts_with('/') {
+            true => PathBuf::concat(&[path])?,
+            false => PathBuf::concat(&[ctx.cwd.as_str(), "/", path])?
+        };
+        let mut loc = Location::root();
+        self.cross(&mut loc);
+        let mut pos = 0;
+        let mut links = 0;
+
+        loop {
+            let rest = buf.as_str()[pos..].trim_start_matches('/');
+            if rest.is_empty() {
+                return Ok(loc)
+            }
+            let (name, after) = rest.split_once('/').unwrap_or((rest, ""));
+            pos = buf.as_str().len() - after.len();
+            let last = after.trim_matches('/').is_empty();
+
+            match name {
+                "." => continue,
+                ".." => {
+                    self.parent(&mut loc);
+                    continue
+                },
+                _ => loc.path.push(name)?
+            }
+            let (file, meta) = self.open_at(&loc)?;
+            match meta.file_type {
+                FileType::Symlink if follow || !last => {
+                    links += 1;
+                    if links > SYMLINK_MAX_DEPTH {
+                        return Err(VfsError::TooManyLinks)
+                    }
+                    // readlink would silently cut a longer target
+                    if meta.size > PATH_MAX as u64 {
+                        return Err(VfsError::NameTooLong)
+                    }
+                    let mut target = [0_u8; PATH_MAX];
+                    let len = self.fs(loc.mount)?.readlink(file, &mut target)?;
+                    let target = core::str::from_utf8(&target[..len])
+                        .or(Err(VfsError::File(FileError::InvalidPath)))?;
+                    // the target replaces the link in the path
+                    loc.path.pop();
+                    if target.starts_with('/') {
+                        loc = Location::root();
+                        self.cross(&mut loc);
+                    }
+                    buf = PathBuf::concat(&[target, "/", after])?;
+                    pos = 0;
+                },
+                FileType::Directory => self.cross(&mut loc),
+                _ if !last => return Err(VfsError::File(FileError::NotADirectory)),
+                _ => ()
+            }
+        }
+    }
+
+    /// Resolve the directory containing the last component of `path`,
+    /// which is returned with it. The last component is not `.` or `..`.
+    fn resolve_parent<'a>(&self, ctx: &FsContext, path: &'a str) -> Result<(Location, &'a str), VfsError> {
+        let (parent, name) = split_path(path)?;
+        // the parent of `/name` is the root, not the working directory
+        let parent = if parent.is_empty() && path.starts_with('/') { "/" } else { parent };
+        let dir = self.resolve(ctx, parent, true)?;
+        if self.open_at(&dir)?.1.file_type != FileType::Directory {
+            return Err(VfsError::File(FileError::NotADirectory))
+        }
+        Ok((dir, name))
+    }
+
+    /// Resolve the parent of `path` and append the last component,
+    /// which must not be a mount point.
+    fn resolve_entry(&self, ctx: &FsContext, path: &str) -> Result<Location, VfsError> {
+        let (mut loc, name) = self.resolve_parent(ctx, path)?;
+        loc.path.push(name)?;
+        if self.mounted_on(&loc).is_some() {
+            return Err(VfsError::Busy)
+        }
+        Ok(loc)
+    }
+
+    /// The absolute path of `loc`
+    fn absolute(&self, loc: &Location) -> Result<PathBuf, VfsError> {
+        let mount = self.mounts[loc.mount].as_ref().ok_or(VfsError::NotMounted)?;
+        let mut path = mount.abs;
+        path.join(&loc.path)?;
+        Ok(path)
+    }
+
+    /// Mount `fs` on the directory `path`. The first file system must be mounted on `/`,
+    /// mounting another one on `/` later hides it.
+    pub fn mount(&mut self, ctx: &FsContext, path: &str, fs: &'static mut (dyn FileSystem + Send)) -> Result<MountId, VfsError> {
+        if self.mounts[ROOT_MOUNT].is_none() {
+            if !path.trim_start_matches('/').is_empty() {
+                return Err(VfsError::NotMounted)
+            }
+            self.mounts[ROOT_MOUNT] = Some(Mount { fs, point: None, abs: PathBuf::new() });
+            return Ok(ROOT_MOUNT)
+        }
+
+        let id = self.mounts.iter().position(Option::is_none).ok_or(VfsError::TooManyMounts)?;
+        let loc = self.resolve(ctx, path, true)?;
+        if self.open_at(&loc)?.1.file_type != FileType::Directory {
+            return Err(VfsError::File(FileError::NotADirectory))
+        }
+        let abs = self.absolute(&loc)?;
+        self.mounts[id] = Some(Mount { fs, point: Some((loc.mount, loc.path)), abs });
+        Ok(id)
+    }
+
+    /// Unmount the file system mounted on `path` and give it back. It must not have
+    /// open files or file systems mounted on its directories.
+    pub fn unmount(&mut self, ctx: &FsContext, path: &str) -> Result<&'static mut (dyn FileSystem + Send), VfsError> {
+        let loc = self.resolve(ctx, path, true)?;
+        if !loc.path.is_root() {
+            return Err(VfsError::NotMounted)
+        }
+        let id = loc.mount;
+        let covering = self.mounts.iter().flatten().any(|m| matches!(m.point, Some((mount, _)) if mount == id));
+        let open = self.files.iter().flatten().any(|file| file.mount == id);
+        if covering || open {
+            return Err(VfsError::Busy)
+        }
+        self.fs_mut(id)?.sync()?;
+        match self.mounts[id].take() {
+            Some(mount) => Ok(mount.fs),
+            None => Err(VfsError::NotMounted)
+        }
+    }
+
+    /// Write cached data of every file system to its device
+    pub fn sync(&mut self) -> Result<(), VfsError> {
+        for mount in self.mounts.iter_mut().flatten() {
+            mount.fs.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Open the file at `path` with the `O_*` flags, returns a new file descriptor of `ctx`
+    pub fn open(&mut self, ctx: &mut FsContext, path: &str, flags: u32) -> Result<Fd, VfsError> {
+        let fd = ctx.free_fd().ok_or(VfsError::TooManyOpenFiles)?;
+        let id = self.files.iter().position(Option::is_none).ok_or(VfsError::FileTableFull)?;
+
+        let loc = match self.resolve(ctx, path, flags & O_NOFOLLOW == 0) {
+            Ok(_) if flags & O_CREATE != 0 && flags & O_EXCL != 0 => {
+                return Err(VfsError::File(FileError::AlreadyExists))
+            },
+            Ok(loc) => loc,
+            Err(VfsError::File(FileError::NotFound)) if flags & O_CREATE != 0 => {
+                let loc = self.resolve_entry(ctx, path)?;
+                self.fs_mut(loc.mount)?.create(loc.path.as_str())?;
+                loc
+            },
+            Err(e) => return Err(e)
+        };
+
+        let (file, meta) = self.open_at(&loc)?;
+        match meta.file_type {
+            FileType::Directory if flags & (O_WRITE | O_TRUNC) != 0 => {
+                return Err(VfsError::File(FileError::IsADirectory))
+            },
+            FileType::Directory => (),
+            _ if flags & O_DIRECTORY != 0 => return Err(VfsError::File(FileError::NotADirectory)),
+            FileType::Symlink => return Err(VfsError::TooManyLinks),
+            _ => ()
+        }
+        if flags & O_TRUNC != 0 && flags & O_WRITE != 0 {
+            self.fs_mut(loc.mount)?.truncate(file, 0)?;
+        }
+
+        self.files[id] = Some(OpenFile { mount: loc.mount, file, offset: 0, flags, refs: 1 });
+        ctx.fds[fd] = Some(id);
+        Ok(fd)
+    }
+
+    /// Drop a reference to the open file description `id`
+    fn release(&mut self, id: FileId) {
+        if let Some(file) = &mut self.files[id] {
+            file.refs -= 1;
+            if file.refs == 0 {
+                self.files[id] = None;
+            }
+        }
+    }
+
+    fn description(&self, ctx: &FsContext, fd: Fd) -> Result<(FileId, OpenFile), VfsError> {
+        let id = ctx.file(fd).ok_or(VfsError::BadFd)?;
+        let file = self.files[id].ok_or(VfsError::BadFd)?;
+        Ok((id, file))
+    }
+
+    pub fn close(&mut self, ctx: &mut FsContext, fd: Fd) -> Result<(), VfsError> {
+        let (id, _) = self.description(ctx, fd)?;
+        ctx.fds[fd] = None;
+        self.release(id);
+        Ok(())
+    }
+
+    /// Close every file descriptor of `ctx`, when the process exits
+    pub fn close_all(&mut self, ctx: &mut FsContext) {
+        for fd in 0..MAX_FDS {
+            let _ = self.close(ctx, fd);
+        }
+    }
+
+    /// A new file descriptor sharing the open file description of `fd`
+    pub fn dup(&mut self, ctx: &mut FsContext, fd: Fd) -> Result<Fd, VfsError> {
+        let new_fd = ctx.free_fd().ok_or(VfsError::TooManyOpenFiles)?;
+        self.dup2(ctx, fd, new_fd)
+    }
+
+    /// Make `new_fd` share the open file description of `fd`, closing it first if needed
+    pub fn dup2(&mut self, ctx: &mut FsContext, fd: Fd, new_fd: Fd) -> Result<Fd, VfsError> {
+        let (id, _) = self.description(ctx, fd)?;
+        if new_fd >= MAX_FDS {
+            return Err(VfsError::BadFd)
+        }
+        if new_fd == fd {
+            return Ok(fd)
+        }
+        let _ = self.close(ctx, new_fd);
+        if let Some(file) = &mut self.files[id] {
+            file.refs += 1;
+        }
+        ctx.fds[new_fd] = Some(id);
+        Ok(new_fd)
+    }
+
+    /// The context of a new process, which shares the open files of `ctx`
+    pub fn fork(&mut self, ctx: &FsContext) -> FsContext {
+        for id in ctx.fds.iter().flatten() {
+            if let Some(file) = &mut self.files[*id] {
+                file.refs += 1;
+            }
+        }
+        FsContext { cwd: ctx.cwd, fds: ctx.fds }
+    }
+
+    /// Read from the position of `fd`, which moves past the bytes read
+    pub fn read(&mut self, ctx: &FsContext, fd: Fd, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let (id, file) = self.description(ctx, fd)?;
+        if !file.readable() {
+            return Err(VfsError::BadFd)
+        }
+        let len = self.fs(file.mount)?.read_at(file.file, file.offset, buf)?;
+        self.files[id] = Some(OpenFile { offset: file.offset + len as u64, ..file });
+        Ok(len)
+    }
+
+    /// Write to the position of `fd`, or to the end with `O_APPEND`
+    pub fn write(&mut self, ctx: &FsContext, fd: Fd, buf: &[u8]) -> Result<usize, VfsError> {
+        let (id, file) = self.description(ctx, fd)?;
+        if !file.writable() {
+            return Err(VfsError::BadFd)
+        }
+        let fs = self.fs_mut(file.mount)?;
+        let offset = match file.flags & O_APPEND != 0 {
+            true => fs.stat(file.file)?.size,
+            false => file.offset
+        };
+        let len = fs.write_at(file.file, offset, buf)?;
+        self.files[id] = Some(OpenFile { offset: offset + len as u64, ..file });
+        Ok(len)
+    }
+
+    /// Move the position of `fd`, returns the new position
+    pub fn seek(&mut self, ctx: &FsContext, fd: Fd, pos: SeekFrom) -> Result<u64, VfsError> {
+        let (id, file) = self.description(ctx, fd)?;
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(delta) => (file.offset, delta),
+            SeekFrom::End(delta) => (self.fs(file.mount)?.stat(file.file)?.size, delta)
+        };
+        let offset = match delta < 0 {
+            true => base.checked_sub(delta.unsigned_abs()),
+            false => base.checked_add(delta as u64)
+        }.ok_or(VfsError::InvalidSeek)?;
+        self.files[id] = Some(OpenFile { offset, ..file });
+        Ok(offset)
+    }
+
+    pub fn fstat(&self, ctx: &FsContext, fd: Fd) -> Result<Metadata, VfsError> {
+        let (_, file) = self.description(ctx, fd)?;
+        Ok(self.fs(file.mount)?.stat(file.file)?)
+    }
+
+    /// Change the size of the file open for writing as `fd`
+    pub fn ftruncate(&mut self, ctx: &FsContext, fd: Fd, size: u64) -> Result<(), VfsError> {
+        let (_, file) = self.description(ctx, fd)?;
+        if !file.writable() {
+            return Err(VfsError::BadFd)
+        }
+        Ok(self.fs_mut(file.mount)?.truncate(file.file, size)?)
+    }
+
+    /// Call `f` with every entry of the directory open as `fd`, except `.` and `..`
+    pub fn readdir(&self, ctx: &FsContext, fd: Fd, f: &mut dyn FnMut(&DirEntry)) -> Result<(), VfsError> {
+        let (_, file) = self.description(ctx, fd)?;
+        Ok(self.fs(file.mount)?.readdir(file.file, f)?)
+    }
+
+    /// The metadata of the file at `path`, following symbolic links
+    pub fn stat(&self, ctx: &FsContext, path: &str) -> Result<Metadata, VfsError> {
+        let loc = self.resolve(ctx, path, true)?;
+        Ok(self.open_at(&loc)?.1)
+    }
+
+    /// The metadata of the file at `path`, or of the symbolic link there
+    pub fn lstat(&self, ctx: &FsContext, path: &str) -> Result<Metadata, VfsError> {
+        let loc = self.resolve(ctx, path, false)?;
+        Ok(self.open_at(&loc)?.1)
+    }
+
+    pub fn mkdir(&mut self, ctx: &FsContext, path: &str) -> Result<(), VfsError> {
+        let loc = self.resolve_entry(ctx, path)?;
+        Ok(self.fs_mut(loc.mount)?.mkdir(loc.path.as_str())?)
+    }
+
+    /// Remove the file or empty directory at `path`, symbolic links are removed
+    /// instead of their targets
+    pub fn unlink(&mut self, ctx: &FsContext, path: &str) -> Result<(), VfsError> {
+        let loc = self.resolve_entry(ctx, path)?;
+        Ok(self.fs_mut(loc.mount)?.unlink(loc.path.as_str())?)
+    }
+
+    /// Move the file at `from` to `to` in the same file system
+    pub fn rename(&mut self, ctx: &FsContext, from: &str, to: &str) -> Result<(), VfsError> {
+        let src = self.resolve_entry(ctx, from)?;
+        let dest = self.resolve_entry(ctx, to)?;
+        if src.mount != dest.mount {
+            return Err(VfsError::CrossDevice)
+        }
+        Ok(self.fs_mut(src.mount)?.rename(src.path.as_str(), dest.path.as_str())?)
+    }
+
+    /// Create a symbolic link at `path` pointing to `target`
+    pub fn symlink(&mut self, ctx: &FsContext, target: &str, path: &str) -> Result<(), VfsError> {
+        let loc = self.resolve_entry(ctx, path)?;
+        Ok(self.fs_mut(loc.mount)?.symlink(loc.path.as_str(), target)?)
+    }
+
+    /// Read the target of the symbolic link at `path` into `buf`, returns its length
+    pub fn readlink(&self, ctx: &FsContext, path: &str, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let loc = self.resolve(ctx, path, false)?;
+        let (file, _) = self.open_at(&loc)?;
+        Ok(self.fs(loc.mount)?.readlink(file, buf)?)
+    }
+
+    /// Change the working directory of `ctx` to the directory at `path`
+    pub fn chdir(&self, ctx: &mut FsContext, path: &str) -> Result<(), VfsError> {
+        let loc = self.resolve(ctx, path, true)?;
+        if self.open_at(&loc)?.1.file_type != FileType::Directory {
+            return Err(VfsError::File(FileError::NotADirectory))
+        }
+        ctx.cwd = self.absolute(&loc)?;
+        Ok(())
+    }
+}
+
+/// The virtual file system of the kernel
+pub static VFS: Mutex<Vfs> = Mutex::new(Vfs::new());
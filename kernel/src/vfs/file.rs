@@ -0,0 +1,97 @@
+//! Open files. An open file description keeps the position and the mode of a file
+//! opened by `Vfs::open`, it lives in the global table of the VFS and is shared by
+//! every file descriptor referring to it (after `dup` or `fork`), so it counts them.
+//! File descriptors are small numbers indexing the table of a process.
+
+use i386::fs::FileHandle;
+
+use super::{path::PathBuf, MountId};
+
+/// The number of open file descriptions in the system
+pub const MAX_OPEN_FILES: usize = 64;
+/// The number of file descriptors of a process
+pub const MAX_FDS: usize = 16;
+
+/// open the file for reading
+pub const O_READ: u32 = 1 << 0;
+/// open the file for writing
+pub const O_WRITE: u32 = 1 << 1;
+/// create the file if it does not exist
+pub const O_CREATE: u32 = 1 << 2;
+/// with `O_CREATE`, fail if the file exists
+pub const O_EXCL: u32 = 1 << 3;
+/// truncate the file to 0 bytes
+pub const O_TRUNC: u32 = 1 << 4;
+/// every write goes to the end of the file
+pub const O_APPEND: u32 = 1 << 5;
+/// fail if the file is not a directory
+pub const O_DIRECTORY: u32 = 1 << 6;
+/// do not follow a symbolic link in the last component
+pub const O_NOFOLLOW: u32 = 1 << 7;
+
+/// The index of an open file description in the table of the VFS
+pub type FileId = usize;
+/// A file descriptor of a process
+pub type Fd = usize;
+
+/// An open file description
+#[derive(Clone, Copy)]
+pub struct OpenFile {
+    pub mount: MountId,
+    pub file: FileHandle,
+    pub offset: u64,
+    pub flags: u32,
+    /// the number of file descriptors referring to the description
+    pub refs: usize
+}
+
+impl OpenFile {
+    pub const fn readable(&self) -> bool {
+        self.flags & O_READ != 0
+    }
+
+    pub const fn writable(&self) -> bool {
+        self.flags & O_WRITE != 0
+    }
+}
+
+/// Where `Vfs::seek` moves the position to
+#[derive(Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64)
+}
+
+/// The file system state of a process: its working directory and file descriptors.
+/// A new process gets a copy of its parent's with `Vfs::fork`.
+pub struct FsContext {
+    /// the absolute path of the working directory, without symbolic links
+    pub(super) cwd: PathBuf,
+    pub(super) fds: [Option<FileId>; MAX_FDS]
+}
+
+impl FsContext {
+    /// A context in the root directory without open files
+    pub const fn new() -> Self {
+        Self { cwd: PathBuf::new(), fds: [None; MAX_FDS] }
+    }
+
+    /// The absolute path of the working directory
+    pub fn cwd(&self) -> &str {
+        match self.cwd.is_root() {
+            true => "/",
+            false => self.cwd.as_str()
+        }
+    }
+
+    /// The description `fd` refers to
+    pub(super) fn file(&self, fd: Fd) -> Option<FileId> {
+        self.fds.get(fd).copied().flatten()
+    }
+
+    /// The lowest free file descriptor
+    pub(super) fn free_fd(&self) -> Option<Fd> {
+        self.fds.iter().position(|fd| fd.is_none())
+    }
+}
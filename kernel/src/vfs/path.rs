@@ -0,0 +1,73 @@
+//! Paths are kept in fixed size buffers, since the kernel has no heap.
+
+use super::VfsError;
+
+/// The longest path the VFS handles, including the paths of mount points
+pub const PATH_MAX: usize = 256;
+
+/// A path made of components separated by `/`. Paths built with `push` have no `.`,
+/// `..` or repeated slashes and start with `/`, except the root, which is empty.
+#[derive(Clone, Copy)]
+pub struct PathBuf {
+    buf: [u8; PATH_MAX],
+    len: usize
+}
+
+impl PathBuf {
+    pub const fn new() -> Self {
+        Self { buf: [0; PATH_MAX], len: 0 }
+    }
+
+    /// Copy `parts` one after another, e.g. the target of a symbolic link
+    /// and the rest of the path it was found in
+    pub fn concat(parts: &[&str]) -> Result<Self, VfsError> {
+        let mut path = Self::new();
+        for part in parts {
+            path.append(part)?;
+        }
+        Ok(path)
+    }
+
+    fn append(&mut self, s: &str) -> Result<(), VfsError> {
+        if self.len + s.len() > PATH_MAX {
+            return Err(VfsError::NameTooLong)
+        }
+        self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only built from `&str`
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append the component `name`
+    pub fn push(&mut self, name: &str) -> Result<(), VfsError> {
+        if self.len + 1 + name.len() > PATH_MAX {
+            return Err(VfsError::NameTooLong)
+        }
+        self.append("/")?;
+        self.append(name)
+    }
+
+    /// Remove the last component, the root stays the root
+    pub fn pop(&mut self) {
+        self.len = self.buf[..self.len].iter().rposition(|&c| c == b'/').unwrap_or(0);
+    }
+
+    /// Append every component of `other`
+    pub fn join(&mut self, other: &PathBuf) -> Result<(), VfsError> {
+        self.append(other.as_str())
+    }
+}
+
+impl PartialEq for PathBuf {
+    fn eq(&self, other: &Self) -> bool {
+        self.buf[..self.len] == other.buf[..other.len]
+    }
+}